@@ -6,7 +6,7 @@ use std::{
 use structopt::StructOpt;
 use tempfile::tempfile;
 use tokio::io::AsyncWriteExt;
-use zstd_seekable_s3::{SeekableDecompress, StreamCompress};
+use zstd_seekable_s3::{SeekableDecompress, StreamCompress, ZstdError};
 
 #[derive(StructOpt)]
 #[structopt(
@@ -85,7 +85,7 @@ async fn main() {
         while let Some(ebytes) = compressed_lines.next().await {
             match ebytes {
                 Ok(bytes) => tempfile.write_all(&bytes).await.unwrap(),
-                Err(e) => panic!("{}", zstd_seekable::Error::from(e)),
+                Err(e) => panic!("{}", ZstdError::from(e)),
             }
         }
     }
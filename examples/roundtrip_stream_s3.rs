@@ -14,7 +14,9 @@ use std::{
     io::{Read, Seek},
 };
 use structopt::StructOpt;
-use zstd_seekable_s3::{GetSeekableObject, SeekableDecompress, StreamCompress, StreamUploadParts};
+use zstd_seekable_s3::{
+    GetSeekableObject, SeekableDecompress, StreamCompress, StreamUploadParts, ZstdError,
+};
 
 #[derive(StructOpt)]
 #[structopt(
@@ -132,7 +134,8 @@ fn main() {
 
         // We don't know the size of the output so we must either dump the
         // content to memory or use multi-part uploads. We go with the latter
-        // for the example.
+        // for the example. We do it by hand to show how the pieces fit
+        // together: upload_compressed_object does all of this for you.
         let req = CreateMultipartUploadRequest {
             bucket: opt.bucket.to_owned(),
             key: opt.key.to_owned(),
@@ -159,7 +162,7 @@ fn main() {
         // multi-part upload if something went wrong.
         #[derive(Debug)]
         enum Error {
-            CompressionError(zstd_seekable::Error),
+            CompressionError(ZstdError),
             PartUploadError(RusotoError<UploadPartError>),
         }
 
@@ -242,10 +245,7 @@ fn main() {
             ..Default::default()
         };
         // We get a wrapper over S3 object that does knows how to do seeking of a file. Note however that this is just the raw data!
-        let seekable_raw_object = s3
-            .get_seekable_object(&runtime, None, req)
-            .unwrap()
-            .unwrap();
+        let seekable_raw_object = s3.get_seekable_object(&runtime, None, req).unwrap();
         // We wrap the seekable S3 object with a shim that actually knows about the
         // compression.
         let mut seekable_uncompressed_object =
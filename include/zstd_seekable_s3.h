@@ -0,0 +1,40 @@
+/* C API of zstd-seekable-s3, built with the ffi feature. See src/ffi.rs. */
+#ifndef ZSTD_SEEKABLE_S3_H
+#define ZSTD_SEEKABLE_S3_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct ZssReader zss_reader;
+typedef struct ZssWriter zss_writer;
+
+/* Message of the last error on this thread, or NULL. */
+const char *zss_last_error(void);
+
+/* Region may be NULL for the default one. Returns NULL on error. */
+zss_reader *zss_open(const char *region, const char *bucket, const char *key);
+/* Returns the number of bytes read, or -1 on error. */
+int64_t zss_read_at(zss_reader *reader, uint64_t offset, uint8_t *buf, size_t len);
+/* Returns the decompressed size, or -1 on error. */
+int64_t zss_length(zss_reader *reader);
+void zss_close(zss_reader *reader);
+
+/* Frame size may be 0 for the default one. Returns NULL on error. */
+zss_writer *zss_compress_begin(const char *region, const char *bucket, const char *key,
+                               int level, size_t frame_size);
+/* Returns 0, or -1 on error. */
+int zss_compress_write(zss_writer *writer, const uint8_t *data, size_t len);
+/* Frees the writer. Returns 0 once the object is uploaded, or -1 on error. */
+int zss_compress_finish(zss_writer *writer);
+/* Frees the writer, aborting the upload instead of finishing the object. */
+void zss_compress_abort(zss_writer *writer);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,70 @@
+use arrow::datatypes::SchemaRef;
+use arrow::error::ArrowError;
+use arrow::ipc::reader::FileReader;
+use arrow::record_batch::RecordBatch;
+use std::io::{Read, Seek};
+
+// Reads record batches out of an Arrow IPC file (Feather version 2), such as
+// the decompressed data of a seekable object. The footer tells where each
+// batch is so reading one seeks straight to it: batches that aren't asked for
+// are never fetched or decompressed.
+//
+// The reader makes lots of small reads, wrap the data in a BufReader if
+// every read going to the underlying object is costly.
+pub struct ArrowIpcReader<R: Read + Seek> {
+    reader: FileReader<R>,
+}
+
+impl<R: Read + Seek> std::fmt::Debug for ArrowIpcReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArrowIpcReader")
+            .field("schema", &self.reader.schema())
+            .field("num_batches", &self.reader.num_batches())
+            .finish()
+    }
+}
+
+impl<R: Read + Seek> ArrowIpcReader<R> {
+    // Reads the footer and schema of the file. If a projection is given, only
+    // the columns with those indices are read out of each batch.
+    pub fn new(data: R, projection: Option<Vec<usize>>) -> Result<Self, ArrowError> {
+        Ok(ArrowIpcReader {
+            reader: FileReader::try_new(data, projection)?,
+        })
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        self.reader.schema()
+    }
+
+    pub fn num_batches(&self) -> usize {
+        self.reader.num_batches()
+    }
+
+    // Reads the batch with the given index, counting from 0.
+    pub fn read_batch(&mut self, index: usize) -> Result<RecordBatch, ArrowError> {
+        if index >= self.reader.num_batches() {
+            return Err(ArrowError::InvalidArgumentError(format!(
+                "Batch {} requested but the file only has {}",
+                index,
+                self.reader.num_batches()
+            )));
+        }
+        self.reader.set_index(index)?;
+        self.reader
+            .next()
+            .unwrap_or_else(|| Err(ArrowError::IoError(format!("Batch {} is missing", index))))
+    }
+
+    // Reads the batches with the given indices, in the order given.
+    pub fn read_batches(&mut self, indices: &[usize]) -> Result<Vec<RecordBatch>, ArrowError> {
+        indices
+            .iter()
+            .map(|&index| self.read_batch(index))
+            .collect()
+    }
+
+    pub fn into_inner(self) -> FileReader<R> {
+        self.reader
+    }
+}
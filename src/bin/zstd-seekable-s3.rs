@@ -0,0 +1,297 @@
+use rusoto_core::Region;
+use rusoto_s3::S3Client;
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use zstd_seekable_s3::{
+    download_resumable, fetch_object_index, inventory, repair_object, sync_directory,
+    upload_compressed_reader, verify_object, CompressionArgs, DownloadOptions, FetchRangesOptions,
+    GetSeekableObject, InventoryOptions, ObjectArgs, RepairTarget, RetryPolicy, SeekableDecompress,
+    SyncOptions, UploadConfig, UploadOptions, SEEK_TABLE_OBJECT_SUFFIX,
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "zstd-seekable-s3",
+    about = "Work with seekable zstd-compressed S3 objects.",
+    rename_all = "kebab"
+)]
+struct Opt {
+    #[structopt(long, help = "Region the bucket is in.")]
+    region: Region,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+enum Command {
+    #[structopt(about = "Compress a file, or stdin, into an object.")]
+    Put {
+        #[structopt(flatten)]
+        object: ObjectArgs,
+        #[structopt(long, help = "File to compress. Reads stdin if not given.")]
+        input_file: Option<PathBuf>,
+        #[structopt(flatten)]
+        compression: CompressionArgs,
+    },
+    #[structopt(about = "Decompress an object into a file, or stdout.")]
+    Get {
+        #[structopt(flatten)]
+        object: ObjectArgs,
+        #[structopt(long, help = "File to write to. Writes to stdout if not given.")]
+        output_file: Option<PathBuf>,
+        #[structopt(
+            long,
+            requires = "output-file",
+            help = "Keep progress in this file to resume an interrupted download."
+        )]
+        state_file: Option<PathBuf>,
+    },
+    #[structopt(about = "Write a range of the decompressed object to stdout.")]
+    Cat {
+        #[structopt(flatten)]
+        object: ObjectArgs,
+        #[structopt(long, help = "Range to write, as start:length.", parse(try_from_str = parse_range))]
+        range: Option<(u64, u64)>,
+    },
+    #[structopt(about = "List the frames of an object.")]
+    LsFrames {
+        #[structopt(flatten)]
+        object: ObjectArgs,
+    },
+    #[structopt(about = "Rebuild the seek table of a damaged object.")]
+    Repair {
+        #[structopt(flatten)]
+        object: ObjectArgs,
+        #[structopt(
+            long,
+            help = "Upload the seek table next to the object instead of rewriting it."
+        )]
+        sidecar: bool,
+    },
+    #[structopt(about = "Check every frame of an object against its seek table.")]
+    Verify {
+        #[structopt(flatten)]
+        object: ObjectArgs,
+    },
+    #[structopt(about = "Compress the files of a directory that changed into objects.")]
+    Sync {
+        #[structopt(long, help = "Directory to sync.")]
+        directory: PathBuf,
+        #[structopt(long, help = "Bucket to put the objects in.")]
+        bucket: String,
+        #[structopt(long, default_value = "", help = "Prefix of the keys of the objects.")]
+        prefix: String,
+        #[structopt(long, default_value = "3", help = "Compression level.")]
+        level: usize,
+        #[structopt(
+            long,
+            help = "Compare the MD5 of files that only changed their modification time."
+        )]
+        checksums: bool,
+        #[structopt(long, help = "Only print what would be uploaded.")]
+        dry_run: bool,
+    },
+    #[structopt(about = "Print the seek table summary of every object under a prefix as CSV.")]
+    Inventory {
+        #[structopt(long, help = "Bucket to list.")]
+        bucket: String,
+        #[structopt(long, default_value = "", help = "Prefix of the keys to list.")]
+        prefix: String,
+    },
+}
+
+fn parse_range(range: &str) -> Result<(u64, u64), String> {
+    let (start, len) = range
+        .split_once(':')
+        .ok_or_else(|| format!("Expected start:length, got {}", range))?;
+    let start = start.parse().map_err(|e| format!("Bad start: {}", e))?;
+    let len = len.parse().map_err(|e| format!("Bad length: {}", e))?;
+    Ok((start, len))
+}
+
+// Copies everything from the reader to the writer.
+fn copy(mut from: impl Read, mut to: impl Write) -> std::io::Result<()> {
+    let mut buffer = vec![0; 512 * 1024];
+    loop {
+        let n = from.read(&mut buffer)?;
+        if n == 0 {
+            break to.flush();
+        }
+        to.write_all(&buffer[..n])?;
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+
+    env_logger::init();
+
+    let s3 = S3Client::new(opt.region);
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    match opt.command {
+        Command::Put {
+            object,
+            input_file,
+            compression,
+        } => {
+            let options = compression.upload_options();
+            runtime.block_on(async {
+                let output = match input_file {
+                    Some(input_file) => {
+                        let input = tokio::fs::File::open(input_file).await?;
+                        upload_compressed_reader(&s3, &object.bucket, &object.key, input, &options)
+                            .await?
+                    }
+                    None => {
+                        let input = tokio::io::stdin();
+                        upload_compressed_reader(&s3, &object.bucket, &object.key, input, &options)
+                            .await?
+                    }
+                };
+                if let Some(e_tag) = output.e_tag {
+                    eprintln!("Uploaded {}", e_tag);
+                }
+                Ok::<_, Box<dyn Error>>(())
+            })?;
+        }
+        Command::Get {
+            object,
+            output_file: Some(output_file),
+            state_file: Some(state_file),
+        } => {
+            let state = runtime.block_on(download_resumable(
+                &s3,
+                &object.request(),
+                &output_file,
+                &state_file,
+                &DownloadOptions::default(),
+            ))?;
+            eprintln!("Wrote {} bytes", state.output_position);
+        }
+        Command::Get {
+            object,
+            output_file,
+            state_file: _,
+        } => {
+            let compressed = s3.get_seekable_object(&runtime, None, object.request())?;
+            let decompressed = SeekableDecompress::new(compressed)?;
+            match output_file {
+                Some(output_file) => copy(decompressed, std::fs::File::create(output_file)?)?,
+                None => copy(decompressed, std::io::stdout().lock())?,
+            }
+        }
+        Command::Cat { object, range } => {
+            let compressed = s3.get_seekable_object(&runtime, None, object.request())?;
+            let mut decompressed = SeekableDecompress::new(compressed)?;
+            let (start, len) = range.unwrap_or((0, u64::MAX));
+            decompressed.seek(SeekFrom::Start(start))?;
+            copy(decompressed.take(len), std::io::stdout().lock())?;
+        }
+        Command::LsFrames { object } => {
+            let index = runtime.block_on(fetch_object_index(&s3, &object.request()))?;
+            println!(
+                "{:>8} {:>16} {:>12} {:>16} {:>12}",
+                "frame", "compressed at", "size", "decompressed at", "size"
+            );
+            for (i, frame) in index.index.frames().iter().enumerate() {
+                println!(
+                    "{:>8} {:>16} {:>12} {:>16} {:>12}",
+                    i,
+                    frame.compressed_offset,
+                    frame.compressed_size,
+                    frame.decompressed_offset,
+                    frame.decompressed_size
+                );
+            }
+        }
+        Command::Repair { object, sidecar } => {
+            let target = if sidecar {
+                RepairTarget::Sidecar(SEEK_TABLE_OBJECT_SUFFIX.to_owned())
+            } else {
+                RepairTarget::Rewrite
+            };
+            let rebuilt = runtime.block_on(repair_object(
+                &s3,
+                &object.request(),
+                &target,
+                &UploadConfig::default(),
+                &RetryPolicy::default(),
+            ))?;
+            eprintln!(
+                "Recovered {} frames holding {} bytes, discarded {} bytes",
+                rebuilt.index.num_frames(),
+                rebuilt.index.decompressed_size(),
+                rebuilt.discarded
+            );
+        }
+        Command::Verify { object } => {
+            let report = runtime.block_on(verify_object(
+                &s3,
+                &object.request(),
+                &FetchRangesOptions::default(),
+            ))?;
+            for frame in report.problems() {
+                if let Some(problem) = &frame.problem {
+                    println!("frame {}: {}", frame.frame, problem);
+                }
+            }
+            if !report.is_ok() {
+                return Err(format!("{} bad frames", report.problems().count()).into());
+            }
+            eprintln!("All {} frames are fine", report.frames.len());
+        }
+        Command::Sync {
+            directory,
+            bucket,
+            prefix,
+            level,
+            checksums,
+            dry_run,
+        } => {
+            let options = SyncOptions {
+                upload: UploadOptions {
+                    compression_level: level,
+                    ..Default::default()
+                },
+                compare_checksums: checksums,
+                dry_run,
+                ..Default::default()
+            };
+            let report =
+                runtime.block_on(sync_directory(&s3, &directory, &bucket, &prefix, &options))?;
+            for key in &report.uploaded {
+                println!("{}", key);
+            }
+            for path in &report.skipped {
+                eprintln!("Skipped {}", path.display());
+            }
+            eprintln!(
+                "{} uploaded, {} unchanged",
+                report.uploaded.len(),
+                report.unchanged.len()
+            );
+        }
+        Command::Inventory { bucket, prefix } => {
+            let inventory = runtime.block_on(inventory(
+                &s3,
+                &bucket,
+                &prefix,
+                &InventoryOptions::default(),
+            ))?;
+            inventory.write_csv(std::io::stdout().lock())?;
+            eprintln!(
+                "{} objects, {} bytes compressed, {} decompressed, ratio {:.3}, {} without a seek table",
+                inventory.entries.len(),
+                inventory.compressed_size(),
+                inventory.decompressed_size(),
+                inventory.ratio(),
+                inventory.problems().count()
+            );
+        }
+    }
+    Ok(())
+}
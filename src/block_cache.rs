@@ -0,0 +1,70 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+
+// Most recently used blocks of an object, keyed by where they start, with a
+// limit on their total size. Finding what to evict goes through all the
+// blocks, which is fine for the few dozen blocks a cache typically holds.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BlockCache {
+    capacity: u64,
+    size: u64,
+    // Block data and when it was last used.
+    blocks: HashMap<u64, (Bytes, u64)>,
+    clock: u64,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        BlockCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn get(&mut self, start: u64) -> Option<Bytes> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.blocks.get_mut(&start).map(|(block, last_used)| {
+            *last_used = clock;
+            block.clone()
+        })
+    }
+
+    // Adds the block, evicting the least recently used ones to make room. The
+    // block we're adding always stays, even if it's bigger than the capacity
+    // on its own: whoever added it is likely to read more of it.
+    pub(crate) fn insert(&mut self, start: u64, block: Bytes) {
+        self.clock += 1;
+        self.size += block.len() as u64;
+        if let Some((old, _)) = self.blocks.insert(start, (block, self.clock)) {
+            self.size -= old.len() as u64;
+        }
+        self.evict(start);
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+        // Nothing was added just now so nothing to keep.
+        self.evict(u64::MAX);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+        self.size = 0;
+    }
+
+    fn evict(&mut self, keep: u64) {
+        while self.size > self.capacity {
+            let oldest = self
+                .blocks
+                .iter()
+                .filter(|(&start, _)| start != keep)
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(&start, _)| start);
+            match oldest.and_then(|start| self.blocks.remove(&start)) {
+                Some((block, _)) => self.size -= block.len() as u64,
+                None => break,
+            }
+        }
+    }
+}
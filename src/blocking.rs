@@ -0,0 +1,63 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::time::error::Elapsed;
+
+// The blocking readers were used from within a current-thread runtime, which
+// has no other thread to run its tasks on while we block: it would hold them
+// up or deadlock. Use them from a thread of their own instead, or from a
+// multi-threaded runtime, where they block in place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsyncContextError;
+
+impl std::fmt::Display for AsyncContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Blocking read from within a current-thread runtime, use a thread of its own"
+        )
+    }
+}
+
+impl std::error::Error for AsyncContextError {}
+
+// Doing the same again fails the same way, so this isn't an error kind that
+// gets retried.
+impl From<AsyncContextError> for std::io::Error {
+    fn from(e: AsyncContextError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::Unsupported, e)
+    }
+}
+
+// Runtime::block_on, which tokio doesn't allow from within an async context.
+// On a multi-threaded runtime we tell it that this thread is about to block,
+// so that it moves its other tasks elsewhere, and go ahead. A current-thread
+// runtime has nowhere to move them to. Threads with a current runtime but no
+// async context, such as those of spawn_blocking, count as being in it: tokio
+// doesn't tell them apart.
+pub(crate) fn block_on<F: Future>(
+    runtime: &tokio::runtime::Runtime,
+    future: F,
+) -> Result<F::Output, AsyncContextError> {
+    match Handle::try_current() {
+        Err(_) => Ok(runtime.block_on(future)),
+        Ok(handle) => match handle.runtime_flavor() {
+            RuntimeFlavor::MultiThread => {
+                Ok(tokio::task::block_in_place(|| runtime.block_on(future)))
+            }
+            _ => Err(AsyncContextError),
+        },
+    }
+}
+
+// block_on giving up on the future after the timeout. The timer is only made
+// once within the runtime, which it needs to be.
+pub(crate) fn block_on_timeout<F: Future>(
+    runtime: &tokio::runtime::Runtime,
+    timeout: Duration,
+    future: F,
+) -> Result<Result<F::Output, Elapsed>, AsyncContextError> {
+    block_on(runtime, async move {
+        tokio::time::timeout(timeout, future).await
+    })
+}
@@ -0,0 +1,64 @@
+use rusoto_core::Region;
+use rusoto_s3::GetObjectRequest;
+use structopt::StructOpt;
+
+use crate::UploadOptions;
+
+// Flags for tools of one's own built on this crate, the same ones the examples
+// and the command line tool take. Add them to an option struct with
+// #[structopt(flatten)].
+
+// --region and --role-arn. Assuming the role is up to the tool, which needs
+// rusoto_sts for it, see examples/decompress_s3.rs.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct S3Args {
+    #[structopt(long, help = "Region the bucket is in.")]
+    pub region: Region,
+    #[structopt(long, help = "Role to assume, if any.")]
+    pub role_arn: Option<String>,
+}
+
+// --bucket and --key.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ObjectArgs {
+    #[structopt(long, help = "Bucket that holds the object.")]
+    pub bucket: String,
+    #[structopt(long, help = "Key of the object.")]
+    pub key: String,
+}
+
+impl ObjectArgs {
+    pub fn request(&self) -> GetObjectRequest {
+        GetObjectRequest {
+            bucket: self.bucket.to_owned(),
+            key: self.key.to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+// --level and --frame-size, defaulting to what UploadOptions does.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct CompressionArgs {
+    #[structopt(long, default_value = "3", help = "Compression level.")]
+    pub level: usize,
+    #[structopt(
+        long,
+        default_value = "1048576",
+        help = "Uncompressed size of each frame."
+    )]
+    pub frame_size: usize,
+}
+
+impl CompressionArgs {
+    pub fn upload_options(&self) -> UploadOptions {
+        UploadOptions {
+            compression_level: self.level,
+            frame_size: self.frame_size,
+            ..Default::default()
+        }
+    }
+}
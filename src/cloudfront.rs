@@ -0,0 +1,259 @@
+use futures::future::BoxFuture;
+use rusoto_core::request::{DispatchSignedRequest, HttpResponse};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use std::io::{Error, ErrorKind, Read, Seek};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::blocking::{block_on, block_on_timeout};
+use crate::retry::{settle_interrupted, RetryableResponse};
+use crate::seek::seek_position;
+use crate::{is_retryable_io, RetryPolicy, TimeoutError, TimeoutKind};
+
+// What lets a request through a CloudFront distribution that only serves
+// signed requests: the query parameters of a signed URL (Expires or Policy,
+// Signature and Key-Pair-Id) or the value of the Cookie header with signed
+// cookies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloudFrontAuth {
+    Query(Vec<(String, String)>),
+    Cookie(String),
+}
+
+// Signs requests for the given path. It's called before every request so
+// that signatures can be made to expire soon and get renewed as reading goes
+// on. Signing needs the private key of the distribution's key pair so that's
+// left to whoever has it, such as a service handing out signed URLs.
+#[derive(Clone)]
+pub struct CloudFrontSigner(Arc<dyn Fn(&str) -> CloudFrontAuth + Send + Sync>);
+
+impl CloudFrontSigner {
+    pub fn new<F>(sign: F) -> Self
+    where
+        F: Fn(&str) -> CloudFrontAuth + Send + Sync + 'static,
+    {
+        CloudFrontSigner(Arc::new(sign))
+    }
+}
+
+impl std::fmt::Debug for CloudFrontSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CloudFrontSigner").finish()
+    }
+}
+
+// Object read through a CloudFront distribution in front of S3, seeking with
+// range requests the same way SeekableS3Object does. The dispatcher sends
+// the requests as they are, without any AWS signing: rusoto's HttpClient does
+// that.
+pub struct CloudFrontObject<'a, D> {
+    dispatcher: D,
+    // The distribution, e.g. https://d111111abcdef8.cloudfront.net.
+    region: Region,
+    path: String,
+    signer: Option<CloudFrontSigner>,
+    runtime: &'a tokio::runtime::Runtime,
+    read_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    position: u64,
+    length: u64,
+    body: Option<Pin<Box<dyn AsyncRead + Send>>>,
+    body_position: u64,
+}
+
+impl<D> std::fmt::Debug for CloudFrontObject<'_, D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CloudFrontObject")
+            .field("region", &self.region)
+            .field("path", &self.path)
+            .field("signer", &self.signer)
+            .field("read_timeout", &self.read_timeout)
+            .field("retry", &self.retry)
+            .field("position", &self.position)
+            .field("length", &self.length)
+            .finish()
+    }
+}
+
+impl<'a, D: DispatchSignedRequest> CloudFrontObject<'a, D> {
+    // Finds out how big the object at the given path of the distribution is
+    // with a HEAD request. The path is the key of the object unless the
+    // distribution maps paths some other way.
+    pub fn new(
+        dispatcher: D,
+        runtime: &'a tokio::runtime::Runtime,
+        distribution_url: &str,
+        path: &str,
+        signer: Option<CloudFrontSigner>,
+        read_timeout: Option<Duration>,
+    ) -> std::io::Result<Self> {
+        let mut object = CloudFrontObject {
+            dispatcher,
+            region: Region::Custom {
+                name: "cloudfront".to_owned(),
+                endpoint: distribution_url.trim_end_matches('/').to_owned(),
+            },
+            path: format!("/{}", path.trim_start_matches('/')),
+            signer,
+            runtime,
+            read_timeout,
+            retry: RetryPolicy::none(),
+            position: 0,
+            length: 0,
+            body: None,
+            body_position: 0,
+        };
+        let response = object.block_on(object.request("HEAD", None))?;
+        object.length = response
+            .headers
+            .get("content-length")
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No Content-Length in response"))?;
+        Ok(object)
+    }
+
+    // Retry requests and reads of the body that failed with something that
+    // may go away on its own. Every attempt is signed again. By default
+    // nothing is retried.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    // Sends a signed request for the object, failing on anything but a
+    // success.
+    fn request(
+        &self,
+        method: &str,
+        range: Option<String>,
+    ) -> BoxFuture<'static, std::io::Result<HttpResponse>> {
+        let mut request = SignedRequest::new(method, "cloudfront", &self.region, &self.path);
+        if let Some(range) = &range {
+            request.add_header("Range", range);
+        }
+        match self
+            .signer
+            .as_ref()
+            .map(|CloudFrontSigner(sign)| sign(&self.path))
+        {
+            Some(CloudFrontAuth::Query(params)) => {
+                for (key, value) in params {
+                    request.add_param(key, value);
+                }
+            }
+            Some(CloudFrontAuth::Cookie(cookie)) => request.add_header("Cookie", &cookie),
+            None => {}
+        }
+        let response = self.dispatcher.dispatch(request, self.read_timeout);
+        Box::pin(async move {
+            let response = response
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            if response.status.is_success() {
+                Ok(response)
+            } else {
+                let message = format!("CloudFront responded with {}", response.status);
+                let kind = match response.status.as_u16() {
+                    // Signatures that expired or don't match.
+                    403 => ErrorKind::PermissionDenied,
+                    404 => ErrorKind::NotFound,
+                    // Worth trying again, see is_retryable_io.
+                    429 | 500..=599 => {
+                        return Err(Error::new(ErrorKind::Other, RetryableResponse(message)))
+                    }
+                    _ => ErrorKind::InvalidInput,
+                };
+                Err(Error::new(kind, message))
+            }
+        })
+    }
+
+    fn block_on<T>(
+        &self,
+        future: impl std::future::Future<Output = std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        match self.read_timeout {
+            Some(timeout) => match block_on_timeout(self.runtime, timeout, future)? {
+                Ok(r) => r,
+                Err(_) => Err(TimeoutError::new(TimeoutKind::Response, timeout).into_io()),
+            },
+            None => block_on(self.runtime, future)?,
+        }
+    }
+
+    fn read_once(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.body.is_none() || self.body_position != self.position {
+            let range = format!("bytes={}-", self.position);
+            let response = self.block_on(self.request("GET", Some(range)))?;
+            self.body =
+                Some(Box::pin(response.body.into_async_read()) as Pin<Box<dyn AsyncRead + Send>>);
+            self.body_position = self.position;
+        }
+        let body = match &mut self.body {
+            Some(body) => body,
+            None => return Ok(0),
+        };
+        let bytes_read = match self.read_timeout {
+            Some(timeout) => match block_on_timeout(self.runtime, timeout, body.read(buf))? {
+                Ok(r) => r,
+                Err(_) => Err(TimeoutError::new(TimeoutKind::BodyRead, timeout).into_io()),
+            },
+            None => block_on(self.runtime, body.read(buf))?,
+        }?;
+        if bytes_read == 0 && !buf.is_empty() && self.body_position < self.length {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Body ended before the object did",
+            ));
+        }
+        self.body_position += bytes_read as u64;
+        self.position = self.body_position;
+        Ok(bytes_read)
+    }
+}
+
+impl<D: DispatchSignedRequest> Read for CloudFrontObject<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.length {
+            return Ok(0);
+        }
+        let mut failed_attempts = 0;
+        loop {
+            match self.read_once(buf) {
+                Ok(bytes_read) => break Ok(bytes_read),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    failed_attempts += 1;
+                    if !is_retryable_io(&e) || !self.retry.should_retry(failed_attempts) {
+                        break Err(settle_interrupted(e));
+                    }
+                    self.body = None;
+                    std::thread::sleep(self.retry.backoff(failed_attempts));
+                }
+            }
+        }
+    }
+}
+
+impl<D> Seek for CloudFrontObject<'_, D> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        // The body is dropped on the next read if it's not at the new
+        // position.
+        self.position = seek_position(pos, self.position, self.length, false)?;
+        Ok(self.position)
+    }
+}
@@ -2,18 +2,210 @@ use bytes::Bytes;
 use futures::{ready, stream::FusedStream, Stream};
 use parking_lot::Mutex;
 use pin_project_lite::pin_project;
-use std::{convert::Infallible, marker::PhantomData, pin::Pin};
+use std::{collections::VecDeque, convert::Infallible, marker::PhantomData, pin::Pin, sync::Arc};
 use zstd_seekable::{self, CStream, SeekableCStream};
 
+use crate::index_frame::append_index_frames;
+use crate::{FrameIndex, IndexBuilder};
+
+// What zstd uses as the frame size when it's asked for a frame size of 0.
+const MAX_FRAME_DECOMPRESSED_SIZE: usize = 0x8000_0000;
+
 pin_project! {
     pub struct Compress<S, E> {
         #[pin]
         stream: S,
+        // The compressor can be sent between threads but not shared by them.
+        // The Mutex keeps Compress Sync, as request bodies have to be, and
+        // never gets locked: we only get at it through get_mut.
         cstream: Mutex<SeekableCStream>,
         buf_out: Box<[u8]>,
+        compression_level: usize,
+        // Compressed data we haven't yielded yet. Only holds anything between
+        // polls if we're aligning output to frame boundaries.
+        pending: Vec<u8>,
+        // Uncompressed size of a full frame and how much of the current frame
+        // we have fed to the compressor so far.
+        frame_size: usize,
+        frame_position: usize,
+        align_frames: bool,
+        // Whether to produce checkpoint items at frame boundaries.
+        checkpoints: bool,
+        // How far into the uncompressed and compressed data we are and how
+        // many frames we have completed so far.
+        uncompressed_offset: u64,
+        compressed_offset: u64,
+        frame_index: u64,
+        // Items we have produced but not yielded yet.
+        output: VecDeque<CompressItem>,
+        // Map of the frames we wrote, filled in once we write the seek table.
+        index: Option<FrameIndex>,
+        // Build index frames that go before the seek table.
+        indexes: Vec<Box<dyn IndexBuilder>>,
         wrote_seek_table: bool,
+        // Where to give our buffers back to once we're done, if anywhere.
+        pool: Option<CompressPool>,
         error_type: PhantomData<E>,
     }
+
+    impl<S, E> PinnedDrop for Compress<S, E> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            if let Some(pool) = this.pool {
+                let buf_out = std::mem::take(this.buf_out);
+                let pending = std::mem::take(this.pending);
+                pool.give_back(buf_out, pending);
+            }
+        }
+    }
+}
+
+// Resources for compressing many streams with the same settings. Streams made
+// through the pool reuse output buffers of previously finished streams instead
+// of allocating their own.
+//
+// zstd-seekable doesn't let us reset a compression context once it wrote out
+// its seek table so those can't be recycled. Instead, the pool can create
+// contexts ahead of time (see prepare) so that the cost of setting them up is
+// paid up front rather than when a stream starts.
+#[derive(Clone)]
+pub struct CompressPool {
+    compression_level: usize,
+    frame_size: usize,
+    // How many of each resource we keep around at most.
+    max_idle: usize,
+    idle: Arc<Mutex<IdleResources>>,
+}
+
+#[derive(Default)]
+struct IdleResources {
+    cstreams: Vec<SeekableCStream>,
+    buffers: Vec<(Box<[u8]>, Vec<u8>)>,
+}
+
+impl std::fmt::Debug for CompressPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let idle = self.idle.lock();
+        f.debug_struct("CompressPool")
+            .field("compression_level", &self.compression_level)
+            .field("frame_size", &self.frame_size)
+            .field("max_idle", &self.max_idle)
+            .field("idle_cstreams", &idle.cstreams.len())
+            .field("idle_buffers", &idle.buffers.len())
+            .finish()
+    }
+}
+
+impl CompressPool {
+    pub fn new(compression_level: usize, frame_size: usize, max_idle: usize) -> Self {
+        CompressPool {
+            compression_level,
+            frame_size,
+            max_idle,
+            idle: Arc::new(parking_lot::const_mutex(IdleResources::default())),
+        }
+    }
+
+    // Makes sure there are at least count compression contexts ready to go,
+    // up to max_idle.
+    pub fn prepare(&self, count: usize) -> Result<(), ZstdError> {
+        let count = std::cmp::min(count, self.max_idle);
+        let missing = count.saturating_sub(self.idle.lock().cstreams.len());
+        // Don't hold the lock while we do the expensive bit.
+        let mut cstreams = Vec::with_capacity(missing);
+        for _ in 0..missing {
+            cstreams.push(
+                SeekableCStream::new(self.compression_level, self.frame_size)
+                    .map_err(ZstdError::new)?,
+            );
+        }
+        self.idle.lock().cstreams.extend(cstreams);
+        Ok(())
+    }
+
+    // Like StreamCompress::compress but using the resources of the pool.
+    pub fn compress<S, I, E>(&self, stream: S) -> Result<Compress<S, E>, ZstdError>
+    where
+        S: Stream<Item = Result<I, E>>,
+        I: AsRef<[u8]>,
+    {
+        self.make_compress(stream, false).map_err(ZstdError::new)
+    }
+
+    // Like StreamCompress::compress_frame_aligned but using the resources of
+    // the pool.
+    pub fn compress_frame_aligned<S, I, E>(&self, stream: S) -> Result<Compress<S, E>, ZstdError>
+    where
+        S: Stream<Item = Result<I, E>>,
+        I: AsRef<[u8]>,
+    {
+        self.make_compress(stream, true).map_err(ZstdError::new)
+    }
+
+    fn make_compress<S, E>(&self, stream: S, align_frames: bool) -> ZstdResult<Compress<S, E>> {
+        let (cstream, buffers) = {
+            let mut idle = self.idle.lock();
+            (idle.cstreams.pop(), idle.buffers.pop())
+        };
+        let cstream = match cstream {
+            Some(cstream) => cstream,
+            None => SeekableCStream::new(self.compression_level, self.frame_size)?,
+        };
+        let (buf_out, pending) = buffers
+            .unwrap_or_else(|| (vec![0; CStream::out_size()].into_boxed_slice(), Vec::new()));
+        Ok(Compress::from_parts(
+            stream,
+            cstream,
+            buf_out,
+            pending,
+            self.compression_level,
+            self.frame_size,
+            align_frames,
+            Some(self.clone()),
+        ))
+    }
+
+    fn give_back(&self, buf_out: Box<[u8]>, mut pending: Vec<u8>) {
+        // A stream that was dropped half-way through may have left some data
+        // behind.
+        pending.clear();
+        let mut idle = self.idle.lock();
+        if idle.buffers.len() < self.max_idle && !buf_out.is_empty() {
+            idle.buffers.push((buf_out, pending));
+        }
+    }
+}
+
+// A point in the compressed stream at which frame number frame_index starts.
+// Everything before compressed_offset has been yielded by the time the
+// checkpoint is seen, and is whole frames holding the first
+// uncompressed_offset bytes of the data: somewhere to cut parts or encrypted
+// chunks, or to tell how far along compression is. It's not somewhere to
+// resume compressing from, as the seek table a new stream writes only lists
+// its own frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+    pub uncompressed_offset: u64,
+    pub compressed_offset: u64,
+    pub frame_index: u64,
+}
+
+// What a compression stream with checkpoints yields. The frame index comes
+// last, after all the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompressItem {
+    Data(Bytes),
+    Checkpoint(Checkpoint),
+    FrameIndex(FrameIndex),
+}
+
+pin_project! {
+    // Compress stream yielding checkpoints alongside the compressed data, see
+    // Compress::with_checkpoints.
+    pub struct WithCheckpoints<S, E> {
+        #[pin]
+        compress: Compress<S, E>,
+    }
 }
 
 impl<S, E> std::fmt::Debug for Compress<S, E>
@@ -26,7 +218,20 @@ where
             .field("stream", &self.stream)
             // .field("cstream", &self.cstream)
             .field("buf_out", &self.buf_out)
+            .field("compression_level", &self.compression_level)
+            .field("pending", &self.pending)
+            .field("frame_size", &self.frame_size)
+            .field("frame_position", &self.frame_position)
+            .field("align_frames", &self.align_frames)
+            .field("checkpoints", &self.checkpoints)
+            .field("uncompressed_offset", &self.uncompressed_offset)
+            .field("compressed_offset", &self.compressed_offset)
+            .field("frame_index", &self.frame_index)
+            .field("output", &self.output)
+            .field("index", &self.index)
+            .field("indexes", &self.indexes.len())
             .field("wrote_seek_table", &self.wrote_seek_table)
+            .field("pool", &self.pool)
             .finish()
     }
 }
@@ -36,10 +241,24 @@ pub trait StreamCompress {
         self,
         compression_level: usize,
         frame_size: usize,
-    ) -> ZstdError<Compress<Self, E>>
+    ) -> Result<Compress<Self, E>, ZstdError>
+    where
+        Self: Stream<Item = Result<I, E>> + Sized,
+        I: AsRef<[u8]>;
+
+    // Like compress but every yielded chunk of compressed data ends on a frame
+    // boundary. As UploadParts only ever cuts parts between the chunks it's
+    // given, feeding this into upload_parts results in parts that each hold
+    // whole frames only. The price is that we hold on to at least a frame
+    // worth of compressed data before yielding anything.
+    fn compress_frame_aligned<I, E>(
+        self,
+        compression_level: usize,
+        frame_size: usize,
+    ) -> Result<Compress<Self, E>, ZstdError>
     where
         Self: Stream<Item = Result<I, E>> + Sized,
-        I: std::borrow::Borrow<[u8]>;
+        I: AsRef<[u8]>;
 }
 
 impl<S> StreamCompress for S {
@@ -47,7 +266,7 @@ impl<S> StreamCompress for S {
         self,
         compression_level: usize,
         frame_size: usize,
-    ) -> ZstdError<Compress<Self, E>>
+    ) -> Result<Compress<Self, E>, ZstdError>
     where
         // By having the bounds at the function level rather than trait level,
         // we get a better error message: when trying to use compress(), we'll
@@ -56,28 +275,130 @@ impl<S> StreamCompress for S {
         // do that, we get messages about the method not being found at all and
         // given a more cryptic error message about missing bounds on the S.
         Self: Stream<Item = Result<I, E>> + Sized,
-        I: std::borrow::Borrow<[u8]>,
+        I: AsRef<[u8]>,
+    {
+        Compress::new(self, compression_level, frame_size, false).map_err(ZstdError::new)
+    }
+
+    fn compress_frame_aligned<I, E>(
+        self,
+        compression_level: usize,
+        frame_size: usize,
+    ) -> Result<Compress<Self, E>, ZstdError>
+    where
+        Self: Stream<Item = Result<I, E>> + Sized,
+        I: AsRef<[u8]>,
     {
-        Compress::new(self, compression_level, frame_size)
+        Compress::new(self, compression_level, frame_size, true).map_err(ZstdError::new)
     }
 }
 
 impl<S, E> Compress<S, E> {
-    fn new<I>(stream: S, compression_level: usize, frame_size: usize) -> ZstdError<Self>
+    fn new<I>(
+        stream: S,
+        compression_level: usize,
+        frame_size: usize,
+        align_frames: bool,
+    ) -> ZstdResult<Self>
     where
         S: Stream<Item = Result<I, E>>,
-        I: std::borrow::Borrow<[u8]>,
+        I: AsRef<[u8]>,
     {
-        let cstream =
-            parking_lot::const_mutex(SeekableCStream::new(compression_level, frame_size)?);
+        let cstream = SeekableCStream::new(compression_level, frame_size)?;
         let buf_out = vec![0; CStream::out_size()].into_boxed_slice();
-        Ok(Self {
+        Ok(Self::from_parts(
             stream,
             cstream,
             buf_out,
+            Vec::new(),
+            compression_level,
+            frame_size,
+            align_frames,
+            None,
+        ))
+    }
+
+    fn from_parts(
+        stream: S,
+        cstream: SeekableCStream,
+        buf_out: Box<[u8]>,
+        pending: Vec<u8>,
+        compression_level: usize,
+        frame_size: usize,
+        align_frames: bool,
+        pool: Option<CompressPool>,
+    ) -> Self {
+        let frame_size = if frame_size == 0 {
+            MAX_FRAME_DECOMPRESSED_SIZE
+        } else {
+            frame_size
+        };
+        Self {
+            stream,
+            cstream: Mutex::new(cstream),
+            buf_out,
+            compression_level,
+            pending,
+            frame_size,
+            frame_position: 0,
+            align_frames,
+            checkpoints: false,
+            uncompressed_offset: 0,
+            compressed_offset: 0,
+            frame_index: 0,
+            output: VecDeque::new(),
+            index: None,
+            indexes: Vec::new(),
             wrote_seek_table: false,
+            pool,
             error_type: PhantomData,
-        })
+        }
+    }
+
+    // Turns the stream into one that also yields a checkpoint every time a
+    // frame is completed. Applications can persist these to know where they
+    // can resume from.
+    pub fn with_checkpoints(mut self) -> WithCheckpoints<S, E> {
+        self.checkpoints = true;
+        WithCheckpoints { compress: self }
+    }
+
+    // Has the builder see all the uncompressed data and stores the index it
+    // makes in an index frame at the end of the stream, see IndexFrame.
+    // Builders have to be added before the stream is first polled.
+    pub fn with_index<B: IndexBuilder + 'static>(mut self, builder: B) -> Self {
+        self.indexes.push(Box::new(builder));
+        self
+    }
+
+    // Map of the frames written out, available once the stream has finished.
+    // This is what lets writers store an index somewhere other than the end
+    // of the compressed data.
+    pub fn frame_index(&self) -> Option<&FrameIndex> {
+        self.index.as_ref()
+    }
+
+    pub fn compression_level(&self) -> usize {
+        self.compression_level
+    }
+
+    // Uncompressed size of each frame, with a frame size of 0 being zstd's
+    // maximum.
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    // How much uncompressed data the stream has taken in so far.
+    pub fn uncompressed_bytes(&self) -> u64 {
+        self.uncompressed_offset
+    }
+
+    // How much compressed data the stream has put out so far, seek table and
+    // index frames included once the stream finishes. Data counts as soon as
+    // it's queued up to be yielded, which may be a chunk before the consumer
+    // gets it.
+    pub fn compressed_bytes(&self) -> u64 {
+        self.compressed_offset
     }
 
     fn next_input<I>(
@@ -86,47 +407,172 @@ impl<S, E> Compress<S, E> {
     ) -> std::task::Poll<Option<S::Item>>
     where
         S: Stream<Item = Result<I, E>>,
-        I: std::borrow::Borrow<[u8]>,
+        I: AsRef<[u8]>,
     {
         self.as_mut().project().stream.poll_next(cx)
     }
 
-    fn compress_input(self: &mut Pin<&mut Self>, mut input: &[u8]) -> ZstdError<bytes::Bytes> {
-        // Don't bother doing anything at all if we didn't get any input in.
-        if input.is_empty() {
-            return Ok(Bytes::new());
+    // Moves any pending compressed data to the output.
+    fn flush_pending(self: &mut Pin<&mut Self>) {
+        let this = self.as_mut().project();
+        // Don't bother yielding empty data.
+        if !this.pending.is_empty() {
+            // It might seem wasteful to make a vector even if we end up only
+            // decompressing once. However, Bytes::copy_from_slice just makes a
+            // vector anyway and converts from there.
+            let compressed_bytes = std::mem::take(this.pending);
+            *this.compressed_offset += compressed_bytes.len() as u64;
+            this.output
+                .push_back(CompressItem::Data(Bytes::from(compressed_bytes)));
         }
+    }
 
-        let this = self.as_mut().project();
-        let cstream: &mut SeekableCStream = this.cstream.get_mut();
-        let buf_out: &mut [u8] = this.buf_out;
-        // It might seem wasteful to make a vector even if we end up only
-        // decompressing once. However, Bytes::copy_from_slice just makes a
-        // vector anyway and converts from there.
-        let mut compressed_bytes = Vec::new();
+    // Called once the compressor has closed a frame.
+    fn end_frame(self: &mut Pin<&mut Self>) {
+        {
+            let this = self.as_mut().project();
+            *this.frame_position = 0;
+            *this.frame_index += 1;
+        }
+        if self.align_frames || self.checkpoints {
+            self.flush_pending();
+        }
+        if self.checkpoints {
+            let this = self.as_mut().project();
+            let checkpoint = Checkpoint {
+                uncompressed_offset: *this.uncompressed_offset,
+                compressed_offset: *this.compressed_offset,
+                frame_index: *this.frame_index,
+            };
+            this.output.push_back(CompressItem::Checkpoint(checkpoint));
+        }
+    }
+
+    fn compress_input(self: &mut Pin<&mut Self>, mut input: &[u8]) -> ZstdResult<()> {
+        for builder in self.as_mut().project().indexes.iter_mut() {
+            builder.update(input);
+        }
         while !input.is_empty() {
-            let (out_pos, in_pos) = cstream.compress(buf_out, input)?;
-            compressed_bytes.extend_from_slice(&buf_out[..out_pos]);
+            let this = self.as_mut().project();
+            let cstream: &mut SeekableCStream = this.cstream.get_mut();
+            let buf_out: &mut [u8] = this.buf_out;
+            let pending: &mut Vec<u8> = this.pending;
+            // Never feed more than what's left of the current frame: this way
+            // we know exactly when the compressor closes a frame.
+            let frame_left = *this.frame_size - *this.frame_position;
+            let chunk = &input[..std::cmp::min(input.len(), frame_left)];
+            let (out_pos, in_pos) = cstream.compress(buf_out, chunk)?;
+            pending.extend_from_slice(&buf_out[..out_pos]);
             input = &input[in_pos..];
+            *this.frame_position += in_pos;
+            *this.uncompressed_offset += in_pos as u64;
+            if *this.frame_position == *this.frame_size {
+                // The compressor ends the frame by itself once it's full but it
+                // may not have managed to flush all of it into buf_out. Keep
+                // poking it with no input until it has nothing more to say.
+                loop {
+                    let (out_pos, _) = cstream.compress(buf_out, &[])?;
+                    if out_pos == 0 {
+                        break;
+                    }
+                    pending.extend_from_slice(&buf_out[..out_pos]);
+                }
+                self.end_frame();
+            }
+        }
+        if !self.align_frames {
+            self.flush_pending();
         }
-        Ok(bytes::Bytes::from(compressed_bytes))
+        Ok(())
     }
 
-    fn end_stream(self: &mut Pin<&mut Self>) -> ZstdError<Bytes> {
-        let this = self.as_mut().project();
-        let wrote_seek_table = this.wrote_seek_table;
-        let cstream: &mut Mutex<SeekableCStream> = this.cstream;
-        let buf_out: &mut [u8] = this.buf_out;
+    fn end_stream(self: &mut Pin<&mut Self>) -> ZstdResult<()> {
+        {
+            let this = self.as_mut().project();
+            let cstream: &mut SeekableCStream = this.cstream.get_mut();
+            let buf_out: &mut [u8] = this.buf_out;
+            // Whatever we held back waiting for a frame boundary goes out
+            // first: the end of the stream closes the last frame. With no input
+            // at all, there's no frame to close and this is just a seek table
+            // of no frames: still a valid stream, of nothing.
+            let compressed_bytes: &mut Vec<u8> = this.pending;
 
-        let mut cstream = cstream.lock();
-        let mut out_pos = cstream.end_stream(buf_out)?;
-        let mut compressed_bytes = (&buf_out[..out_pos]).to_vec();
-        while out_pos > 0 {
-            out_pos = cstream.end_stream(buf_out)?;
-            compressed_bytes.extend_from_slice(&buf_out[..out_pos])
+            let mut out_pos = cstream.end_stream(buf_out)?;
+            compressed_bytes.extend_from_slice(&buf_out[..out_pos]);
+            while out_pos > 0 {
+                out_pos = cstream.end_stream(buf_out)?;
+                compressed_bytes.extend_from_slice(&buf_out[..out_pos])
+            }
+            *this.frame_position = 0;
+            *this.wrote_seek_table = true;
+            // The seek table is always written in full by end_stream so we can
+            // read the final frame map straight out of it. This saves us from
+            // guessing how much of the output belongs to the last frame.
+            *this.index = FrameIndex::from_seek_table(compressed_bytes).ok();
+            if let Some(index) = this.index {
+                let frames = this
+                    .indexes
+                    .drain(..)
+                    .map(|mut builder| builder.finish())
+                    .collect::<Vec<_>>();
+                // Only fails if the table zstd wrote is broken, in which case
+                // we're no worse off leaving it as it is.
+                if !frames.is_empty() {
+                    let _ = append_index_frames(compressed_bytes, index, frames);
+                }
+            }
+        }
+        self.flush_pending();
+        if self.checkpoints {
+            let this = self.as_mut().project();
+            if let Some(index) = this.index {
+                this.output
+                    .push_back(CompressItem::FrameIndex(index.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    // Yields the next item, be it data or a checkpoint. Checkpoints are only
+    // ever produced if they were asked for.
+    fn poll_item<I>(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<CompressItem, CompressError<E>>>>
+    where
+        S: Stream<Item = Result<I, E>>,
+        I: AsRef<[u8]>,
+    {
+        loop {
+            if let Some(item) = self.as_mut().project().output.pop_front() {
+                return std::task::Poll::Ready(Some(Ok(item)));
+            }
+            // We've already consumed everything and finalised our compression
+            // stream. Yield nothing. Notably, we don't want to poke the
+            // upstream again.
+            if self.finished() {
+                return std::task::Poll::Ready(None);
+            }
+            match ready!(self.next_input(cx)) {
+                None => {
+                    if let Err(e) = self.end_stream() {
+                        return std::task::Poll::Ready(Some(Err(CompressError::Zstd(
+                            ZstdError::new(e),
+                        ))));
+                    }
+                }
+                Some(Err(e)) => {
+                    return std::task::Poll::Ready(Some(Err(CompressError::Underlying(e))))
+                }
+                Some(Ok(bytes)) => {
+                    if let Err(e) = self.compress_input(bytes.as_ref()) {
+                        return std::task::Poll::Ready(Some(Err(CompressError::Zstd(
+                            ZstdError::new(e),
+                        ))));
+                    }
+                }
+            }
         }
-        *wrote_seek_table = true;
-        Ok(Bytes::from(compressed_bytes))
     }
 
     fn finished(self: &mut Pin<&mut Self>) -> bool {
@@ -134,19 +580,42 @@ impl<S, E> Compress<S, E> {
     }
 }
 
-type ZstdError<A> = std::result::Result<A, zstd_seekable::Error>;
+type ZstdResult<A> = std::result::Result<A, zstd_seekable::Error>;
+
+// Error from zstd itself, such as an out of range compression level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZstdError {
+    message: String,
+}
+
+impl ZstdError {
+    pub(crate) fn new(e: zstd_seekable::Error) -> Self {
+        ZstdError {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ZstdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ZstdError {}
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum CompressError<E> {
-    ZstdError(zstd_seekable::Error),
+    Zstd(ZstdError),
     Underlying(E),
 }
 
-impl From<CompressError<Infallible>> for zstd_seekable::Error {
+impl From<CompressError<Infallible>> for ZstdError {
     fn from(e: CompressError<Infallible>) -> Self {
         match e {
-            CompressError::ZstdError(e) => e,
-            CompressError::Underlying(inf) => panic!("The impossible happened: {}", inf),
+            CompressError::Zstd(e) => e,
+            CompressError::Underlying(inf) => match inf {},
         }
     }
 }
@@ -154,7 +623,7 @@ impl From<CompressError<Infallible>> for zstd_seekable::Error {
 impl<E: std::fmt::Display> std::fmt::Display for CompressError<E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            CompressError::ZstdError(e) => write!(f, "Compression error: {}", e),
+            CompressError::Zstd(e) => write!(f, "Compression error: {}", e),
             CompressError::Underlying(e) => write!(f, "Underlying error: {}", e),
         }
     }
@@ -163,7 +632,7 @@ impl<E: std::fmt::Display> std::fmt::Display for CompressError<E> {
 impl<E: std::error::Error + std::fmt::Display + 'static> std::error::Error for CompressError<E> {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            CompressError::ZstdError(_) => None,
+            CompressError::Zstd(e) => Some(e),
             CompressError::Underlying(e) => Some(e),
         }
     }
@@ -172,7 +641,7 @@ impl<E: std::error::Error + std::fmt::Display + 'static> std::error::Error for C
 impl<S, I, E> Stream for Compress<S, E>
 where
     S: Stream<Item = Result<I, E>>,
-    I: std::borrow::Borrow<[u8]>,
+    I: AsRef<[u8]>,
 {
     type Item = std::result::Result<Bytes, CompressError<E>>;
 
@@ -180,36 +649,13 @@ where
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        // We've already consumed everything and finalised our compression
-        // stream. Yield nothing. Notably, we don't want to poke the upstream
-        // again.
-        if self.finished() {
-            return std::task::Poll::Ready(None);
-        }
-
         std::task::Poll::Ready(loop {
-            match ready!(self.next_input(cx)) {
-                None => match self.end_stream() {
-                    Err(e) => break Some(Err(CompressError::ZstdError(e))),
-                    Ok(compressed_data) => {
-                        if compressed_data.is_empty() {
-                            break None;
-                        } else {
-                            break Some(Ok(compressed_data));
-                        }
-                    }
-                },
-                Some(Err(e)) => break Some(Err(CompressError::Underlying(e))),
-                Some(Ok(bytes)) => match self.compress_input(bytes.borrow()) {
-                    Err(e) => break Some(Err(CompressError::ZstdError(e))),
-                    Ok(compressed_data) => {
-                        // Maybe we want to return 0 length Bytes unconditionally?
-                        // Who knows.
-                        if !compressed_data.is_empty() {
-                            break Some(Ok(compressed_data));
-                        }
-                    }
-                },
+            match ready!(self.as_mut().poll_item(cx)) {
+                None => break None,
+                Some(Err(e)) => break Some(Err(e)),
+                Some(Ok(CompressItem::Data(compressed_data))) => break Some(Ok(compressed_data)),
+                // Nobody asked for these but skip them all the same.
+                Some(Ok(CompressItem::Checkpoint(_))) | Some(Ok(CompressItem::FrameIndex(_))) => {}
             }
         })
     }
@@ -218,9 +664,60 @@ where
 impl<S, I, E> FusedStream for Compress<S, E>
 where
     S: Stream<Item = Result<I, E>>,
-    I: std::borrow::Borrow<[u8]>,
+    I: AsRef<[u8]>,
+{
+    fn is_terminated(&self) -> bool {
+        self.wrote_seek_table && self.output.is_empty()
+    }
+}
+
+impl<S, E> std::fmt::Debug for WithCheckpoints<S, E>
+where
+    S: Stream + std::fmt::Debug,
+    S::Item: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WithCheckpoints")
+            .field("compress", &self.compress)
+            .finish()
+    }
+}
+
+impl<S, E> WithCheckpoints<S, E> {
+    // Goes back to the plain stream of compressed data.
+    pub fn into_inner(self) -> Compress<S, E> {
+        let mut compress = self.compress;
+        compress.checkpoints = false;
+        compress
+    }
+
+    // The stream being wrapped, for its frame index and counters.
+    pub fn get_ref(&self) -> &Compress<S, E> {
+        &self.compress
+    }
+}
+
+impl<S, I, E> Stream for WithCheckpoints<S, E>
+where
+    S: Stream<Item = Result<I, E>>,
+    I: AsRef<[u8]>,
+{
+    type Item = std::result::Result<CompressItem, CompressError<E>>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.project().compress.poll_item(cx)
+    }
+}
+
+impl<S, I, E> FusedStream for WithCheckpoints<S, E>
+where
+    S: Stream<Item = Result<I, E>>,
+    I: AsRef<[u8]>,
 {
     fn is_terminated(&self) -> bool {
-        self.wrote_seek_table
+        self.compress.is_terminated()
     }
 }
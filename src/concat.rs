@@ -0,0 +1,383 @@
+use bytes::BytesMut;
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    AbortMultipartUploadError, CompleteMultipartUploadError, CompleteMultipartUploadOutput,
+    CompletedPart, GetObjectRequest, UploadPartCopyError, UploadPartCopyRequest, UploadPartError,
+    UploadPartRequest, S3,
+};
+use std::fmt::Display;
+
+use crate::object_index::get_object_range;
+use crate::upload_config::percent_encode;
+use crate::{
+    fetch_object_index, is_retryable, upload_prepared_part, FrameIndex, MultipartCreateError,
+    MultipartUpload, PartData, PreparedPart, ReadObjectError, RetryPolicy, SeekTableError,
+    UploadConfig, MAXIMUM_PARTS, MAXIMUM_PART_SIZE, MINIMUM_PART_SIZE,
+};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConcatError {
+    // Reading the seek table or data of one of the sources failed.
+    Source {
+        bucket: String,
+        key: String,
+        error: ReadObjectError,
+    },
+    // The combined seek table can't be written out.
+    SeekTable(SeekTableError),
+    CreateMultipartUpload(MultipartCreateError),
+    UploadPart(RusotoError<UploadPartError>),
+    UploadPartCopy(RusotoError<UploadPartCopyError>),
+    CompleteMultipartUpload(RusotoError<CompleteMultipartUploadError>),
+    // Copying the sources takes more parts than S3 allows in an upload.
+    TooManyParts {
+        parts: u64,
+    },
+    // The concatenation failed and so did our attempt to abort its upload:
+    // the parts that made it to S3 are left behind and have to be cleaned up
+    // some other way.
+    Abort {
+        upload_id: String,
+        error: Box<ConcatError>,
+        abort_error: RusotoError<AbortMultipartUploadError>,
+    },
+}
+
+impl Display for ConcatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConcatError::Source { bucket, key, error } => {
+                write!(f, "Failed to read s3://{}/{}: {}", bucket, key, error)
+            }
+            ConcatError::SeekTable(e) => write!(f, "Failed to build seek table: {}", e),
+            ConcatError::CreateMultipartUpload(e) => write!(f, "{}", e),
+            ConcatError::UploadPart(e) => write!(f, "Failed to upload part: {}", e),
+            ConcatError::UploadPartCopy(e) => write!(f, "Failed to copy part: {}", e),
+            ConcatError::CompleteMultipartUpload(e) => {
+                write!(f, "Failed to complete multipart upload: {}", e)
+            }
+            ConcatError::TooManyParts { parts } => write!(
+                f,
+                "Concatenation takes {} parts, more than the {} allowed",
+                parts, MAXIMUM_PARTS
+            ),
+            ConcatError::Abort {
+                upload_id,
+                error,
+                abort_error,
+            } => write!(
+                f,
+                "{}; additionally failed to abort upload {}: {}",
+                error, upload_id, abort_error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConcatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConcatError::Source { error, .. } => Some(error),
+            ConcatError::SeekTable(e) => Some(e),
+            ConcatError::CreateMultipartUpload(e) => Some(e),
+            ConcatError::UploadPart(e) => Some(e),
+            ConcatError::UploadPartCopy(e) => Some(e),
+            ConcatError::CompleteMultipartUpload(e) => Some(e),
+            ConcatError::Abort { error, .. } => Some(error.as_ref()),
+            ConcatError::TooManyParts { .. } => None,
+        }
+    }
+}
+
+// Creates a seekable object at bucket/key holding the contents of all the
+// sources one after another, without downloading and uploading them again.
+//
+// The frames of each source are copied server-side with UploadPartCopy and a
+// seek table covering all of them is put at the end. As every part but the
+// last has to be at least MINIMUM_PART_SIZE, stretches of sources that are
+// smaller than that are downloaded and uploaded along with their neighbours
+// instead. Sources are given as requests so that things like their version or
+// SSE-C key can be set; their ranges are ignored.
+//
+// If anything goes wrong after the upload was created, it's aborted.
+pub async fn concatenate_objects<C>(
+    client: &C,
+    sources: &[GetObjectRequest],
+    bucket: &str,
+    key: &str,
+    config: &UploadConfig,
+    retry: &RetryPolicy,
+) -> Result<CompleteMultipartUploadOutput, ConcatError>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    // Work out the combined index up front so that we don't create an upload
+    // for sources that aren't seekable in the first place.
+    let mut index = FrameIndex::new();
+    let mut source_sizes = Vec::with_capacity(sources.len());
+    for source in sources {
+        let source_index = fetch_object_index(client, source)
+            .await
+            .map_err(|error| source_error(source, error))?;
+        for frame in source_index.index.frames() {
+            index
+                .push(
+                    frame.compressed_size,
+                    frame.decompressed_size,
+                    frame.checksum,
+                )
+                .map_err(ConcatError::SeekTable)?;
+        }
+        source_sizes.push(source_index.index.compressed_size());
+    }
+    let seek_table = index.to_seek_table().map_err(ConcatError::SeekTable)?;
+    copy_with_seek_table(
+        client,
+        sources,
+        &source_sizes,
+        &seek_table,
+        bucket,
+        key,
+        config,
+        retry,
+    )
+    .await
+}
+
+// Creates bucket/key out of the first source_sizes[i] bytes of each source,
+// one after another, followed by the seek table, copying server-side where
+// the parts are large enough. The upload is aborted if anything goes wrong.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn copy_with_seek_table<C>(
+    client: &C,
+    sources: &[GetObjectRequest],
+    source_sizes: &[u64],
+    seek_table: &[u8],
+    bucket: &str,
+    key: &str,
+    config: &UploadConfig,
+    retry: &RetryPolicy,
+) -> Result<CompleteMultipartUploadOutput, ConcatError>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    let parts = count_parts(source_sizes);
+    if parts > MAXIMUM_PARTS as u64 {
+        return Err(ConcatError::TooManyParts { parts });
+    }
+    let upload = MultipartUpload::create(client.clone(), config.create_request(bucket, key))
+        .await
+        .map_err(ConcatError::CreateMultipartUpload)?;
+    let part_template = config.part_template(bucket, key, upload.upload_id());
+    let parts = Concat {
+        client,
+        part_template: &part_template,
+        retry,
+        buffer: BytesMut::new(),
+        parts: Vec::new(),
+    }
+    .run(sources, source_sizes, seek_table)
+    .await;
+    match parts {
+        Ok(parts) => upload
+            .complete(parts)
+            .await
+            .map_err(ConcatError::CompleteMultipartUpload),
+        Err(error) => {
+            let upload_id = upload.upload_id().to_owned();
+            match upload.abort().await {
+                Ok(()) => Err(error),
+                Err(abort_error) => Err(ConcatError::Abort {
+                    upload_id,
+                    error: Box::new(error),
+                    abort_error,
+                }),
+            }
+        }
+    }
+}
+
+// How many parts Concat::run makes out of sources of the given sizes, going
+// about it the same way but without the requests. The seek table goes in the
+// last part, which is always there.
+fn count_parts(source_sizes: &[u64]) -> u64 {
+    let minimum_part_size = MINIMUM_PART_SIZE as u64;
+    let mut parts = 1;
+    let mut buffered = 0;
+    for &size in source_sizes {
+        let mut position = 0;
+        while position < size {
+            let remaining = size - position;
+            if buffered == 0 && remaining >= minimum_part_size {
+                position += remaining.min(MAXIMUM_PART_SIZE);
+                parts += 1;
+            } else {
+                let length = if buffered == 0 {
+                    remaining
+                } else {
+                    remaining.min(minimum_part_size - buffered)
+                };
+                position += length;
+                buffered += length;
+                if buffered >= minimum_part_size {
+                    parts += 1;
+                    buffered = 0;
+                }
+            }
+        }
+    }
+    parts
+}
+
+fn source_error(source: &GetObjectRequest, error: ReadObjectError) -> ConcatError {
+    ConcatError::Source {
+        bucket: source.bucket.to_owned(),
+        key: source.key.to_owned(),
+        error,
+    }
+}
+
+// x-amz-copy-source for the source object.
+fn copy_source(source: &GetObjectRequest) -> String {
+    let copy_source = format!(
+        "{}/{}",
+        percent_encode(&source.bucket),
+        percent_encode(&source.key)
+    );
+    match &source.version_id {
+        Some(version_id) => format!("{}?versionId={}", copy_source, percent_encode(version_id)),
+        None => copy_source,
+    }
+}
+
+// State of putting the parts of a concatenation together.
+struct Concat<'a, C> {
+    client: &'a C,
+    part_template: &'a UploadPartRequest,
+    retry: &'a RetryPolicy,
+    // Data downloaded from the sources that's too small to be its own part.
+    // Always smaller than MINIMUM_PART_SIZE between steps.
+    buffer: BytesMut,
+    parts: Vec<CompletedPart>,
+}
+
+impl<C: S3> Concat<'_, C> {
+    async fn run(
+        mut self,
+        sources: &[GetObjectRequest],
+        source_sizes: &[u64],
+        seek_table: &[u8],
+    ) -> Result<Vec<CompletedPart>, ConcatError> {
+        let minimum_part_size = MINIMUM_PART_SIZE as u64;
+        for (source, &size) in sources.iter().zip(source_sizes) {
+            let mut position = 0;
+            while position < size {
+                let remaining = size - position;
+                if self.buffer.is_empty() && remaining >= minimum_part_size {
+                    let length = remaining.min(MAXIMUM_PART_SIZE);
+                    self.copy_part(source, position, length).await?;
+                    position += length;
+                } else {
+                    // Only take as much as we need to fill up a part so that
+                    // we get back to copying as soon as possible.
+                    let length = if self.buffer.is_empty() {
+                        remaining
+                    } else {
+                        remaining.min(minimum_part_size - self.buffer.len() as u64)
+                    };
+                    self.download(source, position, length).await?;
+                    position += length;
+                    if self.buffer.len() as u64 >= minimum_part_size {
+                        self.upload_buffer().await?;
+                    }
+                }
+            }
+        }
+        // The last part has no minimum size so whatever is left goes there.
+        self.buffer.extend_from_slice(seek_table);
+        self.upload_buffer().await?;
+        Ok(self.parts)
+    }
+
+    fn next_part_number(&self) -> i64 {
+        self.parts.len() as i64 + 1
+    }
+
+    async fn copy_part(
+        &mut self,
+        source: &GetObjectRequest,
+        position: u64,
+        length: u64,
+    ) -> Result<(), ConcatError> {
+        let part_template = self.part_template;
+        let part_number = self.next_part_number();
+        let mut failed_attempts = 0;
+        let e_tag = loop {
+            let req = UploadPartCopyRequest {
+                bucket: part_template.bucket.to_owned(),
+                copy_source: copy_source(source),
+                copy_source_range: Some(format!("bytes={}-{}", position, position + length - 1)),
+                copy_source_sse_customer_algorithm: source.sse_customer_algorithm.to_owned(),
+                copy_source_sse_customer_key: source.sse_customer_key.to_owned(),
+                copy_source_sse_customer_key_md5: source.sse_customer_key_md5.to_owned(),
+                expected_bucket_owner: part_template.expected_bucket_owner.to_owned(),
+                expected_source_bucket_owner: source.expected_bucket_owner.to_owned(),
+                key: part_template.key.to_owned(),
+                part_number,
+                request_payer: part_template.request_payer.to_owned(),
+                sse_customer_algorithm: part_template.sse_customer_algorithm.to_owned(),
+                sse_customer_key: part_template.sse_customer_key.to_owned(),
+                sse_customer_key_md5: part_template.sse_customer_key_md5.to_owned(),
+                upload_id: part_template.upload_id.to_owned(),
+                ..Default::default()
+            };
+            match self.client.upload_part_copy(req).await {
+                Ok(output) => break output.copy_part_result.and_then(|result| result.e_tag),
+                Err(e) => {
+                    failed_attempts += 1;
+                    if !is_retryable(&e) || !self.retry.should_retry(failed_attempts) {
+                        return Err(ConcatError::UploadPartCopy(e));
+                    }
+                    tokio::time::sleep(self.retry.backoff(failed_attempts)).await;
+                }
+            }
+        };
+        self.parts.push(CompletedPart {
+            e_tag,
+            part_number: Some(part_number),
+        });
+        Ok(())
+    }
+
+    async fn download(
+        &mut self,
+        source: &GetObjectRequest,
+        position: u64,
+        length: u64,
+    ) -> Result<(), ConcatError> {
+        let range = format!("bytes={}-{}", position, position + length - 1);
+        let (data, _) = get_object_range(self.client, source, range)
+            .await
+            .map_err(|error| source_error(source, error))?;
+        self.buffer.extend_from_slice(&data);
+        Ok(())
+    }
+
+    async fn upload_buffer(&mut self) -> Result<(), ConcatError> {
+        let data = self.buffer.split().freeze();
+        let md5 = md5::compute(&data).0;
+        let part = PreparedPart {
+            part_number: self.next_part_number(),
+            data: PartData::Memory(data),
+            content_md5: Some(base64::encode(md5)),
+            md5: Some(md5),
+        };
+        let completed_part =
+            upload_prepared_part(self.client, self.part_template, &part, self.retry)
+                .await
+                .map_err(ConcatError::UploadPart)?;
+        self.parts.push(completed_part);
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+// Total size of the object out of a Content-Range such as "bytes 0-8/1234".
+pub(crate) fn total_size(content_range: &str) -> Option<u64> {
+    let (_, total) = content_range.rsplit_once('/')?;
+    total.trim().parse().ok()
+}
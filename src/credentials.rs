@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rusoto_core::credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Cached credentials are fetched again once they expire within this many
+// seconds, so that requests signed with them don't outlive them.
+const EXPIRY_MARGIN_SECS: i64 = 5 * 60;
+
+// Gets reads new credentials when S3 says the ones a request was signed with
+// expired, see ReadOptions::refresh_credentials. RefreshingProvider::refresher
+// makes one for that provider; anything else that caches credentials can
+// drop them in a function of its own.
+#[derive(Clone)]
+pub struct CredentialsRefresh(Arc<dyn Fn() + Send + Sync>);
+
+impl CredentialsRefresh {
+    pub fn new<F>(refresh: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        CredentialsRefresh(Arc::new(refresh))
+    }
+
+    pub(crate) fn refresh(&self) {
+        (self.0)()
+    }
+}
+
+impl std::fmt::Debug for CredentialsRefresh {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CredentialsRefresh").finish()
+    }
+}
+
+// Provider holding on to what the given provider gives until shortly before
+// it expires or until it's told to refresh, whichever comes first. Use it
+// instead of rusoto's AutoRefreshingProvider, around a provider that doesn't
+// cache, such as StsAssumeRoleSessionCredentialsProvider, for reads that go on
+// for longer than the credentials of an assumed role last.
+pub struct RefreshingProvider<P> {
+    provider: P,
+    cached: Arc<Mutex<Option<AwsCredentials>>>,
+}
+
+impl<P> RefreshingProvider<P> {
+    pub fn new(provider: P) -> Self {
+        RefreshingProvider {
+            provider,
+            cached: Arc::new(parking_lot::const_mutex(None)),
+        }
+    }
+
+    // Drops the cached credentials so that the next request fetches new
+    // ones. Goes in ReadOptions::refresh_credentials.
+    pub fn refresher(&self) -> CredentialsRefresh {
+        let cached = self.cached.clone();
+        CredentialsRefresh::new(move || {
+            cached.lock().take();
+        })
+    }
+}
+
+impl<P> std::fmt::Debug for RefreshingProvider<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RefreshingProvider")
+            .field("cached", &self.cached.lock().is_some())
+            .finish()
+    }
+}
+
+fn expires_soon(credentials: &AwsCredentials) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    credentials
+        .expires_at()
+        .as_ref()
+        .map_or(false, |expires_at| {
+            expires_at.timestamp() - EXPIRY_MARGIN_SECS <= now
+        })
+}
+
+#[async_trait]
+impl<P: ProvideAwsCredentials + Send + Sync> ProvideAwsCredentials for RefreshingProvider<P> {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let cached = self.cached.lock().clone();
+        if let Some(credentials) = cached.filter(|credentials| !expires_soon(credentials)) {
+            return Ok(credentials);
+        }
+        let credentials = self.provider.credentials().await?;
+        *self.cached.lock() = Some(credentials.clone());
+        Ok(credentials)
+    }
+}
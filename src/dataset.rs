@@ -0,0 +1,410 @@
+use bytes::{Bytes, BytesMut};
+use futures::SinkExt;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectRequest, PutObjectError, S3};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::{Read, Seek, SeekFrom};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::object_index::get_object_range;
+use crate::seek::seek_position;
+use crate::{ReadObjectError, ReadOptions, SeekableDecompress, SeekableS3Object};
+use crate::{UploadError, UploadOptions, UploadSink};
+
+const READ_SIZE: usize = 512 * 1024;
+
+// A seekable object that's part of a dataset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatasetObject {
+    pub bucket: String,
+    pub key: String,
+    pub decompressed_size: u64,
+}
+
+// Lists the objects a dataset is made of, in order. Stored as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatasetManifest {
+    pub objects: Vec<DatasetObject>,
+}
+
+impl DatasetManifest {
+    pub fn decompressed_size(&self) -> u64 {
+        self.objects
+            .iter()
+            .map(|object| object.decompressed_size)
+            .sum()
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DatasetError {
+    GetManifest(ReadObjectError),
+    PutManifest(RusotoError<PutObjectError>),
+    // The manifest isn't valid JSON or doesn't describe a dataset.
+    Manifest(serde_json::Error),
+    Upload(UploadError<std::io::Error>),
+}
+
+impl Display for DatasetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatasetError::GetManifest(e) => write!(f, "Failed to get manifest: {}", e),
+            DatasetError::PutManifest(e) => write!(f, "Failed to upload manifest: {}", e),
+            DatasetError::Manifest(e) => write!(f, "Bad manifest: {}", e),
+            DatasetError::Upload(e) => write!(f, "Failed to upload object: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DatasetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DatasetError::GetManifest(e) => Some(e),
+            DatasetError::PutManifest(e) => Some(e),
+            DatasetError::Manifest(e) => Some(e),
+            DatasetError::Upload(e) => Some(e),
+        }
+    }
+}
+
+pub async fn fetch_manifest<C: S3>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+) -> Result<DatasetManifest, DatasetError> {
+    let req = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let (data, _) = get_object_range(client, &req, "bytes=0-".to_owned())
+        .await
+        .map_err(DatasetError::GetManifest)?;
+    serde_json::from_slice(&data).map_err(DatasetError::Manifest)
+}
+
+pub async fn put_manifest<C: S3>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    manifest: &DatasetManifest,
+) -> Result<(), DatasetError> {
+    let manifest = serde_json::to_vec_pretty(manifest).map_err(DatasetError::Manifest)?;
+    let req = rusoto_s3::PutObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        content_type: Some("application/json".to_owned()),
+        content_md5: Some(base64::encode(md5::compute(&manifest).0)),
+        body: Some(manifest.into()),
+        ..Default::default()
+    };
+    client
+        .put_object(req)
+        .await
+        .map_err(DatasetError::PutManifest)?;
+    Ok(())
+}
+
+// Writes a dataset, starting a new object every time the current one grows
+// past a size. Objects are only ever switched between writes so records that
+// are written whole never straddle two objects, unless writes are split (see
+// set_split_writes). Objects are named after the prefix and their number in
+// the dataset.
+//
+// The writer has to be used from within a tokio runtime, see UploadSink.
+#[derive(Debug)]
+pub struct DatasetWriter<C> {
+    client: C,
+    bucket: String,
+    prefix: String,
+    options: UploadOptions,
+    // Uncompressed size at which to start a new object.
+    object_size: u64,
+    manifest: DatasetManifest,
+    // The object we're writing, and how much went into it so far.
+    current: Option<(UploadSink, DatasetObject)>,
+    split_writes: bool,
+}
+
+impl<C> DatasetWriter<C>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    pub fn new(
+        client: C,
+        bucket: String,
+        prefix: String,
+        object_size: u64,
+        options: UploadOptions,
+    ) -> Self {
+        DatasetWriter {
+            client,
+            bucket,
+            prefix,
+            options,
+            object_size: object_size.max(1),
+            manifest: DatasetManifest::default(),
+            current: None,
+            split_writes: false,
+        }
+    }
+
+    // Cut writes that don't fit into the current object so that every object
+    // but the last holds exactly the object size, for data that has no
+    // records to keep whole. Off by default.
+    pub fn set_split_writes(&mut self, enabled: bool) {
+        self.split_writes = enabled;
+    }
+
+    pub async fn write(&mut self, mut data: Bytes) -> Result<(), DatasetError> {
+        if self.split_writes {
+            loop {
+                let written = self
+                    .current
+                    .as_ref()
+                    .map_or(0, |(_, object)| object.decompressed_size);
+                let room = (self.object_size - written) as usize;
+                if data.len() <= room {
+                    break;
+                }
+                let rest = data.split_off(room);
+                self.write_to_object(data).await?;
+                data = rest;
+            }
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.write_to_object(data).await
+    }
+
+    async fn write_to_object(&mut self, data: Bytes) -> Result<(), DatasetError> {
+        let (mut sink, mut object) = match self.current.take() {
+            Some(current) => current,
+            None => self.start_object()?,
+        };
+        object.decompressed_size += data.len() as u64;
+        let full = object.decompressed_size >= self.object_size;
+        let sent = sink.send(data).await;
+        self.current = Some((sink, object));
+        sent.map_err(DatasetError::Upload)?;
+        if full {
+            self.finish_object().await?;
+        }
+        Ok(())
+    }
+
+    // Finishes the last object and uploads the manifest to the given key,
+    // returning it.
+    pub async fn finish(mut self, manifest_key: &str) -> Result<DatasetManifest, DatasetError> {
+        self.finish_object().await?;
+        put_manifest(&self.client, &self.bucket, manifest_key, &self.manifest).await?;
+        Ok(self.manifest)
+    }
+
+    fn start_object(&self) -> Result<(UploadSink, DatasetObject), DatasetError> {
+        let key = format!("{}{:06}.zst", self.prefix, self.manifest.objects.len());
+        let sink = UploadSink::new(
+            self.client.clone(),
+            self.bucket.to_owned(),
+            key.to_owned(),
+            self.options.to_owned(),
+        )
+        .map_err(DatasetError::Upload)?;
+        let object = DatasetObject {
+            bucket: self.bucket.to_owned(),
+            key,
+            decompressed_size: 0,
+        };
+        Ok((sink, object))
+    }
+
+    async fn finish_object(&mut self) -> Result<(), DatasetError> {
+        if let Some((mut sink, object)) = self.current.take() {
+            sink.close().await.map_err(DatasetError::Upload)?;
+            self.manifest.objects.push(object);
+        }
+        Ok(())
+    }
+}
+
+// Compresses everything the reader gives into a dataset of objects of the
+// given size under the prefix (see DatasetWriter::set_split_writes), for data
+// too big to go into a single object, and uploads its manifest to the given
+// key. Read it back with DatasetReader::open.
+pub async fn upload_split_reader<C, R>(
+    client: C,
+    bucket: &str,
+    prefix: &str,
+    manifest_key: &str,
+    mut reader: R,
+    object_size: u64,
+    options: UploadOptions,
+) -> Result<DatasetManifest, DatasetError>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+    R: AsyncRead + Unpin,
+{
+    let mut writer = DatasetWriter::new(
+        client,
+        bucket.to_owned(),
+        prefix.to_owned(),
+        object_size,
+        options,
+    );
+    writer.set_split_writes(true);
+    loop {
+        let mut chunk = BytesMut::with_capacity(READ_SIZE);
+        let n = reader
+            .read_buf(&mut chunk)
+            .await
+            .map_err(|e| DatasetError::Upload(UploadError::Underlying(e)))?;
+        if n == 0 {
+            break;
+        }
+        writer.write(chunk.freeze()).await?;
+    }
+    writer.finish(manifest_key).await
+}
+
+// Reads the objects of a dataset one after the other, as if they were a
+// single stream of decompressed data. Objects are opened as reads get to
+// them, with the given options.
+pub struct DatasetReader<'a, C> {
+    client: C,
+    runtime: &'a tokio::runtime::Runtime,
+    options: ReadOptions,
+    manifest: DatasetManifest,
+    // Where each object starts in the data.
+    starts: Vec<u64>,
+    size: u64,
+    position: u64,
+    // The object we read last, by its index in the manifest.
+    current: Option<(usize, SeekableDecompress<'a, SeekableS3Object<'a, C>>)>,
+}
+
+impl<C> std::fmt::Debug for DatasetReader<'_, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DatasetReader")
+            .field("options", &self.options)
+            .field("manifest", &self.manifest)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl<'a, C> DatasetReader<'a, C>
+where
+    C: S3 + Clone,
+{
+    pub fn new(
+        client: C,
+        runtime: &'a tokio::runtime::Runtime,
+        manifest: DatasetManifest,
+        options: ReadOptions,
+    ) -> Self {
+        let mut starts = Vec::with_capacity(manifest.objects.len());
+        let mut size = 0;
+        for object in &manifest.objects {
+            starts.push(size);
+            size += object.decompressed_size;
+        }
+        DatasetReader {
+            client,
+            runtime,
+            options,
+            manifest,
+            starts,
+            size,
+            position: 0,
+            current: None,
+        }
+    }
+
+    // Fetches the manifest at bucket/key and reads the dataset it describes.
+    pub fn open(
+        client: C,
+        runtime: &'a tokio::runtime::Runtime,
+        bucket: &str,
+        manifest_key: &str,
+        options: ReadOptions,
+    ) -> Result<Self, DatasetError> {
+        let manifest = runtime.block_on(fetch_manifest(&client, bucket, manifest_key))?;
+        Ok(Self::new(client, runtime, manifest, options))
+    }
+
+    pub fn manifest(&self) -> &DatasetManifest {
+        &self.manifest
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // The object holding the given position, skipping over empty ones.
+    fn object_at(&self, position: u64) -> Option<usize> {
+        let index = self
+            .starts
+            .partition_point(|&start| start <= position)
+            .checked_sub(1)?;
+        if position < self.starts[index] + self.manifest.objects[index].decompressed_size {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn open(
+        &mut self,
+        index: usize,
+    ) -> std::io::Result<&mut SeekableDecompress<'a, SeekableS3Object<'a, C>>> {
+        if self.current.as_ref().map(|(current, _)| *current) != Some(index) {
+            let object = &self.manifest.objects[index];
+            let req = GetObjectRequest {
+                bucket: object.bucket.to_owned(),
+                key: object.key.to_owned(),
+                ..Default::default()
+            };
+            let object =
+                SeekableS3Object::lazy(self.client.clone(), self.runtime, req, &self.options);
+            let object = SeekableDecompress::new(object)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+            self.current = Some((index, object));
+        }
+        self.current
+            .as_mut()
+            .map(|(_, object)| object)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "No object open."))
+    }
+}
+
+impl<C> Read for DatasetReader<'_, C>
+where
+    C: S3 + Clone,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let index = match self.object_at(self.position) {
+            Some(index) => index,
+            None => return Ok(0),
+        };
+        let offset = self.position - self.starts[index];
+        let object = self.open(index)?;
+        object.seek(SeekFrom::Start(offset))?;
+        let n = object.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<C> Seek for DatasetReader<'_, C> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(pos, self.position, self.size, false)?;
+        Ok(self.position)
+    }
+}
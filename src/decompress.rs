@@ -1,105 +1,263 @@
-use std::{convert::TryFrom, fmt::Display, num::TryFromIntError};
-use zstd_seekable::Seekable;
+use parking_lot::{Mutex, MutexGuard};
+use std::{convert::TryFrom, fmt::Display, num::TryFromIntError, sync::Arc};
+use zstd_seekable::{DStream, Seekable};
+
+use crate::index_frame::read_seek_table_size;
+use crate::seek::seek_position;
+use crate::ZstdError;
 
 // The seek/read methods on this object will read/seek uncompress an underlying
 // object and read/seek within it.
 pub struct SeekableDecompress<'a, A> {
-    seekable: Seekable<'a, A>,
+    seekable: Seekable<'a, RetryInterrupted<Shared<A>>>,
+    // The same compressed data seekable reads, for get_ref and the like.
+    compressed: Shared<A>,
     // We use this across read invocations to make sure we don't run off the end
     // of stream so just compute it once ahead of time.
     decompressed_size: u64,
     // Seek position in the decompressed data.
     decompressed_position: u64,
+    // Whether seeking past the end fails, see set_strict_seeks.
+    strict_seeks: bool,
+}
+
+// Compressed data zstd reads from, which we get at too. zstd only takes the
+// lock while it reads, from within our read, so it's never contended.
+struct Shared<A>(Arc<Mutex<A>>);
+
+impl<A: std::io::Read> std::io::Read for Shared<A> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.lock().read(buf)
+    }
+}
+
+impl<A: std::io::Seek> std::io::Seek for Shared<A> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.0.lock().seek(pos)
+    }
+}
+
+// zstd gives up on the first error reading the compressed data, so reads and
+// seeks interrupted before they got to do anything are tried again here.
+struct RetryInterrupted<A>(A);
+
+impl<A: std::io::Read> std::io::Read for RetryInterrupted<A> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                read => return read,
+            }
+        }
+    }
+}
+
+impl<A: std::io::Seek> std::io::Seek for RetryInterrupted<A> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        loop {
+            match self.0.seek(pos) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                seek => return seek,
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
-    NoFrames,
     // Frame size was too big for u64.
     FrameTooLarge(TryFromIntError),
     // End of data was past u64.
     DataTooLarge,
-    ZstdSeekable(zstd_seekable::Error),
+    Zstd(ZstdError),
+    // Reading the seek table failed.
+    Io(std::io::Error),
+    // The frames in the seek table and the table itself don't add up to the
+    // size of the compressed data, as when an upload was cut short.
+    SizeMismatch { object_size: u64, expected: u64 },
+    // zstd held on to the compressed data after being done with it, so
+    // into_inner couldn't give it back.
+    CompressedDataHeld,
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::NoFrames => write!(
-                f,
-                "No frames found in the stream. Use regular decompression."
-            ),
             Error::FrameTooLarge(e) => {
                 write!(f, "Encountered a frame larger than we can work with: {}", e)
             }
             Error::DataTooLarge => write!(f, "Data larger than we can work with."),
-            Error::ZstdSeekable(e) => write!(f, "{}", e),
+            Error::Zstd(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "Failed to read seek table: {}", e),
+            Error::SizeMismatch {
+                object_size,
+                expected,
+            } => write!(
+                f,
+                "Compressed data is {} bytes but its seek table says it should be {}",
+                object_size, expected
+            ),
+            Error::CompressedDataHeld => write!(f, "Compressed data still in use by zstd"),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::FrameTooLarge(e) => Some(e),
+            Error::Zstd(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::DataTooLarge | Error::SizeMismatch { .. } | Error::CompressedDataHeld => None,
+        }
+    }
+}
+
+// Decompresses a whole frame, or None if it doesn't decompress.
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub(crate) fn decompress_frame(dstream: &mut DStream, frame: &[u8]) -> Option<Vec<u8>> {
+    let mut out = vec![0; DStream::out_size()];
+    let mut data = Vec::new();
+    let mut input = frame;
+    loop {
+        let (out_pos, in_pos) = dstream.decompress(&mut out, input).ok()?;
+        data.extend_from_slice(&out[..out_pos]);
+        input = &input[in_pos..];
+        if input.is_empty() && out_pos < out.len() {
+            return Some(data);
+        }
+        if out_pos == 0 && in_pos == 0 {
+            return None;
+        }
+    }
+}
 
 impl<'a, A> SeekableDecompress<'a, A>
 where
     A: std::io::Read + std::io::Seek,
 {
-    pub fn new(compressed: A) -> Result<Self, Error> {
-        let seekable = Seekable::init(Box::new(compressed)).map_err(Error::ZstdSeekable)?;
+    // Checks the seek table against the size of the compressed data before
+    // reading any frames, so that data that's been cut short or added to
+    // fails right away rather than when reading gets to the missing part.
+    pub fn new(mut compressed: A) -> Result<Self, Error> {
+        let object_size = std::io::Seek::seek(
+            &mut RetryInterrupted(&mut compressed),
+            std::io::SeekFrom::End(0),
+        )
+        .map_err(Error::Io)?;
+        // zstd reads the table itself, all we need is how big it is.
+        let table_size =
+            read_seek_table_size(&mut RetryInterrupted(&mut compressed)).map_err(Error::Io)?;
+        let compressed = Shared(Arc::new(parking_lot::const_mutex(compressed)));
+        let seekable = Seekable::init(Box::new(RetryInterrupted(Shared(compressed.0.clone()))))
+            .map_err(|e| Error::Zstd(ZstdError::new(e)))?;
 
-        let decompressed_size = {
+        // Where the last frame ends, in the compressed and the decompressed
+        // data.
+        let (compressed_size, decompressed_size) = {
             let num_frames = seekable.get_num_frames();
-            if num_frames == 0 {
-                return Err(Error::NoFrames);
-            }
-            let last_frame_index = num_frames - 1;
-            let last_frame_start = seekable.get_frame_decompressed_offset(last_frame_index);
-            let last_frame_size = seekable.get_frame_decompressed_size(last_frame_index);
-            match u64::try_from(last_frame_size) {
-                Ok(last_frame_size) => match last_frame_start.checked_add(last_frame_size) {
-                    None => return Err(Error::DataTooLarge),
-                    Some(r) => r,
-                },
-                Err(e) => return Err(Error::FrameTooLarge(e)),
+            // Compressing nothing makes a seek table of no frames, which is
+            // as valid as any: there's just nothing to read.
+            match num_frames.checked_sub(1) {
+                None => (0, 0),
+                Some(last_frame_index) => {
+                    let frame_end = |start: u64, size: usize| {
+                        let size = u64::try_from(size).map_err(Error::FrameTooLarge)?;
+                        start.checked_add(size).ok_or(Error::DataTooLarge)
+                    };
+                    (
+                        frame_end(
+                            seekable.get_frame_compressed_offset(last_frame_index),
+                            seekable.get_frame_compressed_size(last_frame_index),
+                        )?,
+                        frame_end(
+                            seekable.get_frame_decompressed_offset(last_frame_index),
+                            seekable.get_frame_decompressed_size(last_frame_index),
+                        )?,
+                    )
+                }
             }
         };
+        let expected = compressed_size
+            .checked_add(table_size)
+            .ok_or(Error::DataTooLarge)?;
+        if expected != object_size {
+            return Err(Error::SizeMismatch {
+                object_size,
+                expected,
+            });
+        }
+
+        log_event!(
+            debug,
+            target: "zstd_seekable_s3::open",
+            frames = seekable.get_num_frames(),
+            decompressed_size,
+            "Read seek table"
+        );
 
         Ok(SeekableDecompress {
             seekable,
+            compressed,
             decompressed_size,
             decompressed_position: 0,
+            strict_seeks: false,
         })
     }
 }
 
+impl<'a, A> SeekableDecompress<'a, A> {
+    // Make seeks past the end of the decompressed data fail rather than
+    // land there, as SeekableS3Object::set_strict_seeks does for objects.
+    pub fn set_strict_seeks(&mut self, strict: bool) {
+        self.strict_seeks = strict;
+    }
+
+    // The compressed data being read, such as the SeekableS3Object to change
+    // the timeouts of. Reading goes through the same lock, so let go of it
+    // before reading again.
+    pub fn get_ref(&self) -> MutexGuard<'_, A> {
+        self.compressed.0.lock()
+    }
+
+    // As get_ref. zstd keeps track of where it left the compressed data, so
+    // seeking it or reading from it here mixes up what gets decompressed
+    // next.
+    pub fn get_mut(&mut self) -> MutexGuard<'_, A> {
+        self.compressed.0.lock()
+    }
+
+    // Done decompressing, gives back the compressed data, wherever zstd last
+    // left it.
+    pub fn into_inner(self) -> Result<A, Error> {
+        let SeekableDecompress {
+            seekable,
+            compressed,
+            ..
+        } = self;
+        // Dropping the seekable drops its handle on the data, which should
+        // leave ours the only one.
+        drop(seekable);
+        Arc::try_unwrap(compressed.0)
+            .map(Mutex::into_inner)
+            .map_err(|_| Error::CompressedDataHeld)
+    }
+}
+
 impl<'a, A> std::io::Seek for SeekableDecompress<'a, A> {
     // Seeking inside decompressed data does nothing except store the location
     // as there is no actual decompressed data on hand to seek in. We use this
-    // location when we try to perform an actual read of the data.
+    // location when we try to perform an actual read of the data. Where seeks
+    // land and which fail is as for SeekableS3Object.
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        let (base_pos, offset) = match pos {
-            std::io::SeekFrom::Start(pos) => {
-                self.decompressed_position = pos;
-                return Ok(pos);
-            }
-            std::io::SeekFrom::End(pos) => (self.decompressed_size, pos),
-            std::io::SeekFrom::Current(pos) => (self.decompressed_position, pos),
-        };
-        let new_pos = if offset >= 0 {
-            base_pos.checked_add(offset as u64)
-        } else {
-            base_pos.checked_sub((offset.wrapping_neg()) as u64)
-        };
-        match new_pos {
-            Some(n) => {
-                self.decompressed_position = n;
-                Ok(self.decompressed_position)
-            }
-            None => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "invalid seek to a negative or overflowing position",
-            )),
-        }
+        self.decompressed_position = seek_position(
+            pos,
+            self.decompressed_position,
+            self.decompressed_size,
+            self.strict_seeks,
+        )?;
+        Ok(self.decompressed_position)
     }
 }
 
@@ -138,7 +296,7 @@ impl<'a, A> std::io::Read for SeekableDecompress<'a, A> {
         }
 
         let our_error = |e| std::io::Error::new(std::io::ErrorKind::Other, e);
-        let zstd_error = |e: zstd_seekable::Error| our_error(Error::ZstdSeekable(e));
+        let zstd_error = |e: zstd_seekable::Error| our_error(Error::Zstd(ZstdError::new(e)));
 
         // We're finally done setting up the output buffer, actually read in the
         // decompressed data at current position now.
@@ -146,6 +304,13 @@ impl<'a, A> std::io::Read for SeekableDecompress<'a, A> {
             .seekable
             .decompress(buf, self.decompressed_position)
             .map_err(zstd_error)?;
+        log_event!(
+            trace,
+            target: "zstd_seekable_s3::decompress",
+            position = self.decompressed_position,
+            bytes = decompressed_bytes,
+            "Decompressed"
+        );
 
         // Bump the position by however many bytes we have managed to read in.
         {
@@ -0,0 +1,410 @@
+use bytes::{Bytes, BytesMut};
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    GetObjectRequest, HeadObjectError, HeadObjectRequest, PutObjectError, PutObjectRequest, S3,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use zstd_seekable::{CStream, SeekableCStream};
+
+use crate::blocking::block_on;
+use crate::object_index::get_object_range;
+use crate::seek::seek_position;
+use crate::{ReadObjectError, SeekableDecompress, ZstdError};
+
+// A chunk of the logical stream, stored under the SHA-256 of its data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DedupChunk {
+    // Lowercase hex.
+    pub hash: String,
+    pub decompressed_offset: u64,
+    pub decompressed_size: u64,
+}
+
+// Lists the chunks a logical stream is made of, in order, and where they
+// are. Stored as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DedupManifest {
+    pub bucket: String,
+    // Chunks are at {prefix}{hash}.zst.
+    pub prefix: String,
+    pub chunks: Vec<DedupChunk>,
+}
+
+impl DedupManifest {
+    pub fn decompressed_size(&self) -> u64 {
+        self.chunks.last().map_or(0, |chunk| {
+            chunk.decompressed_offset + chunk.decompressed_size
+        })
+    }
+
+    pub fn chunk_key(&self, chunk: &DedupChunk) -> String {
+        chunk_key(&self.prefix, &chunk.hash)
+    }
+}
+
+fn chunk_key(prefix: &str, hash: &str) -> String {
+    format!("{}{}.zst", prefix, hash)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    pub uploaded_chunks: u64,
+    pub uploaded_bytes: u64,
+    // Chunks that were already in the store, or came up earlier in the
+    // stream.
+    pub reused_chunks: u64,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DedupError {
+    Compression(ZstdError),
+    HeadObject(RusotoError<HeadObjectError>),
+    PutObject(RusotoError<PutObjectError>),
+    GetManifest(ReadObjectError),
+    // The manifest isn't valid JSON or doesn't describe a stream.
+    Manifest(serde_json::Error),
+}
+
+impl Display for DedupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DedupError::Compression(e) => write!(f, "Failed to compress chunk: {}", e),
+            DedupError::HeadObject(e) => write!(f, "Failed to look up chunk: {}", e),
+            DedupError::PutObject(e) => write!(f, "Failed to upload: {}", e),
+            DedupError::GetManifest(e) => write!(f, "Failed to get manifest: {}", e),
+            DedupError::Manifest(e) => write!(f, "Bad manifest: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DedupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DedupError::Compression(e) => Some(e),
+            DedupError::HeadObject(e) => Some(e),
+            DedupError::PutObject(e) => Some(e),
+            DedupError::GetManifest(e) => Some(e),
+            DedupError::Manifest(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DedupOptions {
+    // Uncompressed size of each chunk. Chunks are cut at fixed offsets, so
+    // data only dedups against data at the same offset modulo this.
+    pub chunk_size: usize,
+    pub compression_level: usize,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        DedupOptions {
+            chunk_size: 1024 * 1024,
+            compression_level: 3,
+        }
+    }
+}
+
+// Compresses a chunk into a seekable stream of a single frame, so chunks can
+// be read like any other object.
+fn compress_chunk(compression_level: usize, data: &[u8]) -> Result<Vec<u8>, zstd_seekable::Error> {
+    let mut cstream = SeekableCStream::new(compression_level, std::cmp::max(data.len(), 1))?;
+    let mut buf_out = vec![0; CStream::out_size()];
+    let mut compressed = Vec::new();
+    let mut input = data;
+    while !input.is_empty() {
+        let (out_pos, in_pos) = cstream.compress(&mut buf_out, input)?;
+        compressed.extend_from_slice(&buf_out[..out_pos]);
+        input = &input[in_pos..];
+    }
+    loop {
+        let out_pos = cstream.end_stream(&mut buf_out)?;
+        compressed.extend_from_slice(&buf_out[..out_pos]);
+        if out_pos == 0 {
+            break;
+        }
+    }
+    Ok(compressed)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Writes a logical stream as content-addressed chunks: each chunk is stored
+// once under the prefix, named after the hash of its data, and chunks that
+// are already there aren't uploaded again. The manifest maps the stream back
+// onto the chunks. Chunks are uploaded one at a time as they fill up.
+#[derive(Debug)]
+pub struct DedupWriter<C> {
+    client: C,
+    options: DedupOptions,
+    manifest: DedupManifest,
+    // Data of the chunk being filled.
+    pending: BytesMut,
+    // Chunks we know are in the store.
+    known: HashSet<String>,
+    stats: DedupStats,
+}
+
+impl<C: S3> DedupWriter<C> {
+    pub fn new(client: C, bucket: String, prefix: String, options: DedupOptions) -> Self {
+        let options = DedupOptions {
+            chunk_size: options.chunk_size.max(1),
+            ..options
+        };
+        DedupWriter {
+            client,
+            pending: BytesMut::with_capacity(options.chunk_size),
+            options,
+            manifest: DedupManifest {
+                bucket,
+                prefix,
+                chunks: Vec::new(),
+            },
+            known: HashSet::new(),
+            stats: DedupStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        self.stats
+    }
+
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), DedupError> {
+        while !data.is_empty() {
+            let n = std::cmp::min(data.len(), self.options.chunk_size - self.pending.len());
+            self.pending.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.pending.len() == self.options.chunk_size {
+                let chunk = self.pending.split().freeze();
+                self.store_chunk(chunk).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Stores what's left as the last chunk and uploads the manifest to the
+    // given key, returning it.
+    pub async fn finish(mut self, manifest_key: &str) -> Result<DedupManifest, DedupError> {
+        if !self.pending.is_empty() {
+            let chunk = self.pending.split().freeze();
+            self.store_chunk(chunk).await?;
+        }
+        put_dedup_manifest(&self.client, manifest_key, &self.manifest).await?;
+        Ok(self.manifest)
+    }
+
+    async fn store_chunk(&mut self, data: Bytes) -> Result<(), DedupError> {
+        let hash = hex(&Sha256::digest(&data));
+        let key = chunk_key(&self.manifest.prefix, &hash);
+        if self.known.contains(&hash) || self.chunk_exists(&key).await? {
+            self.stats.reused_chunks += 1;
+        } else {
+            let compressed = compress_chunk(self.options.compression_level, &data)
+                .map_err(|e| DedupError::Compression(ZstdError::new(e)))?;
+            self.stats.uploaded_chunks += 1;
+            self.stats.uploaded_bytes += compressed.len() as u64;
+            let req = PutObjectRequest {
+                bucket: self.manifest.bucket.to_owned(),
+                key: key.to_owned(),
+                content_md5: Some(base64::encode(md5::compute(&compressed).0)),
+                body: Some(compressed.into()),
+                ..Default::default()
+            };
+            let put_object = traced!(
+                self.client.put_object(req),
+                "put_object",
+                bucket = %self.manifest.bucket,
+                key = %key,
+            );
+            put_object.await.map_err(DedupError::PutObject)?;
+        }
+        self.known.insert(hash.to_owned());
+        let decompressed_offset = self.manifest.decompressed_size();
+        self.manifest.chunks.push(DedupChunk {
+            hash,
+            decompressed_offset,
+            decompressed_size: data.len() as u64,
+        });
+        Ok(())
+    }
+
+    async fn chunk_exists(&self, key: &str) -> Result<bool, DedupError> {
+        let req = HeadObjectRequest {
+            bucket: self.manifest.bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        };
+        let head_object = traced!(
+            self.client.head_object(req),
+            "head_object",
+            bucket = %self.manifest.bucket,
+            key = %key,
+        );
+        match head_object.await {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+            // HeadObject responses have no body to tell what went wrong.
+            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => Ok(false),
+            Err(e) => Err(DedupError::HeadObject(e)),
+        }
+    }
+}
+
+pub async fn fetch_dedup_manifest<C: S3>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+) -> Result<DedupManifest, DedupError> {
+    let req = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let (data, _) = get_object_range(client, &req, "bytes=0-".to_owned())
+        .await
+        .map_err(DedupError::GetManifest)?;
+    serde_json::from_slice(&data).map_err(DedupError::Manifest)
+}
+
+// Uploads the manifest to the given key of its bucket.
+pub async fn put_dedup_manifest<C: S3>(
+    client: &C,
+    key: &str,
+    manifest: &DedupManifest,
+) -> Result<(), DedupError> {
+    let data = serde_json::to_vec_pretty(manifest).map_err(DedupError::Manifest)?;
+    let req = PutObjectRequest {
+        bucket: manifest.bucket.to_owned(),
+        key: key.to_owned(),
+        content_type: Some("application/json".to_owned()),
+        content_md5: Some(base64::encode(md5::compute(&data).0)),
+        body: Some(data.into()),
+        ..Default::default()
+    };
+    client
+        .put_object(req)
+        .await
+        .map_err(DedupError::PutObject)?;
+    Ok(())
+}
+
+// Reads the logical stream a manifest describes, fetching and decompressing
+// each chunk whole as reads get to it. The last chunk read is kept around.
+pub struct DedupReader<'a, C> {
+    client: C,
+    runtime: &'a tokio::runtime::Runtime,
+    manifest: DedupManifest,
+    position: u64,
+    // The chunk we read last, by its index in the manifest, decompressed.
+    current: Option<(usize, Bytes)>,
+}
+
+impl<C> std::fmt::Debug for DedupReader<'_, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DedupReader")
+            .field("manifest", &self.manifest)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl<'a, C: S3> DedupReader<'a, C> {
+    pub fn new(client: C, runtime: &'a tokio::runtime::Runtime, manifest: DedupManifest) -> Self {
+        DedupReader {
+            client,
+            runtime,
+            manifest,
+            position: 0,
+            current: None,
+        }
+    }
+
+    pub fn manifest(&self) -> &DedupManifest {
+        &self.manifest
+    }
+
+    pub fn len(&self) -> u64 {
+        self.manifest.decompressed_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn chunk_at(&self, position: u64) -> Option<usize> {
+        let index = self
+            .manifest
+            .chunks
+            .partition_point(|chunk| chunk.decompressed_offset <= position)
+            .checked_sub(1)?;
+        let chunk = &self.manifest.chunks[index];
+        if position < chunk.decompressed_offset + chunk.decompressed_size {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn load(&mut self, index: usize) -> std::io::Result<Bytes> {
+        if let Some((current, data)) = &self.current {
+            if *current == index {
+                return Ok(data.clone());
+            }
+        }
+        let chunk = &self.manifest.chunks[index];
+        let req = GetObjectRequest {
+            bucket: self.manifest.bucket.to_owned(),
+            key: self.manifest.chunk_key(chunk),
+            ..Default::default()
+        };
+        let (compressed, _) = block_on(
+            self.runtime,
+            get_object_range(&self.client, &req, "bytes=0-".to_owned()),
+        )?
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let mut decompressed = Vec::with_capacity(chunk.decompressed_size as usize);
+        SeekableDecompress::new(Cursor::new(compressed))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() as u64 != chunk.decompressed_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Chunk {} isn't the size the manifest says", chunk.hash),
+            ));
+        }
+        let decompressed = Bytes::from(decompressed);
+        self.current = Some((index, decompressed.clone()));
+        Ok(decompressed)
+    }
+}
+
+impl<C: S3> Read for DedupReader<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let index = match self.chunk_at(self.position) {
+            Some(index) => index,
+            None => return Ok(0),
+        };
+        let data = self.load(index)?;
+        let offset = (self.position - self.manifest.chunks[index].decompressed_offset) as usize;
+        let n = std::cmp::min(buf.len(), data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<C> Seek for DedupReader<'_, C> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position =
+            seek_position(pos, self.position, self.manifest.decompressed_size(), false)?;
+        Ok(self.position)
+    }
+}
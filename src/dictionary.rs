@@ -0,0 +1,221 @@
+use bytes::Bytes;
+use parking_lot::Mutex;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectRequest, PutObjectError, PutObjectRequest, S3};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::io::{Read, Seek};
+use std::sync::Arc;
+
+use crate::blocking::block_on;
+use crate::object_index::get_object_range;
+use crate::{read_frame_index, read_index_frame, IndexBuilder, IndexFrame, ReadObjectError};
+
+// Tag of the index frame saying which dictionary a stream was made with.
+pub const DICTIONARY_ID_TAG: [u8; 4] = *b"DICT";
+
+// Dictionaries zstd trains start with this, followed by their ID.
+const DICTIONARY_MAGIC_NUMBER: u32 = 0xEC30_A437;
+
+// A trained zstd dictionary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    id: u32,
+    data: Bytes,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DictionaryError {
+    // Not a trained dictionary: raw content dictionaries have no ID to find
+    // them by.
+    MissingId,
+    Fetch(ReadObjectError),
+    Publish(RusotoError<PutObjectError>),
+    // The registry holds a different dictionary under the ID.
+    IdMismatch { expected: u32, actual: u32 },
+}
+
+impl Display for DictionaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DictionaryError::MissingId => write!(f, "Dictionary has no ID."),
+            DictionaryError::Fetch(e) => write!(f, "Failed to fetch dictionary: {}", e),
+            DictionaryError::Publish(e) => write!(f, "Failed to publish dictionary: {}", e),
+            DictionaryError::IdMismatch { expected, actual } => write!(
+                f,
+                "Expected dictionary {} but the registry has {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DictionaryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DictionaryError::Fetch(e) => Some(e),
+            DictionaryError::Publish(e) => Some(e),
+            DictionaryError::MissingId | DictionaryError::IdMismatch { .. } => None,
+        }
+    }
+}
+
+impl Dictionary {
+    pub fn new(data: Bytes) -> Result<Self, DictionaryError> {
+        let word = |at: usize| {
+            data.get(at..at + 4)
+                .and_then(|word| <[u8; 4]>::try_from(word).ok())
+                .map(u32::from_le_bytes)
+        };
+        if word(0) != Some(DICTIONARY_MAGIC_NUMBER) {
+            return Err(DictionaryError::MissingId);
+        }
+        let id = word(4).ok_or(DictionaryError::MissingId)?;
+        Ok(Dictionary { id, data })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    // Index builder stamping a stream with the ID of the dictionary, see
+    // Compress::with_index. Readers find out which dictionary they need
+    // with read_dictionary_id.
+    pub fn stamp(&self) -> DictionaryStamp {
+        DictionaryStamp(self.id)
+    }
+}
+
+// Stamps a stream with a dictionary ID, see Dictionary::stamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DictionaryStamp(pub u32);
+
+impl IndexBuilder for DictionaryStamp {
+    fn update(&mut self, _data: &[u8]) {}
+
+    fn finish(&mut self) -> IndexFrame {
+        IndexFrame {
+            tag: DICTIONARY_ID_TAG,
+            payload: self.0.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+// The ID of the dictionary the compressed data was stamped with, if any.
+pub fn read_dictionary_id<R: Read + Seek>(compressed: &mut R) -> std::io::Result<Option<u32>> {
+    let frames = read_frame_index(compressed)?;
+    match read_index_frame(compressed, &frames, DICTIONARY_ID_TAG)? {
+        None => Ok(None),
+        Some(payload) => <[u8; 4]>::try_from(payload.as_slice())
+            .map(|id| Some(u32::from_le_bytes(id)))
+            .map_err(|_e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Dictionary ID is malformed.",
+                )
+            }),
+    }
+}
+
+// Dictionaries kept in a bucket, one object per dictionary named after its ID
+// under a prefix. Dictionaries are fetched once and then served from memory;
+// clones share what was fetched.
+#[derive(Clone)]
+pub struct DictionaryRegistry<C> {
+    client: C,
+    bucket: String,
+    prefix: String,
+    cache: Arc<Mutex<HashMap<u32, Dictionary>>>,
+}
+
+impl<C> std::fmt::Debug for DictionaryRegistry<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DictionaryRegistry")
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("cached", &self.cache.lock().len())
+            .finish()
+    }
+}
+
+impl<C: S3> DictionaryRegistry<C> {
+    pub fn new(client: C, bucket: String, prefix: String) -> Self {
+        DictionaryRegistry {
+            client,
+            bucket,
+            prefix,
+            cache: Arc::new(parking_lot::const_mutex(HashMap::new())),
+        }
+    }
+
+    fn key(&self, id: u32) -> String {
+        format!("{}{}.dict", self.prefix, id)
+    }
+
+    pub async fn publish(&self, dictionary: &Dictionary) -> Result<(), DictionaryError> {
+        let key = self.key(dictionary.id);
+        let req = PutObjectRequest {
+            bucket: self.bucket.to_owned(),
+            key: key.to_owned(),
+            content_md5: Some(base64::encode(md5::compute(&dictionary.data).0)),
+            body: Some(dictionary.data.to_vec().into()),
+            ..Default::default()
+        };
+        let put_object = traced!(
+            self.client.put_object(req),
+            "put_object",
+            bucket = %self.bucket,
+            key = %key,
+        );
+        put_object.await.map_err(DictionaryError::Publish)?;
+        self.cache
+            .lock()
+            .insert(dictionary.id, dictionary.to_owned());
+        Ok(())
+    }
+
+    pub async fn get(&self, id: u32) -> Result<Dictionary, DictionaryError> {
+        if let Some(dictionary) = self.cache.lock().get(&id) {
+            return Ok(dictionary.to_owned());
+        }
+        let req = GetObjectRequest {
+            bucket: self.bucket.to_owned(),
+            key: self.key(id),
+            ..Default::default()
+        };
+        let (data, _) = get_object_range(&self.client, &req, "bytes=0-".to_owned())
+            .await
+            .map_err(DictionaryError::Fetch)?;
+        let dictionary = Dictionary::new(data)?;
+        if dictionary.id != id {
+            return Err(DictionaryError::IdMismatch {
+                expected: id,
+                actual: dictionary.id,
+            });
+        }
+        self.cache.lock().insert(id, dictionary.to_owned());
+        Ok(dictionary)
+    }
+
+    // Fetches the dictionary the compressed data was stamped with, if it was.
+    // This blocks on the runtime as reads of a SeekableS3Object do, failing
+    // with AsyncContextError within a current-thread runtime.
+    pub fn dictionary_for<R: Read + Seek>(
+        &self,
+        runtime: &tokio::runtime::Runtime,
+        compressed: &mut R,
+    ) -> std::io::Result<Option<Dictionary>> {
+        match read_dictionary_id(compressed)? {
+            None => Ok(None),
+            Some(id) => block_on(runtime, self.get(id))?
+                .map(Some)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use rusoto_core::RusotoError;
+use rusoto_s3::{HeadObjectError, HeadObjectRequest, S3};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::AsyncReadExt;
+
+use crate::{upload_compressed_reader, UploadError, UploadOptions};
+
+// User metadata we put on synced objects to tell whether the file they came
+// from changed since.
+pub const SOURCE_SIZE_METADATA_KEY: &str = "zstd-seekable-source-size";
+pub const SOURCE_MTIME_METADATA_KEY: &str = "zstd-seekable-source-mtime";
+// The MD5 of the uncompressed file in hex, which is what its ETag would be had
+// it been uploaded as it is in one go. Only there with
+// SyncOptions::compare_checksums.
+pub const SOURCE_MD5_METADATA_KEY: &str = "zstd-seekable-source-md5";
+
+const READ_SIZE: usize = 512 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncOptions {
+    // How to compress and upload each file. Metadata given here is added to
+    // that of every object.
+    pub upload: UploadOptions,
+    // Added to the path of each file to make the key of its object, after the
+    // prefix.
+    pub key_suffix: String,
+    // When a file has the same size as its object but a different modification
+    // time, read it through and compare its MD5 with the one stored on the
+    // object rather than uploading it again. Files that are uploaded get read
+    // twice: once for the MD5 and once to compress them.
+    pub compare_checksums: bool,
+    // Only work out what would be uploaded.
+    pub dry_run: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            upload: UploadOptions::default(),
+            key_suffix: ".zst".to_owned(),
+            compare_checksums: false,
+            dry_run: false,
+        }
+    }
+}
+
+// What a sync did, by key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub uploaded: Vec<String>,
+    // Keys of files that didn't change since they were last synced.
+    pub unchanged: Vec<String>,
+    // Files skipped because they can't be named in a key, such as those with
+    // paths that aren't UTF-8.
+    pub skipped: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SyncError {
+    // Reading the directory or one of the files failed.
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    HeadObject {
+        key: String,
+        error: RusotoError<HeadObjectError>,
+    },
+    Upload {
+        key: String,
+        error: UploadError<std::io::Error>,
+    },
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::Io { path, error } => write!(f, "Failed to read {:?}: {}", path, error),
+            SyncError::HeadObject { key, error } => {
+                write!(f, "Failed to look up {}: {}", key, error)
+            }
+            SyncError::Upload { key, error } => write!(f, "Failed to upload {}: {}", key, error),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SyncError::Io { error, .. } => Some(error),
+            SyncError::HeadObject { error, .. } => Some(error),
+            SyncError::Upload { error, .. } => Some(error),
+        }
+    }
+}
+
+// A file found in the directory and what we know about it.
+struct LocalFile {
+    path: PathBuf,
+    key: String,
+    size: u64,
+    mtime: Option<u64>,
+}
+
+// Compresses every regular file under the directory into an object under the
+// prefix, named after the path of the file relative to the directory with
+// forward slashes. Files that didn't change since their object was uploaded,
+// going by the size and modification time stored on it (and the MD5 with
+// SyncOptions::compare_checksums), are left alone. Objects without files
+// aren't touched. Symbolic links aren't followed.
+//
+// Files are uploaded one at a time, each with the concurrency of the upload
+// options.
+pub async fn sync_directory<C: S3 + Clone + Send + Sync + 'static>(
+    client: &C,
+    directory: &Path,
+    bucket: &str,
+    prefix: &str,
+    options: &SyncOptions,
+) -> Result<SyncReport, SyncError> {
+    let mut report = SyncReport::default();
+    for file in walk_directory(directory, prefix, &options.key_suffix, &mut report).await? {
+        let remote = head_metadata(client, bucket, &file.key, options).await?;
+        let mut source_md5 = None;
+        if let Some(remote) = &remote {
+            let same_size = remote.get(SOURCE_SIZE_METADATA_KEY) == Some(&file.size.to_string());
+            let same_mtime = file.mtime.is_some()
+                && remote.get(SOURCE_MTIME_METADATA_KEY)
+                    == file.mtime.map(|m| m.to_string()).as_ref();
+            if same_size && same_mtime {
+                report.unchanged.push(file.key);
+                continue;
+            }
+            if same_size && options.compare_checksums {
+                let md5 = file_md5(&file.path).await?;
+                if remote.get(SOURCE_MD5_METADATA_KEY) == Some(&md5) {
+                    report.unchanged.push(file.key);
+                    continue;
+                }
+                source_md5 = Some(md5);
+            }
+        }
+        if !options.dry_run {
+            if source_md5.is_none() && options.compare_checksums {
+                source_md5 = Some(file_md5(&file.path).await?);
+            }
+            upload_file(client, bucket, &file, source_md5, options).await?;
+        }
+        report.uploaded.push(file.key);
+    }
+    Ok(report)
+}
+
+async fn walk_directory(
+    directory: &Path,
+    prefix: &str,
+    key_suffix: &str,
+    report: &mut SyncReport,
+) -> Result<Vec<LocalFile>, SyncError> {
+    let io_error = |path: &Path| {
+        let path = path.to_owned();
+        move |error: std::io::Error| SyncError::Io { path, error }
+    };
+    let mut files = Vec::new();
+    let mut directories = vec![directory.to_owned()];
+    while let Some(current) = directories.pop() {
+        let mut entries = tokio::fs::read_dir(&current)
+            .await
+            .map_err(io_error(&current))?;
+        while let Some(entry) = entries.next_entry().await.map_err(io_error(&current))? {
+            let path = entry.path();
+            let file_type = entry.file_type().await.map_err(io_error(&path))?;
+            if file_type.is_dir() {
+                directories.push(path);
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            let relative = path.strip_prefix(directory).unwrap_or(&path);
+            let parts: Option<Vec<&str>> = relative
+                .components()
+                .map(|component| component.as_os_str().to_str())
+                .collect();
+            let parts = match parts {
+                Some(parts) => parts,
+                None => {
+                    report.skipped.push(path);
+                    continue;
+                }
+            };
+            let metadata = entry.metadata().await.map_err(io_error(&path))?;
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_secs());
+            files.push(LocalFile {
+                key: format!("{}{}{}", prefix, parts.join("/"), key_suffix),
+                path,
+                size: metadata.len(),
+                mtime,
+            });
+        }
+    }
+    // Go in key order so runs are easy to follow.
+    files.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(files)
+}
+
+// User metadata of the object, or None if there's no such object.
+async fn head_metadata<C: S3>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    options: &SyncOptions,
+) -> Result<Option<HashMap<String, String>>, SyncError> {
+    let config = &options.upload.config;
+    let req = HeadObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        sse_customer_algorithm: config.customer_key.as_ref().map(|k| k.algorithm.to_owned()),
+        sse_customer_key: config.customer_key.as_ref().map(|k| k.key.to_owned()),
+        sse_customer_key_md5: config.customer_key.as_ref().map(|k| k.key_md5.to_owned()),
+        expected_bucket_owner: config.expected_bucket_owner.to_owned(),
+        request_payer: config.request_payer.to_owned(),
+        ..Default::default()
+    };
+    let head_object = traced!(
+        client.head_object(req),
+        "head_object",
+        bucket = %bucket,
+        key = %key,
+    );
+    match head_object.await {
+        Ok(output) => Ok(Some(output.metadata.unwrap_or_default())),
+        // HeadObject responses have no body for rusoto to find the error code
+        // in, so missing objects mostly come back as a bare 404.
+        Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(None),
+        Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => Ok(None),
+        Err(error) => Err(SyncError::HeadObject {
+            key: key.to_owned(),
+            error,
+        }),
+    }
+}
+
+async fn file_md5(path: &Path) -> Result<String, SyncError> {
+    let io_error = |error| SyncError::Io {
+        path: path.to_owned(),
+        error,
+    };
+    let mut file = tokio::fs::File::open(path).await.map_err(io_error)?;
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0; READ_SIZE];
+    loop {
+        let n = file.read(&mut buffer).await.map_err(io_error)?;
+        if n == 0 {
+            break;
+        }
+        context.consume(&buffer[..n]);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+async fn upload_file<C: S3 + Clone + Send + Sync + 'static>(
+    client: &C,
+    bucket: &str,
+    file: &LocalFile,
+    source_md5: Option<String>,
+    options: &SyncOptions,
+) -> Result<(), SyncError> {
+    let mut upload = options.upload.to_owned();
+    let metadata = &mut upload.config.metadata;
+    metadata.insert(SOURCE_SIZE_METADATA_KEY.to_owned(), file.size.to_string());
+    if let Some(mtime) = file.mtime {
+        metadata.insert(SOURCE_MTIME_METADATA_KEY.to_owned(), mtime.to_string());
+    }
+    if let Some(md5) = source_md5 {
+        metadata.insert(SOURCE_MD5_METADATA_KEY.to_owned(), md5);
+    }
+    let input = tokio::fs::File::open(&file.path)
+        .await
+        .map_err(|error| SyncError::Io {
+            path: file.path.to_owned(),
+            error,
+        })?;
+    upload_compressed_reader(client, bucket, &file.key, input, &upload)
+        .await
+        .map_err(|error| SyncError::Upload {
+            key: file.key.to_owned(),
+            error,
+        })?;
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+// Extension of the files holding cached blocks. Only files with it are
+// counted towards the size of the cache or ever evicted.
+const BLOCK_EXTENSION: &str = "block";
+
+// Cache of fetched blocks in a directory on disk, limited in total size. The
+// least recently used blocks are removed when the cache grows past its limit.
+// Blocks are keyed by the object they come from, including its version or
+// ETag, so changed objects never get served stale data.
+//
+// Clones share the same cache so one can be handed to many objects. Several
+// processes can use the same directory but each keeps track of the size on its
+// own, so the limit is only approximate then.
+//
+// The cache is best-effort: failing to read or write it is treated as a miss.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    inner: Arc<Mutex<DiskCacheInner>>,
+}
+
+#[derive(Debug)]
+struct DiskCacheInner {
+    directory: PathBuf,
+    max_size: u64,
+    size: u64,
+    // File name to size and when it was last used.
+    entries: HashMap<String, (u64, SystemTime)>,
+}
+
+impl DiskCache {
+    // Uses the given directory for the cache, creating it if needed. Blocks
+    // already in it from earlier runs are picked up.
+    pub fn new<P: AsRef<Path>>(directory: P, max_size: u64) -> std::io::Result<Self> {
+        let directory = directory.as_ref().to_owned();
+        std::fs::create_dir_all(&directory)?;
+        let mut entries = HashMap::new();
+        let mut size = 0;
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(BLOCK_EXTENSION) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let last_used = metadata
+                .accessed()
+                .or_else(|_e| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                size += metadata.len();
+                entries.insert(name.to_owned(), (metadata.len(), last_used));
+            }
+        }
+        let mut inner = DiskCacheInner {
+            directory,
+            max_size,
+            size,
+            entries,
+        };
+        inner.evict(None);
+        Ok(DiskCache {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    pub fn directory(&self) -> PathBuf {
+        self.inner.lock().directory.to_owned()
+    }
+
+    // Total size of the blocks in the cache.
+    pub fn size(&self) -> u64 {
+        self.inner.lock().size
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<Bytes> {
+        let name = file_name(key);
+        let path = {
+            let mut inner = self.inner.lock();
+            let (_, last_used) = inner.entries.get_mut(&name)?;
+            *last_used = SystemTime::now();
+            inner.directory.join(&name)
+        };
+        match std::fs::read(&path) {
+            Ok(data) => Some(Bytes::from(data)),
+            Err(_e) => {
+                // Someone else evicted it or it's broken: forget about it.
+                self.inner.lock().remove(&name);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn insert(&self, key: &str, data: &[u8]) {
+        let name = file_name(key);
+        let directory = self.directory();
+        // Write somewhere else first so nobody ever reads half a block.
+        let written = tempfile::NamedTempFile::new_in(&directory).and_then(|mut file| {
+            file.write_all(data)?;
+            file.persist(directory.join(&name)).map_err(|e| e.error)?;
+            Ok(())
+        });
+        if written.is_err() {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        inner.remove_entry(&name);
+        inner.size += data.len() as u64;
+        inner
+            .entries
+            .insert(name.to_owned(), (data.len() as u64, SystemTime::now()));
+        inner.evict(Some(&name));
+    }
+}
+
+impl DiskCacheInner {
+    // Forgets about the file and removes it.
+    fn remove(&mut self, name: &str) {
+        if self.remove_entry(name) {
+            let _ = std::fs::remove_file(self.directory.join(name));
+        }
+    }
+
+    fn remove_entry(&mut self, name: &str) -> bool {
+        match self.entries.remove(name) {
+            Some((size, _)) => {
+                self.size -= size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn evict(&mut self, keep: Option<&str>) {
+        while self.size > self.max_size {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(name, _)| Some(name.as_str()) != keep)
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(name, _)| name.to_owned());
+            match oldest {
+                Some(name) => self.remove(&name),
+                None => break,
+            }
+        }
+    }
+}
+
+// Keys can be anything so we name the files after their hash.
+fn file_name(key: &str) -> String {
+    format!("{:x}.{}", md5::compute(key), BLOCK_EXTENSION)
+}
@@ -0,0 +1,299 @@
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectRequest, HeadObjectError, HeadObjectRequest, S3};
+use std::fmt::Display;
+use std::path::Path;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use zstd_seekable::DStream;
+
+use crate::decompress::decompress_frame;
+use crate::{fetch_object_index, fetch_ranges, FetchRangesOptions, ReadObjectError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadOptions {
+    // How much compressed data to fetch between saving progress. Whatever was
+    // fetched since the last save is fetched again after an interruption.
+    pub batch_size: u64,
+    pub fetch: FetchRangesOptions,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            batch_size: 64 * 1024 * 1024,
+            fetch: FetchRangesOptions::default(),
+        }
+    }
+}
+
+// How far a download got, as kept in its state file. Everything before these
+// positions is known to be in the output file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DownloadState {
+    pub bucket: String,
+    pub key: String,
+    // Of the object when the download started: a download only resumes if
+    // the object is still the same.
+    pub e_tag: Option<String>,
+    pub frames_done: usize,
+    pub compressed_offset: u64,
+    pub output_position: u64,
+}
+
+impl DownloadState {
+    // Reads a state file, if there is one.
+    pub fn load(path: &Path) -> std::io::Result<Option<Self>> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let bad_state = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Bad download state in {}", path.display()),
+            )
+        };
+        let mut state = DownloadState::default();
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            let (name, value) = line.split_once('=').ok_or_else(bad_state)?;
+            match name {
+                "bucket" => state.bucket = value.to_owned(),
+                "key" => state.key = value.to_owned(),
+                "e_tag" => state.e_tag = Some(value.to_owned()),
+                "frames_done" => state.frames_done = value.parse().map_err(|_e| bad_state())?,
+                "compressed_offset" => {
+                    state.compressed_offset = value.parse().map_err(|_e| bad_state())?
+                }
+                "output_position" => {
+                    state.output_position = value.parse().map_err(|_e| bad_state())?
+                }
+                _ => return Err(bad_state()),
+            }
+        }
+        Ok(Some(state))
+    }
+
+    // Writes the state file, replacing the old one in one go so that an
+    // interruption leaves either of them whole.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut data = format!("bucket={}\nkey={}\n", self.bucket, self.key);
+        if let Some(e_tag) = &self.e_tag {
+            data.push_str(&format!("e_tag={}\n", e_tag));
+        }
+        data.push_str(&format!(
+            "frames_done={}\ncompressed_offset={}\noutput_position={}\n",
+            self.frames_done, self.compressed_offset, self.output_position
+        ));
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, path)
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DownloadError {
+    HeadObject(RusotoError<HeadObjectError>),
+    ReadObject(ReadObjectError),
+    // Writing the output or the state file failed.
+    Io(std::io::Error),
+    // The state file is for some other object, or the object changed since.
+    StateMismatch,
+    // The frame doesn't decompress to what the seek table says.
+    Corrupt { frame: usize },
+}
+
+impl Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::HeadObject(e) => write!(f, "Failed to head object: {}", e),
+            DownloadError::ReadObject(e) => write!(f, "Failed to read object: {}", e),
+            DownloadError::Io(e) => write!(f, "Failed to write: {}", e),
+            DownloadError::StateMismatch => {
+                write!(f, "Download state is for another object or version.")
+            }
+            DownloadError::Corrupt { frame } => write!(f, "Frame {} is corrupt", frame),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownloadError::HeadObject(e) => Some(e),
+            DownloadError::ReadObject(e) => Some(e),
+            DownloadError::Io(e) => Some(e),
+            DownloadError::StateMismatch | DownloadError::Corrupt { .. } => None,
+        }
+    }
+}
+
+// Decompresses a whole object into the output file, saving how far it got to
+// the state file after every batch of frames. Run again with the same state
+// file after an interruption, it picks up from the last save rather than
+// starting over, as long as the object didn't change. The state file is
+// removed once the download is done and the final state returned.
+pub async fn download_resumable<C: S3>(
+    client: &C,
+    req: &GetObjectRequest,
+    output: &Path,
+    state_path: &Path,
+    options: &DownloadOptions,
+) -> Result<DownloadState, DownloadError> {
+    download(client, req, output, Some(state_path), options).await
+}
+
+// Decompresses the whole object into the file at the given path, replacing
+// whatever is there, and returns how big it came out. Frames are fetched
+// several requests at a time and failed requests retried, as the default
+// DownloadOptions have it. Use download_resumable for anything that has to
+// survive interruptions.
+pub async fn download_decompressed<C: S3>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    path: impl AsRef<Path>,
+) -> Result<u64, DownloadError> {
+    let req = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let state = download(
+        client,
+        &req,
+        path.as_ref(),
+        None,
+        &DownloadOptions::default(),
+    )
+    .await?;
+    Ok(state.output_position)
+}
+
+// Either download, without saving the state anywhere if there's no state
+// file to save it to.
+async fn download<C: S3>(
+    client: &C,
+    req: &GetObjectRequest,
+    output: &Path,
+    state_path: Option<&Path>,
+    options: &DownloadOptions,
+) -> Result<DownloadState, DownloadError> {
+    let head_req = HeadObjectRequest {
+        bucket: req.bucket.to_owned(),
+        key: req.key.to_owned(),
+        version_id: req.version_id.to_owned(),
+        request_payer: req.request_payer.to_owned(),
+        expected_bucket_owner: req.expected_bucket_owner.to_owned(),
+        sse_customer_algorithm: req.sse_customer_algorithm.to_owned(),
+        sse_customer_key: req.sse_customer_key.to_owned(),
+        sse_customer_key_md5: req.sse_customer_key_md5.to_owned(),
+        ..Default::default()
+    };
+    let head_object = traced!(
+        client.head_object(head_req),
+        "head_object",
+        bucket = %req.bucket,
+        key = %req.key,
+    );
+    let e_tag = head_object.await.map_err(DownloadError::HeadObject)?.e_tag;
+    let saved = match state_path {
+        Some(state_path) => DownloadState::load(state_path).map_err(DownloadError::Io)?,
+        None => None,
+    };
+    let mut state = match saved {
+        Some(state) => {
+            if state.bucket != req.bucket || state.key != req.key || state.e_tag != e_tag {
+                return Err(DownloadError::StateMismatch);
+            }
+            state
+        }
+        None => DownloadState {
+            bucket: req.bucket.to_owned(),
+            key: req.key.to_owned(),
+            e_tag: e_tag.to_owned(),
+            ..Default::default()
+        },
+    };
+    // Any change to the object from here on fails the requests.
+    let mut req = req.to_owned();
+    req.if_match = e_tag;
+    req.range = None;
+
+    let index = fetch_object_index(client, &req)
+        .await
+        .map_err(DownloadError::ReadObject)?
+        .index;
+    let frames = index.frames();
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(output)
+        .await
+        .map_err(DownloadError::Io)?;
+    // Whatever was written after the last save gets written again.
+    file.set_len(state.output_position)
+        .await
+        .map_err(DownloadError::Io)?;
+    file.seek(std::io::SeekFrom::Start(state.output_position))
+        .await
+        .map_err(DownloadError::Io)?;
+
+    while state.frames_done < frames.len() {
+        let start = state.frames_done;
+        let mut end = start;
+        let mut batch_size = 0;
+        while end < frames.len() && (end == start || batch_size < options.batch_size) {
+            batch_size += frames[end].compressed_size;
+            end += 1;
+        }
+        let ranges = (start..end)
+            .filter(|&frame| frames[frame].decompressed_size > 0)
+            .map(|frame| {
+                let entry = &frames[frame];
+                let range =
+                    entry.compressed_offset..entry.compressed_offset + entry.compressed_size;
+                (frame, range)
+            })
+            .collect();
+        let fetched = fetch_ranges(client, &req, ranges, &options.fetch)
+            .await
+            .map_err(DownloadError::ReadObject)?;
+        let decompressions = fetched.into_iter().map(|fetched| {
+            let expected = frames[fetched.label].decompressed_size;
+            tokio::task::spawn_blocking(move || {
+                let data = DStream::new()
+                    .ok()
+                    .and_then(|mut dstream| decompress_frame(&mut dstream, &fetched.data))
+                    .filter(|data| data.len() as u64 == expected);
+                (fetched.label, data)
+            })
+        });
+        for decompressed in futures::future::join_all(decompressions).await {
+            let (frame, data) = decompressed.map_err(|e| {
+                DownloadError::Io(std::io::Error::new(std::io::ErrorKind::Other, e))
+            })?;
+            let data = data.ok_or(DownloadError::Corrupt { frame })?;
+            file.write_all(&data).await.map_err(DownloadError::Io)?;
+            state.output_position += data.len() as u64;
+        }
+        state.frames_done = end;
+        state.compressed_offset =
+            frames[end - 1].compressed_offset + frames[end - 1].compressed_size;
+        if let Some(state_path) = state_path {
+            // The state must never get ahead of what's on disk.
+            file.sync_data().await.map_err(DownloadError::Io)?;
+            state.save(state_path).map_err(DownloadError::Io)?;
+        }
+    }
+    file.flush().await.map_err(DownloadError::Io)?;
+    if let Some(state_path) = state_path {
+        match std::fs::remove_file(state_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(DownloadError::Io(e)),
+        }
+    }
+    Ok(state)
+}
@@ -0,0 +1,50 @@
+use rusoto_s3::S3;
+use std::sync::Arc;
+
+// Client SeekableS3Object makes its requests through. Every S3 client is one,
+// and so is DynS3.
+pub trait AsS3 {
+    type Client: S3 + ?Sized;
+
+    fn as_s3(&self) -> &Self::Client;
+}
+
+impl<C: S3> AsS3 for C {
+    type Client = C;
+
+    fn as_s3(&self) -> &C {
+        self
+    }
+}
+
+// Any S3 client behind an Arc, for objects that shouldn't have the type of
+// their client in theirs: every SeekableS3Object<DynS3> is the same type
+// whatever client it reads with. Clones share the client.
+#[derive(Clone)]
+pub struct DynS3(Arc<dyn S3 + Send + Sync>);
+
+impl DynS3 {
+    pub fn new<C: S3 + Send + Sync + 'static>(client: C) -> Self {
+        DynS3(Arc::new(client))
+    }
+}
+
+impl From<Arc<dyn S3 + Send + Sync>> for DynS3 {
+    fn from(client: Arc<dyn S3 + Send + Sync>) -> Self {
+        DynS3(client)
+    }
+}
+
+impl AsS3 for DynS3 {
+    type Client = dyn S3 + Send + Sync;
+
+    fn as_s3(&self) -> &Self::Client {
+        self.0.as_ref()
+    }
+}
+
+impl std::fmt::Debug for DynS3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DynS3").finish()
+    }
+}
@@ -0,0 +1,419 @@
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use bytes::Bytes;
+use futures::{ready, stream::FusedStream, Stream};
+use pin_project_lite::pin_project;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::io::{Read, Seek, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::seek::seek_position;
+use crate::{read_frame_index, CompressError, CompressItem, FrameIndex, ObjectMetadata};
+
+// Metadata key the wrapped data key of an encrypted object is stored under.
+pub const ENVELOPE_METADATA_KEY: &str = "zstd-seekable-envelope";
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+// Every encrypted frame is this much bigger than the frame itself.
+pub const FRAME_TAG_SIZE: usize = 16;
+
+// Key that only the application holds, used to wrap the data key of each
+// object. Never leaves the application.
+#[derive(Clone)]
+pub struct MasterKey([u8; KEY_SIZE]);
+
+impl MasterKey {
+    pub fn new(key: [u8; KEY_SIZE]) -> Self {
+        MasterKey(key)
+    }
+}
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MasterKey").field(&"<redacted>").finish()
+    }
+}
+
+// Key the frames of a single object are encrypted with. Every object gets a
+// fresh one, which is stored with the object wrapped with the master key.
+//
+// Frames are encrypted with AES-256-GCM on their own so that each can be
+// fetched and decrypted without the others. The nonce of each frame is its
+// number: as no two objects share a data key, no nonce is ever used twice
+// with the same key, and frames can't be swapped around without failing to
+// decrypt.
+#[derive(Clone)]
+pub struct DataKey {
+    cipher: Aes256Gcm,
+    key: [u8; KEY_SIZE],
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DataKey").field(&"<redacted>").finish()
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EncryptionError {
+    // Couldn't get random bytes for a key or nonce.
+    Random(getrandom::Error),
+    // Encrypting or decrypting failed: for decryption, the data was tampered
+    // with or the key is the wrong one.
+    Cipher,
+    // The wrapped data key isn't in the form we store it in.
+    MalformedEnvelope,
+    // The object has no wrapped data key in its metadata.
+    MissingEnvelope,
+}
+
+impl Display for EncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptionError::Random(e) => write!(f, "Failed to get random bytes: {}", e),
+            EncryptionError::Cipher => write!(f, "Encryption or decryption failed."),
+            EncryptionError::MalformedEnvelope => write!(f, "Wrapped data key is malformed."),
+            EncryptionError::MissingEnvelope => write!(f, "Object has no wrapped data key."),
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EncryptionError::Random(e) => Some(e),
+            EncryptionError::Cipher
+            | EncryptionError::MalformedEnvelope
+            | EncryptionError::MissingEnvelope => None,
+        }
+    }
+}
+
+impl DataKey {
+    pub fn generate() -> Result<Self, EncryptionError> {
+        let mut key = [0; KEY_SIZE];
+        getrandom::getrandom(&mut key).map_err(EncryptionError::Random)?;
+        Ok(Self::from_bytes(key))
+    }
+
+    fn from_bytes(key: [u8; KEY_SIZE]) -> Self {
+        DataKey {
+            cipher: Aes256Gcm::new(Key::from_slice(&key)),
+            key,
+        }
+    }
+
+    // The key encrypted with the master key, as base64 of nonce | ciphertext.
+    // This is what goes in the metadata of the object, see
+    // ENVELOPE_METADATA_KEY.
+    pub fn wrap(&self, master: &MasterKey) -> Result<String, EncryptionError> {
+        let mut nonce = [0; NONCE_SIZE];
+        getrandom::getrandom(&mut nonce).map_err(EncryptionError::Random)?;
+        let wrapped = Aes256Gcm::new(Key::from_slice(&master.0))
+            .encrypt(Nonce::from_slice(&nonce), &self.key[..])
+            .map_err(|_e| EncryptionError::Cipher)?;
+        let mut envelope = nonce.to_vec();
+        envelope.extend_from_slice(&wrapped);
+        Ok(base64::encode(envelope))
+    }
+
+    pub fn unwrap(envelope: &str, master: &MasterKey) -> Result<Self, EncryptionError> {
+        let envelope = base64::decode(envelope).map_err(|_e| EncryptionError::MalformedEnvelope)?;
+        if envelope.len() < NONCE_SIZE {
+            return Err(EncryptionError::MalformedEnvelope);
+        }
+        let (nonce, wrapped) = envelope.split_at(NONCE_SIZE);
+        let key = Aes256Gcm::new(Key::from_slice(&master.0))
+            .decrypt(Nonce::from_slice(nonce), wrapped)
+            .map_err(|_e| EncryptionError::Cipher)?;
+        let key = <[u8; KEY_SIZE]>::try_from(key.as_slice())
+            .map_err(|_e| EncryptionError::MalformedEnvelope)?;
+        Ok(Self::from_bytes(key))
+    }
+
+    // Unwraps the data key stored in the metadata of an encrypted object.
+    pub fn from_metadata(
+        metadata: &ObjectMetadata,
+        master: &MasterKey,
+    ) -> Result<Self, EncryptionError> {
+        let envelope = metadata
+            .metadata
+            .get(ENVELOPE_METADATA_KEY)
+            .ok_or(EncryptionError::MissingEnvelope)?;
+        Self::unwrap(envelope, master)
+    }
+
+    fn nonce(frame: u64) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0; NONCE_SIZE];
+        nonce[NONCE_SIZE - 8..].copy_from_slice(&frame.to_be_bytes());
+        nonce
+    }
+
+    pub fn encrypt_frame(&self, frame: u64, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        self.cipher
+            .encrypt(Nonce::from_slice(&Self::nonce(frame)), data)
+            .map_err(|_e| EncryptionError::Cipher)
+    }
+
+    pub fn decrypt_frame(&self, frame: u64, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        self.cipher
+            .decrypt(Nonce::from_slice(&Self::nonce(frame)), data)
+            .map_err(|_e| EncryptionError::Cipher)
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EncryptFramesError<E> {
+    Compress(CompressError<E>),
+    Encryption(EncryptionError),
+}
+
+impl<E: Display> Display for EncryptFramesError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptFramesError::Compress(e) => write!(f, "{}", e),
+            EncryptFramesError::Encryption(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for EncryptFramesError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EncryptFramesError::Compress(e) => Some(e),
+            EncryptFramesError::Encryption(e) => Some(e),
+        }
+    }
+}
+
+pin_project! {
+    // Encrypts each frame of a compression stream with checkpoints, see
+    // Compress::with_checkpoints, yielding an encrypted object ready to be
+    // uploaded. Every frame is followed by its authentication tag; the seek
+    // table at the end is left as it is so readers can find the frames. Store
+    // the wrapped data key in the metadata of the object, see DataKey::wrap.
+    // Read the object back through a DecryptFrames.
+    pub struct EncryptFrames<S> {
+        #[pin]
+        stream: S,
+        key: DataKey,
+        // Compressed data of frames we haven't seen the end of yet.
+        buffer: Vec<u8>,
+        // Number of the next frame to encrypt.
+        frame: u64,
+        done: bool,
+    }
+}
+
+impl<S> std::fmt::Debug for EncryptFrames<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptFrames")
+            .field("buffered", &self.buffer.len())
+            .field("frame", &self.frame)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<S> EncryptFrames<S> {
+    pub fn new(stream: S, key: DataKey) -> Self {
+        EncryptFrames {
+            stream,
+            key,
+            buffer: Vec::new(),
+            frame: 0,
+            done: false,
+        }
+    }
+}
+
+impl<S, E> Stream for EncryptFrames<S>
+where
+    S: Stream<Item = Result<CompressItem, CompressError<E>>>,
+{
+    type Item = Result<Bytes, EncryptFramesError<E>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if *this.done {
+                return Poll::Ready(None);
+            }
+            let item = match ready!(this.stream.as_mut().poll_next(cx)) {
+                // The frame index always comes last, we must have seen it.
+                None => {
+                    *this.done = true;
+                    continue;
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(EncryptFramesError::Compress(e)))),
+                Some(Ok(item)) => item,
+            };
+            match item {
+                CompressItem::Data(data) => this.buffer.extend_from_slice(&data),
+                // Whatever we buffered is exactly the frame that just ended.
+                CompressItem::Checkpoint(_) => {
+                    let frame = std::mem::take(this.buffer);
+                    let encrypted = this
+                        .key
+                        .encrypt_frame(*this.frame, &frame)
+                        .map_err(EncryptFramesError::Encryption);
+                    *this.frame += 1;
+                    return Poll::Ready(Some(encrypted.map(Bytes::from)));
+                }
+                // The last frames come along with the seek table, which the
+                // index tells apart.
+                CompressItem::FrameIndex(index) => {
+                    *this.done = true;
+                    let data = std::mem::take(this.buffer);
+                    let mut output = Vec::with_capacity(data.len());
+                    let mut at = 0;
+                    for entry in index.frames().iter().skip(*this.frame as usize) {
+                        let end = at + entry.compressed_size as usize;
+                        let frame = match data.get(at..end) {
+                            Some(frame) => frame,
+                            None => {
+                                return Poll::Ready(Some(Err(EncryptFramesError::Encryption(
+                                    EncryptionError::Cipher,
+                                ))))
+                            }
+                        };
+                        match this.key.encrypt_frame(*this.frame, frame) {
+                            Ok(encrypted) => output.extend_from_slice(&encrypted),
+                            Err(e) => {
+                                return Poll::Ready(Some(Err(EncryptFramesError::Encryption(e))))
+                            }
+                        }
+                        *this.frame += 1;
+                        at = end;
+                    }
+                    output.extend_from_slice(&data[at..]);
+                    return Poll::Ready(Some(Ok(Bytes::from(output))));
+                }
+            }
+        }
+    }
+}
+
+impl<S, E> FusedStream for EncryptFrames<S>
+where
+    S: Stream<Item = Result<CompressItem, CompressError<E>>>,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+// Presents an object encrypted with EncryptFrames as the compressed data it
+// was made from, decrypting frames as they are read. Wrap it in a
+// SeekableDecompress to get at the decompressed data. Each frame is fetched
+// and checked as a whole so reads only fetch the frames they touch.
+pub struct DecryptFrames<R> {
+    encrypted: R,
+    key: DataKey,
+    index: FrameIndex,
+    // The seek table at the end, which isn't encrypted.
+    seek_table: Vec<u8>,
+    position: u64,
+    // The frame we decrypted last.
+    frame: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: std::fmt::Debug> std::fmt::Debug for DecryptFrames<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecryptFrames")
+            .field("encrypted", &self.encrypted)
+            .field("frames", &self.index.num_frames())
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl<R: Read + Seek> DecryptFrames<R> {
+    pub fn new(mut encrypted: R, key: DataKey) -> std::io::Result<Self> {
+        let index = read_frame_index(&mut encrypted)?;
+        let seek_table = index
+            .to_seek_table()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(DecryptFrames {
+            encrypted,
+            key,
+            index,
+            seek_table,
+            position: 0,
+            frame: None,
+        })
+    }
+
+    fn len(&self) -> u64 {
+        self.index.compressed_size() + self.seek_table.len() as u64
+    }
+
+    // Frame holding the given offset of the compressed data.
+    fn frame_at(&self, offset: u64) -> Option<usize> {
+        let frames = self.index.frames();
+        let index = frames
+            .partition_point(|frame| frame.compressed_offset <= offset)
+            .checked_sub(1)?;
+        let frame = &frames[index];
+        if offset < frame.compressed_offset + frame.compressed_size {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn decrypt(&mut self, index: usize) -> std::io::Result<&[u8]> {
+        if self.frame.as_ref().map(|(frame, _)| *frame) != Some(index) {
+            let entry = self.index.frames()[index];
+            // Each frame before this one is a tag longer than it was.
+            let offset = entry.compressed_offset + (index * FRAME_TAG_SIZE) as u64;
+            let mut data = vec![0; entry.compressed_size as usize + FRAME_TAG_SIZE];
+            self.encrypted.seek(SeekFrom::Start(offset))?;
+            self.encrypted.read_exact(&mut data)?;
+            let frame = self
+                .key
+                .decrypt_frame(index as u64, &data)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            self.frame = Some((index, frame));
+        }
+        Ok(self
+            .frame
+            .as_ref()
+            .map_or(&[][..], |(_, frame)| frame.as_slice()))
+    }
+}
+
+impl<R: Read + Seek> Read for DecryptFrames<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let frames_end = self.index.compressed_size();
+        let position = self.position;
+        let data = if position >= frames_end {
+            let at = std::cmp::min(position - frames_end, self.seek_table.len() as u64);
+            &self.seek_table[at as usize..]
+        } else {
+            match self.frame_at(position) {
+                None => return Ok(0),
+                Some(index) => {
+                    let start = self.index.frames()[index].compressed_offset;
+                    &self.decrypt(index)?[(position - start) as usize..]
+                }
+            }
+        };
+        let n = std::cmp::min(buf.len(), data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecryptFrames<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(pos, self.position, self.len(), false)?;
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,108 @@
+use bytes::Bytes;
+use rusoto_s3::{GetObjectRequest, S3};
+use std::ops::Range;
+
+use crate::object_index::get_object_range;
+use crate::{FrameEntry, FrameIndex, ReadObjectError, SeekTableError};
+
+// The frames of an object holding some range of its decompressed data, for
+// handing over as they are to someone who decompresses them themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressedRange {
+    // Frames the range touches, with their offsets as in the object.
+    pub frames: Vec<FrameEntry>,
+    // Compressed data of those frames, back to back, starting with the first.
+    pub data: Bytes,
+    // Where the asked for range is in the decompressed frames: the frames
+    // hold some data before and after it, unless it's frame aligned.
+    pub skip: u64,
+    pub len: u64,
+}
+
+impl CompressedRange {
+    // Range of the object the data is.
+    pub fn compressed_range(&self) -> Range<u64> {
+        compressed_range_of(&self.frames)
+    }
+
+    // Range of the decompressed data of the object the frames decompress to.
+    pub fn decompressed_range(&self) -> Range<u64> {
+        match (self.frames.first(), self.frames.last()) {
+            (Some(first), Some(last)) => {
+                first.decompressed_offset..last.decompressed_offset + last.decompressed_size
+            }
+            _ => 0..0,
+        }
+    }
+
+    // Seek table for just these frames. The data followed by the table is a
+    // seekable stream of its own, which any zstd seekable reader can open.
+    pub fn seek_table(&self) -> Result<Vec<u8>, SeekTableError> {
+        let mut index = FrameIndex::new();
+        for frame in &self.frames {
+            index.push(
+                frame.compressed_size,
+                frame.decompressed_size,
+                frame.checksum,
+            )?;
+        }
+        index.to_seek_table()
+    }
+}
+
+fn compressed_range_of(frames: &[FrameEntry]) -> Range<u64> {
+    match (frames.first(), frames.last()) {
+        (Some(first), Some(last)) => {
+            first.compressed_offset..last.compressed_offset + last.compressed_size
+        }
+        _ => 0..0,
+    }
+}
+
+// Frames of the index holding the given range of decompressed data, along
+// with any skippable frames in between, which decompress to nothing. None if
+// the range is empty or goes past the end of the data, like
+// FrameIndex::compressed_range.
+pub fn frames_for_range(index: &FrameIndex, decompressed: Range<u64>) -> Option<&[FrameEntry]> {
+    if decompressed.start >= decompressed.end {
+        return None;
+    }
+    let first = index.frame_index_at(decompressed.start)?;
+    let last = index.frame_index_at(decompressed.end - 1)?;
+    Some(&index.frames()[first..=last])
+}
+
+// Fetches the compressed frames holding the given range of the decompressed
+// data of the object, in a single request, without decompressing anything.
+// The index is that of the object, as from fetch_object_index. None if the
+// range is empty or goes past the end of the data.
+pub async fn export_compressed_range<C: S3>(
+    client: &C,
+    req: &GetObjectRequest,
+    index: &FrameIndex,
+    decompressed: Range<u64>,
+) -> Result<Option<CompressedRange>, ReadObjectError> {
+    let frames = match frames_for_range(index, decompressed.to_owned()) {
+        Some(frames) => frames.to_vec(),
+        None => return Ok(None),
+    };
+    let range = compressed_range_of(&frames);
+    let (data, _) = get_object_range(
+        client,
+        req,
+        format!("bytes={}-{}", range.start, range.end - 1),
+    )
+    .await?;
+    if data.len() as u64 != range.end - range.start {
+        return Err(ReadObjectError::ReadBody(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "Object ended before the frames did.",
+        )));
+    }
+    Ok(Some(CompressedRange {
+        skip: decompressed.start - frames[0].decompressed_offset,
+        len: decompressed.end - decompressed.start,
+        frames,
+        data,
+    }))
+}
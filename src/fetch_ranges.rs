@@ -0,0 +1,137 @@
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use rusoto_s3::{GetObjectRequest, S3};
+use std::ops::Range;
+
+use crate::object_index::get_object_range;
+use crate::{is_retryable, is_retryable_io, ReadObjectError, RetryPolicy};
+
+// How to turn a bunch of ranges into requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchRangesOptions {
+    // Ranges at most this far apart are fetched with one request, throwing
+    // away the bytes in between. A request costs about as much as reading a
+    // few hundred KiB so small gaps are cheaper to read through.
+    pub max_gap: u64,
+    // Don't merge ranges into requests bigger than this. Single ranges
+    // bigger than this are still fetched in one go.
+    pub max_request_size: u64,
+    // How many requests to have in flight at once.
+    pub concurrency: usize,
+    pub retry: RetryPolicy,
+}
+
+impl Default for FetchRangesOptions {
+    fn default() -> Self {
+        FetchRangesOptions {
+            max_gap: 256 * 1024,
+            max_request_size: 64 * 1024 * 1024,
+            concurrency: 8,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+// Data of one of the ranges asked for, along with the label it came with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchedRange<L> {
+    pub label: L,
+    pub range: Range<u64>,
+    pub data: Bytes,
+}
+
+// Merges the ranges into as few requests as the options allow. The ranges
+// don't need to be in order and may overlap. Empty ranges need no request.
+pub fn plan_range_requests(ranges: &[Range<u64>], options: &FetchRangesOptions) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = ranges
+        .iter()
+        .filter(|range| range.start < range.end)
+        .cloned()
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+    let mut requests: Vec<Range<u64>> = Vec::new();
+    for range in ranges {
+        match requests.last_mut() {
+            Some(last)
+                if range.start <= last.end.saturating_add(options.max_gap)
+                    && range.end.max(last.end) - last.start <= options.max_request_size =>
+            {
+                last.end = last.end.max(range.end);
+            }
+            _ => requests.push(range),
+        }
+    }
+    requests
+}
+
+// Fetches the given ranges of the object, merging ones close to each other
+// into single requests (see plan_range_requests). Ranges are usually the
+// compressed ranges of the frames a reader is after, see
+// FrameIndex::compressed_range. The data comes back in the same order as the
+// ranges, sharing the buffers of the requests it came from. Ranges past the
+// end of the object come back cut short.
+pub async fn fetch_ranges<C, L>(
+    client: &C,
+    req: &GetObjectRequest,
+    ranges: Vec<(L, Range<u64>)>,
+    options: &FetchRangesOptions,
+) -> Result<Vec<FetchedRange<L>>, ReadObjectError>
+where
+    C: S3,
+{
+    let wanted: Vec<Range<u64>> = ranges.iter().map(|(_, range)| range.to_owned()).collect();
+    let requests = plan_range_requests(&wanted, options);
+    let fetched: Vec<(u64, Bytes)> = futures::stream::iter(requests)
+        .map(|range| async move {
+            let data = get_range_with_retry(client, req, &range, &options.retry).await?;
+            Ok((range.start, data))
+        })
+        // With no requests allowed in flight we'd never make any progress.
+        .buffered(std::cmp::max(options.concurrency, 1))
+        .try_collect()
+        .await?;
+
+    Ok(ranges
+        .into_iter()
+        .map(|(label, range)| {
+            let data = if range.start < range.end {
+                // The last request starting at or before the range holds it.
+                let i = fetched.partition_point(|(start, _)| *start <= range.start) - 1;
+                let (start, data) = &fetched[i];
+                let from = ((range.start - start) as usize).min(data.len());
+                let to = ((range.end - start) as usize).min(data.len());
+                data.slice(from..to)
+            } else {
+                Bytes::new()
+            };
+            FetchedRange { label, range, data }
+        })
+        .collect())
+}
+
+async fn get_range_with_retry<C: S3>(
+    client: &C,
+    req: &GetObjectRequest,
+    range: &Range<u64>,
+    retry: &RetryPolicy,
+) -> Result<Bytes, ReadObjectError> {
+    let mut failed_attempts = 0;
+    loop {
+        let http_range = format!("bytes={}-{}", range.start, range.end - 1);
+        match get_object_range(client, req, http_range).await {
+            Ok((data, _)) => break Ok(data),
+            Err(e) => {
+                failed_attempts += 1;
+                let retryable = match &e {
+                    ReadObjectError::GetObject(e) => is_retryable(e),
+                    ReadObjectError::ReadBody(e) => is_retryable_io(e),
+                    _ => false,
+                };
+                if !retryable || !retry.should_retry(failed_attempts) {
+                    break Err(e);
+                }
+                tokio::time::sleep(retry.backoff(failed_attempts)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use bytes::Bytes;
+use futures::SinkExt;
+use rusoto_core::Region;
+use rusoto_s3::{GetObjectRequest, S3Client};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::str::FromStr;
+
+use crate::global_runtime::global_runtime;
+use crate::{ReadOptions, SeekableDecompress, SeekableS3Object, UploadOptions, UploadSink};
+
+// C API for reading objects and compressing data into them, built with the
+// ffi feature. See include/zstd_seekable_s3.h for the declarations. Functions
+// that fail return NULL or -1 and leave a message for zss_last_error.
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: String) {
+    // Messages with NULs in them would be cut short anyway.
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+// Runs f, turning errors and panics into the last error and the fallback.
+fn guard<T, F>(fallback: T, f: F) -> T
+where
+    F: FnOnce() -> Result<T, String>,
+{
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(message)) => {
+            set_last_error(message);
+            fallback
+        }
+        Err(_) => {
+            set_last_error("Panicked.".to_owned());
+            fallback
+        }
+    }
+}
+
+unsafe fn string_arg(s: *const c_char, name: &str) -> Result<String, String> {
+    if s.is_null() {
+        return Err(format!("{} is NULL.", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(|s| s.to_owned())
+        .map_err(|_e| format!("{} isn't UTF-8.", name))
+}
+
+unsafe fn client(region: *const c_char) -> Result<S3Client, String> {
+    let region = if region.is_null() {
+        Region::default()
+    } else {
+        Region::from_str(&string_arg(region, "region")?).map_err(|e| e.to_string())?
+    };
+    Ok(S3Client::new(region))
+}
+
+// Reader of the decompressed data of an object.
+pub struct ZssReader {
+    inner: SeekableDecompress<'static, SeekableS3Object<'static, S3Client>>,
+}
+
+// Compresses data into an object as it's written.
+pub struct ZssWriter {
+    sink: UploadSink,
+}
+
+// Message of the last error on this thread, valid until the next call that
+// fails on it. NULL if nothing failed yet.
+#[no_mangle]
+pub extern "C" fn zss_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+// Opens s3://bucket/key for reading. The region may be NULL for the default
+// one. Nothing is fetched until the first read.
+//
+// # Safety
+//
+// The strings have to be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn zss_open(
+    region: *const c_char,
+    bucket: *const c_char,
+    key: *const c_char,
+) -> *mut ZssReader {
+    guard(std::ptr::null_mut(), || {
+        let req = GetObjectRequest {
+            bucket: string_arg(bucket, "bucket")?,
+            key: string_arg(key, "key")?,
+            ..Default::default()
+        };
+        let runtime = global_runtime().map_err(|e| e.to_string())?;
+        let object = SeekableS3Object::lazy(client(region)?, runtime, req, &ReadOptions::default());
+        let inner = SeekableDecompress::new(object).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(ZssReader { inner })))
+    })
+}
+
+// Reads up to len bytes of decompressed data from the offset into buf,
+// returning how many were read: fewer than len only at the end of the data.
+//
+// # Safety
+//
+// The reader has to come from zss_open and buf has to have room for len
+// bytes. A reader can't be used from several threads at once.
+#[no_mangle]
+pub unsafe extern "C" fn zss_read_at(
+    reader: *mut ZssReader,
+    offset: u64,
+    buf: *mut u8,
+    len: usize,
+) -> i64 {
+    guard(-1, || {
+        let reader = reader.as_mut().ok_or("reader is NULL.")?;
+        if buf.is_null() && len > 0 {
+            return Err("buf is NULL.".to_owned());
+        }
+        let buf = if len == 0 {
+            &mut []
+        } else {
+            std::slice::from_raw_parts_mut(buf, len)
+        };
+        reader
+            .inner
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| e.to_string())?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match reader.inner.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(filled as i64)
+    })
+}
+
+// Size of the decompressed data.
+//
+// # Safety
+//
+// The reader has to come from zss_open.
+#[no_mangle]
+pub unsafe extern "C" fn zss_length(reader: *mut ZssReader) -> i64 {
+    guard(-1, || {
+        let reader = reader.as_mut().ok_or("reader is NULL.")?;
+        let length = reader
+            .inner
+            .seek(SeekFrom::End(0))
+            .map_err(|e| e.to_string())?;
+        Ok(length as i64)
+    })
+}
+
+// Frees the reader. NULL is fine.
+//
+// # Safety
+//
+// The reader has to come from zss_open and can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn zss_close(reader: *mut ZssReader) {
+    if !reader.is_null() {
+        drop(Box::from_raw(reader));
+    }
+}
+
+// Starts compressing data into s3://bucket/key. The region may be NULL for the
+// default one. A frame size of 0 takes the default.
+//
+// # Safety
+//
+// The strings have to be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn zss_compress_begin(
+    region: *const c_char,
+    bucket: *const c_char,
+    key: *const c_char,
+    level: c_int,
+    frame_size: usize,
+) -> *mut ZssWriter {
+    guard(std::ptr::null_mut(), || {
+        let defaults = UploadOptions::default();
+        let options = UploadOptions {
+            compression_level: usize::try_from(level).map_err(|_e| "level is negative.")?,
+            frame_size: if frame_size == 0 {
+                defaults.frame_size
+            } else {
+                frame_size
+            },
+            ..defaults
+        };
+        let client = client(region)?;
+        let bucket = string_arg(bucket, "bucket")?;
+        let key = string_arg(key, "key")?;
+        let runtime = global_runtime().map_err(|e| e.to_string())?;
+        let _runtime = runtime.enter();
+        let sink = UploadSink::new(client, bucket, key, options).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(ZssWriter { sink })))
+    })
+}
+
+// Compresses len bytes from data into the object.
+//
+// # Safety
+//
+// The writer has to come from zss_compress_begin and data has to hold len
+// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn zss_compress_write(
+    writer: *mut ZssWriter,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    guard(-1, || {
+        let writer = writer.as_mut().ok_or("writer is NULL.")?;
+        if len == 0 {
+            return Ok(0);
+        }
+        if data.is_null() {
+            return Err("data is NULL.".to_owned());
+        }
+        let data = Bytes::copy_from_slice(std::slice::from_raw_parts(data, len));
+        let runtime = global_runtime().map_err(|e| e.to_string())?;
+        runtime
+            .block_on(writer.sink.send(data))
+            .map_err(|e| e.to_string())?;
+        Ok(0)
+    })
+}
+
+// Finishes the object, waiting for the upload to complete, and frees the
+// writer whether or not it worked. Returns 0 once the object is in S3.
+//
+// # Safety
+//
+// The writer has to come from zss_compress_begin and can't be used
+// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn zss_compress_finish(writer: *mut ZssWriter) -> c_int {
+    guard(-1, || {
+        if writer.is_null() {
+            return Err("writer is NULL.".to_owned());
+        }
+        let mut writer = Box::from_raw(writer);
+        let runtime = global_runtime().map_err(|e| e.to_string())?;
+        runtime
+            .block_on(writer.sink.close())
+            .map_err(|e| e.to_string())?;
+        Ok(0)
+    })
+}
+
+// Gives up on the object, aborting the upload rather than completing it with
+// what was written so far, and frees the writer. NULL is fine.
+//
+// # Safety
+//
+// The writer has to come from zss_compress_begin and can't be used
+// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn zss_compress_abort(writer: *mut ZssWriter) {
+    if !writer.is_null() {
+        Box::from_raw(writer).sink.abort();
+    }
+}
@@ -0,0 +1,267 @@
+use std::{convert::TryFrom, fmt::Display, ops::Range};
+
+// Layout of the seek table, as described in zstd's
+// contrib/seekable_format/zstd_seekable_compression_format.md. The table is a
+// skippable frame at the very end of the stream:
+//
+// skippable magic (4) | frame size (4) | entries | number of frames (4) |
+// descriptor (1) | seekable magic (4)
+//
+// with each entry being compressed size (4) | decompressed size (4) and an
+// optional checksum (4). All the numbers are little-endian.
+const SKIPPABLE_MAGIC_NUMBER: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC_NUMBER: u32 = 0x8F92_EAB1;
+const SKIPPABLE_HEADER_SIZE: usize = 8;
+pub const SEEK_TABLE_FOOTER_SIZE: usize = 9;
+const CHECKSUM_FLAG: u8 = 0x80;
+// Bits 2 to 6 of the descriptor are reserved and must be zero.
+const RESERVED_BITS: u8 = 0x7C;
+// Limits zstd sets for seekable streams. Tables going past them can't have
+// come from a real stream, only from a corrupted or hostile one.
+const MAX_FRAMES: u64 = 0x800_0000;
+const MAX_FRAME_DECOMPRESSED_SIZE: u64 = 0x8000_0000;
+
+// Where a single frame lives in the compressed and decompressed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameEntry {
+    pub compressed_offset: u64,
+    pub compressed_size: u64,
+    pub decompressed_offset: u64,
+    pub decompressed_size: u64,
+    // Lowest 32 bits of the XXH64 of the decompressed frame, if the writer
+    // stored one.
+    pub checksum: Option<u32>,
+}
+
+// Map of all the frames of a seekable stream. This holds the same information
+// as the seek table at the end of the stream but in a form that's easy to
+// store elsewhere: readers that have it on hand don't have to go fetch the
+// table from the end of the compressed data.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameIndex {
+    frames: Vec<FrameEntry>,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SeekTableError {
+    // Not enough data to hold the seek table.
+    Truncated,
+    // One of the magic numbers was wrong: this isn't a seekable stream.
+    BadMagic,
+    ReservedBitsSet,
+    // The sizes in the table add up to more than we can represent.
+    TooLarge,
+    // More frames than a seekable stream can have.
+    TooManyFrames,
+    // A frame with no data at all or more decompressed data than a frame
+    // can hold.
+    BadFrameSize { frame: usize },
+}
+
+impl Display for SeekTableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeekTableError::Truncated => write!(f, "Seek table is truncated."),
+            SeekTableError::BadMagic => write!(f, "Seek table magic number mismatch."),
+            SeekTableError::ReservedBitsSet => {
+                write!(f, "Seek table descriptor has reserved bits set.")
+            }
+            SeekTableError::TooLarge => write!(f, "Seek table sizes overflow."),
+            SeekTableError::TooManyFrames => write!(f, "Seek table has too many frames."),
+            SeekTableError::BadFrameSize { frame } => {
+                write!(f, "Seek table has an impossible size for frame {}.", frame)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SeekTableError {}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&data[at..at + 4]);
+    u32::from_le_bytes(word)
+}
+
+// Given the footer (last SEEK_TABLE_FOOTER_SIZE bytes of a stream), works out
+// how big the whole seek table is, including the footer itself. This is what
+// a reader needs to know to fetch just the table from the end of an object.
+pub fn seek_table_size(footer: &[u8]) -> Result<u64, SeekTableError> {
+    if footer.len() < SEEK_TABLE_FOOTER_SIZE {
+        return Err(SeekTableError::Truncated);
+    }
+    let footer = &footer[footer.len() - SEEK_TABLE_FOOTER_SIZE..];
+    if read_u32(footer, 5) != SEEKABLE_MAGIC_NUMBER {
+        return Err(SeekTableError::BadMagic);
+    }
+    let descriptor = footer[4];
+    if descriptor & RESERVED_BITS != 0 {
+        return Err(SeekTableError::ReservedBitsSet);
+    }
+    let entry_size = if descriptor & CHECKSUM_FLAG != 0 {
+        12
+    } else {
+        8
+    };
+    let num_frames = u64::from(read_u32(footer, 0));
+    if num_frames > MAX_FRAMES {
+        return Err(SeekTableError::TooManyFrames);
+    }
+    Ok((SKIPPABLE_HEADER_SIZE + SEEK_TABLE_FOOTER_SIZE) as u64 + num_frames * entry_size)
+}
+
+impl FrameIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Reads the index out of a seek table. The data must end with the seek
+    // table but is allowed to have anything before it so the whole compressed
+    // stream can be passed in as well.
+    pub fn from_seek_table(data: &[u8]) -> Result<Self, SeekTableError> {
+        let table_size =
+            usize::try_from(seek_table_size(data)?).map_err(|_e| SeekTableError::TooLarge)?;
+        if data.len() < table_size {
+            return Err(SeekTableError::Truncated);
+        }
+        let table = &data[data.len() - table_size..];
+        if read_u32(table, 0) != SKIPPABLE_MAGIC_NUMBER {
+            return Err(SeekTableError::BadMagic);
+        }
+        if read_u32(table, 4) as usize != table_size - SKIPPABLE_HEADER_SIZE {
+            return Err(SeekTableError::Truncated);
+        }
+        let footer = &table[table_size - SEEK_TABLE_FOOTER_SIZE..];
+        let with_checksums = footer[4] & CHECKSUM_FLAG != 0;
+        let num_frames = read_u32(footer, 0) as usize;
+        let entry_size = if with_checksums { 12 } else { 8 };
+
+        let mut index = FrameIndex {
+            frames: Vec::with_capacity(num_frames),
+        };
+        for (frame, entry) in table[SKIPPABLE_HEADER_SIZE..table_size - SEEK_TABLE_FOOTER_SIZE]
+            .chunks(entry_size)
+            .enumerate()
+        {
+            let compressed_size = u64::from(read_u32(entry, 0));
+            let decompressed_size = u64::from(read_u32(entry, 4));
+            if compressed_size == 0 || decompressed_size > MAX_FRAME_DECOMPRESSED_SIZE {
+                return Err(SeekTableError::BadFrameSize { frame });
+            }
+            let checksum = if with_checksums {
+                Some(read_u32(entry, 8))
+            } else {
+                None
+            };
+            index.push(compressed_size, decompressed_size, checksum)?;
+        }
+        Ok(index)
+    }
+
+    // Renders the index as a seek table that can be appended to the frames it
+    // describes. Checksums are only written out if every frame has one.
+    pub fn to_seek_table(&self) -> Result<Vec<u8>, SeekTableError> {
+        let with_checksums = self.frames.iter().all(|frame| frame.checksum.is_some());
+        let entry_size = if with_checksums { 12 } else { 8 };
+        let num_frames = u32::try_from(self.frames.len()).map_err(|_e| SeekTableError::TooLarge)?;
+        let frame_size = u32::try_from(entry_size * self.frames.len() + SEEK_TABLE_FOOTER_SIZE)
+            .map_err(|_e| SeekTableError::TooLarge)?;
+
+        let mut table = Vec::with_capacity(SKIPPABLE_HEADER_SIZE + frame_size as usize);
+        table.extend_from_slice(&SKIPPABLE_MAGIC_NUMBER.to_le_bytes());
+        table.extend_from_slice(&frame_size.to_le_bytes());
+        for frame in &self.frames {
+            let compressed_size =
+                u32::try_from(frame.compressed_size).map_err(|_e| SeekTableError::TooLarge)?;
+            let decompressed_size =
+                u32::try_from(frame.decompressed_size).map_err(|_e| SeekTableError::TooLarge)?;
+            table.extend_from_slice(&compressed_size.to_le_bytes());
+            table.extend_from_slice(&decompressed_size.to_le_bytes());
+            if let (true, Some(checksum)) = (with_checksums, frame.checksum) {
+                table.extend_from_slice(&checksum.to_le_bytes());
+            }
+        }
+        table.extend_from_slice(&num_frames.to_le_bytes());
+        table.push(if with_checksums { CHECKSUM_FLAG } else { 0 });
+        table.extend_from_slice(&SEEKABLE_MAGIC_NUMBER.to_le_bytes());
+        Ok(table)
+    }
+
+    // Adds a frame directly after the last one.
+    pub fn push(
+        &mut self,
+        compressed_size: u64,
+        decompressed_size: u64,
+        checksum: Option<u32>,
+    ) -> Result<(), SeekTableError> {
+        let (compressed_offset, decompressed_offset) = match self.frames.last() {
+            None => (0, 0),
+            Some(last) => (
+                last.compressed_offset
+                    .checked_add(last.compressed_size)
+                    .ok_or(SeekTableError::TooLarge)?,
+                last.decompressed_offset
+                    .checked_add(last.decompressed_size)
+                    .ok_or(SeekTableError::TooLarge)?,
+            ),
+        };
+        self.frames.push(FrameEntry {
+            compressed_offset,
+            compressed_size,
+            decompressed_offset,
+            decompressed_size,
+            checksum,
+        });
+        Ok(())
+    }
+
+    pub fn frames(&self) -> &[FrameEntry] {
+        &self.frames
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Size of all the frames, not counting the seek table.
+    pub fn compressed_size(&self) -> u64 {
+        self.frames
+            .last()
+            .map_or(0, |last| last.compressed_offset + last.compressed_size)
+    }
+
+    pub fn decompressed_size(&self) -> u64 {
+        self.frames
+            .last()
+            .map_or(0, |last| last.decompressed_offset + last.decompressed_size)
+    }
+
+    // Range of the compressed data holding the given range of decompressed
+    // data: all the frames it touches. None if the range is empty or goes
+    // past the end of the data.
+    pub fn compressed_range(&self, decompressed: Range<u64>) -> Option<Range<u64>> {
+        if decompressed.start >= decompressed.end {
+            return None;
+        }
+        let first = &self.frames[self.frame_index_at(decompressed.start)?];
+        let last = &self.frames[self.frame_index_at(decompressed.end - 1)?];
+        Some(first.compressed_offset..last.compressed_offset + last.compressed_size)
+    }
+
+    // Finds the frame holding the given decompressed offset, if any.
+    pub fn frame_index_at(&self, decompressed_offset: u64) -> Option<usize> {
+        let index = self
+            .frames
+            .partition_point(|frame| frame.decompressed_offset <= decompressed_offset);
+        let index = index.checked_sub(1)?;
+        let frame = &self.frames[index];
+        if decompressed_offset < frame.decompressed_offset + frame.decompressed_size {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request, FUSE_ROOT_ID,
+};
+use rusoto_s3::{GetObjectRequest, S3Client};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    fetch_object_index, GetSeekableObjectError, ReadObjectError, ReadOptions, SeekableDecompress,
+    SeekableS3Object,
+};
+
+// Nothing ever changes so the kernel can hang on to what we tell it.
+const TTL: Duration = Duration::from_secs(3600);
+
+type Reader = SeekableDecompress<'static, SeekableS3Object<'static, S3Client>>;
+
+// A file in the filesystem and the object it's the decompressed data of.
+#[derive(Debug)]
+struct MountedFile {
+    name: String,
+    req: GetObjectRequest,
+    size: u64,
+}
+
+// Read-only filesystem with a single directory holding the decompressed data
+// of seekable objects as regular files. Reads only fetch and decompress the
+// frames they touch, so tools that read files in bits, such as sqlite or
+// anything working on indexed files, get along without downloading whole
+// objects.
+pub struct S3Filesystem {
+    client: S3Client,
+    runtime: &'static tokio::runtime::Runtime,
+    options: ReadOptions,
+    files: Vec<MountedFile>,
+    // Objects opened by reads so far, by the index of their file.
+    readers: HashMap<usize, Reader>,
+}
+
+impl std::fmt::Debug for S3Filesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Filesystem")
+            .field("options", &self.options)
+            .field("files", &self.files)
+            .finish()
+    }
+}
+
+impl S3Filesystem {
+    // Files are named after the given names and hold the decompressed data
+    // of the objects the requests are for. The seek table of each object is
+    // fetched up front to find out how big the files are. Objects are read
+    // with the given options.
+    pub fn new(
+        client: S3Client,
+        runtime: &'static tokio::runtime::Runtime,
+        objects: Vec<(String, GetObjectRequest)>,
+        options: ReadOptions,
+    ) -> Result<Self, ReadObjectError> {
+        let mut files = Vec::with_capacity(objects.len());
+        for (name, req) in objects {
+            let index = runtime.block_on(fetch_object_index(&client, &req))?;
+            files.push(MountedFile {
+                name,
+                req,
+                size: index.index.decompressed_size(),
+            });
+        }
+        Ok(S3Filesystem {
+            client,
+            runtime,
+            options,
+            files,
+            readers: HashMap::new(),
+        })
+    }
+
+    // Mounts the filesystem read-only at the given directory, serving
+    // requests until it's unmounted.
+    pub fn mount(self, mountpoint: &Path) -> std::io::Result<()> {
+        let options = [
+            MountOption::RO,
+            MountOption::FSName("zstd-seekable-s3".to_owned()),
+        ];
+        fuser::mount2(self, mountpoint, &options)
+    }
+
+    fn file_ino(index: usize) -> u64 {
+        FUSE_ROOT_ID + 1 + index as u64
+    }
+
+    fn file_index(&self, ino: u64) -> Option<usize> {
+        let index = ino.checked_sub(FUSE_ROOT_ID + 1)? as usize;
+        if index < self.files.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, size, perm, nlink) = if ino == FUSE_ROOT_ID {
+            (FileType::Directory, 0, 0o555, 2)
+        } else {
+            let file = &self.files[self.file_index(ino)?];
+            (FileType::RegularFile, file.size, 0o444, 1)
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind,
+            perm,
+            nlink,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        })
+    }
+
+    fn read_file(&mut self, index: usize, offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+        let reader = match self.readers.entry(index) {
+            std::collections::hash_map::Entry::Occupied(reader) => reader.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let object = SeekableS3Object::with_options(
+                    self.client.clone(),
+                    self.runtime,
+                    self.files[index].req.to_owned(),
+                    &self.options,
+                )
+                .map_err(|e| {
+                    let kind = match e {
+                        GetSeekableObjectError::TimedOut(_) => std::io::ErrorKind::TimedOut,
+                        _ => std::io::ErrorKind::Other,
+                    };
+                    std::io::Error::new(kind, e)
+                })?;
+                let reader = SeekableDecompress::new(object).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+                })?;
+                entry.insert(reader)
+            }
+        };
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(size);
+        reader.take(size as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Filesystem for S3Filesystem {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let index = self
+            .files
+            .iter()
+            .position(|file| OsStr::new(&file.name) == name);
+        match index.filter(|_| parent == FUSE_ROOT_ID) {
+            Some(index) => match self.attr(Self::file_ino(index)) {
+                Some(attr) => reply.entry(&TTL, &attr, 0),
+                None => reply.error(ENOENT),
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let index = match self.file_index(ino) {
+            Some(index) => index,
+            None => return reply.error(ENOENT),
+        };
+        let offset = offset.max(0) as u64;
+        match self.read_file(index, offset, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(_) => {
+                // A broken reader isn't worth keeping, start over next time.
+                self.readers.remove(&index);
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != FUSE_ROOT_ID {
+            return reply.error(ENOENT);
+        }
+        let entries = [
+            (FUSE_ROOT_ID, FileType::Directory, "."),
+            (FUSE_ROOT_ID, FileType::Directory, ".."),
+        ];
+        let files = self.files.iter().enumerate().map(|(index, file)| {
+            (
+                Self::file_ino(index),
+                FileType::RegularFile,
+                file.name.as_str(),
+            )
+        });
+        // The offset is that of the entry after the last one we sent.
+        for (i, (ino, kind, name)) in entries
+            .iter()
+            .copied()
+            .chain(files)
+            .enumerate()
+            .skip(offset.max(0) as usize)
+        {
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+}
+
+// From errno.h, the same everywhere FUSE runs.
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
@@ -0,0 +1,280 @@
+use bytes::Bytes;
+use hyper::body::Sender;
+use hyper::header::{HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use parking_lot::Mutex;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectError, GetObjectRequest, S3Client};
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
+use std::io::{Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::http_request::{percent_decode, requested_range, RequestedRange};
+use crate::{
+    fetch_object_index, ObjectIndex, ReadObjectError, ReadOptions, SeekableDecompress,
+    SeekableS3Object,
+};
+
+// How much decompressed data to send the client at a time.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+// Bodies sent at once unless set otherwise, see HttpGateway::set_max_streams.
+const MAX_STREAMS: usize = 64;
+
+// Frame indexes kept for objects requested before.
+const MAX_CACHED_INDEXES: usize = 1024;
+
+// HTTP server handing out the decompressed data of seekable objects. A GET of
+// /bucket/key gets the decompressed object, and a Range header gets just that
+// range of it, so browsers and anything else that reads files over HTTP in
+// bits can work with the compressed objects directly. Only the frames a
+// request touches are fetched and decompressed.
+pub struct HttpGateway {
+    client: S3Client,
+    runtime: &'static tokio::runtime::Runtime,
+    options: ReadOptions,
+    // Each body being sent holds one of these, the rest wait for theirs.
+    streams: Arc<Semaphore>,
+    indexes: Arc<Mutex<IndexCache>>,
+}
+
+impl std::fmt::Debug for HttpGateway {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpGateway")
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl HttpGateway {
+    // Objects are read with the given options. Reading blocks on the runtime,
+    // so it happens on its blocking threads rather than on the server's, and
+    // the runtime has to be multi-threaded for that, see AsyncContextError.
+    pub fn new(
+        client: S3Client,
+        runtime: &'static tokio::runtime::Runtime,
+        options: ReadOptions,
+    ) -> Self {
+        HttpGateway {
+            client,
+            runtime,
+            options,
+            streams: Arc::new(Semaphore::new(MAX_STREAMS)),
+            indexes: Arc::new(Mutex::new(IndexCache::default())),
+        }
+    }
+
+    // How many bodies are sent at once, each taking up a blocking thread while
+    // it is. Requests beyond that wait for one of them to finish.
+    pub fn set_max_streams(&mut self, max_streams: usize) {
+        self.streams = Arc::new(Semaphore::new(max_streams.max(1)));
+    }
+
+    // Serves requests on the given address until the future is dropped.
+    pub async fn serve(self, addr: SocketAddr) -> hyper::Result<()> {
+        let gateway = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let gateway = Arc::clone(&gateway);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let gateway = Arc::clone(&gateway);
+                    async move { Ok::<_, Infallible>(gateway.respond(req).await) }
+                }))
+            }
+        });
+        Server::try_bind(&addr)?.serve(make_service).await
+    }
+
+    async fn respond(&self, req: Request<Body>) -> Response<Body> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return status_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let object = match object_request(req.uri().path()) {
+            Some(object) => object,
+            None => return status_response(StatusCode::NOT_FOUND),
+        };
+        let cache_key = (object.bucket.to_owned(), object.key.to_owned());
+        let cached = self.indexes.lock().get(&cache_key);
+        let index = match cached {
+            Some(index) => index,
+            None => match fetch_object_index(&self.client, &object).await {
+                Ok(index) => {
+                    self.indexes
+                        .lock()
+                        .insert(cache_key.to_owned(), index.to_owned());
+                    index
+                }
+                Err(ReadObjectError::GetObject(e)) if is_not_found(&e) => {
+                    return status_response(StatusCode::NOT_FOUND)
+                }
+                Err(_) => return status_response(StatusCode::BAD_GATEWAY),
+            },
+        };
+        let size = index.index.decompressed_size();
+        // Read the object the index is for. If it's changed since, reading
+        // fails and takes the index out of the cache.
+        let object = GetObjectRequest {
+            if_match: index.e_tag,
+            ..object
+        };
+        let requested = req
+            .headers()
+            .get(RANGE)
+            .and_then(|range| range.to_str().ok())
+            .map_or(RequestedRange::Whole, |range| requested_range(range, size));
+        let (status, range) = match requested {
+            RequestedRange::Whole => (StatusCode::OK, 0..size),
+            RequestedRange::Part(range) => (StatusCode::PARTIAL_CONTENT, range),
+            RequestedRange::Unsatisfiable => {
+                let mut response = status_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                if let Ok(content_range) = HeaderValue::try_from(format!("bytes */{}", size)) {
+                    response.headers_mut().insert(CONTENT_RANGE, content_range);
+                }
+                return response;
+            }
+        };
+
+        let body = if req.method() == Method::HEAD || range.start == range.end {
+            Body::empty()
+        } else {
+            let permit = match Arc::clone(&self.streams).acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return status_response(StatusCode::SERVICE_UNAVAILABLE),
+            };
+            self.stream_range(object, cache_key, range.to_owned(), permit)
+        };
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+        let headers = response.headers_mut();
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(range.end - range.start));
+        if status == StatusCode::PARTIAL_CONTENT {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, size);
+            if let Ok(content_range) = HeaderValue::try_from(content_range) {
+                headers.insert(CONTENT_RANGE, content_range);
+            }
+        }
+        response
+    }
+
+    // Body with the given decompressed range of the object, read on a blocking
+    // thread that holds the permit until it's done. If reading fails partway
+    // through the body is cut off, and the cached index dropped in case the
+    // object changed since.
+    fn stream_range(
+        &self,
+        object: GetObjectRequest,
+        cache_key: (String, String),
+        range: Range<u64>,
+        permit: OwnedSemaphorePermit,
+    ) -> Body {
+        let (mut sender, body) = Body::channel();
+        let object =
+            SeekableS3Object::lazy(self.client.clone(), self.runtime, object, &self.options);
+        let indexes = Arc::clone(&self.indexes);
+        self.runtime.spawn_blocking(move || {
+            let _permit = permit;
+            if send_range(object, range, &mut sender).is_err() {
+                indexes.lock().remove(&cache_key);
+                sender.abort();
+            }
+        });
+        body
+    }
+}
+
+fn send_range(
+    object: SeekableS3Object<'static, S3Client>,
+    range: Range<u64>,
+    sender: &mut Sender,
+) -> std::io::Result<()> {
+    let mut reader = SeekableDecompress::new(object)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    reader.seek(SeekFrom::Start(range.start))?;
+    let mut reader = reader.take(range.end - range.start);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(());
+        }
+        let chunk = Bytes::copy_from_slice(&buffer[..n]);
+        if futures::executor::block_on(sender.send_data(chunk)).is_err() {
+            // The client went away, nobody is left to read the rest.
+            return Ok(());
+        }
+    }
+}
+
+// Most recently used frame indexes by bucket and key, along with the ETag of
+// the object they're for, so that requests for objects we've seen don't fetch
+// their seek table first. Finding what to evict
+// goes through all of them, as for BlockCache.
+#[derive(Debug, Default)]
+struct IndexCache {
+    // Index and when it was last used.
+    indexes: HashMap<(String, String), (ObjectIndex, u64)>,
+    clock: u64,
+}
+
+impl IndexCache {
+    fn get(&mut self, key: &(String, String)) -> Option<ObjectIndex> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.indexes.get_mut(key).map(|(index, last_used)| {
+            *last_used = clock;
+            index.to_owned()
+        })
+    }
+
+    fn insert(&mut self, key: (String, String), index: ObjectIndex) {
+        self.clock += 1;
+        self.indexes.insert(key, (index, self.clock));
+        if self.indexes.len() > MAX_CACHED_INDEXES {
+            let oldest = self
+                .indexes
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.to_owned());
+            if let Some(oldest) = oldest {
+                self.indexes.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &(String, String)) {
+        self.indexes.remove(key);
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn is_not_found(error: &RusotoError<GetObjectError>) -> bool {
+    match error {
+        RusotoError::Service(GetObjectError::NoSuchKey(_)) => true,
+        RusotoError::Unknown(response) => response.status.as_u16() == 404,
+        _ => false,
+    }
+}
+
+// The object a path such as /bucket/some/key is for.
+fn object_request(path: &str) -> Option<GetObjectRequest> {
+    let (bucket, key) = path.strip_prefix('/')?.split_once('/')?;
+    if bucket.is_empty() || key.is_empty() {
+        return None;
+    }
+    Some(GetObjectRequest {
+        bucket: percent_decode(bucket)?,
+        key: percent_decode(key)?,
+        ..Default::default()
+    })
+}
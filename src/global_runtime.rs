@@ -0,0 +1,21 @@
+use parking_lot::Mutex;
+
+// Runtime for callers that can't give us one of their own, such as the Python
+// module and the C API. Made on first use and kept for the life of the
+// process.
+static RUNTIME: Mutex<Option<&'static tokio::runtime::Runtime>> = parking_lot::const_mutex(None);
+
+pub(crate) fn global_runtime() -> std::io::Result<&'static tokio::runtime::Runtime> {
+    let mut runtime = RUNTIME.lock();
+    match *runtime {
+        Some(runtime) => Ok(runtime),
+        None => {
+            let new = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?;
+            let new: &'static tokio::runtime::Runtime = Box::leak(Box::new(new));
+            *runtime = Some(new);
+            Ok(new)
+        }
+    }
+}
@@ -0,0 +1,74 @@
+// Bits of HTTP shared by the gateway and the mock S3.
+
+use std::ops::Range;
+
+// What a Range header asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RequestedRange {
+    Whole,
+    Part(Range<u64>),
+    // Starts past the end of the object.
+    Unsatisfiable,
+}
+
+// Range of an object of the given size a Range header such as "bytes=0-99",
+// "bytes=100-" or "bytes=-100" asks for. Headers we don't understand,
+// including ones asking for several ranges at once, get the whole object as
+// HTTP allows.
+pub(crate) fn requested_range(header: &str, size: u64) -> RequestedRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return RequestedRange::Whole,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return RequestedRange::Whole,
+    };
+    let range = if start.is_empty() {
+        // The last so many bytes.
+        match end.parse::<u64>() {
+            Ok(0) => return RequestedRange::Unsatisfiable,
+            Ok(len) => size.saturating_sub(len)..size,
+            Err(_) => return RequestedRange::Whole,
+        }
+    } else {
+        let start = match start.parse::<u64>() {
+            Ok(start) => start,
+            Err(_) => return RequestedRange::Whole,
+        };
+        // The end is inclusive and may be past the end of the object.
+        let end = if end.is_empty() {
+            size
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end.saturating_add(1).min(size),
+                _ => return RequestedRange::Whole,
+            }
+        };
+        start..end
+    };
+    if range.start >= size {
+        RequestedRange::Unsatisfiable
+    } else {
+        RequestedRange::Part(range)
+    }
+}
+
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            decoded.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
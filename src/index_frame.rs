@@ -0,0 +1,175 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{seek_table_size, FrameIndex, SeekTableError, SEEK_TABLE_FOOTER_SIZE};
+
+// Index frames are skippable frames of their own, one of the magic numbers
+// zstd sets aside for them other than the one the seek table uses:
+//
+// skippable magic (4) | frame size (4) | tag (4) | payload
+//
+// They sit right before the seek table, which lists each of them as a frame
+// with no decompressed data. Decompressors never end up in such frames so the
+// stream reads the same as it would without them, while readers that know
+// about them find them through the frame index.
+const INDEX_FRAME_MAGIC_NUMBER: u32 = 0x184D_2A5D;
+const INDEX_FRAME_HEADER_SIZE: usize = 12;
+// Lowest 32 bits of the XXH64 of no data, for seek tables with checksums.
+pub(crate) const EMPTY_CHECKSUM: u32 = 0x51D8_E999;
+
+// Data stored alongside the compressed data in an index frame. The tag tells
+// apart different kinds of index in the same stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexFrame {
+    pub tag: [u8; 4],
+    pub payload: Vec<u8>,
+}
+
+// Builds an index frame out of the uncompressed data as it's compressed, see
+// Compress::with_index. Builders are Sync so that Compress stays Sync with
+// them in it, as request bodies have to be.
+pub trait IndexBuilder: Send + Sync {
+    // Sees all of the uncompressed data, in order, a chunk at a time.
+    fn update(&mut self, data: &[u8]);
+
+    // Called once all the data went by.
+    fn finish(&mut self) -> IndexFrame;
+}
+
+impl IndexFrame {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let frame_size = (self.tag.len() + self.payload.len()) as u32;
+        let mut frame = Vec::with_capacity(INDEX_FRAME_HEADER_SIZE + self.payload.len());
+        frame.extend_from_slice(&INDEX_FRAME_MAGIC_NUMBER.to_le_bytes());
+        frame.extend_from_slice(&frame_size.to_le_bytes());
+        frame.extend_from_slice(&self.tag);
+        frame.extend_from_slice(&self.payload);
+        frame
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SeekTableError> {
+        if data.len() < INDEX_FRAME_HEADER_SIZE {
+            return Err(SeekTableError::Truncated);
+        }
+        if read_u32(data, 0) != INDEX_FRAME_MAGIC_NUMBER {
+            return Err(SeekTableError::BadMagic);
+        }
+        if read_u32(data, 4) as usize != data.len() - 8 {
+            return Err(SeekTableError::Truncated);
+        }
+        let mut tag = [0; 4];
+        tag.copy_from_slice(&data[8..INDEX_FRAME_HEADER_SIZE]);
+        Ok(IndexFrame {
+            tag,
+            payload: data[INDEX_FRAME_HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&data[at..at + 4]);
+    u32::from_le_bytes(word)
+}
+
+// Puts the index frames at the end of a compressed stream, between the last
+// frame and the seek table, and lists them in the table. The stream and its
+// frame index are updated in place. Frames too large to list in the table
+// are left out.
+pub(crate) fn append_index_frames(
+    stream: &mut Vec<u8>,
+    index: &mut FrameIndex,
+    frames: Vec<IndexFrame>,
+) -> Result<(), SeekTableError> {
+    let table_size =
+        usize::try_from(seek_table_size(stream)?).map_err(|_e| SeekTableError::TooLarge)?;
+    let with_checksums = index.frames().iter().all(|frame| frame.checksum.is_some());
+    let mut new_index = index.clone();
+    let mut appended = Vec::new();
+    for frame in frames {
+        let frame = frame.to_bytes();
+        if u32::try_from(frame.len()).is_err() {
+            continue;
+        }
+        let checksum = if with_checksums {
+            Some(EMPTY_CHECKSUM)
+        } else {
+            None
+        };
+        new_index.push(frame.len() as u64, 0, checksum)?;
+        appended.extend_from_slice(&frame);
+    }
+    let table = new_index.to_seek_table()?;
+    stream.truncate(stream.len() - table_size);
+    stream.extend_from_slice(&appended);
+    stream.extend_from_slice(&table);
+    *index = new_index;
+    Ok(())
+}
+
+// Reads the frame index out of the seek table at the end of the compressed
+// data.
+pub fn read_frame_index<R: Read + Seek>(compressed: &mut R) -> std::io::Result<FrameIndex> {
+    read_seek_table(compressed).map(|(index, _)| index)
+}
+
+// Like read_frame_index, also giving the size of the seek table, footer
+// included.
+pub(crate) fn read_seek_table<R: Read + Seek>(
+    compressed: &mut R,
+) -> std::io::Result<(FrameIndex, u64)> {
+    let table_size = read_seek_table_size(compressed)?;
+    let seek_back =
+        i64::try_from(table_size).map_err(|_e| invalid_data(SeekTableError::TooLarge))?;
+    compressed.seek(SeekFrom::End(-seek_back))?;
+    let mut table = vec![0; table_size as usize];
+    compressed.read_exact(&mut table)?;
+    let index = FrameIndex::from_seek_table(&table).map_err(invalid_data)?;
+    Ok((index, table_size))
+}
+
+// Size of the seek table, footer included, going by just the footer.
+pub(crate) fn read_seek_table_size<R: Read + Seek>(compressed: &mut R) -> std::io::Result<u64> {
+    let stream_size = compressed.seek(SeekFrom::End(0))?;
+    if stream_size < SEEK_TABLE_FOOTER_SIZE as u64 {
+        return Err(invalid_data(SeekTableError::Truncated));
+    }
+    let mut footer = [0; SEEK_TABLE_FOOTER_SIZE];
+    compressed.seek(SeekFrom::End(-(SEEK_TABLE_FOOTER_SIZE as i64)))?;
+    compressed.read_exact(&mut footer)?;
+    let table_size = seek_table_size(&footer).map_err(invalid_data)?;
+    // Don't go allocating whatever a corrupted footer says.
+    if table_size > stream_size {
+        return Err(invalid_data(SeekTableError::Truncated));
+    }
+    Ok(table_size)
+}
+
+// Reads the payload of the last index frame with the given tag, if there is
+// one. The frame index says where the frames are, see read_frame_index.
+pub fn read_index_frame<R: Read + Seek>(
+    compressed: &mut R,
+    index: &FrameIndex,
+    tag: [u8; 4],
+) -> std::io::Result<Option<Vec<u8>>> {
+    let candidates = index.frames().iter().rev().filter(|frame| {
+        frame.decompressed_size == 0 && frame.compressed_size >= INDEX_FRAME_HEADER_SIZE as u64
+    });
+    for entry in candidates {
+        compressed.seek(SeekFrom::Start(entry.compressed_offset))?;
+        let mut data = vec![0; entry.compressed_size as usize];
+        compressed.read_exact(&mut data)?;
+        // Empty frames that aren't ours are fine, they just aren't what we're
+        // looking for.
+        if let Ok(frame) = IndexFrame::from_bytes(&data) {
+            if frame.tag == tag {
+                return Ok(Some(frame.payload));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn invalid_data(e: SeekTableError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
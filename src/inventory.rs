@@ -0,0 +1,256 @@
+use futures::StreamExt;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectRequest, ListObjectsV2Error, ListObjectsV2Request, S3};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::io::Write;
+
+use crate::{fetch_object_index, fetch_seek_table_object, FrameIndex, SEEK_TABLE_OBJECT_SUFFIX};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InventoryOptions {
+    // Objects with a seek table object next to them, under their key with this
+    // suffix, get their frames from that rather than from their tail. Seek
+    // table objects with their object next to them aren't listed themselves.
+    pub seek_table_suffix: Option<String>,
+    // How many objects to look at at once.
+    pub concurrency: usize,
+}
+
+impl Default for InventoryOptions {
+    fn default() -> Self {
+        InventoryOptions {
+            seek_table_suffix: Some(SEEK_TABLE_OBJECT_SUFFIX.to_owned()),
+            concurrency: 16,
+        }
+    }
+}
+
+// What the seek table of a single object says about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InventoryEntry {
+    pub key: String,
+    pub object_size: u64,
+    // Whether the frames came from a seek table object.
+    pub from_sidecar: bool,
+    // Frames holding data, leaving out index frames and other skippable ones.
+    pub frames: usize,
+    // Of those, how many have a checksum in the seek table.
+    pub checksummed_frames: usize,
+    pub compressed_size: u64,
+    pub decompressed_size: u64,
+    // Why there's no seek table to speak of, such as the object not being
+    // seekable at all. The counts are all 0 then.
+    pub problem: Option<String>,
+}
+
+impl InventoryEntry {
+    fn new(key: String, object_size: u64, from_sidecar: bool, index: &FrameIndex) -> Self {
+        let data_frames = index
+            .frames()
+            .iter()
+            .filter(|frame| frame.decompressed_size > 0);
+        InventoryEntry {
+            key,
+            object_size,
+            from_sidecar,
+            frames: data_frames.clone().count(),
+            checksummed_frames: data_frames.filter(|frame| frame.checksum.is_some()).count(),
+            compressed_size: index.compressed_size(),
+            decompressed_size: index.decompressed_size(),
+            problem: None,
+        }
+    }
+
+    // Decompressed size over compressed size, 0 for empty objects.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.decompressed_size, self.compressed_size)
+    }
+}
+
+fn ratio(decompressed_size: u64, compressed_size: u64) -> f64 {
+    if compressed_size == 0 {
+        0.0
+    } else {
+        decompressed_size as f64 / compressed_size as f64
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Inventory {
+    // In the order the keys were listed.
+    pub entries: Vec<InventoryEntry>,
+}
+
+impl Inventory {
+    pub fn compressed_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.compressed_size).sum()
+    }
+
+    pub fn decompressed_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| entry.decompressed_size)
+            .sum()
+    }
+
+    pub fn ratio(&self) -> f64 {
+        ratio(self.decompressed_size(), self.compressed_size())
+    }
+
+    pub fn problems(&self) -> impl Iterator<Item = &InventoryEntry> {
+        self.entries.iter().filter(|entry| entry.problem.is_some())
+    }
+
+    // Writes the entries out as CSV with a header line.
+    pub fn write_csv<W: Write>(&self, mut out: W) -> std::io::Result<()> {
+        writeln!(
+            out,
+            "key,object_size,from_sidecar,frames,checksummed_frames,compressed_size,decompressed_size,ratio,problem"
+        )?;
+        for entry in &self.entries {
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{:.3},{}",
+                csv_field(&entry.key),
+                entry.object_size,
+                entry.from_sidecar,
+                entry.frames,
+                entry.checksummed_frames,
+                entry.compressed_size,
+                entry.decompressed_size,
+                entry.ratio(),
+                csv_field(entry.problem.as_deref().unwrap_or("")),
+            )?;
+        }
+        out.flush()
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum InventoryError {
+    ListObjects(RusotoError<ListObjectsV2Error>),
+}
+
+impl Display for InventoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InventoryError::ListObjects(e) => write!(f, "Failed to list objects: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InventoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InventoryError::ListObjects(e) => Some(e),
+        }
+    }
+}
+
+// Keys and sizes of all the objects under the prefix.
+async fn list_objects<C: S3>(
+    client: &C,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Vec<(String, u64)>, InventoryError> {
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let req = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(prefix.to_owned()),
+            continuation_token,
+            ..Default::default()
+        };
+        let list_objects = traced!(
+            client.list_objects_v2(req),
+            "list_objects_v2",
+            bucket = %bucket,
+            prefix = %prefix,
+        );
+        let output = list_objects.await.map_err(InventoryError::ListObjects)?;
+        for object in output.contents.unwrap_or_default() {
+            if let Some(key) = object.key {
+                objects.push((key, object.size.unwrap_or(0).max(0) as u64));
+            }
+        }
+        match output.next_continuation_token {
+            Some(token) if output.is_truncated == Some(true) => continuation_token = Some(token),
+            _ => break,
+        }
+    }
+    Ok(objects)
+}
+
+// Lists the objects under the prefix and reads the seek table of each, from
+// a seek table object if there's one next to it or from the tail of the
+// object otherwise, without reading any of the frames. Objects whose seek
+// table can't be read end up in the inventory with the problem noted rather
+// than stopping it.
+pub async fn inventory<C: S3>(
+    client: &C,
+    bucket: &str,
+    prefix: &str,
+    options: &InventoryOptions,
+) -> Result<Inventory, InventoryError> {
+    let objects = list_objects(client, bucket, prefix).await?;
+    let suffix = options
+        .seek_table_suffix
+        .as_deref()
+        .filter(|s| !s.is_empty());
+    let keys: HashSet<&str> = objects.iter().map(|(key, _)| key.as_str()).collect();
+    let entries = futures::stream::iter(objects.iter().filter(|(key, _)| {
+        match suffix.and_then(|suffix| key.strip_suffix(suffix)) {
+            Some(object_key) => !keys.contains(object_key),
+            None => true,
+        }
+    }))
+    .map(|(key, object_size)| {
+        let sidecar = suffix
+            .map(|suffix| format!("{}{}", key, suffix))
+            .filter(|sidecar| keys.contains(sidecar.as_str()));
+        async move {
+            let req = GetObjectRequest {
+                bucket: bucket.to_owned(),
+                key: sidecar.to_owned().unwrap_or_else(|| key.to_owned()),
+                ..Default::default()
+            };
+            let index = match sidecar {
+                Some(_) => fetch_seek_table_object(client, req).await,
+                None => fetch_object_index(client, &req)
+                    .await
+                    .map(|index| index.index),
+            };
+            match index {
+                Ok(index) => {
+                    InventoryEntry::new(key.to_owned(), *object_size, sidecar.is_some(), &index)
+                }
+                Err(e) => InventoryEntry {
+                    problem: Some(e.to_string()),
+                    ..InventoryEntry::new(
+                        key.to_owned(),
+                        *object_size,
+                        sidecar.is_some(),
+                        &FrameIndex::new(),
+                    )
+                },
+            }
+        }
+    })
+    .buffered(std::cmp::max(options.concurrency, 1))
+    .collect()
+    .await;
+    Ok(Inventory { entries })
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{read_frame_index, read_index_frame, IndexBuilder, IndexFrame, SeekableDecompress};
+
+// Tag of the index frame holding a key index.
+pub const KEY_INDEX_TAG: [u8; 4] = *b"KEYI";
+
+// Where the records with each key start in the uncompressed data. Keys are
+// whatever the application pulls out of its records, see KeyIndexBuilder.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyIndex {
+    offsets: BTreeMap<Vec<u8>, u64>,
+}
+
+impl KeyIndex {
+    // Reads the key index out of the compressed data, if it was compressed
+    // with one. Indexes stored elsewhere are read with from_bytes instead.
+    pub fn read<R: Read + Seek>(compressed: &mut R) -> std::io::Result<Option<Self>> {
+        let frames = read_frame_index(compressed)?;
+        match read_index_frame(compressed, &frames, KEY_INDEX_TAG)? {
+            None => Ok(None),
+            Some(payload) => Self::from_bytes(&payload).map(Some).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "Key index is malformed.")
+            }),
+        }
+    }
+
+    // Offset of the first record with the given key.
+    pub fn lookup(&self, key: &[u8]) -> Option<u64> {
+        self.offsets.get(key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    // Keys in order, along with where their records start.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], u64)> {
+        self.offsets
+            .iter()
+            .map(|(key, &offset)| (key.as_slice(), offset))
+    }
+
+    // Renders the index so it can be stored on its own, say as an object
+    // next to the compressed one. Each key is key length (4) | key |
+    // offset (8), with the numbers little-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (key, offset) in &self.offsets {
+            data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            data.extend_from_slice(key);
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        data
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> Option<Self> {
+        let mut offsets = BTreeMap::new();
+        while !data.is_empty() {
+            let key_len = u32::from_le_bytes(<[u8; 4]>::try_from(data.get(..4)?).ok()?);
+            let key_end = 4usize.checked_add(key_len as usize)?;
+            let key = data.get(4..key_end)?.to_vec();
+            let offset =
+                u64::from_le_bytes(<[u8; 8]>::try_from(data.get(key_end..key_end + 8)?).ok()?);
+            offsets.insert(key, offset);
+            data = &data[key_end + 8..];
+        }
+        Some(KeyIndex { offsets })
+    }
+}
+
+// Builds a KeyIndex as the data is compressed. Records are whatever is
+// between delimiters and the key of each is whatever the callback says, if
+// anything. Add it to a compression stream with Compress::with_index to store
+// the index in the compressed data, or feed it the data and take the index
+// with into_index to store it elsewhere.
+pub struct KeyIndexBuilder {
+    delimiter: u8,
+    key: Box<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>,
+    index: KeyIndex,
+    // The record we're in the middle of and where it starts.
+    record: Vec<u8>,
+    record_start: u64,
+}
+
+impl std::fmt::Debug for KeyIndexBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyIndexBuilder")
+            .field("delimiter", &self.delimiter)
+            .field("index", &self.index)
+            .field("record_start", &self.record_start)
+            .finish()
+    }
+}
+
+impl KeyIndexBuilder {
+    // The callback gets each record without its delimiter. Only the first
+    // record with any given key is indexed.
+    pub fn new<F>(delimiter: u8, key: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        KeyIndexBuilder {
+            delimiter,
+            key: Box::new(key),
+            index: KeyIndex::default(),
+            record: Vec::new(),
+            record_start: 0,
+        }
+    }
+
+    // The index of everything seen so far, with the data taken to end there.
+    pub fn into_index(mut self) -> KeyIndex {
+        self.end_record();
+        self.index
+    }
+
+    fn end_record(&mut self) {
+        if let Some(key) = (self.key)(&self.record) {
+            self.index.offsets.entry(key).or_insert(self.record_start);
+        }
+        self.record_start += self.record.len() as u64;
+        self.record.clear();
+    }
+}
+
+impl IndexBuilder for KeyIndexBuilder {
+    fn update(&mut self, mut data: &[u8]) {
+        while let Some(at) = data.iter().position(|&b| b == self.delimiter) {
+            self.record.extend_from_slice(&data[..at]);
+            self.end_record();
+            // The delimiter belongs to the record before it.
+            self.record_start += 1;
+            data = &data[at + 1..];
+        }
+        self.record.extend_from_slice(data);
+    }
+
+    fn finish(&mut self) -> IndexFrame {
+        if !self.record.is_empty() {
+            self.end_record();
+        }
+        IndexFrame {
+            tag: KEY_INDEX_TAG,
+            payload: self.index.to_bytes(),
+        }
+    }
+}
+
+// Compressed records along with their key index: records can be looked up by
+// key without reading anything else.
+pub struct KeyedReader<'a, A> {
+    data: SeekableDecompress<'a, A>,
+    index: KeyIndex,
+}
+
+impl<A> std::fmt::Debug for KeyedReader<'_, A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyedReader")
+            .field("keys", &self.index.len())
+            .finish()
+    }
+}
+
+impl<'a, A> KeyedReader<'a, A>
+where
+    A: Read + Seek,
+{
+    // Reads the key index out of the compressed data. Fails if it was
+    // compressed without one.
+    pub fn new(mut compressed: A) -> std::io::Result<Self> {
+        let index = KeyIndex::read(&mut compressed)?.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Data has no key index.")
+        })?;
+        Self::with_index(compressed, index)
+    }
+
+    // Uses an index stored somewhere other than the compressed data.
+    pub fn with_index(compressed: A, index: KeyIndex) -> std::io::Result<Self> {
+        let data = SeekableDecompress::new(compressed)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(KeyedReader { data, index })
+    }
+
+    pub fn index(&self) -> &KeyIndex {
+        &self.index
+    }
+
+    // Reader positioned at the start of the first record with the given key,
+    // if there is one. It reads on to the end of the data, past the record.
+    pub fn lookup(
+        &mut self,
+        key: &[u8],
+    ) -> std::io::Result<Option<&mut SeekableDecompress<'a, A>>> {
+        match self.index.lookup(key) {
+            None => Ok(None),
+            Some(offset) => {
+                self.data.seek(SeekFrom::Start(offset))?;
+                Ok(Some(&mut self.data))
+            }
+        }
+    }
+}
@@ -1,9 +1,209 @@
+#[macro_use]
+mod trace;
+
+#[cfg(feature = "arrow")]
+mod arrow_ipc;
+#[cfg(feature = "s3")]
+mod block_cache;
+#[cfg(feature = "s3")]
+mod blocking;
+#[cfg(all(feature = "s3", feature = "structopt"))]
+mod cli_args;
+#[cfg(feature = "s3")]
+mod cloudfront;
 mod compress;
+#[cfg(feature = "s3")]
+mod concat;
+#[cfg(any(feature = "s3", all(feature = "wasm", target_arch = "wasm32")))]
+mod content_range;
+#[cfg(feature = "s3")]
+mod credentials;
+#[cfg(feature = "dataset")]
+mod dataset;
 mod decompress;
+#[cfg(feature = "dedup")]
+mod dedup;
+#[cfg(feature = "s3")]
+mod dictionary;
+#[cfg(feature = "s3")]
+mod directory_sync;
+#[cfg(feature = "s3")]
+mod disk_cache;
+#[cfg(feature = "s3")]
+mod download;
+#[cfg(feature = "s3")]
+mod dyn_s3;
+#[cfg(feature = "encryption")]
+mod encryption;
+#[cfg(feature = "s3")]
+mod export;
+#[cfg(feature = "s3")]
+mod fetch_ranges;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod frame_index;
+#[cfg(feature = "fuse")]
+mod fuse;
+#[cfg(feature = "gateway")]
+mod gateway;
+#[cfg(any(feature = "python", feature = "ffi"))]
+mod global_runtime;
+#[cfg(any(feature = "gateway", feature = "test-util"))]
+mod http_request;
+mod index_frame;
+#[cfg(feature = "s3")]
+mod inventory;
+mod key_index;
+#[cfg(feature = "s3")]
+mod log;
+#[cfg(feature = "test-util")]
+mod mock_s3;
+#[cfg(feature = "s3")]
+mod multipart;
+#[cfg(feature = "s3")]
+mod object_index;
+#[cfg(feature = "s3")]
+mod plan;
+#[cfg(feature = "s3")]
+mod progress;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "s3")]
+mod read_session;
+mod record_index;
+#[cfg(feature = "s3")]
+mod repair;
+#[cfg(feature = "s3")]
+mod restore;
+#[cfg(feature = "s3")]
+mod resume;
+#[cfg(feature = "s3")]
+mod retry;
+#[cfg(feature = "s3")]
+mod scan;
+mod seek;
+#[cfg(feature = "s3")]
 mod seekable_s3;
+mod tar_index;
+#[cfg(feature = "s3")]
+mod timeout;
+#[cfg(feature = "gzip")]
+mod transcode;
+#[cfg(feature = "s3")]
+mod upload_config;
+#[cfg(feature = "s3")]
+mod upload_object;
+#[cfg(feature = "s3")]
 mod upload_s3;
+#[cfg(feature = "s3")]
+mod upload_sink;
+#[cfg(feature = "s3")]
+mod verify;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+mod wasm;
+
+// The rusoto crates the API is in terms of, and the types from them it takes
+// and gives most, so that users get the very same versions rather than
+// having to depend on them and pin them to match.
+#[cfg(feature = "s3")]
+pub use rusoto_core;
+#[cfg(feature = "s3")]
+pub use rusoto_core::{Region, RusotoError};
+#[cfg(feature = "s3")]
+pub use rusoto_s3;
+#[cfg(feature = "s3")]
+pub use rusoto_s3::{
+    AbortMultipartUploadError, CompleteMultipartUploadError, CompleteMultipartUploadOutput,
+    CompletedPart, CreateMultipartUploadError, CreateMultipartUploadRequest, GetObjectError,
+    GetObjectRequest, HeadObjectError, HeadObjectRequest, ListObjectsV2Error, PutObjectError,
+    PutObjectRequest, RestoreObjectError, S3Client, UploadPartCopyError, UploadPartError,
+    UploadPartRequest, S3,
+};
 
+#[cfg(feature = "arrow")]
+pub use arrow_ipc::*;
+#[cfg(feature = "s3")]
+pub use blocking::*;
+#[cfg(all(feature = "s3", feature = "structopt"))]
+pub use cli_args::*;
+#[cfg(feature = "s3")]
+pub use cloudfront::*;
 pub use compress::*;
+#[cfg(feature = "s3")]
+pub use concat::*;
+#[cfg(feature = "s3")]
+pub use credentials::*;
+#[cfg(feature = "dataset")]
+pub use dataset::*;
 pub use decompress::*;
+#[cfg(feature = "dedup")]
+pub use dedup::*;
+#[cfg(feature = "s3")]
+pub use dictionary::*;
+#[cfg(feature = "s3")]
+pub use directory_sync::*;
+#[cfg(feature = "s3")]
+pub use disk_cache::*;
+#[cfg(feature = "s3")]
+pub use download::*;
+#[cfg(feature = "s3")]
+pub use dyn_s3::*;
+#[cfg(feature = "encryption")]
+pub use encryption::*;
+#[cfg(feature = "s3")]
+pub use export::*;
+#[cfg(feature = "s3")]
+pub use fetch_ranges::*;
+pub use frame_index::*;
+#[cfg(feature = "fuse")]
+pub use fuse::*;
+#[cfg(feature = "gateway")]
+pub use gateway::*;
+pub use index_frame::*;
+#[cfg(feature = "s3")]
+pub use inventory::*;
+pub use key_index::*;
+#[cfg(feature = "s3")]
+pub use log::*;
+#[cfg(feature = "test-util")]
+pub use mock_s3::*;
+#[cfg(feature = "s3")]
+pub use multipart::*;
+#[cfg(feature = "s3")]
+pub use object_index::*;
+#[cfg(feature = "s3")]
+pub use plan::*;
+#[cfg(feature = "s3")]
+pub use progress::*;
+#[cfg(feature = "s3")]
+pub use read_session::*;
+pub use record_index::*;
+#[cfg(feature = "s3")]
+pub use repair::*;
+#[cfg(feature = "s3")]
+pub use restore::*;
+#[cfg(feature = "s3")]
+pub use resume::*;
+#[cfg(feature = "s3")]
+pub use retry::*;
+#[cfg(feature = "s3")]
+pub use scan::*;
+#[cfg(feature = "s3")]
 pub use seekable_s3::*;
+pub use tar_index::*;
+#[cfg(feature = "s3")]
+pub use timeout::*;
+#[cfg(feature = "gzip")]
+pub use transcode::*;
+#[cfg(feature = "s3")]
+pub use upload_config::*;
+#[cfg(feature = "s3")]
+pub use upload_object::*;
+#[cfg(feature = "s3")]
 pub use upload_s3::*;
+#[cfg(feature = "s3")]
+pub use upload_sink::*;
+#[cfg(feature = "s3")]
+pub use verify::*;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub use wasm::*;
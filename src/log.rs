@@ -0,0 +1,403 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::SinkExt;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectRequest, ListObjectsV2Error, ListObjectsV2Request, S3};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use zstd_seekable::DStream;
+
+use crate::decompress::decompress_frame;
+use crate::object_index::get_object_range;
+use crate::{fetch_object_index, ObjectIndex, ReadObjectError};
+use crate::{UploadError, UploadOptions, UploadSink};
+
+// How much compressed data a LogReader fetches at once.
+const LOG_READ_SIZE: u64 = 8 * 1024 * 1024;
+
+// Each record is its length (4, little-endian) followed by its data.
+const RECORD_HEADER_SIZE: u64 = 4;
+
+const SEGMENT_SUFFIX: &str = ".zst";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogOptions {
+    // Uncompressed size at which to start a new segment.
+    pub segment_size: u64,
+    pub upload: UploadOptions,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            segment_size: 256 * 1024 * 1024,
+            upload: UploadOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LogError {
+    ListObjects(RusotoError<ListObjectsV2Error>),
+    ReadSegment(ReadObjectError),
+    Upload(UploadError<std::io::Error>),
+    // Records are at most u32::MAX bytes.
+    RecordTooLarge(usize),
+    // The data at the offset isn't a whole record: the offset isn't one a
+    // record starts at, or a segment is damaged.
+    Corrupt { offset: u64 },
+}
+
+impl Display for LogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogError::ListObjects(e) => write!(f, "Failed to list segments: {}", e),
+            LogError::ReadSegment(e) => write!(f, "Failed to read segment: {}", e),
+            LogError::Upload(e) => write!(f, "Failed to upload segment: {}", e),
+            LogError::RecordTooLarge(size) => write!(f, "Record of {} bytes is too large", size),
+            LogError::Corrupt { offset } => write!(f, "No record at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for LogError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LogError::ListObjects(e) => Some(e),
+            LogError::ReadSegment(e) => Some(e),
+            LogError::Upload(e) => Some(e),
+            LogError::RecordTooLarge(_) | LogError::Corrupt { .. } => None,
+        }
+    }
+}
+
+fn segment_key(prefix: &str, start: u64) -> String {
+    format!("{}{:020}{}", prefix, start, SEGMENT_SUFFIX)
+}
+
+// Segments under the prefix as where they start in the log and their keys,
+// in order.
+async fn list_segments<C: S3>(
+    client: &C,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Vec<(u64, String)>, LogError> {
+    let mut segments = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let req = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(prefix.to_owned()),
+            continuation_token,
+            ..Default::default()
+        };
+        let list_objects = traced!(
+            client.list_objects_v2(req),
+            "list_objects_v2",
+            bucket = %bucket,
+            prefix = %prefix,
+        );
+        let output = list_objects.await.map_err(LogError::ListObjects)?;
+        for key in output.contents.into_iter().flatten().filter_map(|o| o.key) {
+            let start = key
+                .strip_prefix(prefix)
+                .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+                .filter(|name| !name.contains('/'))
+                .and_then(|start| start.parse().ok());
+            if let Some(start) = start {
+                segments.push((start, key));
+            }
+        }
+        match output.next_continuation_token {
+            Some(token) if output.is_truncated == Some(true) => continuation_token = Some(token),
+            _ => break,
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn segment_request(bucket: &str, key: &str) -> GetObjectRequest {
+    GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    }
+}
+
+// Append-only log of records kept as seekable objects under a prefix, called
+// segments. Records are batched into frames as they're appended and a new
+// segment is started once the current one grows past the segment size, so a
+// record never straddles two segments. Each segment is named after the offset
+// in the log it starts at, offsets being positions in the uncompressed log.
+//
+// Records only become durable and readable once their segment is uploaded:
+// when it fills up, on rotate or on close. A log has one writer at a time.
+//
+// The log has to be used from within a tokio runtime, see UploadSink.
+#[derive(Debug)]
+pub struct Log<C> {
+    client: C,
+    bucket: String,
+    prefix: String,
+    options: LogOptions,
+    // Offset of the next record.
+    end: u64,
+    // The segment being written and where it starts.
+    current: Option<(UploadSink, u64)>,
+}
+
+impl<C> Log<C>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    // Opens the log under the prefix to append to it, picking up after the
+    // last segment there is, if any.
+    pub async fn open(
+        client: C,
+        bucket: String,
+        prefix: String,
+        options: LogOptions,
+    ) -> Result<Self, LogError> {
+        let end = match list_segments(&client, &bucket, &prefix).await?.pop() {
+            None => 0,
+            Some((start, key)) => {
+                let index = fetch_object_index(&client, &segment_request(&bucket, &key))
+                    .await
+                    .map_err(LogError::ReadSegment)?;
+                start + index.index.decompressed_size()
+            }
+        };
+        Ok(Log {
+            client,
+            bucket,
+            prefix,
+            options,
+            end,
+            current: None,
+        })
+    }
+
+    // Offset the next record is going to have.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    // Appends a record, returning its offset.
+    pub async fn append(&mut self, record: &[u8]) -> Result<u64, LogError> {
+        let len =
+            u32::try_from(record.len()).map_err(|_e| LogError::RecordTooLarge(record.len()))?;
+        let mut data = BytesMut::with_capacity(RECORD_HEADER_SIZE as usize + record.len());
+        data.put_u32_le(len);
+        data.extend_from_slice(record);
+        let (mut sink, start) = match self.current.take() {
+            Some(current) => current,
+            None => {
+                let key = segment_key(&self.prefix, self.end);
+                let sink = UploadSink::new(
+                    self.client.clone(),
+                    self.bucket.to_owned(),
+                    key,
+                    self.options.upload.to_owned(),
+                )
+                .map_err(LogError::Upload)?;
+                (sink, self.end)
+            }
+        };
+        let offset = self.end;
+        let sent = sink.send(data.freeze()).await;
+        self.current = Some((sink, start));
+        sent.map_err(LogError::Upload)?;
+        self.end += RECORD_HEADER_SIZE + record.len() as u64;
+        if self.end - start >= self.options.segment_size {
+            self.rotate().await?;
+        }
+        Ok(offset)
+    }
+
+    // Finishes the current segment, making every record appended so far
+    // readable. The next record starts a new one.
+    pub async fn rotate(&mut self) -> Result<(), LogError> {
+        if let Some((mut sink, _)) = self.current.take() {
+            sink.close().await.map_err(LogError::Upload)?;
+        }
+        Ok(())
+    }
+
+    // Finishes the current segment, returning the end of the log.
+    pub async fn close(mut self) -> Result<u64, LogError> {
+        self.rotate().await?;
+        Ok(self.end)
+    }
+
+    // Reads the log from the given offset, see LogReader.
+    pub fn read_from(&self, offset: u64) -> LogReader<C> {
+        LogReader::new(
+            self.client.clone(),
+            self.bucket.to_owned(),
+            self.prefix.to_owned(),
+            offset,
+        )
+    }
+}
+
+// Reads the records of a log in order, starting from an offset a record
+// starts at: 0, one that Log::append returned or one that came with a record
+// read before. Only the frames from the one holding the offset onwards are
+// fetched. Segments uploaded after the reader got to the end are picked up
+// by reading on.
+#[derive(Debug)]
+pub struct LogReader<C> {
+    client: C,
+    bucket: String,
+    prefix: String,
+    // Offset of the next record.
+    position: u64,
+    segments: Vec<(u64, String)>,
+    // The segment we're reading, its seek table and the next frame of it to
+    // fetch.
+    segment: Option<(u64, ObjectIndex, usize)>,
+    // Decompressed data from buffer_start on.
+    buffer: BytesMut,
+    buffer_start: u64,
+}
+
+impl<C: S3> LogReader<C> {
+    pub fn new(client: C, bucket: String, prefix: String, offset: u64) -> Self {
+        LogReader {
+            client,
+            bucket,
+            prefix,
+            position: offset,
+            segments: Vec::new(),
+            segment: None,
+            buffer: BytesMut::new(),
+            buffer_start: offset,
+        }
+    }
+
+    // Offset of the next record.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // Next record along with its offset, or None when there's nothing more in
+    // the log yet.
+    pub async fn next(&mut self) -> Result<Option<(u64, Bytes)>, LogError> {
+        loop {
+            if let Some(record) = self.take_record() {
+                return Ok(Some(record));
+            }
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // The record at the position, if the buffer holds all of it.
+    fn take_record(&mut self) -> Option<(u64, Bytes)> {
+        // Starting in the middle of a frame leaves the part of it before the
+        // position in the buffer.
+        let skip = std::cmp::min(self.position - self.buffer_start, self.buffer.len() as u64);
+        self.buffer.advance(skip as usize);
+        self.buffer_start += skip;
+        if self.buffer_start < self.position || self.buffer.len() < RECORD_HEADER_SIZE as usize {
+            return None;
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&self.buffer[..4]);
+        let len = u32::from_le_bytes(len) as usize;
+        if self.buffer.len() < RECORD_HEADER_SIZE as usize + len {
+            return None;
+        }
+        let mut record = self.buffer.split_to(RECORD_HEADER_SIZE as usize + len);
+        let offset = self.position;
+        self.position += record.len() as u64;
+        self.buffer_start = self.position;
+        Some((offset, record.split_off(4).freeze()))
+    }
+
+    // Decompresses more of the log into the buffer, moving on to the next
+    // segment when the one we're in is done. False once there's nothing more.
+    async fn fill(&mut self) -> Result<bool, LogError> {
+        loop {
+            let position = self.position;
+            if let Some((start, index, next_frame)) = &mut self.segment {
+                let frames = &index.index.frames()[*next_frame..];
+                if !frames.is_empty() {
+                    let mut end = 0;
+                    let mut size = 0;
+                    while end < frames.len() && (end == 0 || size < LOG_READ_SIZE) {
+                        size += frames[end].compressed_size;
+                        end += 1;
+                    }
+                    let batch = &frames[..end];
+                    let from = batch[0].compressed_offset;
+                    let to = from + size;
+                    let key = segment_key(&self.prefix, *start);
+                    let req = segment_request(&self.bucket, &key);
+                    let (data, _) =
+                        get_object_range(&self.client, &req, format!("bytes={}-{}", from, to - 1))
+                            .await
+                            .map_err(LogError::ReadSegment)?;
+                    let mut dstream =
+                        DStream::new().map_err(|_e| LogError::Corrupt { offset: position })?;
+                    for frame in batch.iter().filter(|frame| frame.decompressed_size > 0) {
+                        let at = (frame.compressed_offset - from) as usize;
+                        let decompressed = data
+                            .get(at..at + frame.compressed_size as usize)
+                            .and_then(|frame| decompress_frame(&mut dstream, frame))
+                            .ok_or(LogError::Corrupt { offset: position })?;
+                        self.buffer.extend_from_slice(&decompressed);
+                    }
+                    *next_frame += end;
+                    return Ok(true);
+                }
+            }
+            if !self.next_segment().await? {
+                return Ok(false);
+            }
+        }
+    }
+
+    // Moves on to the segment holding the buffer's end, listing the segments
+    // again if we don't know of one. A record left unfinished at the end of a
+    // segment means it's damaged: records never straddle segments.
+    async fn next_segment(&mut self) -> Result<bool, LogError> {
+        if !self.buffer.is_empty() {
+            return Err(LogError::Corrupt {
+                offset: self.position,
+            });
+        }
+        let offset = self.buffer_start;
+        for &refreshed in &[false, true] {
+            if refreshed {
+                self.segments = list_segments(&self.client, &self.bucket, &self.prefix).await?;
+            }
+            let at = self.segments.partition_point(|(start, _)| *start <= offset);
+            let (start, key) = match at.checked_sub(1).map(|at| &self.segments[at]) {
+                Some(segment) => segment.to_owned(),
+                None => continue,
+            };
+            // Don't go through the segment we just finished again.
+            if self.segment.as_ref().map(|(current, _, _)| *current) == Some(start) {
+                continue;
+            }
+            let index = fetch_object_index(&self.client, &segment_request(&self.bucket, &key))
+                .await
+                .map_err(LogError::ReadSegment)?;
+            let segment_end = start + index.index.decompressed_size();
+            if offset >= segment_end {
+                continue;
+            }
+            let frame = index
+                .index
+                .frame_index_at(offset - start)
+                .ok_or(LogError::Corrupt { offset })?;
+            self.buffer_start = start + index.index.frames()[frame].decompressed_offset;
+            self.segment = Some((start, index, frame));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
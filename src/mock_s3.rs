@@ -0,0 +1,488 @@
+use bytes::{Bytes, BytesMut};
+use http::{HeaderMap, StatusCode};
+use parking_lot::Mutex;
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::request::{DispatchSignedRequestFuture, HttpDispatchError, HttpResponse};
+use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+use rusoto_core::{DispatchSignedRequest, Region};
+use rusoto_s3::S3Client;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::http_request::{percent_decode, requested_range, RequestedRange};
+use crate::object_index::read_body;
+use crate::{multipart_etag, MINIMUM_PART_SIZE};
+
+const METADATA_PREFIX: &str = "x-amz-meta-";
+
+// An object as the mock stores it.
+#[derive(Debug, Clone)]
+struct MockObject {
+    data: Bytes,
+    e_tag: String,
+    metadata: HashMap<String, String>,
+    // Sizes of the parts the object was uploaded in, if it was.
+    part_sizes: Vec<u64>,
+}
+
+#[derive(Debug)]
+struct MockUpload {
+    bucket: String,
+    key: String,
+    metadata: HashMap<String, String>,
+    parts: BTreeMap<i64, (Bytes, [u8; 16])>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    objects: HashMap<(String, String), MockObject>,
+    uploads: HashMap<String, MockUpload>,
+    next_upload_id: u64,
+    requests: u64,
+}
+
+// In-memory stand-in for S3, for testing code that reads and writes seekable
+// objects without a network. It answers the HTTP requests of a real
+// S3Client (see MockS3::client), so anything taking an S3 works with it:
+// GetObject and HeadObject with ranges and part numbers, PutObject,
+// DeleteObject and multipart uploads, UploadPartCopy included. Anything else
+// gets a NotImplemented error. Buckets spring into being as they're used.
+//
+// Multipart uploads check part sizes as S3 does: every part but the last has
+// to be at least MINIMUM_PART_SIZE, unless told otherwise with
+// with_minimum_part_size. Clones share the same objects.
+#[derive(Debug, Clone)]
+pub struct MockS3 {
+    state: Arc<Mutex<MockState>>,
+    minimum_part_size: u64,
+}
+
+impl Default for MockS3 {
+    fn default() -> Self {
+        MockS3 {
+            state: Arc::new(parking_lot::const_mutex(MockState::default())),
+            minimum_part_size: MINIMUM_PART_SIZE as u64,
+        }
+    }
+}
+
+impl MockS3 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_minimum_part_size(mut self, minimum_part_size: u64) -> Self {
+        self.minimum_part_size = minimum_part_size;
+        self
+    }
+
+    // A client whose requests go to the mock.
+    pub fn client(&self) -> S3Client {
+        let credentials = StaticProvider::new_minimal("mock".to_owned(), "mock".to_owned());
+        S3Client::new_with(self.clone(), credentials, Region::UsEast1)
+    }
+
+    pub fn put_object(&self, bucket: &str, key: &str, data: impl Into<Bytes>) {
+        let data = data.into();
+        let object = MockObject {
+            e_tag: format!("\"{:x}\"", md5::compute(&data)),
+            data,
+            metadata: HashMap::new(),
+            part_sizes: Vec::new(),
+        };
+        self.state
+            .lock()
+            .objects
+            .insert((bucket.to_owned(), key.to_owned()), object);
+    }
+
+    pub fn get_object(&self, bucket: &str, key: &str) -> Option<Bytes> {
+        self.state
+            .lock()
+            .objects
+            .get(&(bucket.to_owned(), key.to_owned()))
+            .map(|object| object.data.to_owned())
+    }
+
+    pub fn object_metadata(&self, bucket: &str, key: &str) -> Option<HashMap<String, String>> {
+        self.state
+            .lock()
+            .objects
+            .get(&(bucket.to_owned(), key.to_owned()))
+            .map(|object| object.metadata.to_owned())
+    }
+
+    // Keys in the bucket, in order.
+    pub fn keys(&self, bucket: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .state
+            .lock()
+            .objects
+            .keys()
+            .filter(|(object_bucket, _)| object_bucket == bucket)
+            .map(|(_, key)| key.to_owned())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // Multipart uploads that were neither completed nor aborted.
+    pub fn pending_uploads(&self) -> usize {
+        self.state.lock().uploads.len()
+    }
+
+    // How many requests the mock answered so far.
+    pub fn requests(&self) -> u64 {
+        self.state.lock().requests
+    }
+
+    fn handle(&self, request: &SignedRequest, body: Bytes) -> HttpResponse {
+        let path = request.path.strip_prefix('/').unwrap_or(&request.path);
+        let (bucket, key) = match path.split_once('/') {
+            Some((bucket, key)) if !key.is_empty() => (bucket, key),
+            _ => return not_implemented(),
+        };
+        let header = |name: &str| {
+            request
+                .headers
+                .get(name)
+                .and_then(|values| values.first())
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+        };
+        let param = |name: &str| request.params.get(name).cloned().flatten();
+        let has_param = |name: &str| request.params.contains_key(name);
+        let mut state = self.state.lock();
+        state.requests += 1;
+        match request.method.as_str() {
+            "GET" | "HEAD" => {
+                let object = match state.objects.get(&(bucket.to_owned(), key.to_owned())) {
+                    Some(object) => object,
+                    None => return error(StatusCode::NOT_FOUND, "NoSuchKey"),
+                };
+                if let Some(if_match) = header("if-match") {
+                    if if_match != object.e_tag {
+                        return error(StatusCode::PRECONDITION_FAILED, "PreconditionFailed");
+                    }
+                }
+                let size = object.data.len() as u64;
+                let mut headers = object_headers(object);
+                let part_number = param("partNumber").and_then(|n| n.parse::<usize>().ok());
+                let requested = match (part_number, header("range")) {
+                    (Some(part_number), _) => {
+                        let part_sizes = if object.part_sizes.is_empty() {
+                            vec![size]
+                        } else {
+                            object.part_sizes.to_owned()
+                        };
+                        if part_number == 0 || part_number > part_sizes.len() {
+                            return error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidPartNumber");
+                        }
+                        headers.insert("x-amz-mp-parts-count", part_sizes.len().to_string());
+                        let start: u64 = part_sizes[..part_number - 1].iter().sum();
+                        RequestedRange::Part(start..start + part_sizes[part_number - 1])
+                    }
+                    (None, Some(range)) => requested_range(&range, size),
+                    (None, None) => RequestedRange::Whole,
+                };
+                let (status, range) = match requested {
+                    RequestedRange::Whole => (StatusCode::OK, 0..size),
+                    RequestedRange::Part(range) => (StatusCode::PARTIAL_CONTENT, range),
+                    RequestedRange::Unsatisfiable => {
+                        return error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange")
+                    }
+                };
+                if status == StatusCode::PARTIAL_CONTENT {
+                    headers.insert(
+                        "content-range",
+                        format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                    );
+                }
+                headers.insert("content-length", (range.end - range.start).to_string());
+                let data = if request.method == "HEAD" {
+                    Bytes::new()
+                } else {
+                    object.data.slice(range.start as usize..range.end as usize)
+                };
+                response(status, headers, data)
+            }
+            "PUT" if has_param("uploadId") => {
+                let upload_id = param("uploadId").unwrap_or_default();
+                let part_number = match param("partNumber").and_then(|n| n.parse::<i64>().ok()) {
+                    Some(part_number) if (1..=10_000).contains(&part_number) => part_number,
+                    _ => return error(StatusCode::BAD_REQUEST, "InvalidArgument"),
+                };
+                let copy_source = header("x-amz-copy-source");
+                let data = match &copy_source {
+                    None => {
+                        if let Some(content_md5) = header("content-md5") {
+                            if content_md5 != base64::encode(md5::compute(&body).0) {
+                                return error(StatusCode::BAD_REQUEST, "BadDigest");
+                            }
+                        }
+                        body
+                    }
+                    Some(copy_source) => {
+                        match copied_data(&state, copy_source, header("x-amz-copy-source-range")) {
+                            Ok(data) => data,
+                            Err(response) => return response,
+                        }
+                    }
+                };
+                let upload = match state.uploads.get_mut(&upload_id) {
+                    Some(upload) => upload,
+                    None => return error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+                };
+                let md5 = md5::compute(&data).0;
+                let e_tag = format!("\"{:x}\"", md5::Digest(md5));
+                upload.parts.insert(part_number, (data, md5));
+                if copy_source.is_some() {
+                    let result = format!(
+                        "<CopyPartResult><ETag>{}</ETag></CopyPartResult>",
+                        xml_escape(&e_tag)
+                    );
+                    response(StatusCode::OK, HeaderMap::new(), result.into())
+                } else {
+                    let mut headers = HeaderMap::new();
+                    headers.insert("etag", e_tag);
+                    response(StatusCode::OK, headers, Bytes::new())
+                }
+            }
+            "PUT" => {
+                let data = match header("x-amz-copy-source") {
+                    Some(copy_source) => match copied_data(&state, &copy_source, None) {
+                        Ok(data) => data,
+                        Err(response) => return response,
+                    },
+                    None => body,
+                };
+                if let Some(content_md5) = header("content-md5") {
+                    if content_md5 != base64::encode(md5::compute(&data).0) {
+                        return error(StatusCode::BAD_REQUEST, "BadDigest");
+                    }
+                }
+                let object = MockObject {
+                    e_tag: format!("\"{:x}\"", md5::compute(&data)),
+                    data,
+                    metadata: user_metadata(request),
+                    part_sizes: Vec::new(),
+                };
+                let mut headers = HeaderMap::new();
+                headers.insert("etag", object.e_tag.to_owned());
+                state
+                    .objects
+                    .insert((bucket.to_owned(), key.to_owned()), object);
+                response(StatusCode::OK, headers, Bytes::new())
+            }
+            "POST" if has_param("uploads") => {
+                state.next_upload_id += 1;
+                let upload_id = format!("mock-upload-{}", state.next_upload_id);
+                let upload = MockUpload {
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    metadata: user_metadata(request),
+                    parts: BTreeMap::new(),
+                };
+                state.uploads.insert(upload_id.to_owned(), upload);
+                let result = format!(
+                    "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key>\
+                     <UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    xml_escape(bucket),
+                    xml_escape(key),
+                    upload_id
+                );
+                response(StatusCode::OK, HeaderMap::new(), result.into())
+            }
+            "POST" if has_param("uploadId") => {
+                let upload_id = param("uploadId").unwrap_or_default();
+                match state.uploads.get(&upload_id) {
+                    Some(upload) if upload.bucket == bucket && upload.key == key => {}
+                    _ => return error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+                }
+                let listed = completed_parts(&body);
+                let object = {
+                    let upload = &state.uploads[&upload_id];
+                    let mut data = BytesMut::new();
+                    let mut md5s = Vec::with_capacity(listed.len());
+                    let mut part_sizes = Vec::with_capacity(listed.len());
+                    let mut previous = 0;
+                    for (i, (part_number, e_tag)) in listed.iter().enumerate() {
+                        if *part_number <= previous {
+                            return error(StatusCode::BAD_REQUEST, "InvalidPartOrder");
+                        }
+                        previous = *part_number;
+                        let (part, md5) = match upload.parts.get(part_number) {
+                            Some(part) => part,
+                            None => return error(StatusCode::BAD_REQUEST, "InvalidPart"),
+                        };
+                        if e_tag.trim_matches('"') != format!("{:x}", md5::Digest(*md5)) {
+                            return error(StatusCode::BAD_REQUEST, "InvalidPart");
+                        }
+                        if i + 1 < listed.len() && (part.len() as u64) < self.minimum_part_size {
+                            return error(StatusCode::BAD_REQUEST, "EntityTooSmall");
+                        }
+                        data.extend_from_slice(part);
+                        md5s.push(*md5);
+                        part_sizes.push(part.len() as u64);
+                    }
+                    if listed.is_empty() {
+                        return error(StatusCode::BAD_REQUEST, "MalformedXML");
+                    }
+                    MockObject {
+                        data: data.freeze(),
+                        e_tag: format!("\"{}\"", multipart_etag(&md5s)),
+                        metadata: upload.metadata.to_owned(),
+                        part_sizes,
+                    }
+                };
+                state.uploads.remove(&upload_id);
+                let result = format!(
+                    "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key>\
+                     <ETag>{}</ETag></CompleteMultipartUploadResult>",
+                    xml_escape(bucket),
+                    xml_escape(key),
+                    xml_escape(&object.e_tag)
+                );
+                state
+                    .objects
+                    .insert((bucket.to_owned(), key.to_owned()), object);
+                response(StatusCode::OK, HeaderMap::new(), result.into())
+            }
+            "DELETE" if has_param("uploadId") => {
+                let upload_id = param("uploadId").unwrap_or_default();
+                match state.uploads.remove(&upload_id) {
+                    Some(_) => response(StatusCode::NO_CONTENT, HeaderMap::new(), Bytes::new()),
+                    None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+                }
+            }
+            "DELETE" => {
+                state.objects.remove(&(bucket.to_owned(), key.to_owned()));
+                response(StatusCode::NO_CONTENT, HeaderMap::new(), Bytes::new())
+            }
+            _ => not_implemented(),
+        }
+    }
+}
+
+impl DispatchSignedRequest for MockS3 {
+    fn dispatch(
+        &self,
+        mut request: SignedRequest,
+        _timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let mock = self.clone();
+        Box::pin(async move {
+            let body = match request.payload.take() {
+                None => Bytes::new(),
+                Some(SignedRequestPayload::Buffer(data)) => data,
+                Some(SignedRequestPayload::Stream(stream)) => read_body(stream)
+                    .await
+                    .map_err(|e| HttpDispatchError::new(e.to_string()))?,
+            };
+            Ok(mock.handle(&request, body))
+        })
+    }
+}
+
+fn response(status: StatusCode, headers: HeaderMap<String>, body: Bytes) -> HttpResponse {
+    HttpResponse {
+        status,
+        body: body.to_vec().into(),
+        headers,
+    }
+}
+
+fn error(status: StatusCode, code: &str) -> HttpResponse {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <Error><Code>{}</Code><Message>{}</Message></Error>",
+        code, code
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/xml".to_owned());
+    response(status, headers, body.into())
+}
+
+fn not_implemented() -> HttpResponse {
+    error(StatusCode::NOT_IMPLEMENTED, "NotImplemented")
+}
+
+fn object_headers(object: &MockObject) -> HeaderMap<String> {
+    let mut headers = HeaderMap::new();
+    headers.insert("etag", object.e_tag.to_owned());
+    headers.insert("accept-ranges", "bytes".to_owned());
+    for (name, value) in &object.metadata {
+        let name = format!("{}{}", METADATA_PREFIX, name);
+        if let Ok(name) = http::header::HeaderName::from_bytes(name.as_bytes()) {
+            headers.insert(name, value.to_owned());
+        }
+    }
+    headers
+}
+
+fn user_metadata(request: &SignedRequest) -> HashMap<String, String> {
+    request
+        .headers
+        .iter()
+        .filter_map(|(name, values)| {
+            let name = name.strip_prefix(METADATA_PREFIX)?;
+            let value = values.first()?;
+            Some((name.to_owned(), String::from_utf8_lossy(value).into_owned()))
+        })
+        .collect()
+}
+
+// The data an x-amz-copy-source such as "bucket/key?versionId=1" and an
+// optional x-amz-copy-source-range refer to.
+fn copied_data(
+    state: &MockState,
+    copy_source: &str,
+    range: Option<String>,
+) -> Result<Bytes, HttpResponse> {
+    let source = copy_source.split('?').next().unwrap_or(copy_source);
+    let source = percent_decode(source.trim_start_matches('/'))
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "InvalidArgument"))?;
+    let (bucket, key) = source
+        .split_once('/')
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "InvalidArgument"))?;
+    let object = state
+        .objects
+        .get(&(bucket.to_owned(), key.to_owned()))
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "NoSuchKey"))?;
+    let size = object.data.len() as u64;
+    match range.map(|range| requested_range(&range, size)) {
+        None | Some(RequestedRange::Whole) => Ok(object.data.to_owned()),
+        Some(RequestedRange::Part(range)) => {
+            Ok(object.data.slice(range.start as usize..range.end as usize))
+        }
+        Some(RequestedRange::Unsatisfiable) => {
+            Err(error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange"))
+        }
+    }
+}
+
+// Part numbers and ETags listed in a CompleteMultipartUpload request.
+fn completed_parts(body: &[u8]) -> Vec<(i64, String)> {
+    let body = String::from_utf8_lossy(body);
+    body.split("<Part>")
+        .skip(1)
+        .filter_map(|part| {
+            let part_number = xml_element(part, "PartNumber")?.trim().parse().ok()?;
+            let e_tag = xml_element(part, "ETag")?.replace("&quot;", "\"");
+            Some((part_number, e_tag))
+        })
+        .collect()
+}
+
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
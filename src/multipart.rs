@@ -0,0 +1,209 @@
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    AbortMultipartUploadError, AbortMultipartUploadRequest, CompleteMultipartUploadError,
+    CompleteMultipartUploadOutput, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadError, CreateMultipartUploadRequest, UploadPartRequest, S3,
+};
+
+// A multipart upload that gets aborted if it's dropped before it's completed.
+// This makes sure that panics and early returns don't leave parts behind that
+// we keep paying for but can never use.
+//
+// As dropping can't wait for anything, the abort request is spawned onto the
+// tokio runtime we're dropped in. If we're dropped outside of a runtime, no
+// abort happens: use abort explicitly in that case.
+pub struct MultipartUpload<C>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    client: C,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    // Carried over from the request that created the upload, as S3 wants
+    // them on completing and aborting it too.
+    expected_bucket_owner: Option<String>,
+    request_payer: Option<String>,
+    // Set once the upload was completed or aborted explicitly.
+    finished: bool,
+}
+
+impl<C> std::fmt::Debug for MultipartUpload<C>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MultipartUpload")
+            .field("bucket", &self.bucket)
+            .field("key", &self.key)
+            .field("upload_id", &self.upload_id)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MultipartCreateError {
+    Create(RusotoError<CreateMultipartUploadError>),
+    // S3 didn't give us an upload ID to upload the parts with.
+    MissingUploadId,
+}
+
+impl std::fmt::Display for MultipartCreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultipartCreateError::Create(e) => {
+                write!(f, "Failed to create multipart upload: {}", e)
+            }
+            MultipartCreateError::MissingUploadId => {
+                write!(f, "No upload ID in multipart upload response.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MultipartCreateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MultipartCreateError::Create(e) => Some(e),
+            MultipartCreateError::MissingUploadId => None,
+        }
+    }
+}
+
+impl<C> MultipartUpload<C>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    pub async fn create(
+        client: C,
+        req: CreateMultipartUploadRequest,
+    ) -> Result<Self, MultipartCreateError> {
+        let bucket = req.bucket.to_owned();
+        let key = req.key.to_owned();
+        let expected_bucket_owner = req.expected_bucket_owner.to_owned();
+        let request_payer = req.request_payer.to_owned();
+        let create = traced!(
+            client.create_multipart_upload(req),
+            "create_multipart_upload",
+            bucket = %bucket,
+            key = %key,
+        );
+        let upload_id = create
+            .await
+            .map_err(MultipartCreateError::Create)?
+            .upload_id
+            .ok_or(MultipartCreateError::MissingUploadId)?;
+        Ok(MultipartUpload {
+            client,
+            bucket,
+            key,
+            upload_id,
+            expected_bucket_owner,
+            request_payer,
+            finished: false,
+        })
+    }
+
+    pub fn upload_id(&self) -> &str {
+        &self.upload_id
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // Part request with the upload's location filled in, suitable to pass to
+    // upload_parts.
+    pub fn part_template(&self) -> UploadPartRequest {
+        UploadPartRequest {
+            bucket: self.bucket.to_owned(),
+            key: self.key.to_owned(),
+            upload_id: self.upload_id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // Completes the upload with the given parts. If completing fails, the
+    // upload is aborted.
+    pub async fn complete(
+        mut self,
+        parts: Vec<CompletedPart>,
+    ) -> Result<CompleteMultipartUploadOutput, RusotoError<CompleteMultipartUploadError>> {
+        let req = CompleteMultipartUploadRequest {
+            bucket: self.bucket.to_owned(),
+            key: self.key.to_owned(),
+            upload_id: self.upload_id.to_owned(),
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            expected_bucket_owner: self.expected_bucket_owner.to_owned(),
+            request_payer: self.request_payer.to_owned(),
+            ..Default::default()
+        };
+        let complete = traced!(
+            self.client.complete_multipart_upload(req),
+            "complete_multipart_upload",
+            bucket = %self.bucket,
+            key = %self.key,
+            upload_id = %self.upload_id,
+        );
+        let output = complete.await?;
+        self.finished = true;
+        Ok(output)
+    }
+
+    // Aborts the upload, waiting for S3 to confirm.
+    pub async fn abort(mut self) -> Result<(), RusotoError<AbortMultipartUploadError>> {
+        // Whatever happens, don't try again on drop.
+        self.finished = true;
+        traced!(
+            self.client.abort_multipart_upload(self.abort_request()),
+            "abort_multipart_upload",
+            bucket = %self.bucket,
+            key = %self.key,
+            upload_id = %self.upload_id,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    fn abort_request(&self) -> AbortMultipartUploadRequest {
+        AbortMultipartUploadRequest {
+            bucket: self.bucket.to_owned(),
+            key: self.key.to_owned(),
+            upload_id: self.upload_id.to_owned(),
+            expected_bucket_owner: self.expected_bucket_owner.to_owned(),
+            request_payer: self.request_payer.to_owned(),
+        }
+    }
+}
+
+impl<C> Drop for MultipartUpload<C>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let client = self.client.clone();
+            let req = self.abort_request();
+            let abort = traced!(
+                async move { client.abort_multipart_upload(req).await },
+                "abort_multipart_upload",
+                bucket = %self.bucket,
+                key = %self.key,
+                upload_id = %self.upload_id,
+            );
+            handle.spawn(async move {
+                // Nobody's around to hear about it if this fails.
+                let _ = abort.await;
+            });
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use bytes::{Bytes, BytesMut};
+use futures::TryStreamExt;
+use rusoto_core::{ByteStream, RusotoError};
+use rusoto_s3::{GetObjectError, GetObjectOutput, GetObjectRequest, S3};
+use std::fmt::Display;
+
+use crate::content_range::total_size;
+use crate::{seek_table_size, FrameIndex, SeekTableError, SEEK_TABLE_FOOTER_SIZE};
+
+// Frame index of a seekable object on S3 along with how it's laid out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectIndex {
+    pub index: FrameIndex,
+    pub object_size: u64,
+    // Size of the seek table at the end of the object, footer included.
+    pub seek_table_size: u64,
+    // ETag of the object the table was read from, if S3 sent one. Pass it as
+    // if_match to read the same object the index is for.
+    pub e_tag: Option<String>,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReadObjectError {
+    GetObject(RusotoError<GetObjectError>),
+    ReadBody(std::io::Error),
+    // S3 didn't tell us how big the object is.
+    MissingContentRange,
+    SeekTable(SeekTableError),
+    // The frames in the seek table and the table itself don't add up to the
+    // size of the object.
+    SizeMismatch { object_size: u64, expected: u64 },
+}
+
+impl Display for ReadObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadObjectError::GetObject(e) => write!(f, "Failed to get object: {}", e),
+            ReadObjectError::ReadBody(e) => write!(f, "Failed to read object body: {}", e),
+            ReadObjectError::MissingContentRange => {
+                write!(f, "Content range not set in response.")
+            }
+            ReadObjectError::SeekTable(e) => write!(f, "Bad seek table: {}", e),
+            ReadObjectError::SizeMismatch {
+                object_size,
+                expected,
+            } => write!(
+                f,
+                "Object is {} bytes but its seek table says it should be {}",
+                object_size, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReadObjectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadObjectError::GetObject(e) => Some(e),
+            ReadObjectError::ReadBody(e) => Some(e),
+            ReadObjectError::SeekTable(e) => Some(e),
+            ReadObjectError::MissingContentRange | ReadObjectError::SizeMismatch { .. } => None,
+        }
+    }
+}
+
+// Fetches just the seek table of a seekable object: the footer first to find
+// out how big the table is, then the rest of it. The request says which
+// object to look at; its range is ignored.
+pub async fn fetch_object_index<C: S3>(
+    client: &C,
+    req: &GetObjectRequest,
+) -> Result<ObjectIndex, ReadObjectError> {
+    let (footer, output) =
+        get_range_output(client, req, format!("bytes=-{}", SEEK_TABLE_FOOTER_SIZE)).await?;
+    let object_size = output
+        .content_range
+        .as_deref()
+        .and_then(total_size)
+        .ok_or(ReadObjectError::MissingContentRange)?;
+    let table_size = seek_table_size(&footer).map_err(ReadObjectError::SeekTable)?;
+    if table_size > object_size {
+        return Err(ReadObjectError::SeekTable(SeekTableError::Truncated));
+    }
+    // The table has to come from the object we got the footer of.
+    let table_req = GetObjectRequest {
+        if_match: output.e_tag.to_owned().or_else(|| req.if_match.to_owned()),
+        ..req.to_owned()
+    };
+    let (table, _) = get_object_range(client, &table_req, format!("bytes=-{}", table_size)).await?;
+    let index = FrameIndex::from_seek_table(&table).map_err(ReadObjectError::SeekTable)?;
+    let expected = index
+        .compressed_size()
+        .checked_add(table_size)
+        .ok_or(ReadObjectError::SeekTable(SeekTableError::TooLarge))?;
+    if expected != object_size {
+        return Err(ReadObjectError::SizeMismatch {
+            object_size,
+            expected,
+        });
+    }
+    Ok(ObjectIndex {
+        index,
+        object_size,
+        seek_table_size: table_size,
+        e_tag: output.e_tag,
+    })
+}
+
+// Fetches a seek table object, as uploaded along with an object when
+// UploadOptions::seek_table_suffix is set. The request says which seek table
+// object to get.
+pub async fn fetch_seek_table_object<C: S3>(
+    client: &C,
+    req: GetObjectRequest,
+) -> Result<FrameIndex, ReadObjectError> {
+    let get_object = traced!(
+        client.get_object(req),
+        "get_object",
+        bucket = %req.bucket,
+        key = %req.key,
+    );
+    let output = get_object.await.map_err(ReadObjectError::GetObject)?;
+    let seek_table = match output.body {
+        None => Bytes::new(),
+        Some(body) => read_body(body).await.map_err(ReadObjectError::ReadBody)?,
+    };
+    FrameIndex::from_seek_table(&seek_table).map_err(ReadObjectError::SeekTable)
+}
+
+// Gets the given range of the object, returning the data along with the
+// Content-Range S3 sent.
+pub(crate) async fn get_object_range<C: S3>(
+    client: &C,
+    req: &GetObjectRequest,
+    range: String,
+) -> Result<(Bytes, Option<String>), ReadObjectError> {
+    let (data, output) = get_range_output(client, req, range).await?;
+    Ok((data, output.content_range))
+}
+
+// As get_object_range, with the rest of what S3 sent along, bar the body.
+async fn get_range_output<C: S3>(
+    client: &C,
+    req: &GetObjectRequest,
+    range: String,
+) -> Result<(Bytes, GetObjectOutput), ReadObjectError> {
+    let mut req = req.to_owned();
+    req.range = Some(range);
+    let get_object = traced!(
+        client.get_object(req),
+        "get_object",
+        bucket = %req.bucket,
+        key = %req.key,
+        range = ?req.range,
+    );
+    let mut output = get_object.await.map_err(ReadObjectError::GetObject)?;
+    let data = match output.body.take() {
+        None => Bytes::new(),
+        Some(body) => read_body(body).await.map_err(ReadObjectError::ReadBody)?,
+    };
+    Ok((data, output))
+}
+
+pub(crate) async fn read_body(body: ByteStream) -> std::io::Result<Bytes> {
+    Ok(body
+        .try_fold(BytesMut::new(), |mut data, chunk| async move {
+            data.extend_from_slice(&chunk);
+            Ok(data)
+        })
+        .await?
+        .freeze())
+}
@@ -0,0 +1,84 @@
+use futures::{Stream, TryStreamExt};
+use parking_lot::Mutex;
+use rusoto_s3::UploadPartRequest;
+
+use crate::upload_object::configure_upload_parts;
+use crate::{
+    CompressItem, FrameIndex, StreamCompress, StreamUploadParts, UploadError, UploadOptions,
+    MAXIMUM_PARTS,
+};
+
+// What uploading some data with some options would look like.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UploadPlan {
+    pub uncompressed_size: u64,
+    // Size of the object, seek table included.
+    pub compressed_size: u64,
+    pub num_frames: usize,
+    // Size of each part, in order.
+    pub part_sizes: Vec<u64>,
+    // Requests the upload takes if nothing has to be retried.
+    pub requests: u64,
+}
+
+impl UploadPlan {
+    pub fn num_parts(&self) -> usize {
+        self.part_sizes.len()
+    }
+
+    // Whether S3 would accept this many parts. If not, raise the minimum part
+    // size or turn on automatic part sizing.
+    pub fn within_part_limit(&self) -> bool {
+        self.num_parts() as i64 <= MAXIMUM_PARTS
+    }
+}
+
+// Compresses the stream and chunks it into parts like upload_compressed_object
+// would with the same options, without talking to S3 at all. Useful to check
+// the options give sensible parts before uploading anything for real. Options
+// that only matter for S3 requests are ignored, spilling to disk is not.
+pub async fn plan_upload<S, I, E>(
+    stream: S,
+    options: &UploadOptions,
+) -> Result<UploadPlan, UploadError<E>>
+where
+    S: Stream<Item = Result<I, E>>,
+    I: AsRef<[u8]>,
+{
+    let frame_index = Mutex::new(None);
+    let parts = stream
+        .compress(options.compression_level, options.frame_size)
+        .map_err(UploadError::Compression)?
+        .with_checkpoints()
+        .try_filter_map(|item| {
+            futures::future::ready(Ok(match item {
+                CompressItem::Data(data) => Some(data),
+                CompressItem::FrameIndex(index) => {
+                    *frame_index.lock() = Some(index);
+                    None
+                }
+                CompressItem::Checkpoint(_) => None,
+            }))
+        })
+        .map_err(UploadError::from)
+        .upload_parts(UploadPartRequest::default(), options.minimum_part_size);
+    let part_sizes: Vec<u64> = configure_upload_parts(parts, options)
+        .prepared_parts()
+        .map_ok(|part| part.data.len())
+        .try_collect()
+        .await?;
+
+    let frame_index = frame_index.into_inner().unwrap_or_else(FrameIndex::new);
+    // Creating and completing the upload, then the parts.
+    let mut requests = 2 + part_sizes.len() as u64;
+    if options.seek_table_suffix.is_some() {
+        requests += 1;
+    }
+    Ok(UploadPlan {
+        uncompressed_size: frame_index.decompressed_size(),
+        compressed_size: part_sizes.iter().sum(),
+        num_frames: frame_index.num_frames(),
+        part_sizes,
+        requests,
+    })
+}
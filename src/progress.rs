@@ -0,0 +1,98 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+// Snapshot of how far along an upload is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UploadProgress {
+    // Data taken from the input stream.
+    pub uncompressed_bytes: u64,
+    // Data produced by compression, buffered until there's enough for a part.
+    pub compressed_bytes: u64,
+    // Parts that started uploading and how many of those have finished.
+    pub parts_started: u64,
+    pub parts_completed: u64,
+    // Data in the parts that started uploading and in those that finished.
+    pub bytes_started: u64,
+    pub bytes_completed: u64,
+}
+
+// Gets called with the current progress every time an upload makes any. This
+// happens from within the upload so it should be quick: send the progress
+// down a channel if there's more work to do with it.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&UploadProgress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&UploadProgress) + Send + Sync + 'static,
+    {
+        ProgressCallback(Arc::new(callback))
+    }
+}
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ProgressCallback").finish()
+    }
+}
+
+// Callbacks are only equal to their clones.
+impl PartialEq for ProgressCallback {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.0) as *const u8 == Arc::as_ptr(&other.0) as *const u8
+    }
+}
+
+impl Eq for ProgressCallback {}
+
+// Keeps track of the progress of a single upload, reporting every change.
+#[derive(Debug, Default)]
+pub(crate) struct ProgressTracker {
+    progress: Mutex<UploadProgress>,
+    callback: Option<ProgressCallback>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(callback: Option<ProgressCallback>) -> Self {
+        ProgressTracker {
+            progress: Mutex::new(UploadProgress::default()),
+            callback,
+        }
+    }
+
+    fn update<F: FnOnce(&mut UploadProgress)>(&self, f: F) {
+        let progress = {
+            let mut progress = self.progress.lock();
+            f(&mut progress);
+            *progress
+        };
+        // Don't hold the lock while calling out: the callback might take a
+        // while and other parts want to report in too.
+        if let Some(ProgressCallback(callback)) = &self.callback {
+            callback(&progress);
+        }
+    }
+
+    pub(crate) fn uncompressed(&self, bytes: usize) {
+        self.update(|progress| progress.uncompressed_bytes += bytes as u64);
+    }
+
+    pub(crate) fn compressed(&self, bytes: usize) {
+        self.update(|progress| progress.compressed_bytes += bytes as u64);
+    }
+
+    pub(crate) fn part_started(&self, bytes: usize) {
+        self.update(|progress| {
+            progress.parts_started += 1;
+            progress.bytes_started += bytes as u64;
+        });
+    }
+
+    pub(crate) fn part_completed(&self, bytes: usize) {
+        self.update(|progress| {
+            progress.parts_completed += 1;
+            progress.bytes_completed += bytes as u64;
+        });
+    }
+}
@@ -0,0 +1,203 @@
+use bytes::Bytes;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use rusoto_core::Region;
+use rusoto_s3::{GetObjectRequest, S3Client};
+use std::io::{Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+use crate::global_runtime::global_runtime;
+use crate::{
+    upload_compressed_object, upload_compressed_reader, ReadOptions, SeekableDecompress,
+    SeekableS3Object, UploadOptions,
+};
+
+// Python module exposing reading objects as files and compressing data into
+// objects. Built with the python feature, and with pyo3's extension-module
+// feature on top when building the module itself, with maturin for example.
+
+fn runtime() -> PyResult<&'static tokio::runtime::Runtime> {
+    global_runtime().map_err(|e| PyIOError::new_err(e.to_string()))
+}
+
+fn client(region: Option<&str>) -> PyResult<S3Client> {
+    let region = match region {
+        Some(region) => {
+            Region::from_str(region).map_err(|e| PyValueError::new_err(e.to_string()))?
+        }
+        None => Region::default(),
+    };
+    Ok(S3Client::new(region))
+}
+
+fn io_error(e: impl std::fmt::Display) -> PyErr {
+    PyIOError::new_err(e.to_string())
+}
+
+// Read-only binary file over the decompressed data of an object, for use
+// wherever Python wants a file: io.BufferedReader, pandas, pyarrow and so on.
+#[pyclass(name = "SeekableReader", unsendable)]
+pub struct PySeekableReader {
+    inner: Option<SeekableDecompress<'static, SeekableS3Object<'static, S3Client>>>,
+}
+
+impl PySeekableReader {
+    fn inner(
+        &mut self,
+    ) -> PyResult<&mut SeekableDecompress<'static, SeekableS3Object<'static, S3Client>>> {
+        self.inner
+            .as_mut()
+            .ok_or_else(|| PyValueError::new_err("I/O operation on closed file."))
+    }
+}
+
+#[pymethods]
+impl PySeekableReader {
+    // Reads up to size bytes, or everything up to the end with a negative
+    // size.
+    #[args(size = "-1")]
+    fn read<'py>(&mut self, py: Python<'py>, size: i64) -> PyResult<&'py PyBytes> {
+        let inner = self.inner()?;
+        let mut data = Vec::new();
+        if size < 0 {
+            inner.read_to_end(&mut data).map_err(io_error)?;
+        } else {
+            inner
+                .take(size as u64)
+                .read_to_end(&mut data)
+                .map_err(io_error)?;
+        }
+        Ok(PyBytes::new(py, &data))
+    }
+
+    #[args(whence = "0")]
+    fn seek(&mut self, offset: i64, whence: i32) -> PyResult<u64> {
+        let pos = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            0 => return Err(PyValueError::new_err("Negative seek position.")),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(PyValueError::new_err("Invalid whence.")),
+        };
+        self.inner()?.seek(pos).map_err(io_error)
+    }
+
+    fn tell(&mut self) -> PyResult<u64> {
+        self.inner()?.seek(SeekFrom::Current(0)).map_err(io_error)
+    }
+
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn seekable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    fn close(&mut self) {
+        self.inner = None;
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.inner.is_none()
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __exit__(&mut self, _type: &PyAny, _value: &PyAny, _traceback: &PyAny) -> bool {
+        self.close();
+        false
+    }
+}
+
+// Opens s3://bucket/key for reading. Nothing is fetched until the first read
+// or a seek from the end.
+#[pyfunction(region = "None")]
+fn open(bucket: String, key: String, region: Option<&str>) -> PyResult<PySeekableReader> {
+    let req = GetObjectRequest {
+        bucket,
+        key,
+        ..Default::default()
+    };
+    let object = SeekableS3Object::lazy(client(region)?, runtime()?, req, &ReadOptions::default());
+    let inner = SeekableDecompress::new(object).map_err(io_error)?;
+    Ok(PySeekableReader { inner: Some(inner) })
+}
+
+fn upload_options(level: usize, frame_size: usize) -> UploadOptions {
+    UploadOptions {
+        compression_level: level,
+        frame_size,
+        ..Default::default()
+    }
+}
+
+// Compresses the bytes into s3://bucket/key, returning the ETag of the
+// object.
+#[pyfunction(region = "None", level = "3", frame_size = "1048576")]
+fn upload(
+    py: Python,
+    data: &[u8],
+    bucket: &str,
+    key: &str,
+    region: Option<&str>,
+    level: usize,
+    frame_size: usize,
+) -> PyResult<Option<String>> {
+    let client = client(region)?;
+    let runtime = runtime()?;
+    let options = upload_options(level, frame_size);
+    let data = Bytes::copy_from_slice(data);
+    py.allow_threads(|| {
+        let chunks = futures::stream::iter(std::iter::once(Ok::<_, std::io::Error>(data)));
+        runtime
+            .block_on(upload_compressed_object(
+                &client, bucket, key, chunks, &options,
+            ))
+            .map(|output| output.e_tag)
+            .map_err(io_error)
+    })
+}
+
+// Compresses the file at the path into s3://bucket/key, returning the ETag of
+// the object.
+#[pyfunction(region = "None", level = "3", frame_size = "1048576")]
+fn upload_file(
+    py: Python,
+    path: &str,
+    bucket: &str,
+    key: &str,
+    region: Option<&str>,
+    level: usize,
+    frame_size: usize,
+) -> PyResult<Option<String>> {
+    let client = client(region)?;
+    let runtime = runtime()?;
+    let options = upload_options(level, frame_size);
+    py.allow_threads(|| {
+        runtime.block_on(async {
+            let input = tokio::fs::File::open(path).await.map_err(io_error)?;
+            let output = upload_compressed_reader(&client, bucket, key, input, &options)
+                .await
+                .map_err(io_error)?;
+            Ok(output.e_tag)
+        })
+    })
+}
+
+#[pymodule]
+fn zstd_seekable_s3(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PySeekableReader>()?;
+    m.add_function(wrap_pyfunction!(open, m)?)?;
+    m.add_function(wrap_pyfunction!(upload, m)?)?;
+    m.add_function(wrap_pyfunction!(upload_file, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,343 @@
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectRequest, S3};
+use std::fmt::Display;
+use std::io::{Read, Seek, SeekFrom};
+use zstd_seekable::DStream;
+
+use crate::decompress::decompress_frame;
+use crate::index_frame::read_seek_table;
+use crate::seek::seek_position;
+use crate::{FrameIndex, GetSeekableObjectError, ReadOptions, SeekableS3Object};
+
+const TOKEN_MAGIC: &[u8; 4] = b"ZSRS";
+const TOKEN_VERSION: u8 = 1;
+
+// Everything needed to carry on with a read later, possibly somewhere else:
+// which version of which object, its seek table so it doesn't have to be
+// fetched again, and where in the decompressed data the read was at.
+// Resuming only works against the very same object, going by its ETag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReadSession {
+    pub bucket: String,
+    pub key: String,
+    pub version_id: Option<String>,
+    pub e_tag: Option<String>,
+    pub index: FrameIndex,
+    pub position: u64,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReadSessionError {
+    GetObject(GetSeekableObjectError),
+    // Reading the seek table failed.
+    Io(std::io::Error),
+    // The object isn't the one the session was made for anymore.
+    ObjectChanged,
+    // The token isn't one made by ReadSession::to_token.
+    BadToken,
+}
+
+impl Display for ReadSessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadSessionError::GetObject(e) => write!(f, "{}", e),
+            ReadSessionError::Io(e) => write!(f, "Failed to read seek table: {}", e),
+            ReadSessionError::ObjectChanged => {
+                write!(f, "Object changed since the session was made.")
+            }
+            ReadSessionError::BadToken => write!(f, "Not a read session token."),
+        }
+    }
+}
+
+impl std::error::Error for ReadSessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadSessionError::GetObject(e) => Some(e),
+            ReadSessionError::Io(e) => Some(e),
+            ReadSessionError::ObjectChanged | ReadSessionError::BadToken => None,
+        }
+    }
+}
+
+fn push_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn push_optional_string(out: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            out.push(1);
+            push_string(out, s);
+        }
+        None => out.push(0),
+    }
+}
+
+// Reads the fields of a token back, front to back.
+struct TokenReader<'t> {
+    data: &'t [u8],
+}
+
+impl<'t> TokenReader<'t> {
+    fn take(&mut self, n: usize) -> Result<&'t [u8], ReadSessionError> {
+        if self.data.len() < n {
+            return Err(ReadSessionError::BadToken);
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, ReadSessionError> {
+        let mut word = [0; 4];
+        word.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn u64(&mut self) -> Result<u64, ReadSessionError> {
+        let mut word = [0; 8];
+        word.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(word))
+    }
+
+    fn string(&mut self) -> Result<String, ReadSessionError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_e| ReadSessionError::BadToken)
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>, ReadSessionError> {
+        match self.take(1)?[0] {
+            0 => Ok(None),
+            1 => self.string().map(Some),
+            _ => Err(ReadSessionError::BadToken),
+        }
+    }
+}
+
+impl ReadSession {
+    // Packs the session into a string that can be handed around, e.g. to
+    // another host, and turned back into the session with from_token.
+    pub fn to_token(&self) -> String {
+        let mut data = TOKEN_MAGIC.to_vec();
+        data.push(TOKEN_VERSION);
+        push_string(&mut data, &self.bucket);
+        push_string(&mut data, &self.key);
+        push_optional_string(&mut data, self.version_id.as_deref());
+        push_optional_string(&mut data, self.e_tag.as_deref());
+        data.extend_from_slice(&self.position.to_le_bytes());
+        // The index came from a seek table, so it fits into one.
+        if let Ok(table) = self.index.to_seek_table() {
+            data.extend_from_slice(&table);
+        }
+        data.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn from_token(token: &str) -> Result<Self, ReadSessionError> {
+        if token.len() % 2 != 0 || !token.is_ascii() {
+            return Err(ReadSessionError::BadToken);
+        }
+        let data = (0..token.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&token[at..at + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_e| ReadSessionError::BadToken)?;
+        let mut reader = TokenReader { data: &data };
+        if reader.take(4)? != TOKEN_MAGIC || reader.take(1)?[0] != TOKEN_VERSION {
+            return Err(ReadSessionError::BadToken);
+        }
+        Ok(ReadSession {
+            bucket: reader.string()?,
+            key: reader.string()?,
+            version_id: reader.optional_string()?,
+            e_tag: reader.optional_string()?,
+            position: reader.u64()?,
+            index: FrameIndex::from_seek_table(reader.data)
+                .map_err(|_e| ReadSessionError::BadToken)?,
+        })
+    }
+
+    // Opens the object again and carries on reading where the session left
+    // off. Fails with ObjectChanged if the object was overwritten since.
+    pub fn resume<'a, A: S3>(
+        &self,
+        client: A,
+        runtime: &'a tokio::runtime::Runtime,
+        options: &ReadOptions,
+    ) -> Result<SessionReader<'a, A>, ReadSessionError> {
+        let req = GetObjectRequest {
+            bucket: self.bucket.to_owned(),
+            key: self.key.to_owned(),
+            version_id: self.version_id.to_owned(),
+            if_match: self.e_tag.to_owned(),
+            ..Default::default()
+        };
+        let compressed = open_object(client, runtime, req, options)?;
+        if compressed.metadata().e_tag != self.e_tag {
+            return Err(ReadSessionError::ObjectChanged);
+        }
+        Ok(SessionReader {
+            compressed,
+            session: self.to_owned(),
+            dstream: None,
+            frame: None,
+        })
+    }
+}
+
+fn open_object<'a, A: S3>(
+    client: A,
+    runtime: &'a tokio::runtime::Runtime,
+    req: GetObjectRequest,
+    options: &ReadOptions,
+) -> Result<SeekableS3Object<'a, A>, ReadSessionError> {
+    match SeekableS3Object::with_options(client, runtime, req, options) {
+        // The ETag didn't match.
+        Err(GetSeekableObjectError::GetObject(RusotoError::Unknown(response)))
+            if response.status.as_u16() == 412 =>
+        {
+            Err(ReadSessionError::ObjectChanged)
+        }
+        Err(e) => Err(ReadSessionError::GetObject(e)),
+        Ok(compressed) => Ok(compressed),
+    }
+}
+
+// Reads the decompressed data of an object frame by frame, going by a seek
+// table it holds on to so that the whole read can be captured as a
+// ReadSession at any point.
+pub struct SessionReader<'a, A> {
+    compressed: SeekableS3Object<'a, A>,
+    session: ReadSession,
+    dstream: Option<DStream>,
+    // Last frame we decompressed.
+    frame: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a, A: S3> SessionReader<'a, A> {
+    // Starts reading the object from the beginning, fetching its seek table
+    // from its tail. Later requests all insist on the version first seen.
+    pub fn open(
+        client: A,
+        runtime: &'a tokio::runtime::Runtime,
+        req: GetObjectRequest,
+        options: &ReadOptions,
+    ) -> Result<Self, ReadSessionError> {
+        let bucket = req.bucket.to_owned();
+        let key = req.key.to_owned();
+        let mut compressed = open_object(client, runtime, req, options)?;
+        let object_size = compressed.len();
+        let index = read_index(&mut compressed, object_size).map_err(ReadSessionError::Io)?;
+        let metadata = compressed.metadata();
+        let session = ReadSession {
+            bucket,
+            key,
+            version_id: metadata.version_id.to_owned(),
+            e_tag: metadata.e_tag.to_owned(),
+            index,
+            position: 0,
+        };
+        Ok(SessionReader {
+            compressed,
+            session,
+            dstream: None,
+            frame: None,
+        })
+    }
+}
+
+impl<A> SessionReader<'_, A> {
+    // Snapshot of the read as it stands, to resume it from later.
+    pub fn session(&self) -> &ReadSession {
+        &self.session
+    }
+
+    pub fn len(&self) -> u64 {
+        self.session.index.decompressed_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Reads the seek table off the tail of the object, making sure it adds up
+// to the size of the object.
+fn read_index<R: Read + Seek>(compressed: &mut R, object_size: u64) -> std::io::Result<FrameIndex> {
+    let (index, table_size) = read_seek_table(compressed)?;
+    let expected = index.compressed_size().saturating_add(table_size);
+    if expected != object_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Object is {} bytes but its seek table says it should be {}",
+                object_size, expected
+            ),
+        ));
+    }
+    Ok(index)
+}
+
+impl<A: S3> SessionReader<'_, A> {
+    // Makes sure the frame is the one we have decompressed.
+    fn load(&mut self, index: usize) -> std::io::Result<()> {
+        if self.frame.as_ref().map(|(loaded, _)| *loaded) == Some(index) {
+            return Ok(());
+        }
+        let entry = self.session.index.frames()[index];
+        let mut frame = vec![0; entry.compressed_size as usize];
+        self.compressed
+            .seek(SeekFrom::Start(entry.compressed_offset))?;
+        self.compressed.read_exact(&mut frame)?;
+        let mut dstream = match self.dstream.take() {
+            Some(dstream) => dstream,
+            None => DStream::new()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?,
+        };
+        // The stream is in an unknown state after a failure, so it's only
+        // kept for the next frame if this one went fine.
+        match decompress_frame(&mut dstream, &frame)
+            .filter(|data| data.len() as u64 == entry.decompressed_size)
+        {
+            Some(data) => {
+                self.dstream = Some(dstream);
+                self.frame = Some((index, data));
+                Ok(())
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Frame {} is corrupt", index),
+            )),
+        }
+    }
+}
+
+impl<A: S3> Read for SessionReader<'_, A> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let position = self.session.position;
+        let index = match self.session.index.frame_index_at(position) {
+            Some(index) => index,
+            None => return Ok(0),
+        };
+        let offset = (position - self.session.index.frames()[index].decompressed_offset) as usize;
+        self.load(index)?;
+        let data = match &self.frame {
+            Some((_, data)) => data,
+            None => return Ok(0),
+        };
+        let n = std::cmp::min(buf.len(), data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        self.session.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<A> Seek for SessionReader<'_, A> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.session.position = seek_position(pos, self.session.position, self.len(), false)?;
+        Ok(self.session.position)
+    }
+}
@@ -0,0 +1,194 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{read_frame_index, read_index_frame, IndexBuilder, IndexFrame, SeekableDecompress};
+
+// Tag of the index frame holding a record index.
+pub const RECORD_INDEX_TAG: [u8; 4] = *b"RECI";
+
+// Where every so many records start in the uncompressed data, records being
+// whatever is between delimiters: lines, with a delimiter of b'\n'. Seeking
+// to a record then only has to scan from the closest record in the index
+// rather than from the start of the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordIndex {
+    // Every how many records we know where one starts, and the delimiter
+    // between them.
+    every: u64,
+    delimiter: u8,
+    // Offsets of records 0, every, 2 * every and so on.
+    offsets: Vec<u64>,
+    // Records in the data, counting a last one without a delimiter after it.
+    records: u64,
+}
+
+impl RecordIndex {
+    // Reads the record index out of the compressed data, if it was compressed
+    // with one.
+    pub fn read<R: Read + Seek>(compressed: &mut R) -> std::io::Result<Option<Self>> {
+        let frames = read_frame_index(compressed)?;
+        match read_index_frame(compressed, &frames, RECORD_INDEX_TAG)? {
+            None => Ok(None),
+            Some(payload) => Self::from_bytes(&payload).map(Some).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Record index is malformed.",
+                )
+            }),
+        }
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    // The closest record at or before the given one we know the offset of,
+    // along with that offset.
+    pub fn nearest(&self, record: u64) -> Option<(u64, u64)> {
+        let slot = usize::try_from(record / self.every).ok()?;
+        let slot = std::cmp::min(slot, self.offsets.len().checked_sub(1)?);
+        Some((slot as u64 * self.every, self.offsets[slot]))
+    }
+
+    // every (8) | records (8) | delimiter (1) | offsets (8 each), with the
+    // numbers little-endian.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(17 + 8 * self.offsets.len());
+        data.extend_from_slice(&self.every.to_le_bytes());
+        data.extend_from_slice(&self.records.to_le_bytes());
+        data.push(self.delimiter);
+        for offset in &self.offsets {
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        data
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        let read_u64 = |at: usize| {
+            let word = <[u8; 8]>::try_from(data.get(at..at + 8)?).ok()?;
+            Some(u64::from_le_bytes(word))
+        };
+        let every = read_u64(0).filter(|&every| every > 0)?;
+        let records = read_u64(8)?;
+        let delimiter = *data.get(16)?;
+        let offsets = data.get(17..)?;
+        if offsets.len() % 8 != 0 {
+            return None;
+        }
+        let offsets = (17..data.len())
+            .step_by(8)
+            .map(read_u64)
+            .collect::<Option<Vec<_>>>()?;
+        Some(RecordIndex {
+            every,
+            delimiter,
+            offsets,
+            records,
+        })
+    }
+}
+
+// Builds a RecordIndex as the data is compressed, see Compress::with_index.
+// The more often a record is indexed, the bigger the index and the less data
+// has to be scanned to find a record.
+#[derive(Debug, Clone)]
+pub struct RecordIndexBuilder {
+    index: RecordIndex,
+    // How far into the data we are and whether anything came after the last
+    // delimiter.
+    offset: u64,
+    in_record: bool,
+}
+
+impl RecordIndexBuilder {
+    // Indexes every so many records, with the given byte between records.
+    // Indexing every record is the most often we go.
+    pub fn new(every: u64, delimiter: u8) -> Self {
+        RecordIndexBuilder {
+            index: RecordIndex {
+                every: every.max(1),
+                delimiter,
+                offsets: vec![0],
+                records: 0,
+            },
+            offset: 0,
+            in_record: false,
+        }
+    }
+}
+
+impl IndexBuilder for RecordIndexBuilder {
+    fn update(&mut self, data: &[u8]) {
+        let mut start = 0;
+        while let Some(at) = data[start..]
+            .iter()
+            .position(|&b| b == self.index.delimiter)
+        {
+            start += at + 1;
+            self.index.records += 1;
+            if self.index.records % self.index.every == 0 {
+                self.index.offsets.push(self.offset + start as u64);
+            }
+        }
+        if !data.is_empty() {
+            self.in_record = start < data.len();
+        }
+        self.offset += data.len() as u64;
+    }
+
+    fn finish(&mut self) -> IndexFrame {
+        let mut index = self.index.to_owned();
+        if self.in_record {
+            index.records += 1;
+        } else if index.offsets.last() == Some(&self.offset) && self.offset > 0 {
+            // The data ended with a delimiter: there's no record after it.
+            index.offsets.pop();
+        }
+        IndexFrame {
+            tag: RECORD_INDEX_TAG,
+            payload: index.to_bytes(),
+        }
+    }
+}
+
+impl<'a, A> SeekableDecompress<'a, A> {
+    // Seeks to the start of the given record, counting from 0, returning the
+    // new position. Starts from the closest indexed record before it and
+    // reads forward from there to find it.
+    pub fn seek_to_record(&mut self, index: &RecordIndex, record: u64) -> std::io::Result<u64> {
+        if record >= index.records() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Record {} is past the last one.", record),
+            ));
+        }
+        let (mut current, offset) = index.nearest(record).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Record index is empty.")
+        })?;
+        let mut position = self.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0; 64 * 1024];
+        while current < record {
+            let n = self.read(&mut buffer)?;
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Data ended before the record.",
+                ));
+            }
+            for (at, &b) in buffer[..n].iter().enumerate() {
+                if b == index.delimiter() {
+                    current += 1;
+                    if current == record {
+                        return self.seek(SeekFrom::Start(position + at as u64 + 1));
+                    }
+                }
+            }
+            position += n as u64;
+        }
+        self.seek(SeekFrom::Start(position))
+    }
+}
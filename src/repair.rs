@@ -0,0 +1,493 @@
+use futures::TryStreamExt;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectRequest, PutObjectError, S3};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::io::Read;
+use zstd_seekable::DStream;
+
+use crate::concat::copy_with_seek_table;
+use crate::index_frame::EMPTY_CHECKSUM;
+use crate::upload_object::upload_seek_table_object;
+use crate::{
+    ConcatError, FrameIndex, ReadObjectError, RetryPolicy, SeekTableError, UploadConfig, ZstdError,
+};
+
+// Frame layout as described in zstd's doc/zstd_compression_format.md.
+const ZSTD_MAGIC_NUMBER: u32 = 0xFD2F_B528;
+// Skippable frames have any of the 16 magic numbers from here on, the seek
+// table being one of them.
+const SKIPPABLE_MAGIC_NUMBERS: std::ops::RangeInclusive<u32> = 0x184D_2A50..=0x184D_2A5F;
+const SEEK_TABLE_MAGIC_NUMBER: u32 = 0x184D_2A5E;
+const SKIPPABLE_HEADER_SIZE: usize = 8;
+const BLOCK_HEADER_SIZE: usize = 3;
+const MAXIMUM_BLOCK_SIZE: usize = 128 * 1024;
+const CHECKSUM_SIZE: usize = 4;
+const READ_SIZE: usize = 512 * 1024;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RepairError {
+    Read(ReadObjectError),
+    Io(std::io::Error),
+    Zstd(ZstdError),
+    SeekTable(SeekTableError),
+    Rewrite(ConcatError),
+    Sidecar(RusotoError<PutObjectError>),
+}
+
+impl Display for RepairError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepairError::Read(e) => write!(f, "Failed to read archive: {}", e),
+            RepairError::Io(e) => write!(f, "Failed to read archive: {}", e),
+            RepairError::Zstd(e) => write!(f, "{}", e),
+            RepairError::SeekTable(e) => write!(f, "Failed to build seek table: {}", e),
+            RepairError::Rewrite(e) => write!(f, "Failed to rewrite archive: {}", e),
+            RepairError::Sidecar(e) => write!(f, "Failed to upload seek table: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RepairError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RepairError::Read(e) => Some(e),
+            RepairError::Io(e) => Some(e),
+            RepairError::Zstd(e) => Some(e),
+            RepairError::SeekTable(e) => Some(e),
+            RepairError::Rewrite(e) => Some(e),
+            RepairError::Sidecar(e) => Some(e),
+        }
+    }
+}
+
+// A seek table worked out from the frames of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebuiltSeekTable {
+    // The frames that were found whole, from the start of the archive. The
+    // archive is seekable again once the data these cover is followed by
+    // the table.
+    pub index: FrameIndex,
+    // Bytes past the last good frame, which are either damaged, truncated or
+    // the old seek table.
+    pub discarded: u64,
+}
+
+// What to do with a rebuilt seek table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairTarget {
+    // Write the object again with its good frames followed by the rebuilt
+    // table. The frames are copied server-side where they can be.
+    Rewrite,
+    // Leave the object be and upload the table to the key of the object with
+    // this suffix added, see SEEK_TABLE_OBJECT_SUFFIX and
+    // fetch_seek_table_object.
+    Sidecar(String),
+}
+
+// A frame found while scanning.
+#[derive(Debug, Clone, Copy)]
+struct ScannedFrame {
+    compressed_size: u64,
+    decompressed_size: u64,
+    checksum: Option<u32>,
+    skippable: bool,
+    seek_table: bool,
+}
+
+pub(crate) enum Parsed {
+    // Can't tell what this is without more data.
+    Incomplete,
+    // Not a frame: the stream is damaged from here on.
+    Corrupt,
+    Skippable {
+        size: u64,
+        seek_table: bool,
+    },
+    Frame {
+        size: usize,
+        decompressed_size: Option<u64>,
+        checksum: Option<u32>,
+    },
+}
+
+fn read_le(field: &[u8]) -> u64 {
+    field
+        .iter()
+        .rev()
+        .fold(0, |value, &b| (value << 8) | u64::from(b))
+}
+
+// Works out where the frame at the start of the data ends by walking its
+// blocks, and how big it is decompressed if that can be told without
+// decompressing it.
+pub(crate) fn parse_frame(data: &[u8]) -> Parsed {
+    let magic = match data.get(..4) {
+        Some(magic) => read_le(magic) as u32,
+        None => return Parsed::Incomplete,
+    };
+    if SKIPPABLE_MAGIC_NUMBERS.contains(&magic) {
+        return match data.get(4..SKIPPABLE_HEADER_SIZE) {
+            Some(size) => Parsed::Skippable {
+                size: SKIPPABLE_HEADER_SIZE as u64 + read_le(size),
+                seek_table: magic == SEEK_TABLE_MAGIC_NUMBER,
+            },
+            None => Parsed::Incomplete,
+        };
+    }
+    if magic != ZSTD_MAGIC_NUMBER {
+        return Parsed::Corrupt;
+    }
+    let descriptor = match data.get(4) {
+        Some(&descriptor) => descriptor,
+        None => return Parsed::Incomplete,
+    };
+    if descriptor & 0x08 != 0 {
+        return Parsed::Corrupt;
+    }
+    let single_segment = descriptor & 0x20 != 0;
+    let has_checksum = descriptor & 0x04 != 0;
+    let dictionary_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let content_size_size = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => 0,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let window_size = if single_segment { 0 } else { 1 };
+    let mut at = 5 + window_size + dictionary_id_size;
+    let content_size = match data.get(at..at + content_size_size) {
+        None => return Parsed::Incomplete,
+        Some([]) => None,
+        // Two byte sizes are stored less 256.
+        Some(field) if field.len() == 2 => Some(read_le(field) + 256),
+        Some(field) => Some(read_le(field)),
+    };
+    at += content_size_size;
+    // Raw and RLE blocks say how much they decompress to, compressed ones
+    // don't.
+    let mut block_contents = Some(0u64);
+    loop {
+        let header = match data.get(at..at + BLOCK_HEADER_SIZE) {
+            Some(header) => read_le(header),
+            None => return Parsed::Incomplete,
+        };
+        let last = header & 1 != 0;
+        let block_size = (header >> 3) as usize;
+        if block_size > MAXIMUM_BLOCK_SIZE {
+            return Parsed::Corrupt;
+        }
+        at += BLOCK_HEADER_SIZE;
+        at += match (header >> 1) & 0x03 {
+            0 => {
+                block_contents = block_contents.map(|size| size + block_size as u64);
+                block_size
+            }
+            1 => {
+                block_contents = block_contents.map(|size| size + block_size as u64);
+                1
+            }
+            2 => {
+                block_contents = None;
+                block_size
+            }
+            _ => return Parsed::Corrupt,
+        };
+        if last {
+            break;
+        }
+    }
+    let checksum = if has_checksum {
+        match data.get(at..at + CHECKSUM_SIZE) {
+            Some(checksum) => Some(read_le(checksum) as u32),
+            None => return Parsed::Incomplete,
+        }
+    } else {
+        None
+    };
+    if has_checksum {
+        at += CHECKSUM_SIZE;
+    }
+    if data.len() < at {
+        return Parsed::Incomplete;
+    }
+    Parsed::Frame {
+        size: at,
+        decompressed_size: content_size.or(block_contents),
+        checksum,
+    }
+}
+
+// Works out the seek table of an archive by going through all of its frames,
+// for archives that lost theirs or have a damaged one. Feed it the compressed
+// data from the start, in order.
+//
+// Frames are found by walking their blocks. How much each one decompresses
+// to comes from its header when the writer put it there and otherwise by
+// decompressing it. Skippable frames, such as index frames, are kept. The
+// first frame that isn't whole ends the archive: it and everything after it
+// is discarded.
+#[derive(Default)]
+pub struct SeekTableRebuilder {
+    dstream: Option<DStream>,
+    out: Vec<u8>,
+    // Data we've seen that doesn't make a whole frame yet.
+    buffer: Vec<u8>,
+    // A skippable frame we're going past and how much of it is left.
+    skipping: Option<(ScannedFrame, u64)>,
+    frames: Vec<ScannedFrame>,
+    damaged: bool,
+    discarded: u64,
+}
+
+impl std::fmt::Debug for SeekTableRebuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeekTableRebuilder")
+            .field("frames", &self.frames.len())
+            .field("damaged", &self.damaged)
+            .field("discarded", &self.discarded)
+            .finish()
+    }
+}
+
+impl SeekTableRebuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), RepairError> {
+        if let Some((frame, remaining)) = &mut self.skipping {
+            let n = std::cmp::min(*remaining, data.len() as u64);
+            *remaining -= n;
+            data = &data[n as usize..];
+            if *remaining > 0 {
+                return Ok(());
+            }
+            self.frames.push(*frame);
+            self.skipping = None;
+        }
+        if self.damaged {
+            self.discarded += data.len() as u64;
+            return Ok(());
+        }
+        self.buffer.extend_from_slice(data);
+        let mut start = 0;
+        while !self.damaged {
+            let pending = &self.buffer[start..];
+            match parse_frame(pending) {
+                Parsed::Incomplete => break,
+                Parsed::Corrupt => self.damaged = true,
+                Parsed::Skippable { size, seek_table } => {
+                    let frame = ScannedFrame {
+                        compressed_size: size,
+                        decompressed_size: 0,
+                        checksum: None,
+                        skippable: true,
+                        seek_table,
+                    };
+                    match usize::try_from(size) {
+                        Ok(size) if size <= pending.len() => {
+                            self.frames.push(frame);
+                            start += size;
+                        }
+                        _ => {
+                            self.skipping = Some((frame, size - pending.len() as u64));
+                            start = self.buffer.len();
+                            break;
+                        }
+                    }
+                }
+                Parsed::Frame {
+                    size,
+                    decompressed_size,
+                    checksum,
+                } => {
+                    let decompressed_size = match decompressed_size {
+                        Some(decompressed_size) => Some(decompressed_size),
+                        None => {
+                            if self.dstream.is_none() {
+                                let dstream = DStream::new()
+                                    .map_err(|e| RepairError::Zstd(ZstdError::new(e)))?;
+                                self.dstream = Some(dstream);
+                            }
+                            match &mut self.dstream {
+                                Some(dstream) => frame_decompressed_size(
+                                    dstream,
+                                    &mut self.out,
+                                    &pending[..size],
+                                ),
+                                None => None,
+                            }
+                        }
+                    };
+                    match decompressed_size {
+                        Some(decompressed_size) => {
+                            self.frames.push(ScannedFrame {
+                                compressed_size: size as u64,
+                                decompressed_size,
+                                checksum,
+                                skippable: false,
+                                seek_table: false,
+                            });
+                            start += size;
+                        }
+                        // The stream can't be used again after failing.
+                        None => {
+                            self.dstream = None;
+                            self.damaged = true;
+                        }
+                    }
+                }
+            }
+        }
+        if self.damaged {
+            self.discarded += (self.buffer.len() - start) as u64;
+            self.buffer.clear();
+        } else {
+            self.buffer.drain(..start);
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<RebuiltSeekTable, RepairError> {
+        let mut discarded = self.discarded + self.buffer.len() as u64;
+        if let Some((frame, remaining)) = self.skipping {
+            discarded += frame.compressed_size - remaining;
+        }
+        // The old seek table goes, the new one takes its place.
+        while let Some(frame) = self.frames.last().filter(|frame| frame.seek_table) {
+            discarded += frame.compressed_size;
+            self.frames.pop();
+        }
+        let data_frames = self.frames.iter().filter(|frame| !frame.skippable);
+        let with_checksums =
+            data_frames.clone().count() > 0 && data_frames.clone().all(|f| f.checksum.is_some());
+        let mut index = FrameIndex::new();
+        for frame in &self.frames {
+            let checksum = match (with_checksums, frame.skippable) {
+                (false, _) => None,
+                (true, true) => Some(EMPTY_CHECKSUM),
+                (true, false) => frame.checksum,
+            };
+            index
+                .push(frame.compressed_size, frame.decompressed_size, checksum)
+                .map_err(RepairError::SeekTable)?;
+        }
+        Ok(RebuiltSeekTable { index, discarded })
+    }
+}
+
+// Decompresses a whole frame to see how much data it holds, or None if it
+// doesn't decompress.
+pub(crate) fn frame_decompressed_size(
+    dstream: &mut DStream,
+    out: &mut Vec<u8>,
+    frame: &[u8],
+) -> Option<u64> {
+    if out.is_empty() {
+        out.resize(DStream::out_size(), 0);
+    }
+    let mut input = frame;
+    let mut size = 0;
+    loop {
+        let (out_pos, in_pos) = dstream.decompress(out, input).ok()?;
+        size += out_pos as u64;
+        input = &input[in_pos..];
+        // Once the input is gone the output not filling up means it's all out.
+        if input.is_empty() && out_pos < out.len() {
+            return Some(size);
+        }
+        if out_pos == 0 && in_pos == 0 {
+            return None;
+        }
+    }
+}
+
+// Rebuilds the seek table of a compressed stream, reading it to the end, see
+// SeekTableRebuilder.
+pub fn rebuild_seek_table<R: Read>(mut compressed: R) -> Result<RebuiltSeekTable, RepairError> {
+    let mut rebuilder = SeekTableRebuilder::new();
+    let mut buffer = vec![0; READ_SIZE];
+    loop {
+        let n = match compressed.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(RepairError::Io(e)),
+        };
+        rebuilder.update(&buffer[..n])?;
+    }
+    rebuilder.finish()
+}
+
+// Makes a damaged object seekable again: downloads it once to rebuild its
+// seek table and then either rewrites the object or uploads the table next to
+// it. The range of the request is ignored. Rewriting copies from the version
+// of the object that was scanned.
+pub async fn repair_object<C>(
+    client: &C,
+    source: &GetObjectRequest,
+    target: &RepairTarget,
+    config: &UploadConfig,
+    retry: &RetryPolicy,
+) -> Result<RebuiltSeekTable, RepairError>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    let mut source = GetObjectRequest {
+        range: None,
+        ..source.to_owned()
+    };
+    let get_object = traced!(
+        client.get_object(source.to_owned()),
+        "get_object",
+        bucket = %source.bucket,
+        key = %source.key,
+    );
+    let output = get_object
+        .await
+        .map_err(|e| RepairError::Read(ReadObjectError::GetObject(e)))?;
+    let mut rebuilder = SeekTableRebuilder::new();
+    if let Some(mut body) = output.body {
+        while let Some(chunk) = body
+            .try_next()
+            .await
+            .map_err(|e| RepairError::Read(ReadObjectError::ReadBody(e)))?
+        {
+            rebuilder.update(&chunk)?;
+        }
+    }
+    let rebuilt = rebuilder.finish()?;
+    match target {
+        RepairTarget::Rewrite => {
+            if source.version_id.is_none() {
+                source.version_id = output.version_id;
+            }
+            let seek_table = rebuilt
+                .index
+                .to_seek_table()
+                .map_err(RepairError::SeekTable)?;
+            copy_with_seek_table(
+                client,
+                std::slice::from_ref(&source),
+                &[rebuilt.index.compressed_size()],
+                &seek_table,
+                &source.bucket,
+                &source.key,
+                config,
+                retry,
+            )
+            .await
+            .map_err(RepairError::Rewrite)?;
+        }
+        RepairTarget::Sidecar(suffix) => {
+            let key = format!("{}{}", source.key, suffix);
+            upload_seek_table_object(client, &source.bucket, &key, &rebuilt.index, config)
+                .await
+                .map_err(RepairError::Sidecar)?;
+        }
+    }
+    Ok(rebuilt)
+}
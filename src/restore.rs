@@ -0,0 +1,260 @@
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    GetObjectError, GetObjectRequest, GlacierJobParameters, HeadObjectError, HeadObjectRequest,
+    RestoreObjectError, RestoreObjectRequest, RestoreRequest, S3,
+};
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::ObjectErrorContext;
+
+// How fast a restore from Glacier or Deep Archive should be, and so how much
+// it costs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RestoreTier {
+    Expedited,
+    Standard,
+    Bulk,
+}
+
+impl RestoreTier {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RestoreTier::Expedited => "Expedited",
+            RestoreTier::Standard => "Standard",
+            RestoreTier::Bulk => "Bulk",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RestoreOptions {
+    // How long the restored copy sticks around.
+    pub days: i64,
+    pub tier: RestoreTier,
+    // How often wait_for_restore checks on the restore, and how long it
+    // waits for it at most. Restores take minutes at best and up to two days.
+    pub poll_interval: Duration,
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        RestoreOptions {
+            days: 1,
+            tier: RestoreTier::Standard,
+            poll_interval: Duration::from_secs(60),
+            timeout: None,
+        }
+    }
+}
+
+// Where an object is at as far as reading it goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreStatus {
+    // In a storage class that can be read right away.
+    NotArchived,
+    // Archived with no restore going on: nothing can be read.
+    Archived,
+    InProgress,
+    // Can be read until the expiry date, as S3 gives it.
+    Restored { expiry_date: Option<String> },
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RestoreError {
+    HeadObject(RusotoError<HeadObjectError>),
+    RestoreObject(RusotoError<RestoreObjectError>),
+    // Reading failed as the object is archived. Whether a restore was
+    // requested on the way, see ReadOptions::restore.
+    Archived { restore_requested: bool },
+    // The restore didn't finish within the timeout of the options.
+    TimedOut,
+}
+
+impl Display for RestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestoreError::HeadObject(e) => write!(f, "Failed to head object: {}", e),
+            RestoreError::RestoreObject(e) => write!(f, "Failed to restore object: {}", e),
+            RestoreError::Archived {
+                restore_requested: true,
+            } => write!(f, "Object is archived, restore requested."),
+            RestoreError::Archived {
+                restore_requested: false,
+            } => write!(f, "Object is archived and has to be restored first."),
+            RestoreError::TimedOut => write!(f, "Timed out waiting for restore."),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RestoreError::HeadObject(e) => Some(e),
+            RestoreError::RestoreObject(e) => Some(e),
+            RestoreError::Archived { .. } | RestoreError::TimedOut => None,
+        }
+    }
+}
+
+// Whether reading failed because the object is archived, either straight
+// from S3 or from a SeekableS3Object.
+pub fn is_archived_error(error: &std::io::Error) -> bool {
+    let inner = match error.get_ref() {
+        Some(inner) => inner,
+        None => return false,
+    };
+    if let Some(context) = inner.downcast_ref::<ObjectErrorContext>() {
+        return is_archived_error(context.error());
+    }
+    if let Some(RestoreError::Archived { .. }) = inner.downcast_ref::<RestoreError>() {
+        return true;
+    }
+    matches!(
+        inner.downcast_ref::<RusotoError<GetObjectError>>(),
+        Some(RusotoError::Service(GetObjectError::InvalidObjectState(_)))
+    )
+}
+
+pub(crate) fn is_invalid_object_state(error: &RusotoError<GetObjectError>) -> bool {
+    matches!(
+        error,
+        RusotoError::Service(GetObjectError::InvalidObjectState(_))
+    )
+}
+
+// Works out the status from the storage class and x-amz-restore header of a
+// HeadObject response, e.g. ongoing-request="false", expiry-date="Fri, 21
+// Dec 2012 00:00:00 GMT".
+fn restore_status_of(storage_class: Option<&str>, restore: Option<&str>) -> RestoreStatus {
+    match restore {
+        Some(restore) if restore.contains("ongoing-request=\"true\"") => RestoreStatus::InProgress,
+        Some(restore) => {
+            let expiry_date = restore
+                .split_once("expiry-date=\"")
+                .and_then(|(_, rest)| rest.split_once('"'))
+                .map(|(date, _)| date.to_owned());
+            RestoreStatus::Restored { expiry_date }
+        }
+        None => match storage_class {
+            Some("GLACIER") | Some("DEEP_ARCHIVE") => RestoreStatus::Archived,
+            _ => RestoreStatus::NotArchived,
+        },
+    }
+}
+
+fn head_request(req: &GetObjectRequest) -> HeadObjectRequest {
+    HeadObjectRequest {
+        bucket: req.bucket.to_owned(),
+        key: req.key.to_owned(),
+        version_id: req.version_id.to_owned(),
+        request_payer: req.request_payer.to_owned(),
+        expected_bucket_owner: req.expected_bucket_owner.to_owned(),
+        ..Default::default()
+    }
+}
+
+// Finds out whether the object the request is for is archived, being
+// restored or restored.
+pub async fn restore_status<C: S3 + ?Sized>(
+    client: &C,
+    req: &GetObjectRequest,
+) -> Result<RestoreStatus, RestoreError> {
+    let head_object = traced!(
+        client.head_object(head_request(req)),
+        "head_object",
+        bucket = %req.bucket,
+        key = %req.key,
+    );
+    let output = head_object.await.map_err(RestoreError::HeadObject)?;
+    Ok(restore_status_of(
+        output.storage_class.as_deref(),
+        output.restore.as_deref(),
+    ))
+}
+
+// Asks S3 to restore the object the request is for. Asking again while a
+// restore is going on or for an object that isn't archived is fine.
+pub async fn request_restore<C: S3 + ?Sized>(
+    client: &C,
+    req: &GetObjectRequest,
+    options: &RestoreOptions,
+) -> Result<(), RestoreError> {
+    let restore_req = RestoreObjectRequest {
+        bucket: req.bucket.to_owned(),
+        key: req.key.to_owned(),
+        version_id: req.version_id.to_owned(),
+        request_payer: req.request_payer.to_owned(),
+        expected_bucket_owner: req.expected_bucket_owner.to_owned(),
+        restore_request: Some(RestoreRequest {
+            days: Some(options.days),
+            glacier_job_parameters: Some(GlacierJobParameters {
+                tier: options.tier.as_str().to_owned(),
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let restore_object = traced!(
+        client.restore_object(restore_req),
+        "restore_object",
+        bucket = %req.bucket,
+        key = %req.key,
+    );
+    match restore_object.await {
+        Ok(_) => Ok(()),
+        Err(RusotoError::Service(RestoreObjectError::ObjectAlreadyInActiveTierError(_))) => Ok(()),
+        // RestoreAlreadyInProgress.
+        Err(RusotoError::Unknown(response)) if response.status.as_u16() == 409 => Ok(()),
+        Err(e) => Err(RestoreError::RestoreObject(e)),
+    }
+}
+
+// Waits for a restore of the object to finish, checking on it as often as
+// the options say. Objects that aren't archived are ready right away, ones
+// with no restore going on never will be.
+pub async fn wait_for_restore<C: S3 + ?Sized>(
+    client: &C,
+    req: &GetObjectRequest,
+    options: &RestoreOptions,
+) -> Result<RestoreStatus, RestoreError> {
+    let started = Instant::now();
+    loop {
+        match restore_status(client, req).await? {
+            RestoreStatus::Archived => {
+                return Err(RestoreError::Archived {
+                    restore_requested: false,
+                })
+            }
+            RestoreStatus::InProgress => {}
+            status => return Ok(status),
+        }
+        let mut wait = options.poll_interval;
+        if let Some(timeout) = options.timeout {
+            let left = timeout.saturating_sub(started.elapsed());
+            if left == Duration::from_secs(0) {
+                return Err(RestoreError::TimedOut);
+            }
+            wait = wait.min(left);
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// Requests a restore of the object if it's archived and waits for it, see
+// wait_for_restore.
+pub async fn restore_and_wait<C: S3 + ?Sized>(
+    client: &C,
+    req: &GetObjectRequest,
+    options: &RestoreOptions,
+) -> Result<RestoreStatus, RestoreError> {
+    if restore_status(client, req).await? == RestoreStatus::Archived {
+        request_restore(client, req, options).await?;
+    }
+    wait_for_restore(client, req, options).await
+}
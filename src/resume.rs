@@ -0,0 +1,101 @@
+use futures::{Stream, TryStreamExt};
+use rusoto_core::RusotoError;
+use rusoto_s3::{CompletedPart, ListPartsError, ListPartsRequest, Part, UploadPartRequest, S3};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::upload_object::prepare_part_request;
+use crate::{upload_prepared_part, PartData, PreparedPart, RetryPolicy, UploadError};
+
+// Lists all the parts that made it to S3 for the given multipart upload.
+pub async fn list_uploaded_parts<C: S3>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<Vec<Part>, RusotoError<ListPartsError>> {
+    let mut parts = Vec::new();
+    let mut part_number_marker = None;
+    loop {
+        let req = ListPartsRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            part_number_marker,
+            ..Default::default()
+        };
+        let output = client.list_parts(req).await?;
+        parts.extend(output.parts.unwrap_or_default());
+        match (output.is_truncated, output.next_part_number_marker) {
+            (Some(true), Some(marker)) => part_number_marker = Some(marker),
+            _ => break Ok(parts),
+        }
+    }
+}
+
+// Whether the part that's already on S3 holds exactly the given data. The
+// ETag of a part is the MD5 of its data, except when the object is encrypted
+// with SSE-C or SSE-KMS: those parts never match and get uploaded again.
+fn part_matches(part: &Part, prepared: &PreparedPart) -> bool {
+    let size_matches = part
+        .size
+        .and_then(|size| u64::try_from(size).ok())
+        .map_or(false, |size| size == prepared.data.len());
+    let md5 = match (prepared.md5, &prepared.data) {
+        (Some(md5), _) => md5,
+        (None, PartData::Memory(data)) => md5::compute(data).0,
+        // Not worth reading the file back just for this.
+        (None, PartData::File(_)) => return false,
+    };
+    size_matches
+        && part.e_tag.as_deref().map_or(false, |e_tag| {
+            e_tag.trim_matches('"') == format!("{:x}", md5::Digest(md5))
+        })
+}
+
+// Continues an interrupted multipart upload. The stream has to produce the
+// same parts as the interrupted upload did: as compression is deterministic,
+// compressing the same input with the same settings does that. Parts already
+// on S3 (see list_uploaded_parts) that match what the stream produces are
+// skipped, everything else is uploaded. The completed parts are returned in
+// part number order, ready to be passed on to CompleteMultipartUpload.
+pub async fn resume_upload_parts<C, S, E>(
+    client: &C,
+    parts: S,
+    uploaded: &[Part],
+    concurrency: usize,
+    retry: &RetryPolicy,
+) -> Result<Vec<CompletedPart>, UploadError<E>>
+where
+    C: S3,
+    S: Stream<Item = Result<UploadPartRequest, UploadError<E>>>,
+{
+    let uploaded: HashMap<i64, &Part> = uploaded
+        .iter()
+        .filter_map(|part| part.part_number.map(|part_number| (part_number, part)))
+        .collect();
+    let uploaded = &uploaded;
+
+    let mut completed_parts: Vec<CompletedPart> = parts
+        .map_ok(|part| async move {
+            let (part_template, part) = prepare_part_request(part)
+                .await
+                .map_err(UploadError::UploadPart)?;
+            match uploaded.get(&part.part_number) {
+                Some(existing) if part_matches(existing, &part) => Ok(CompletedPart {
+                    e_tag: existing.e_tag.to_owned(),
+                    part_number: Some(part.part_number),
+                }),
+                _ => upload_prepared_part(client, &part_template, &part, retry)
+                    .await
+                    .map_err(UploadError::UploadPart),
+            }
+        })
+        // With no parts allowed in flight we'd never make any progress.
+        .try_buffer_unordered(std::cmp::max(concurrency, 1))
+        .try_collect()
+        .await?;
+    // Parts finish in whatever order S3 gets through them.
+    completed_parts.sort_by_key(|part| part.part_number);
+    Ok(completed_parts)
+}
@@ -0,0 +1,236 @@
+use rusoto_core::request::HttpDispatchError;
+use rusoto_core::RusotoError;
+use std::cell::Cell;
+use std::time::Duration;
+
+use crate::{timeout_kind, TimeoutKind};
+
+// How to retry requests that failed for reasons that might go away on their
+// own: dropped connections, throttling and internal S3 errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RetryPolicy {
+    // Total number of attempts, including the first one. 1 means no retries.
+    pub max_attempts: u32,
+    // How long to wait before the first retry. Each following retry waits
+    // twice as long as the previous one, up to max_backoff.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Wait a random amount of time between nothing and the backoff instead of
+    // the full backoff. This stops many clients that failed at the same time
+    // from retrying at the same time too.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(20),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Policy that never retries anything.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    // Whether we're allowed another go after the given number of failed
+    // attempts.
+    pub fn should_retry(&self, failed_attempts: u32) -> bool {
+        failed_attempts < self.max_attempts
+    }
+
+    // How long to wait after the given number of failed attempts.
+    pub fn backoff(&self, failed_attempts: u32) -> Duration {
+        let exponent = failed_attempts.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .checked_mul(1 << exponent)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff));
+        if self.jitter {
+            backoff.mul_f64(random_fraction())
+        } else {
+            backoff
+        }
+    }
+}
+
+// Whether the error is one that retrying the same request may fix.
+pub fn is_retryable<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        // We didn't manage to talk to S3 or the connection went away.
+        RusotoError::HttpDispatch(_) => true,
+        // Errors the S3 API doesn't model, which is where throttling and
+        // internal errors end up.
+        RusotoError::Unknown(response) => {
+            let status = response.status.as_u16();
+            status >= 500
+                || status == 429
+                || response
+                    .body
+                    .windows(REQUEST_TIMEOUT_CODE.len())
+                    .any(|window| window == REQUEST_TIMEOUT_CODE)
+        }
+        _ => false,
+    }
+}
+
+// Whether S3 is telling us to slow down: a 503 SlowDown or a 429.
+pub fn is_throttling<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::Unknown(response) => {
+            let status = response.status.as_u16();
+            status == 429
+                || (status == 503
+                    && response
+                        .body
+                        .windows(SLOW_DOWN_CODE.len())
+                        .any(|window| window == SLOW_DOWN_CODE))
+        }
+        _ => false,
+    }
+}
+
+// Whether S3 turned the request down because the credentials it was signed
+// with expired, as happens to long reads under an assumed role. Getting new
+// credentials and trying again fixes these.
+pub fn is_expired_credentials<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::Unknown(response) => {
+            let status = response.status.as_u16();
+            (status == 400 || status == 403)
+                && EXPIRED_CREDENTIALS_CODES.iter().any(|code| {
+                    response
+                        .body
+                        .windows(code.len())
+                        .any(|window| window == *code)
+                })
+        }
+        _ => false,
+    }
+}
+
+// Whether an error reading a response body is one that reading again from a
+// fresh request may fix. Errors from the HTTP client come as "other" errors,
+// as do plenty of ours that another go won't fix, so those only count when
+// they're the client's.
+pub fn is_retryable_io(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    // Another go would only run past the deadline further.
+    if timeout_kind(error) == Some(TimeoutKind::Deadline) {
+        return false;
+    }
+    match error.kind() {
+        ErrorKind::TimedOut
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof
+        | ErrorKind::Interrupted => true,
+        ErrorKind::Other => error.get_ref().map_or(false, is_http_client_error),
+        _ => false,
+    }
+}
+
+// rusoto hands on hyper's errors reading a body as strings starting with
+// this rather than as they are.
+const BODY_CHUNK_ERROR: &str = "Error obtaining chunk";
+
+fn is_http_client_error(error: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    error.downcast_ref::<hyper::Error>().is_some()
+        || error.downcast_ref::<HttpDispatchError>().is_some()
+        || error.downcast_ref::<RetryableResponse>().is_some()
+        || error.to_string().starts_with(BODY_CHUNK_ERROR)
+}
+
+// An error response that's worth another go, from requests we send ourselves
+// rather than through S3Client.
+#[derive(Debug)]
+pub(crate) struct RetryableResponse(pub(crate) String);
+
+impl std::fmt::Display for RetryableResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RetryableResponse {}
+
+// Readers retry Interrupted errors themselves, as std's readers do, so one
+// coming out of them is final and must not look like it's worth another go:
+// io::copy and friends would keep calling read forever.
+pub(crate) fn settle_interrupted(error: std::io::Error) -> std::io::Error {
+    if error.kind() == std::io::ErrorKind::Interrupted {
+        std::io::Error::new(std::io::ErrorKind::Other, error)
+    } else {
+        error
+    }
+}
+
+// S3 reports connections that were idle for too long in the middle of a
+// request as a 400 with this code. It's fine to retry these.
+const REQUEST_TIMEOUT_CODE: &[u8] = b"<Code>RequestTimeout</Code>";
+
+const SLOW_DOWN_CODE: &[u8] = b"<Code>SlowDown</Code>";
+
+const EXPIRED_CREDENTIALS_CODES: &[&[u8]] = &[
+    b"<Code>ExpiredToken</Code>",
+    b"<Code>TokenRefreshRequired</Code>",
+    b"<Code>RequestExpired</Code>",
+];
+
+// Spaces out a series of requests while S3 is throttling them. Every
+// throttled request doubles the delay before each following request, between
+// the policy's initial_backoff and max_backoff, and every successful one
+// halves it until it's gone.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    delay: Cell<Duration>,
+}
+
+impl Throttle {
+    // How long to wait before the next request, jittered so that requests
+    // made together don't all go out together again.
+    pub(crate) fn delay(&self) -> Duration {
+        self.delay.get().mul_f64(random_fraction() / 2.0 + 0.5)
+    }
+
+    pub(crate) fn throttled(&self, retry: &RetryPolicy) {
+        let delay = self
+            .delay
+            .get()
+            .saturating_mul(2)
+            .max(retry.initial_backoff);
+        self.delay.set(delay.min(retry.max_backoff));
+    }
+
+    pub(crate) fn succeeded(&self, retry: &RetryPolicy) {
+        let delay = self.delay.get() / 2;
+        self.delay.set(if delay < retry.initial_backoff / 2 {
+            Duration::from_secs(0)
+        } else {
+            delay
+        });
+    }
+}
+
+// Random number in [0, 1). We don't need anything good, just something that
+// differs between calls and processes. RandomState is seeded randomly for
+// every instance which is plenty for that.
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,198 @@
+use bytes::Bytes;
+use rusoto_s3::{GetObjectRequest, S3};
+use std::ops::Range;
+use std::sync::Arc;
+use zstd_seekable::DStream;
+
+use crate::decompress::decompress_frame;
+use crate::{fetch_object_index, fetch_ranges, FetchRangesOptions, FrameEntry, ReadObjectError};
+
+// How much compressed data scan_object holds in memory at once.
+const SCAN_BATCH_SIZE: u64 = 64 * 1024 * 1024;
+
+// A record the predicate picked, without its delimiter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanMatch {
+    // Where the record starts in the decompressed data.
+    pub offset: u64,
+    pub record: Bytes,
+}
+
+// What a frame holds of the scanned range, split up on its own: the records
+// wholly inside it are already through the predicate, the ones running into
+// the frames around it are left for scan_object to put together.
+struct ScannedFrame {
+    // Decompressed offset of the data.
+    offset: u64,
+    data: Bytes,
+    // Where the first delimiter is and where the data after the last one
+    // starts, if there's a delimiter at all.
+    delimiters: Option<(usize, usize)>,
+    matches: Vec<ScanMatch>,
+}
+
+fn scan_frame<F>(
+    entry: &FrameEntry,
+    compressed: &[u8],
+    range: &Range<u64>,
+    delimiter: u8,
+    predicate: &F,
+) -> std::io::Result<ScannedFrame>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let data = DStream::new()
+        .ok()
+        .and_then(|mut dstream| decompress_frame(&mut dstream, compressed))
+        .filter(|data| data.len() as u64 == entry.decompressed_size)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Frame doesn't decompress to its size in the seek table.",
+            )
+        })?;
+    let from = range.start.saturating_sub(entry.decompressed_offset) as usize;
+    let to = (std::cmp::min(
+        range.end,
+        entry.decompressed_offset + entry.decompressed_size,
+    ) - entry.decompressed_offset) as usize;
+    let offset = entry.decompressed_offset + from as u64;
+    let data = Bytes::from(data).slice(from..to);
+
+    let first = data.iter().position(|&b| b == delimiter);
+    let last = data.iter().rposition(|&b| b == delimiter);
+    let mut matches = Vec::new();
+    let delimiters = match (first, last) {
+        (Some(first), Some(last)) => {
+            if first < last {
+                let mut start = first + 1;
+                for record in data[start..last].split(|&b| b == delimiter) {
+                    let end = start + record.len();
+                    if predicate(record) {
+                        matches.push(ScanMatch {
+                            offset: offset + start as u64,
+                            record: data.slice(start..end),
+                        });
+                    }
+                    start = end + 1;
+                }
+            }
+            Some((first, last + 1))
+        }
+        _ => None,
+    };
+    Ok(ScannedFrame {
+        offset,
+        data,
+        delimiters,
+        matches,
+    })
+}
+
+// Goes through the records in the given range of the decompressed data of an
+// object, records being whatever is between delimiters, and returns the ones
+// the predicate picks along with where they start. The range should start at
+// a record, see RecordIndex::nearest; whatever is after the last delimiter in
+// it counts as a record of its own. Frames are fetched in batches, with as
+// many requests in flight as the options allow, and decompressed and scanned
+// on tokio's blocking threads.
+pub async fn scan_object<C, F>(
+    client: &C,
+    req: &GetObjectRequest,
+    range: Range<u64>,
+    delimiter: u8,
+    predicate: F,
+    options: &FetchRangesOptions,
+) -> Result<Vec<ScanMatch>, ReadObjectError>
+where
+    C: S3,
+    F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+{
+    let object_index = fetch_object_index(client, req).await?;
+    let frames: Vec<FrameEntry> = object_index
+        .index
+        .frames()
+        .iter()
+        .copied()
+        .filter(|entry| {
+            entry.decompressed_size > 0
+                && entry.decompressed_offset < range.end
+                && range.start < entry.decompressed_offset + entry.decompressed_size
+        })
+        .collect();
+    let predicate = Arc::new(predicate);
+    let range = Arc::new(range);
+    let mut matches = Vec::new();
+    // The record the last frame ended in the middle of.
+    let mut pending: Option<(u64, Vec<u8>)> = None;
+    let consider = |offset: u64, record: Vec<u8>, matches: &mut Vec<ScanMatch>| {
+        if (*predicate)(&record) {
+            matches.push(ScanMatch {
+                offset,
+                record: Bytes::from(record),
+            });
+        }
+    };
+    let mut start = 0;
+    while start < frames.len() {
+        let mut end = start;
+        let mut batch_size = 0;
+        while end < frames.len() && (end == start || batch_size < SCAN_BATCH_SIZE) {
+            batch_size += frames[end].compressed_size;
+            end += 1;
+        }
+        let ranges = frames[start..end]
+            .iter()
+            .map(|entry| {
+                let range =
+                    entry.compressed_offset..entry.compressed_offset + entry.compressed_size;
+                (*entry, range)
+            })
+            .collect();
+        let fetched = fetch_ranges(client, req, ranges, options).await?;
+        let scans = fetched.into_iter().map(|fetched| {
+            let range = range.clone();
+            let predicate = predicate.clone();
+            tokio::task::spawn_blocking(move || {
+                scan_frame(
+                    &fetched.label,
+                    &fetched.data,
+                    &range,
+                    delimiter,
+                    predicate.as_ref(),
+                )
+            })
+        });
+        for scan in futures::future::join_all(scans).await {
+            let frame = scan
+                .map_err(|e| {
+                    ReadObjectError::ReadBody(std::io::Error::new(std::io::ErrorKind::Other, e))
+                })?
+                .map_err(ReadObjectError::ReadBody)?;
+            match frame.delimiters {
+                None => {
+                    let (_, record) = pending.get_or_insert_with(|| (frame.offset, Vec::new()));
+                    record.extend_from_slice(&frame.data);
+                }
+                Some((first, after_last)) => {
+                    let (offset, mut record) =
+                        pending.take().unwrap_or_else(|| (frame.offset, Vec::new()));
+                    record.extend_from_slice(&frame.data[..first]);
+                    consider(offset, record, &mut matches);
+                    matches.extend(frame.matches);
+                    if after_last < frame.data.len() {
+                        pending = Some((
+                            frame.offset + after_last as u64,
+                            frame.data[after_last..].to_vec(),
+                        ));
+                    }
+                }
+            }
+        }
+        start = end;
+    }
+    if let Some((offset, record)) = pending {
+        consider(offset, record, &mut matches);
+    }
+    Ok(matches)
+}
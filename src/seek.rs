@@ -0,0 +1,36 @@
+use std::io::{Error, ErrorKind, SeekFrom};
+
+// Where a seek from the given position in data of the given length lands.
+// Seeks before the start or past u64::MAX fail, as they do for std's files
+// and cursors, and leave the position as it was. Seeks past the end, be it
+// from the start, from the current position or with a positive offset from
+// the end, are fine unless strict: reads from there read nothing. Strict
+// seeks fail rather than go past the end.
+pub(crate) fn seek_position(
+    pos: SeekFrom,
+    position: u64,
+    length: u64,
+    strict: bool,
+) -> std::io::Result<u64> {
+    let (base_pos, offset) = match pos {
+        SeekFrom::Start(pos) => (pos, 0),
+        SeekFrom::End(pos) => (length, pos),
+        SeekFrom::Current(pos) => (position, pos),
+    };
+    let new_pos = if offset >= 0 {
+        base_pos.checked_add(offset as u64)
+    } else {
+        base_pos.checked_sub((offset.wrapping_neg()) as u64)
+    };
+    match new_pos {
+        Some(n) if strict && n > length => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid seek to {}, past the end at {}", n, length),
+        )),
+        Some(n) => Ok(n),
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )),
+    }
+}
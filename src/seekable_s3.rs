@@ -1,11 +1,247 @@
-use futures::TryFutureExt;
+use bytes::Bytes;
+use futures::future::{self, Either};
+use futures::{StreamExt, TryFutureExt, TryStreamExt};
 use rusoto_core::RusotoError;
-use rusoto_s3::{GetObjectError, GetObjectRequest, S3Client, S3};
+use rusoto_s3::{
+    GetObjectError, GetObjectOutput, GetObjectRequest, HeadObjectError, HeadObjectOutput,
+    HeadObjectRequest, S3Client, S3,
+};
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io::{Error, ErrorKind, Read, Seek};
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncRead;
 use tokio::io::AsyncReadExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::block_cache::BlockCache;
+use crate::blocking::{block_on, block_on_timeout};
+use crate::content_range::total_size;
+use crate::object_index::read_body;
+use crate::restore::is_invalid_object_state;
+use crate::retry::{settle_interrupted, Throttle};
+use crate::seek::seek_position;
+use crate::{is_expired_credentials, is_retryable, is_retryable_io, is_throttling};
+use crate::{request_restore, wait_for_restore, RestoreError, RestoreOptions, RestoreStatus};
+use crate::{AsS3, AsyncContextError, CredentialsRefresh, DiskCache, DynS3, RetryPolicy};
+use crate::{TimeoutError, TimeoutKind};
+
+// Requests S3 throttled are tried this many times even if the retry policy
+// would give up sooner. Throttling slows down everything after it (see
+// Throttle) so it usually passes after a few goes.
+const THROTTLED_ATTEMPTS: u32 = 5;
+
+// Bodies breaking off partway through happens every so often on long reads
+// and is nearly always fixed by asking for the rest again.
+const DEFAULT_MAX_RESUMES: u32 = 3;
+
+// S3 closes connections that sit idle for about 20 seconds.
+const DEFAULT_MAX_BODY_IDLE: Duration = Duration::from_secs(15);
+
+// How many HeadObject requests to have in flight when finding out where the
+// parts of an object are.
+const PART_LAYOUT_CONCURRENCY: usize = 16;
+
+// Counts of what reading an object took so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadMetrics {
+    // GetObject requests made, including the initial one and retries.
+    pub requests: u64,
+    // Data received from S3, including any that was skipped over.
+    pub bytes_downloaded: u64,
+    // Failed reads that were tried again.
+    pub retries: u64,
+    // Bodies that broke off and were requested again from where they did.
+    pub resumes: u64,
+    // Requests sent again because the first one took too long.
+    pub hedged_requests: u64,
+    // Blocks served from memory and from the disk cache without a request.
+    pub cache_hits: u64,
+    pub disk_cache_hits: u64,
+    // Time spent waiting on S3, both for responses and for the body.
+    pub time_blocked: Duration,
+}
+
+// How to read an object, see the setters on SeekableS3Object for what each of
+// these does. Throttled requests are retried even when retry says not to,
+// see set_retry_policy.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ReadOptions {
+    pub read_timeout: Option<std::time::Duration>,
+    pub retry: RetryPolicy,
+    pub max_skip: u64,
+    pub max_request_size: Option<u64>,
+    pub min_throughput: Option<MinThroughput>,
+    pub max_resumes: u32,
+    pub max_body_idle: Option<Duration>,
+    pub hedge_after: Option<Duration>,
+    pub max_concurrent_requests: Option<usize>,
+    pub min_fetch_size: Option<u64>,
+    pub block_cache_size: u64,
+    pub prefetch: usize,
+    // Caches and callbacks are left out of config files.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub disk_cache: Option<DiskCache>,
+    // Read this version of the object rather than the one in the request.
+    pub version_id: Option<String>,
+    // Request a restore when a read fails because the object is archived,
+    // see SeekableS3Object::wait_for_restore. Only lazy objects get that far:
+    // with_options fails on an archived object straight away.
+    pub restore: Option<RestoreOptions>,
+    // Get new credentials and try again, once, when S3 says the ones a
+    // request was signed with expired. This doesn't count as an attempt.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub refresh_credentials: Option<CredentialsRefresh>,
+    pub strict_seeks: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            read_timeout: None,
+            retry: RetryPolicy::none(),
+            max_skip: 0,
+            max_request_size: None,
+            min_throughput: None,
+            max_resumes: DEFAULT_MAX_RESUMES,
+            max_body_idle: Some(DEFAULT_MAX_BODY_IDLE),
+            hedge_after: None,
+            max_concurrent_requests: None,
+            min_fetch_size: None,
+            block_cache_size: 0,
+            prefetch: 0,
+            disk_cache: None,
+            version_id: None,
+            restore: None,
+            refresh_credentials: None,
+            strict_seeks: false,
+        }
+    }
+}
+
+// Slowest a body is allowed to send data, see
+// SeekableS3Object::set_min_throughput.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MinThroughput {
+    pub bytes_per_second: u64,
+    // How long the body gets to make up for slow stretches.
+    pub window: Duration,
+}
+
+// How much a body sent since the current watchdog window started.
+#[derive(Debug, Clone, Copy)]
+struct BodyWatch {
+    started: Instant,
+    bytes: u64,
+}
+
+// Part of the object we fetch and cache as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockSpan {
+    start: u64,
+    end: u64,
+    // Set when the block is a part we get by its number.
+    part_number: Option<i64>,
+}
+
+// What S3 told us about the object when we first got it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectMetadata {
+    pub e_tag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub storage_class: Option<String>,
+    pub version_id: Option<String>,
+    // User metadata, without the x-amz-meta- prefix.
+    pub metadata: HashMap<String, String>,
+}
+
+// Why getting an object to read failed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum GetSeekableObjectError {
+    GetObject(RusotoError<GetObjectError>),
+    // The initial request took longer than the read timeout.
+    TimedOut(TimeoutError),
+    AsyncContext(AsyncContextError),
+}
+
+impl std::fmt::Display for GetSeekableObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GetSeekableObjectError::GetObject(e) => write!(f, "Failed to get object: {}", e),
+            GetSeekableObjectError::TimedOut(e) => write!(f, "Timed out getting object: {}", e),
+            GetSeekableObjectError::AsyncContext(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for GetSeekableObjectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GetSeekableObjectError::GetObject(e) => Some(e),
+            GetSeekableObjectError::TimedOut(e) => Some(e),
+            GetSeekableObjectError::AsyncContext(e) => Some(e),
+        }
+    }
+}
+
+// What a SeekableS3Object was up to when it failed. Errors coming out of
+// reading an object carry this as their inner error, so
+// io::Error::get_ref can downcast to it, with the error as it was before
+// that as its source.
+#[derive(Debug)]
+pub struct ObjectErrorContext {
+    pub bucket: String,
+    pub key: String,
+    // Of the whole object, not of the window, that the failed request asked
+    // for. None when no data was being asked for, as when opening a lazily
+    // created object.
+    pub range: Option<std::ops::Range<u64>>,
+    // How many attempts were made, retries included.
+    pub attempts: u32,
+    error: Error,
+}
+
+impl ObjectErrorContext {
+    // The error the last attempt failed with.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    pub fn into_error(self) -> Error {
+        self.error
+    }
+}
+
+impl std::fmt::Display for ObjectErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)?;
+        if let Some(range) = &self.range {
+            write!(f, " bytes {}-{}", range.start, range.end.saturating_sub(1))?;
+        }
+        write!(
+            f,
+            " failed after {} attempt{}: {}",
+            self.attempts,
+            if self.attempts == 1 { "" } else { "s" },
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for ObjectErrorContext {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
 
 pub struct SeekableS3Object<'a, A> {
     client: A,
@@ -13,10 +249,121 @@ pub struct SeekableS3Object<'a, A> {
     position: u64,
     // Updated when we first read the object.
     length: u64,
+    // Where the part of the object we read starts and how big the whole
+    // object is, see window. Positions and length are within the window.
+    window_start: u64,
+    object_length: u64,
     body: Option<Pin<Box<dyn AsyncRead + Send>>>,
+    // Where in the object the body is at. This is behind position when we
+    // seeked forward and are going to skip over data in the body.
+    body_position: u64,
+    // Where the body we asked for ends.
+    body_end: u64,
+    // When we last got anything from the body, and how long we let it sit
+    // before getting a new one.
+    body_used: Option<Instant>,
+    max_body_idle: Option<Duration>,
+    // How many times in a row to request the rest of a body again when it
+    // ends early or the connection drops, and how many times we did so far.
+    max_resumes: u32,
+    resumes: u32,
+    // How far forward we skip within the body rather than making a new
+    // request.
+    max_skip: u64,
+    // Ask for at most this much of the object per request.
+    max_request_size: Option<u64>,
+    // Watchdog of the body, if it has to keep up a minimum throughput.
+    min_throughput: Option<MinThroughput>,
+    watch: Option<BodyWatch>,
     runtime: &'a tokio::runtime::Runtime,
     // Limit reads to this amount of time.
     read_timeout: Option<std::time::Duration>,
+    // Give up on reading altogether once it's passed, see set_deadline.
+    deadline: Option<Instant>,
+    // How to retry failed requests and reads of the body.
+    retry: RetryPolicy,
+    // Slows down our requests while S3 is throttling them.
+    throttle: Throttle,
+    // Send a request again when it didn't get a response in this long.
+    hedge_after: Option<Duration>,
+    // Shared by clones, see set_max_concurrent_requests.
+    request_limit: Option<Arc<Semaphore>>,
+    // If set, fetch the object in blocks of this size rather than streaming
+    // it from wherever we're reading.
+    min_fetch_size: Option<u64>,
+    // Blocks we fetched recently.
+    blocks: BlockCache,
+    // How many blocks to fetch ahead when reading blocks in order, and the
+    // ones we fetched that haven't been read yet.
+    prefetch: usize,
+    prefetched: HashMap<u64, Bytes>,
+    // Block we read last, to tell whether we're reading in order.
+    last_block: Option<BlockSpan>,
+    // Where each part of the object starts, when reading it by part number.
+    parts: Option<Vec<u64>>,
+    // Blocks fetched by anyone using the same cache.
+    disk_cache: Option<DiskCache>,
+    // From the initial request. The ETag also tells apart different versions
+    // of the object in the disk cache.
+    metadata: ObjectMetadata,
+    // Behind a Cell as requests are made through &self.
+    metrics: Cell<ReadMetrics>,
+    // Whether we know the length and metadata of the object yet, see lazy.
+    opened: bool,
+    // How to restore the object if it turns out to be archived.
+    restore: Option<RestoreOptions>,
+    refresh_credentials: Option<CredentialsRefresh>,
+    // Whether seeking past the end fails, see set_strict_seeks.
+    strict_seeks: bool,
+}
+
+// Clones start out at the same position with the same settings and cached
+// blocks, but read independently of each other and of the original: each
+// makes its own requests and keeps its own metrics. Give each thread reading
+// the object a clone of its own.
+impl<A: Clone> Clone for SeekableS3Object<'_, A> {
+    fn clone(&self) -> Self {
+        SeekableS3Object {
+            client: self.client.clone(),
+            req: self.req.to_owned(),
+            position: self.position,
+            length: self.length,
+            window_start: self.window_start,
+            object_length: self.object_length,
+            // Bodies can't be shared, the clone gets one once it reads.
+            body: None,
+            body_position: self.position,
+            body_end: self.position,
+            body_used: None,
+            max_body_idle: self.max_body_idle,
+            max_resumes: self.max_resumes,
+            resumes: 0,
+            max_skip: self.max_skip,
+            max_request_size: self.max_request_size,
+            min_throughput: self.min_throughput,
+            watch: None,
+            runtime: self.runtime,
+            read_timeout: self.read_timeout,
+            deadline: self.deadline,
+            retry: self.retry.to_owned(),
+            throttle: Throttle::default(),
+            hedge_after: self.hedge_after,
+            request_limit: self.request_limit.clone(),
+            min_fetch_size: self.min_fetch_size,
+            blocks: self.blocks.clone(),
+            prefetch: self.prefetch,
+            prefetched: self.prefetched.clone(),
+            last_block: self.last_block,
+            parts: self.parts.clone(),
+            disk_cache: self.disk_cache.clone(),
+            metadata: self.metadata.to_owned(),
+            metrics: Cell::new(ReadMetrics::default()),
+            opened: self.opened,
+            restore: self.restore.to_owned(),
+            refresh_credentials: self.refresh_credentials.clone(),
+            strict_seeks: self.strict_seeks,
+        }
+    }
 }
 
 impl<A: std::fmt::Debug> std::fmt::Debug for SeekableS3Object<'_, A> {
@@ -26,7 +373,14 @@ impl<A: std::fmt::Debug> std::fmt::Debug for SeekableS3Object<'_, A> {
             .field("req", &self.req)
             .field("position", &self.position)
             .field("length", &self.length)
+            .field("window_start", &self.window_start)
             .field("runtime", &self.runtime)
+            .field("retry", &self.retry)
+            .field("min_fetch_size", &self.min_fetch_size)
+            .field("max_skip", &self.max_skip)
+            .field("max_request_size", &self.max_request_size)
+            .field("prefetch", &self.prefetch)
+            .field("metrics", &self.metrics.get())
             .finish()
     }
 }
@@ -36,29 +390,59 @@ impl<'a, A> SeekableS3Object<'a, A> {
         client: A,
         runtime: &'a tokio::runtime::Runtime,
         read_timeout: Option<std::time::Duration>,
+        req: GetObjectRequest,
+    ) -> Result<Self, GetSeekableObjectError>
+    where
+        A: AsS3,
+    {
+        let options = ReadOptions {
+            read_timeout,
+            ..Default::default()
+        };
+        Self::with_options(client, runtime, req, &options)
+    }
+
+    // Like new, with all the settings in one place rather than set one by one
+    // afterwards. The read timeout already applies to the initial request.
+    pub fn with_options(
+        client: A,
+        runtime: &'a tokio::runtime::Runtime,
         mut req: GetObjectRequest,
-    ) -> Result<Result<Self, RusotoError<GetObjectError>>, tokio::time::error::Elapsed>
+        options: &ReadOptions,
+    ) -> Result<Self, GetSeekableObjectError>
     where
-        A: S3,
+        A: AsS3,
     {
+        let read_timeout = options.read_timeout;
+        let min_fetch_size = options.min_fetch_size.filter(|&size| size > 0);
+        if let Some(version_id) = &options.version_id {
+            req.version_id = Some(version_id.to_owned());
+        }
         // If for some reason we get request with range field filled, get rid of
         // it or we will end up with the wrong content length returned.
         // Alternatively we may want to use HeadObject request instead.
         req.range = None;
-        let get_object = client.get_object(req.to_owned());
+        let get_object = traced!(
+            client.as_s3().get_object(req.to_owned()),
+            "get_object",
+            bucket = %req.bucket,
+            key = %req.key,
+        );
 
+        let started = Instant::now();
         let object = match read_timeout {
-            Some(timeout) => {
-                let _executor = runtime.enter();
-                runtime.block_on(tokio::time::timeout(timeout, get_object))?
-            }
-            None => runtime.block_on(get_object),
+            Some(timeout) => block_on_timeout(runtime, timeout, get_object)
+                .map_err(GetSeekableObjectError::AsyncContext)?
+                .map_err(|_| {
+                    GetSeekableObjectError::TimedOut(TimeoutError::new(
+                        TimeoutKind::Response,
+                        timeout,
+                    ))
+                })?,
+            None => block_on(runtime, get_object).map_err(GetSeekableObjectError::AsyncContext)?,
         };
 
-        let object = match object {
-            Ok(o) => o,
-            Err(err) => return Ok(Err(err)),
-        };
+        let object = object.map_err(GetSeekableObjectError::GetObject)?;
 
         let body = object
             .body
@@ -68,34 +452,161 @@ impl<'a, A> SeekableS3Object<'a, A> {
             // found enum `Option<Box<impl std::marker::Send+Sync+tokio::io::AsyncRead>>`
             //
             // https://stackoverflow.com/questions/61259521/struct-with-boxed-impl-trait
-            .map(|bs| Box::pin(bs.into_async_read()) as Pin<Box<dyn AsyncRead + Send>>);
+            .map(|bs| Box::pin(bs.into_async_read()) as Pin<Box<dyn AsyncRead + Send>>)
+            // Reading in blocks never uses the body.
+            .filter(|_| min_fetch_size.is_none());
 
+        let metrics = ReadMetrics {
+            requests: 1,
+            time_blocked: started.elapsed(),
+            ..Default::default()
+        };
+        let metadata = ObjectMetadata {
+            e_tag: object.e_tag,
+            last_modified: object.last_modified,
+            content_type: object.content_type,
+            content_encoding: object.content_encoding,
+            storage_class: object.storage_class,
+            version_id: object.version_id,
+            metadata: object.metadata.unwrap_or_default(),
+        };
         let length = match object.content_length {
             None => {
-                return Ok(Err(RusotoError::Validation(
+                return Err(GetSeekableObjectError::GetObject(RusotoError::Validation(
                     "Content length not set in response.".to_owned(),
                 )))
             }
             Some(length) => match u64::try_from(length) {
                 Ok(length) => length,
                 Err(_e) => {
-                    return Ok(Err(RusotoError::Validation(format!(
-                        "Content length didn't fit into a u64, got {}",
-                        length
-                    ))))
+                    return Err(GetSeekableObjectError::GetObject(RusotoError::Validation(
+                        format!("Content length didn't fit into a u64, got {}", length),
+                    )))
                 }
             },
         };
+        log_event!(
+            debug,
+            target: "zstd_seekable_s3::open",
+            bucket = %req.bucket,
+            key = %req.key,
+            length,
+            "Opened object"
+        );
 
-        Ok(Ok(SeekableS3Object {
+        Ok(SeekableS3Object {
             client,
             req,
             position: 0,
             length,
+            window_start: 0,
+            object_length: length,
             body,
+            body_position: 0,
+            body_end: length,
+            body_used: Some(Instant::now()),
+            max_body_idle: options.max_body_idle,
+            max_resumes: options.max_resumes,
+            resumes: 0,
+            max_skip: options.max_skip,
+            max_request_size: options.max_request_size.filter(|&size| size > 0),
+            min_throughput: options
+                .min_throughput
+                .filter(|min| min.window > Duration::from_secs(0)),
+            watch: None,
             runtime,
             read_timeout,
-        }))
+            deadline: None,
+            retry: options.retry.to_owned(),
+            throttle: Throttle::default(),
+            hedge_after: options.hedge_after,
+            request_limit: options.max_concurrent_requests.map(request_limit),
+            min_fetch_size,
+            blocks: BlockCache::new(options.block_cache_size),
+            prefetch: options.prefetch,
+            prefetched: HashMap::new(),
+            last_block: None,
+            parts: None,
+            disk_cache: options.disk_cache.to_owned(),
+            metadata,
+            metrics: Cell::new(metrics),
+            opened: true,
+            restore: options.restore.to_owned(),
+            refresh_credentials: options.refresh_credentials.clone(),
+            strict_seeks: options.strict_seeks,
+        })
+    }
+
+    // Like with_options, without making any requests until the object is
+    // first read from or seeked from its end. Creating lots of objects up
+    // front, most of which are going to be seeked somewhere else first
+    // thing, then doesn't open lots of bodies that are never read. Finding
+    // out the size and metadata then takes a HeadObject request rather than
+    // a GetObject. Until then the object is empty and has no metadata: use
+    // open to make the request early.
+    pub fn lazy(
+        client: A,
+        runtime: &'a tokio::runtime::Runtime,
+        mut req: GetObjectRequest,
+        options: &ReadOptions,
+    ) -> Self {
+        if let Some(version_id) = &options.version_id {
+            req.version_id = Some(version_id.to_owned());
+        }
+        req.range = None;
+        SeekableS3Object {
+            client,
+            req,
+            position: 0,
+            length: 0,
+            window_start: 0,
+            object_length: 0,
+            body: None,
+            body_position: 0,
+            body_end: 0,
+            body_used: None,
+            max_body_idle: options.max_body_idle,
+            max_resumes: options.max_resumes,
+            resumes: 0,
+            max_skip: options.max_skip,
+            max_request_size: options.max_request_size.filter(|&size| size > 0),
+            min_throughput: options
+                .min_throughput
+                .filter(|min| min.window > Duration::from_secs(0)),
+            watch: None,
+            runtime,
+            read_timeout: options.read_timeout,
+            deadline: None,
+            retry: options.retry.to_owned(),
+            throttle: Throttle::default(),
+            hedge_after: options.hedge_after,
+            request_limit: options.max_concurrent_requests.map(request_limit),
+            min_fetch_size: options.min_fetch_size.filter(|&size| size > 0),
+            blocks: BlockCache::new(options.block_cache_size),
+            prefetch: options.prefetch,
+            prefetched: HashMap::new(),
+            last_block: None,
+            parts: None,
+            disk_cache: options.disk_cache.to_owned(),
+            metadata: ObjectMetadata::default(),
+            metrics: Cell::new(ReadMetrics::default()),
+            opened: false,
+            restore: options.restore.to_owned(),
+            refresh_credentials: options.refresh_credentials.clone(),
+            strict_seeks: options.strict_seeks,
+        }
+    }
+
+    // Makes the request a lazily created object put off, if it wasn't made
+    // yet. Does nothing for other objects.
+    pub fn open(&mut self) -> std::io::Result<()>
+    where
+        A: AsS3,
+    {
+        if self.opened {
+            return Ok(());
+        }
+        self.with_retries(Self::open_once)
     }
 
     // Sets current position. If the position actually changes, invalidates the
@@ -104,39 +615,230 @@ impl<'a, A> SeekableS3Object<'a, A> {
     // You should only use this if you're not consuming the body. If the body is
     // being consumed, you just want to update the position directly based on
     // how much you've consumed.
+    //
+    // Small enough hops forward keep the body around: it's cheaper to read and
+    // throw away what we skipped than to make a new request.
     fn set_position(&mut self, new_position: u64) {
         if self.position != new_position {
             self.position = new_position;
-            self.body = None;
+            let keep_body = new_position >= self.body_position
+                && new_position - self.body_position <= self.max_skip;
+            if !keep_body && self.body.is_some() {
+                log_event!(
+                    debug,
+                    target: "zstd_seekable_s3::fetch",
+                    bucket = %self.req.bucket,
+                    key = %self.req.key,
+                    body_position = self.body_position,
+                    position = new_position,
+                    "Dropped body after seeking away from it"
+                );
+                self.body = None;
+            }
         }
     }
 
-    // Reads some data from the body while remebering to update the position.
+    // Reads some data from the body while remebering to update the position of
+    // the body.
     fn read_body(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if let Some(body) = &mut self.body {
-            let bytes_read = match self.read_timeout {
-                Some(timeout) => {
-                    let _executor = self.runtime.enter();
-                    match self
-                        .runtime
-                        .block_on(tokio::time::timeout(timeout, body.read(buf)))
-                    {
-                        Ok(r) => r,
-                        Err(timeout_err) => Err(Error::new(ErrorKind::TimedOut, timeout_err)),
+        loop {
+            // The watchdog has to check on the body by the end of each window,
+            // so don't wait past then.
+            let watch_deadline = self.min_throughput.map(|min_throughput| {
+                let watch = self.watch.get_or_insert_with(|| BodyWatch {
+                    started: Instant::now(),
+                    bytes: 0,
+                });
+                watch.started + min_throughput.window
+            });
+            let watch_wait =
+                watch_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let limit = self.wait_limit(TimeoutKind::BodyRead);
+            let watchdog_first = match (watch_wait, limit) {
+                (Some(watch_wait), Some((limit, _))) => watch_wait < limit,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            let body = match &mut self.body {
+                Some(body) => body,
+                // No body.
+                None => return Ok(0),
+            };
+            let started = Instant::now();
+            // None when we stopped waiting for the watchdog.
+            let timeout = match watch_wait.filter(|_| watchdog_first) {
+                Some(watch_wait) => Some(watch_wait),
+                None => limit.map(|(limit, _)| limit),
+            };
+            let bytes_read = match timeout {
+                Some(timeout) => match block_on_timeout(self.runtime, timeout, body.read(buf))? {
+                    Ok(r) => r.map(Some),
+                    Err(_) if watchdog_first => Ok(None),
+                    Err(_) => {
+                        let kind = limit.map_or(TimeoutKind::BodyRead, |(_, kind)| kind);
+                        Err(TimeoutError::new(kind, timeout).into_io())
                     }
-                }
+                },
 
-                None => self.runtime.block_on(body.read(buf)),
-            }?;
-            // If we managed to read something, make sure to update position.
-            // This saves us work if we something calls seek into the new
-            // position.
-            self.position += bytes_read as u64;
-            Ok(bytes_read)
-        } else {
-            // No body.
-            Ok(0)
+                None => block_on(self.runtime, body.read(buf))?.map(Some),
+            };
+            let elapsed = started.elapsed();
+            self.record(|metrics| metrics.time_blocked += elapsed);
+            let bytes_read = bytes_read?;
+            if let Some(bytes_read) = bytes_read {
+                self.record(|metrics| metrics.bytes_downloaded += bytes_read as u64);
+                // If we managed to read something, make sure to update
+                // position. This saves us work if we something calls seek
+                // into the new position.
+                self.body_position += bytes_read as u64;
+                self.body_used = Some(Instant::now());
+            }
+            self.check_throughput(bytes_read.unwrap_or(0))?;
+            if let Some(bytes_read) = bytes_read {
+                break Ok(bytes_read);
+            }
+        }
+    }
+
+    // Counts the bytes towards the current watchdog window and fails if the
+    // window is over and the body didn't keep up.
+    fn check_throughput(&mut self, bytes_read: usize) -> std::io::Result<()> {
+        let (min_throughput, watch) = match (self.min_throughput, &mut self.watch) {
+            (Some(min_throughput), Some(watch)) => (min_throughput, watch),
+            _ => return Ok(()),
+        };
+        watch.bytes += bytes_read as u64;
+        let elapsed = watch.started.elapsed();
+        if elapsed < min_throughput.window {
+            return Ok(());
+        }
+        let expected = elapsed.as_secs_f64() * min_throughput.bytes_per_second as f64;
+        if (watch.bytes as f64) < expected {
+            return Err(TimeoutError::new(TimeoutKind::Stalled, elapsed).into_io());
+        }
+        self.watch = None;
+        Ok(())
+    }
+
+    // Give up on bodies that send less than the given amount of data over the
+    // given window of time, starting over with a new request. Unlike the read
+    // timeout, which only catches reads that get nothing at all, this also
+    // catches connections that trickle along far slower than S3 normally
+    // sends. Retrying depends on the retry policy like any other failed
+    // read. Only applies to reading the body, not to fetching blocks.
+    pub fn set_min_throughput(&mut self, min_throughput: Option<MinThroughput>) {
+        self.min_throughput = min_throughput.filter(|min| min.window > Duration::from_secs(0));
+        self.watch = None;
+    }
+
+    // Reader of just len bytes of the object starting at offset. It reads as
+    // if it was an object of its own of that size, for example a compressed
+    // archive stored among others in one bigger object. Settings carry over
+    // except for reading by part number, which only works on whole objects.
+    // The offset of a window of a window is within the outer window. Lazily
+    // created objects have to be opened first.
+    pub fn window(&self, offset: u64, len: u64) -> std::io::Result<Self>
+    where
+        A: Clone,
+    {
+        let end = offset.checked_add(len).filter(|&end| end <= self.length);
+        if end.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Window of {} bytes at {} goes past the end of {} bytes",
+                    len, offset, self.length
+                ),
+            ));
+        }
+        let mut window = self.clone();
+        window.window_start += offset;
+        window.length = len;
+        window.position = 0;
+        window.body_position = 0;
+        // Blocks are cached by where they start, which is different in the
+        // window.
+        window.blocks.clear();
+        window.prefetched.clear();
+        window.last_block = None;
+        window.parts = None;
+        Ok(window)
+    }
+
+    // ETag, content type, user metadata and such of the object, as S3 sent
+    // them when we first got it.
+    pub fn metadata(&self) -> &ObjectMetadata {
+        &self.metadata
+    }
+
+    // Size of the object, or of the window if this is one.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    // Waits for a restore of the object to finish, as started by a read that
+    // failed because the object is archived (see ReadOptions::restore) or by
+    // anyone else. Reads work again once it's done.
+    pub fn wait_for_restore(&self) -> std::io::Result<RestoreStatus>
+    where
+        A: AsS3,
+    {
+        let options = self.restore.to_owned().unwrap_or_default();
+        block_on(
+            self.runtime,
+            wait_for_restore(self.client.as_s3(), &self.req, &options),
+        )?
+        .map_err(|e| self.with_context(Error::new(ErrorKind::Other, e), None, 1))
+    }
+
+    // Wraps the error up with what we were reading, see ObjectErrorContext.
+    fn with_context(
+        &self,
+        error: Error,
+        range: Option<std::ops::Range<u64>>,
+        attempts: u32,
+    ) -> Error {
+        let context = ObjectErrorContext {
+            bucket: self.req.bucket.to_owned(),
+            key: self.req.key.to_owned(),
+            range,
+            attempts,
+            error,
+        };
+        Error::new(context.error.kind(), context)
+    }
+
+    // Range of the object reading at the current position asks for: the
+    // block holding it, or from it to as far as a body goes.
+    fn attempted_range(&self) -> Option<std::ops::Range<u64>> {
+        if !self.opened || self.position >= self.length {
+            return None;
         }
+        let range = match self.block_at(self.position) {
+            Some(span) => span.start..span.end,
+            None => {
+                let end = self.max_request_size.map_or(self.length, |size| {
+                    self.position.saturating_add(size).min(self.length)
+                });
+                self.position..end
+            }
+        };
+        Some(self.window_start + range.start..self.window_start + range.end)
+    }
+
+    // What reading the object took so far.
+    pub fn metrics(&self) -> ReadMetrics {
+        self.metrics.get()
+    }
+
+    fn record<F: FnOnce(&mut ReadMetrics)>(&self, f: F) {
+        let mut metrics = self.metrics.get();
+        f(&mut metrics);
+        self.metrics.set(metrics);
     }
 
     /// Set the read timeout to the given duration. Set to None to disable
@@ -144,112 +846,945 @@ impl<'a, A> SeekableS3Object<'a, A> {
     pub fn set_read_timeout(&mut self, read_timeout: Option<std::time::Duration>) {
         self.read_timeout = read_timeout;
     }
-}
 
-impl<'a, A> Read for SeekableS3Object<'_, A>
-where
-    A: S3,
-{
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        // We're done reading, AWS API throws a fit for out-of-range range
-        // requests so we exit early.
-        if self.position >= self.length {
-            return Ok(0);
+    // Fail reads with a Deadline timeout rather than keep waiting or retrying
+    // past the given time. The read timeout still applies to each wait before
+    // then. None, the default, reads for as long as it takes.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    // How long the next wait may take and what kind of timeout running out of
+    // time is: the read timeout, unless the deadline comes first.
+    fn wait_limit(&self, kind: TimeoutKind) -> Option<(Duration, TimeoutKind)> {
+        let left = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match (self.read_timeout, left) {
+            (Some(timeout), Some(left)) if left < timeout => Some((left, TimeoutKind::Deadline)),
+            (Some(timeout), _) => Some((timeout, kind)),
+            (None, Some(left)) => Some((left, TimeoutKind::Deadline)),
+            (None, None) => None,
+        }
+    }
+
+    // When seeking forward by at most this many bytes, keep reading the body
+    // we have and throw away the bytes in between rather than making a new
+    // request. Defaults to 0, meaning every seek makes a new request.
+    pub fn set_max_skip(&mut self, max_skip: u64) {
+        self.max_skip = max_skip;
+    }
+
+    // When S3 takes longer than this to respond to a request, send it again
+    // and go with whichever of the two responds first. A small fraction of
+    // requests take many times longer than the rest, so for reads that wait
+    // on every request, such as interactive random access, this cuts the
+    // worst waits down a lot for a few more requests. Something around the
+    // 95th percentile of how long requests normally take works well. None,
+    // the default, never sends anything twice.
+    pub fn set_hedge_after(&mut self, hedge_after: Option<Duration>) {
+        self.hedge_after = hedge_after;
+    }
+
+    // Have at most this many requests in flight at once, between prefetching,
+    // fetching blocks in parallel and hedging. Keeps a single reader from
+    // taking up all the connections of the HTTP client. Clones made after
+    // this share the limit with this object. None, the default, means no
+    // limit; 0 counts as 1.
+    pub fn set_max_concurrent_requests(&mut self, max_concurrent_requests: Option<usize>) {
+        self.request_limit = max_concurrent_requests.map(request_limit);
+    }
+
+    // Once we haven't read from a body for this long, request a new one
+    // rather than reading on from it: the connection is likely closed by
+    // then and reading on would only fail. Defaults to 15 seconds. None
+    // keeps bodies around for however long.
+    pub fn set_max_body_idle(&mut self, max_body_idle: Option<Duration>) {
+        self.max_body_idle = max_body_idle;
+    }
+
+    // When a body ends early or its connection drops, request the rest of it
+    // again up to this many times in a row before failing the read. This
+    // happens before and on top of the retry policy. Defaults to 3.
+    pub fn set_max_resumes(&mut self, max_resumes: u32) {
+        self.max_resumes = max_resumes;
+    }
+
+    // Rather than asking for everything from the current position to the end
+    // of the object, ask for at most this many bytes at a time. A body that's
+    // read only a little of after a seek then doesn't stay open until it
+    // times out, holding on to a connection. Once a body runs out, the next
+    // one is requested. None or 0, the default, means no limit.
+    pub fn set_max_request_size(&mut self, max_request_size: Option<u64>) {
+        self.max_request_size = max_request_size.filter(|&size| size > 0);
+    }
+
+    // Reads and throws away the part of the body we seeked past. If the body
+    // ends before we get there, it's dropped.
+    fn skip_body(&mut self) -> std::io::Result<()> {
+        let mut scratch = [0; 8 * 1024];
+        while self.body.is_some() && self.body_position < self.position {
+            let wanted = (self.position - self.body_position).min(scratch.len() as u64) as usize;
+            if self.read_body(&mut scratch[..wanted])? == 0 {
+                self.body = None;
+            }
+        }
+        Ok(())
+    }
+
+    // Retry GetObject requests and reads of the body that failed with
+    // something that may go away on its own, such as throttling or a dropped
+    // connection. Retries pick up from where the failed read left off. By
+    // default nothing is retried, bar requests S3 throttled: those get up to
+    // five goes whatever the policy, RetryPolicy::none included.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    // See ReadOptions::refresh_credentials.
+    pub fn set_refresh_credentials(&mut self, refresh: Option<CredentialsRefresh>) {
+        self.refresh_credentials = refresh;
+    }
+
+    // Make seeks past the end of the object fail with InvalidInput rather
+    // than land there. Off by default, as with files.
+    pub fn set_strict_seeks(&mut self, strict: bool) {
+        self.strict_seeks = strict;
+    }
+
+    // Rather than getting everything from the current position onwards on
+    // every seek, get blocks of this size (aligned to it) and serve reads out
+    // of them. Scattered small reads then need a request per block they touch
+    // rather than a request per seek. None or 0 turns this off, which is the
+    // default.
+    pub fn set_min_fetch_size(&mut self, min_fetch_size: Option<u64>) {
+        self.min_fetch_size = min_fetch_size.filter(|&size| size > 0);
+        self.body = None;
+        self.blocks.clear();
+        self.prefetched.clear();
+        self.last_block = None;
+    }
+
+    // Read the object a part at a time with GetObject's partNumber rather
+    // than by ranges. This suits objects uploaded with parts that line up
+    // with how they're read, such as parts holding whole frames: S3 can
+    // serve whole parts more cheaply and sends along their checksums. Blocks
+    // are then the parts, taking the place of set_min_fetch_size, and caching
+    // and prefetching work on them in the same way. Turning this on finds out
+    // where the parts are with a HeadObject request per part.
+    pub fn set_part_reads(&mut self, enabled: bool) -> std::io::Result<()>
+    where
+        A: AsS3,
+    {
+        if enabled && self.length != self.object_length {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Can't read parts of a window of an object",
+            ));
+        }
+        self.parts = if enabled {
+            let parts = self.block_on_with_timeout(self.part_layout());
+            Some(parts.map_err(|(e, _)| self.with_context(e, None, 1))?)
+        } else {
+            None
+        };
+        self.body = None;
+        self.blocks.clear();
+        self.prefetched.clear();
+        self.last_block = None;
+        Ok(())
+    }
+
+    // When reading blocks (see set_min_fetch_size) one after another, fetch
+    // this many following blocks at the same time as the one we need. A
+    // single request rarely gets anywhere near the bandwidth available from
+    // S3 so this speeds up reading through whole objects a lot. Defaults to
+    // 0, fetching one block at a time.
+    pub fn set_prefetch(&mut self, prefetch: usize) {
+        self.prefetch = prefetch;
+    }
+
+    // Keep up to this many bytes of recently read blocks around so that
+    // reading them again doesn't download them again. Only used together with
+    // set_min_fetch_size. The block being read is always kept, so the default
+    // of 0 means only that one is.
+    pub fn set_block_cache_size(&mut self, size: u64) {
+        self.blocks.set_capacity(size);
+    }
+
+    // Also look for blocks in the given cache before fetching them, and put
+    // the ones we fetch there. Like set_block_cache_size, this only does
+    // anything together with set_min_fetch_size. Objects that S3 gave us no
+    // ETag for and that aren't requested at a specific version aren't cached
+    // as we can't tell if what's in the cache is still current.
+    pub fn set_disk_cache(&mut self, disk_cache: Option<DiskCache>) {
+        self.disk_cache = disk_cache;
+    }
+
+    // Key of the block in the disk cache, if it can be cached there.
+    fn disk_cache_key(&self, block: &BlockSpan) -> Option<String> {
+        let version = self
+            .req
+            .version_id
+            .as_ref()
+            .or(self.metadata.e_tag.as_ref())?;
+        Some(format!(
+            "{}\0{}\0{}\0{}\0{}",
+            self.req.bucket,
+            self.req.key,
+            version,
+            self.window_start + block.start,
+            block.end - block.start
+        ))
+    }
+
+    // The block holding the given position, if we're reading in blocks at
+    // all: the part holding it when reading parts, otherwise the aligned
+    // block of min_fetch_size around it.
+    fn block_at(&self, position: u64) -> Option<BlockSpan> {
+        if let Some(parts) = &self.parts {
+            let i = match parts.binary_search(&position) {
+                Ok(i) => i,
+                Err(i) => i.saturating_sub(1),
+            };
+            return Some(BlockSpan {
+                start: parts[i],
+                end: parts.get(i + 1).copied().unwrap_or(self.length),
+                part_number: Some(i as i64 + 1),
+            });
+        }
+        self.min_fetch_size.map(|block_size| {
+            let start = position - position % block_size;
+            BlockSpan {
+                start,
+                end: start.saturating_add(block_size).min(self.length),
+                part_number: None,
+            }
+        })
+    }
+
+    // Gets the given blocks from the disk cache or S3. Blocks that aren't in
+    // the cache are all fetched at the same time.
+    fn fetch_blocks(&self, spans: &[BlockSpan]) -> Result<Vec<Bytes>, (Error, bool)>
+    where
+        A: AsS3,
+    {
+        let cache_keys: Vec<Option<String>> = spans
+            .iter()
+            .map(|span| {
+                self.disk_cache
+                    .as_ref()
+                    .and_then(|_| self.disk_cache_key(span))
+            })
+            .collect();
+        let mut blocks: Vec<Option<Bytes>> = cache_keys
+            .iter()
+            .map(|key| {
+                key.as_ref()
+                    .zip(self.disk_cache.as_ref())
+                    .and_then(|(key, disk_cache)| disk_cache.get(key))
+            })
+            .collect();
+
+        let missing: Vec<usize> = (0..blocks.len()).filter(|&i| blocks[i].is_none()).collect();
+        let disk_cache_hits = (blocks.len() - missing.len()) as u64;
+        self.record(|metrics| metrics.disk_cache_hits += disk_cache_hits);
+        let fetched = self.block_on_with_timeout(futures::future::try_join_all(
+            missing.iter().map(|&i| self.block_future(spans[i])),
+        ))?;
+        for (i, block) in missing.into_iter().zip(fetched) {
+            if let (Some(key), Some(disk_cache)) = (&cache_keys[i], &self.disk_cache) {
+                disk_cache.insert(key, &block);
+            }
+            blocks[i] = Some(block);
         }
+        Ok(blocks.into_iter().flatten().collect())
+    }
 
+    // A single go at reading, telling whether it's worth trying again if it
+    // fails.
+    fn read_once(&mut self, buf: &mut [u8]) -> Result<usize, (Error, bool)>
+    where
+        A: AsS3,
+    {
+        if let Some(block) = self.block_at(self.position) {
+            return self.read_block(block, buf);
+        }
         // We may have a body already present in which case we just read from
         // it. Only if we don't have the body (for example, we performed a seek)
         // do we issue any new requests.
-        if self.body.is_some() {
-            return self.read_body(buf);
-        }
-
-        // We didn't have existing body to read from: probably we have done a
-        // seek. Get the body at the new position, read some data and store the
-        // new body for the future.
-        self.req.range = Some(format!("bytes={}-", self.position));
-
-        let get_object = self
-            .client
-            .get_object(self.req.to_owned())
-            .map_err(|e| Error::new(ErrorKind::Other, e));
-
-        let object = match self.read_timeout {
-            Some(timeout) => {
-                let _executor = self.runtime.enter();
-                match self
-                    .runtime
-                    .block_on(tokio::time::timeout(timeout, get_object))
-                {
-                    Ok(r) => r,
-                    Err(timeout_err) => Err(Error::new(ErrorKind::TimedOut, timeout_err)),
+        let to_read_error = |e: Error| {
+            let retryable = is_retryable_io(&e);
+            (e, retryable)
+        };
+        // S3 closes connections that go quiet for a while, so a body we left
+        // alone for too long is likely dead: don't bother finding out.
+        let idle = self.body_used.map(|used| used.elapsed());
+        if idle
+            .zip(self.max_body_idle)
+            .map_or(false, |(idle, max)| idle > max)
+        {
+            self.body = None;
+        }
+        self.skip_body().map_err(to_read_error)?;
+        loop {
+            if self.body.is_none() {
+                self.fetch_body()?;
+            }
+            let read = match self.read_body(buf) {
+                Ok(0) if !buf.is_empty() && self.body_position < self.length => {
+                    self.body = None;
+                    if self.body_position >= self.body_end {
+                        // A body with a capped size runs out before the
+                        // object does: carry on with the next one.
+                        continue;
+                    }
+                    Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!(
+                            "Body ended at {} rather than at {}",
+                            self.body_position, self.body_end
+                        ),
+                    ))
                 }
+                read => read,
+            };
+            self.position = self.body_position;
+            match read {
+                Ok(bytes_read) => {
+                    self.resumes = 0;
+                    break Ok(bytes_read);
+                }
+                // The connection went away partway through: pick up from
+                // where it left off.
+                Err(e) if is_retryable_io(&e) && self.resumes < self.max_resumes => {
+                    self.resumes += 1;
+                    self.body = None;
+                    self.record(|metrics| metrics.resumes += 1);
+                }
+                Err(e) => break Err(to_read_error(e)),
             }
-            None => self.runtime.block_on(get_object),
-        }?;
+        }
+    }
+
+    // Reads from the given block, which holds the current position, fetching
+    // it if we don't have it.
+    fn read_block(&mut self, span: BlockSpan, buf: &mut [u8]) -> Result<usize, (Error, bool)>
+    where
+        A: AsS3,
+    {
+        let sequential = span.start > 0 && self.last_block.map(|last| last.end) == Some(span.start);
+        self.last_block = Some(span);
+        let block = match self.blocks.get(span.start) {
+            Some(block) => {
+                self.record(|metrics| metrics.cache_hits += 1);
+                block
+            }
+            None => match self.prefetched.remove(&span.start) {
+                Some(block) => {
+                    self.record(|metrics| metrics.cache_hits += 1);
+                    self.blocks.insert(span.start, block.clone());
+                    block
+                }
+                None => {
+                    // Reading through the blocks in order: get the next few
+                    // while we're at it.
+                    let num_blocks = if sequential { 1 + self.prefetch } else { 1 };
+                    let mut spans = vec![span];
+                    while spans.len() < num_blocks {
+                        let end = spans[spans.len() - 1].end;
+                        match self.block_at(end) {
+                            Some(next) if end < self.length => spans.push(next),
+                            _ => break,
+                        }
+                    }
+                    let mut blocks = self.fetch_blocks(&spans)?.into_iter();
+                    let block = blocks.next().unwrap_or_default();
+                    // Anything we prefetched earlier and didn't get to is
+                    // unlikely to be read now.
+                    self.prefetched = spans[1..]
+                        .iter()
+                        .map(|span| span.start)
+                        .zip(blocks)
+                        .collect();
+                    self.blocks.insert(span.start, block.clone());
+                    block
+                }
+            },
+        };
+        let offset = (self.position - span.start) as usize;
+        if offset >= block.len() {
+            return Ok(0);
+        }
+        let bytes_read = buf.len().min(block.len() - offset);
+        buf[..bytes_read].copy_from_slice(&block[offset..offset + bytes_read]);
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+
+    // Gets the body at the current position and stores it for future reads.
+    fn fetch_body(&mut self) -> Result<(), (Error, bool)>
+    where
+        A: AsS3,
+    {
+        let end = self.max_request_size.map_or(self.length, |size| {
+            self.position.saturating_add(size).min(self.length)
+        });
+        self.body_end = end;
+        let start = self.window_start + self.position;
+        let end = self.window_start + end;
+        self.req.range = Some(if end == self.object_length {
+            format!("bytes={}-", start)
+        } else {
+            format!("bytes={}-{}", start, end - 1)
+        });
+        self.record(|metrics| metrics.requests += 1);
+        log_event!(
+            debug,
+            target: "zstd_seekable_s3::fetch",
+            bucket = %self.req.bucket,
+            key = %self.req.key,
+            start,
+            end,
+            "Fetching body"
+        );
+
+        let get_object = traced!(
+            self.get_object(self.req.to_owned()),
+            "get_object",
+            bucket = %self.req.bucket,
+            key = %self.req.key,
+            range = ?self.req.range,
+        );
+        // There's only ever one body, no need to hold on to its slot.
+        let (object, _) = self.block_on_with_timeout(get_object)?;
+        self.check_response(&object, Some(start..end))?;
 
         self.body = object
             .body
             .map(|bs| Box::pin(bs.into_async_read()) as Pin<Box<dyn AsyncRead + Send>>);
+        self.body_position = self.position;
+        self.body_used = Some(Instant::now());
+        self.watch = None;
+        Ok(())
+    }
 
-        self.read_body(buf)
+    // Gets the whole block in one go, by its part number if it's a part.
+    fn block_future(
+        &self,
+        span: BlockSpan,
+    ) -> impl std::future::Future<Output = Result<Bytes, (Error, bool)>> + '_
+    where
+        A: AsS3,
+    {
+        let mut req = self.req.to_owned();
+        let range = match span.part_number {
+            Some(part_number) => {
+                req.part_number = Some(part_number);
+                None
+            }
+            None => {
+                let start = self.window_start + span.start;
+                let end = self.window_start + span.end;
+                req.range = Some(format!("bytes={}-{}", start, end - 1));
+                Some(start..end)
+            }
+        };
+        self.record(|metrics| metrics.requests += 1);
+        let get_object = self.get_object(req);
+        let read_block = async move {
+            // Keep the slot until we've read the whole block.
+            let (object, _permit) = get_object.await?;
+            self.check_response(&object, range)?;
+            let data = match object.body {
+                None => Bytes::new(),
+                Some(body) => read_body(body).await.map_err(|e| {
+                    let retryable = is_retryable_io(&e);
+                    (e, retryable)
+                })?,
+            };
+            self.record(|metrics| metrics.bytes_downloaded += data.len() as u64);
+            // Parts are wherever the uploader put them: make sure they're
+            // still where we think they are.
+            let expected = span.end - span.start;
+            if span.part_number.is_some() && data.len() as u64 != expected {
+                return Err((
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Got a part of {} bytes, expected {}", data.len(), expected),
+                    ),
+                    false,
+                ));
+            }
+            Ok(data)
+        };
+        traced!(
+            read_block,
+            "get_object",
+            bucket = %self.req.bucket,
+            key = %self.req.key,
+            start = span.start,
+            end = span.end,
+            part_number = ?span.part_number,
+        )
     }
-}
 
-impl<A> Seek for SeekableS3Object<'_, A> {
-    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        // Implementation roughly lifted from std::io::cursor Seek trait
-        // implementation.
-        let (base_pos, offset) = match pos {
-            std::io::SeekFrom::Start(pos) => {
-                self.set_position(pos);
-                return Ok(pos);
-            }
-            std::io::SeekFrom::End(pos) => (self.length, pos),
-            std::io::SeekFrom::Current(pos) => (self.position, pos),
-        };
-        let new_pos = if offset >= 0 {
-            base_pos.checked_add(offset as u64)
-        } else {
-            base_pos.checked_sub((offset.wrapping_neg()) as u64)
+    // Checks that S3 answered a request for the given range of the object,
+    // if it was for a range, with just that range of an object of the size we
+    // know. If not, the object changed since we first got it and reading on
+    // would give short or garbled data.
+    fn check_response(
+        &self,
+        object: &GetObjectOutput,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<(), (Error, bool)> {
+        let total = object.content_range.as_deref().and_then(total_size);
+        if let Some(total) = total.filter(|&total| total != self.object_length) {
+            return Err((
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Object is {} bytes rather than {}, it changed while reading",
+                        total, self.object_length
+                    ),
+                ),
+                false,
+            ));
+        }
+        let length = object
+            .content_length
+            .and_then(|length| u64::try_from(length).ok());
+        if let (Some(range), Some(length)) = (range, length) {
+            if length != range.end - range.start {
+                return Err((
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Got {} bytes for bytes {}-{}, the object changed while reading",
+                            length,
+                            range.start,
+                            range.end - 1
+                        ),
+                    ),
+                    false,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // HeadObject request for the object we're reading.
+    fn head_request(&self) -> HeadObjectRequest {
+        HeadObjectRequest {
+            bucket: self.req.bucket.to_owned(),
+            key: self.req.key.to_owned(),
+            version_id: self.req.version_id.to_owned(),
+            if_match: self.req.if_match.to_owned(),
+            request_payer: self.req.request_payer.to_owned(),
+            expected_bucket_owner: self.req.expected_bucket_owner.to_owned(),
+            sse_customer_algorithm: self.req.sse_customer_algorithm.to_owned(),
+            sse_customer_key: self.req.sse_customer_key.to_owned(),
+            sse_customer_key_md5: self.req.sse_customer_key_md5.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // Finds out the size and metadata of a lazily created object.
+    fn open_once(&mut self) -> Result<(), (Error, bool)>
+    where
+        A: AsS3,
+    {
+        self.record(|metrics| metrics.requests += 1);
+        let head_object = traced!(
+            self.client.as_s3().head_object(self.head_request()),
+            "head_object",
+            bucket = %self.req.bucket,
+            key = %self.req.key,
+        )
+        .map_err(|e| {
+            let retryable = is_retryable(&e);
+            (Error::new(ErrorKind::Other, e), retryable)
+        });
+        let object = self.block_on_with_timeout(head_object)?;
+        let length = object
+            .content_length
+            .and_then(|length| u64::try_from(length).ok())
+            .ok_or_else(|| {
+                let e = Error::new(ErrorKind::InvalidData, "No content length for object");
+                (e, false)
+            })?;
+        self.length = length;
+        self.object_length = length;
+        self.metadata = ObjectMetadata {
+            e_tag: object.e_tag,
+            last_modified: object.last_modified,
+            content_type: object.content_type,
+            content_encoding: object.content_encoding,
+            storage_class: object.storage_class,
+            version_id: object.version_id,
+            metadata: object.metadata.unwrap_or_default(),
+        };
+        self.opened = true;
+        log_event!(
+            debug,
+            target: "zstd_seekable_s3::open",
+            bucket = %self.req.bucket,
+            key = %self.req.key,
+            length,
+            "Opened object"
+        );
+        Ok(())
+    }
+
+    // Where every part of the object starts followed by where the object
+    // ends, going by the sizes HeadObject gives for each part. Objects that
+    // weren't uploaded in parts are a single part.
+    fn part_layout(&self) -> impl std::future::Future<Output = Result<Vec<u64>, (Error, bool)>> + '_
+    where
+        A: AsS3,
+    {
+        let head_part = move |part_number: i64| {
+            let req = HeadObjectRequest {
+                if_match: self.metadata.e_tag.to_owned(),
+                part_number: Some(part_number),
+                ..self.head_request()
+            };
+            self.record(|metrics| metrics.requests += 1);
+            self.client.as_s3().head_object(req).map_err(|e| {
+                let retryable = is_retryable(&e);
+                (Error::new(ErrorKind::Other, e), retryable)
+            })
         };
-        match new_pos {
-            Some(n) => {
-                self.set_position(n);
-                Ok(self.position)
+        let part_size = |output: &HeadObjectOutput| {
+            output
+                .content_length
+                .and_then(|length| u64::try_from(length).ok())
+                .ok_or_else(|| {
+                    let e = Error::new(ErrorKind::InvalidData, "No size for part of object");
+                    (e, false)
+                })
+        };
+        async move {
+            let first = head_part(1).await?;
+            let num_parts = first.parts_count.unwrap_or(1).max(1);
+            let mut parts = vec![0, part_size(&first)?];
+            let rest: Vec<HeadObjectOutput> = futures::stream::iter(2..=num_parts)
+                .map(head_part)
+                .buffered(PART_LAYOUT_CONCURRENCY)
+                .try_collect()
+                .await?;
+            for output in &rest {
+                let end = parts[parts.len() - 1].saturating_add(part_size(output)?);
+                parts.push(end);
+            }
+            if parts[parts.len() - 1] != self.length {
+                return Err((
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        "Sizes of the parts don't add up to the size of the object",
+                    ),
+                    false,
+                ));
+            }
+            // The end of the object isn't the start of a part.
+            parts.pop();
+            Ok(parts)
+        }
+    }
+
+    // Runs f until it succeeds, doesn't fail in a way worth retrying or the
+    // retry policy gives up.
+    fn with_retries<T, F>(&mut self, mut f: F) -> std::io::Result<T>
+    where
+        F: FnMut(&mut Self) -> Result<T, (Error, bool)>,
+    {
+        let mut failed_attempts = 0;
+        // Credentials only get refreshed once: if new ones expired too,
+        // something else is wrong.
+        let mut refreshed = false;
+        loop {
+            enter_span!(
+                "read",
+                bucket = %self.req.bucket,
+                key = %self.req.key,
+                position = self.position,
+                attempt = failed_attempts + 1,
+            );
+            if self
+                .deadline
+                .map_or(false, |deadline| Instant::now() >= deadline)
+            {
+                let range = self.attempted_range();
+                let e = TimeoutError::new(TimeoutKind::Deadline, Duration::from_secs(0));
+                break Err(self.with_context(e.into_io(), range, failed_attempts));
+            }
+            match f(self) {
+                Ok(result) => break Ok(result),
+                // Nothing went wrong as such, whatever we were doing just
+                // has to be done again.
+                Err((e, _)) if e.kind() == ErrorKind::Interrupted => {}
+                Err((e, _))
+                    if !refreshed
+                        && self.refresh_credentials.is_some()
+                        && is_expired_credentials_error(&e) =>
+                {
+                    refreshed = true;
+                    if let Some(refresh) = &self.refresh_credentials {
+                        refresh.refresh();
+                    }
+                    self.body = None;
+                }
+                Err((e, retryable)) => {
+                    failed_attempts += 1;
+                    let should_retry = self.retry.should_retry(failed_attempts)
+                        || (is_throttling_error(&e) && failed_attempts < THROTTLED_ATTEMPTS);
+                    if !retryable || !should_retry {
+                        let range = self.attempted_range();
+                        break Err(self.with_context(
+                            settle_interrupted(e),
+                            range,
+                            failed_attempts,
+                        ));
+                    }
+                    // Whatever body we had is no good anymore: start over
+                    // from where we are.
+                    self.body = None;
+                    self.record(|metrics| metrics.retries += 1);
+                    std::thread::sleep(self.retry.backoff(failed_attempts));
+                }
+            }
+        }
+    }
+
+    // Makes a GetObject request, first waiting for as long as S3 throttling us
+    // calls for and for a free slot under the request limit. The slot stays
+    // taken until the returned permit is dropped, which should be once the
+    // body is read.
+    fn get_object(
+        &self,
+        req: GetObjectRequest,
+    ) -> impl std::future::Future<
+        Output = Result<(GetObjectOutput, Option<OwnedSemaphorePermit>), (Error, bool)>,
+    > + '_
+    where
+        A: AsS3,
+    {
+        let delay = self.throttle.delay();
+        // Sending more requests while S3 asks for fewer doesn't help anyone.
+        let hedge_after = self.hedge_after.filter(|_| delay == Duration::from_secs(0));
+        async move {
+            if delay > Duration::from_secs(0) {
+                tokio::time::sleep(delay).await;
+            }
+            let first_req = req.to_owned();
+            let first = Box::pin(async move {
+                let permit = match &self.request_limit {
+                    // Only closed semaphores fail and we never close ours.
+                    Some(limit) => limit.clone().acquire_owned().await.ok(),
+                    None => None,
+                };
+                (self.client.as_s3().get_object(first_req).await, permit)
+            });
+            let (result, permit) = match hedge_after {
+                None => first.await,
+                Some(hedge_after) => {
+                    let wait = Box::pin(tokio::time::sleep(hedge_after));
+                    match future::select(first, wait).await {
+                        Either::Left((result, _)) => result,
+                        // Taking a while: ask again and go with whichever
+                        // answers first, unless we're at the request limit.
+                        Either::Right((_, first)) => {
+                            let permit = match &self.request_limit {
+                                Some(limit) => limit.clone().try_acquire_owned().ok(),
+                                None => None,
+                            };
+                            if self.request_limit.is_some() && permit.is_none() {
+                                first.await
+                            } else {
+                                self.record(|metrics| {
+                                    metrics.requests += 1;
+                                    metrics.hedged_requests += 1;
+                                });
+                                let second = Box::pin(async move {
+                                    (self.client.as_s3().get_object(req).await, permit)
+                                });
+                                future::select(first, second).await.factor_first().0
+                            }
+                        }
+                    }
+                }
+            };
+            match result {
+                Ok(object) => {
+                    self.throttle.succeeded(&self.retry);
+                    Ok((object, permit))
+                }
+                Err(e) => {
+                    if is_throttling(&e) {
+                        self.throttle.throttled(&self.retry);
+                    }
+                    if let (true, Some(restore)) = (is_invalid_object_state(&e), &self.restore) {
+                        let restore_requested =
+                            request_restore(self.client.as_s3(), &self.req, restore)
+                                .await
+                                .is_ok();
+                        let e = RestoreError::Archived { restore_requested };
+                        return Err((Error::new(ErrorKind::Other, e), false));
+                    }
+                    let retryable = is_retryable(&e);
+                    Err((Error::new(ErrorKind::Other, e), retryable))
+                }
             }
-            None => Err(Error::new(
-                ErrorKind::InvalidInput,
-                "invalid seek to a negative or overflowing position",
-            )),
         }
     }
+
+    fn block_on_with_timeout<T>(
+        &self,
+        future: impl std::future::Future<Output = Result<T, (Error, bool)>>,
+    ) -> Result<T, (Error, bool)> {
+        let started = Instant::now();
+        let result = match self.wait_limit(TimeoutKind::Response) {
+            Some((timeout, kind)) => {
+                match block_on_timeout(self.runtime, timeout, future) {
+                    Ok(Ok(r)) => r,
+                    Err(e) => Err((e.into(), false)),
+                    // Timeouts are worth retrying, unless it's the deadline.
+                    Ok(Err(_)) => Err((
+                        TimeoutError::new(kind, timeout).into_io(),
+                        kind != TimeoutKind::Deadline,
+                    )),
+                }
+            }
+            None => block_on(self.runtime, future).unwrap_or_else(|e| Err((e.into(), false))),
+        };
+        let elapsed = started.elapsed();
+        self.record(|metrics| metrics.time_blocked += elapsed);
+        result
+    }
+}
+
+// Errors that might go away are retried as the retry policy says before
+// they're returned, Interrupted ones always: whatever read returns is final.
+impl<'a, A> Read for SeekableS3Object<'_, A>
+where
+    A: AsS3,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.open()?;
+        // We're done reading, AWS API throws a fit for out-of-range range
+        // requests so we exit early.
+        if self.position >= self.length {
+            return Ok(0);
+        }
+        self.with_retries(|object| object.read_once(buf))
+    }
+}
+
+fn request_limit(max_concurrent_requests: usize) -> Arc<Semaphore> {
+    Arc::new(Semaphore::new(max_concurrent_requests.max(1)))
+}
+
+// Whether the read failed because the credentials expired.
+fn is_expired_credentials_error(error: &Error) -> bool {
+    error.get_ref().map_or(false, |e| {
+        e.downcast_ref::<RusotoError<GetObjectError>>()
+            .map_or(false, is_expired_credentials)
+            || e.downcast_ref::<RusotoError<HeadObjectError>>()
+                .map_or(false, is_expired_credentials)
+    })
+}
+
+// Whether the read failed because S3 throttled the request.
+fn is_throttling_error(error: &Error) -> bool {
+    error.get_ref().map_or(false, |e| {
+        e.downcast_ref::<RusotoError<GetObjectError>>()
+            .map_or(false, is_throttling)
+            || e.downcast_ref::<RusotoError<HeadObjectError>>()
+                .map_or(false, is_throttling)
+    })
+}
+
+// Seeks past the end are fine and reads from there read nothing, unless
+// seeks are strict, in which case they fail. Seeks before the start or past
+// u64::MAX always fail. Failed seeks leave the position as it was.
+impl<A: AsS3> Seek for SeekableS3Object<'_, A> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        // Seeks from the start don't need the length unless they're strict,
+        // so they don't open lazy objects.
+        let needs_length = self.strict_seeks || !matches!(pos, std::io::SeekFrom::Start(_));
+        if needs_length {
+            self.open()?;
+        }
+        let new_pos = seek_position(pos, self.position, self.length, self.strict_seeks)?;
+        self.set_position(new_pos);
+        Ok(self.position)
+    }
 }
 
 // Allows to simply say `s3.get_seekable_object` to be consistent with rest of
-// rusoto API.
+// rusoto API. Clients are cheap to clone and share their connections, so
+// borrowed and shared clients give each object a clone: one client can back
+// any number of objects.
 pub trait GetSeekableObject: Sized {
+    // Client the object makes its requests with.
+    type Client: AsS3;
+
     fn get_seekable_object(
         self,
         runtime: &tokio::runtime::Runtime,
         read_timeout: Option<std::time::Duration>,
         input: GetObjectRequest,
-    ) -> Result<
-        Result<SeekableS3Object<'_, Self>, RusotoError<GetObjectError>>,
-        tokio::time::error::Elapsed,
-    >;
+    ) -> Result<SeekableS3Object<'_, Self::Client>, GetSeekableObjectError>;
 }
 
 impl GetSeekableObject for S3Client {
+    type Client = S3Client;
+
     fn get_seekable_object(
         self,
         runtime: &tokio::runtime::Runtime,
         read_timeout: Option<std::time::Duration>,
         input: GetObjectRequest,
-    ) -> Result<
-        Result<SeekableS3Object<'_, Self>, RusotoError<GetObjectError>>,
-        tokio::time::error::Elapsed,
-    > {
+    ) -> Result<SeekableS3Object<'_, Self::Client>, GetSeekableObjectError> {
         SeekableS3Object::new(self, runtime, read_timeout, input)
     }
 }
+
+impl GetSeekableObject for &S3Client {
+    type Client = S3Client;
+
+    fn get_seekable_object(
+        self,
+        runtime: &tokio::runtime::Runtime,
+        read_timeout: Option<std::time::Duration>,
+        input: GetObjectRequest,
+    ) -> Result<SeekableS3Object<'_, Self::Client>, GetSeekableObjectError> {
+        SeekableS3Object::new(self.clone(), runtime, read_timeout, input)
+    }
+}
+
+impl GetSeekableObject for Arc<S3Client> {
+    type Client = S3Client;
+
+    fn get_seekable_object(
+        self,
+        runtime: &tokio::runtime::Runtime,
+        read_timeout: Option<std::time::Duration>,
+        input: GetObjectRequest,
+    ) -> Result<SeekableS3Object<'_, Self::Client>, GetSeekableObjectError> {
+        SeekableS3Object::new(S3Client::clone(&self), runtime, read_timeout, input)
+    }
+}
+
+impl GetSeekableObject for Arc<dyn S3 + Send + Sync> {
+    type Client = DynS3;
+
+    fn get_seekable_object(
+        self,
+        runtime: &tokio::runtime::Runtime,
+        read_timeout: Option<std::time::Duration>,
+        input: GetObjectRequest,
+    ) -> Result<SeekableS3Object<'_, Self::Client>, GetSeekableObjectError> {
+        SeekableS3Object::new(DynS3::from(self), runtime, read_timeout, input)
+    }
+}
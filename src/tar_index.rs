@@ -0,0 +1,290 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Take};
+
+use crate::{read_frame_index, read_index_frame, IndexBuilder, IndexFrame, SeekableDecompress};
+
+// Tag of the index frame holding a tar index.
+pub const TAR_INDEX_TAG: [u8; 4] = *b"TARI";
+
+const BLOCK_SIZE: u64 = 512;
+
+// A file, directory, link or anything else stored in a tar archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarMember {
+    pub path: String,
+    // Where the contents of the member start in the uncompressed archive, just
+    // past its header.
+    pub offset: u64,
+    pub size: u64,
+    // The type flag from the header: b'0' or 0 for regular files, b'5' for
+    // directories and so on.
+    pub kind: u8,
+}
+
+// Where each member of a tar archive is, in the order they are in the
+// archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TarIndex {
+    members: Vec<TarMember>,
+}
+
+impl TarIndex {
+    pub fn members(&self) -> &[TarMember] {
+        &self.members
+    }
+
+    // Finds the member with the given path. Archives can hold several members
+    // with the same path, in which case the last one wins as it does when
+    // extracting the archive.
+    pub fn member(&self, path: &str) -> Option<&TarMember> {
+        self.members.iter().rev().find(|member| member.path == path)
+    }
+
+    // Each member is offset (8) | size (8) | kind (1) | path length (4) | path,
+    // with the numbers little-endian.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for member in &self.members {
+            data.extend_from_slice(&member.offset.to_le_bytes());
+            data.extend_from_slice(&member.size.to_le_bytes());
+            data.push(member.kind);
+            data.extend_from_slice(&(member.path.len() as u32).to_le_bytes());
+            data.extend_from_slice(member.path.as_bytes());
+        }
+        data
+    }
+
+    fn from_bytes(mut data: &[u8]) -> Option<Self> {
+        let mut members = Vec::new();
+        while !data.is_empty() {
+            let offset = u64::from_le_bytes(<[u8; 8]>::try_from(data.get(..8)?).ok()?);
+            let size = u64::from_le_bytes(<[u8; 8]>::try_from(data.get(8..16)?).ok()?);
+            let kind = *data.get(16)?;
+            let path_len = u32::from_le_bytes(<[u8; 4]>::try_from(data.get(17..21)?).ok()?);
+            let path_end = 21usize.checked_add(path_len as usize)?;
+            let path = String::from_utf8(data.get(21..path_end)?.to_vec()).ok()?;
+            members.push(TarMember {
+                path,
+                offset,
+                size,
+                kind,
+            });
+            data = &data[path_end..];
+        }
+        Some(TarIndex { members })
+    }
+}
+
+// Builds a TarIndex out of a tar archive as it's compressed. Add it to a
+// compression stream with Compress::with_index. Handles the ustar path
+// prefix, GNU long names and paths in pax headers.
+#[derive(Debug, Default)]
+pub struct TarIndexBuilder {
+    // How far into the archive we are.
+    offset: u64,
+    header: Vec<u8>,
+    // Contents of the current member, padding included, still to go by.
+    skip: u64,
+    // Contents of a long name or pax header we're collecting, which says
+    // what the path of the next member is.
+    extended: Option<(u8, Vec<u8>, u64)>,
+    next_path: Option<String>,
+    members: Vec<TarMember>,
+}
+
+impl TarIndexBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn header_done(&mut self) {
+        let header = std::mem::take(&mut self.header);
+        // Zero blocks mark the end of the archive.
+        if header.iter().all(|&b| b == 0) {
+            return;
+        }
+        let size = parse_size(&header[124..136]);
+        let kind = header[156];
+        self.skip = size.saturating_add(BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        match kind {
+            b'L' | b'x' => self.extended = Some((kind, Vec::new(), size)),
+            // Global pax headers and long link names don't change the path.
+            b'g' | b'K' => {}
+            _ => {
+                let path = self
+                    .next_path
+                    .take()
+                    .unwrap_or_else(|| header_path(&header));
+                self.members.push(TarMember {
+                    path,
+                    offset: self.offset,
+                    size,
+                    kind,
+                });
+            }
+        }
+        if self.skip == 0 {
+            self.extended_done();
+        }
+    }
+
+    fn extended_done(&mut self) {
+        let path = match self.extended.take() {
+            Some((b'L', data, _)) => Some(c_string(&data)),
+            Some((_, data, _)) => pax_path(&data),
+            None => None,
+        };
+        if path.is_some() {
+            self.next_path = path;
+        }
+    }
+}
+
+impl IndexBuilder for TarIndexBuilder {
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.skip > 0 {
+                let n = std::cmp::min(self.skip, data.len() as u64) as usize;
+                if let Some((_, contents, size)) = &mut self.extended {
+                    let wanted = (*size as usize).saturating_sub(contents.len());
+                    contents.extend_from_slice(&data[..std::cmp::min(n, wanted)]);
+                }
+                self.skip -= n as u64;
+                self.offset += n as u64;
+                data = &data[n..];
+                if self.skip == 0 {
+                    self.extended_done();
+                }
+            } else {
+                let n = std::cmp::min(BLOCK_SIZE as usize - self.header.len(), data.len());
+                self.header.extend_from_slice(&data[..n]);
+                self.offset += n as u64;
+                data = &data[n..];
+                if self.header.len() == BLOCK_SIZE as usize {
+                    self.header_done();
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self) -> IndexFrame {
+        let index = TarIndex {
+            members: std::mem::take(&mut self.members),
+        };
+        IndexFrame {
+            tag: TAR_INDEX_TAG,
+            payload: index.to_bytes(),
+        }
+    }
+}
+
+// Sizes are octal numbers, or big-endian binary ones when the top bit of the
+// first byte is set.
+fn parse_size(field: &[u8]) -> u64 {
+    if field[0] & 0x80 != 0 {
+        let first = u64::from(field[0] & 0x7F);
+        return field[1..]
+            .iter()
+            .fold(first, |size, &b| size.wrapping_shl(8) | u64::from(b));
+    }
+    field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| (b'0'..=b'7').contains(&b))
+        .fold(0, |size: u64, &b| {
+            size.wrapping_mul(8).wrapping_add(u64::from(b - b'0'))
+        })
+}
+
+fn c_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn header_path(header: &[u8]) -> String {
+    let name = c_string(&header[..100]);
+    let prefix = if &header[257..262] == b"ustar" {
+        c_string(&header[345..500])
+    } else {
+        String::new()
+    };
+    if prefix.is_empty() {
+        name
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+// Pax headers are records such as "30 path=some/very/long/path\n".
+fn pax_path(data: &[u8]) -> Option<String> {
+    let mut rest = data;
+    let mut path = None;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&b| b == b' ')?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        let record = rest.get(space + 1..len)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        rest = &rest[len..];
+    }
+    path
+}
+
+// A tar archive compressed with a tar index, see TarIndexBuilder. Members
+// can be read without going through the rest of the archive: only the frames
+// holding them are fetched and decompressed.
+pub struct TarArchive<'a, A> {
+    archive: SeekableDecompress<'a, A>,
+    index: TarIndex,
+}
+
+impl<A> std::fmt::Debug for TarArchive<'_, A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TarArchive")
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
+impl<'a, A> TarArchive<'a, A>
+where
+    A: Read + Seek,
+{
+    // Reads the tar index out of the compressed archive. Fails if the archive
+    // was compressed without one.
+    pub fn new(mut compressed: A) -> std::io::Result<Self> {
+        let frames = read_frame_index(&mut compressed)?;
+        let index =
+            read_index_frame(&mut compressed, &frames, TAR_INDEX_TAG)?.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "Archive has no tar index.")
+            })?;
+        let index = TarIndex::from_bytes(&index).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Tar index is malformed.")
+        })?;
+        let archive = SeekableDecompress::new(compressed)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(TarArchive { archive, index })
+    }
+
+    pub fn index(&self) -> &TarIndex {
+        &self.index
+    }
+
+    // Reader of the contents of the member with the given path.
+    pub fn open_member(
+        &mut self,
+        path: &str,
+    ) -> std::io::Result<Take<&mut SeekableDecompress<'a, A>>> {
+        let member = self.index.member(path).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No {} in archive.", path),
+            )
+        })?;
+        let size = member.size;
+        self.archive.seek(SeekFrom::Start(member.offset))?;
+        Ok((&mut self.archive).take(size))
+    }
+}
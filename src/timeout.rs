@@ -0,0 +1,66 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::ObjectErrorContext;
+
+// What it was that took too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TimeoutKind {
+    // A request got no response in time, connecting to S3 included.
+    Response,
+    // Reading the body of a response got nothing in time.
+    BodyRead,
+    // The body kept going but slower than the minimum throughput.
+    Stalled,
+    // The deadline for the whole read passed. Retrying is no use.
+    Deadline,
+}
+
+// Inner error of the TimedOut io::Errors reads give, see timeout_kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TimeoutError {
+    pub kind: TimeoutKind,
+    // How long we waited.
+    pub after: Duration,
+}
+
+impl TimeoutError {
+    pub(crate) fn new(kind: TimeoutKind, after: Duration) -> Self {
+        TimeoutError { kind, after }
+    }
+
+    pub(crate) fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, self)
+    }
+}
+
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            TimeoutKind::Response => write!(f, "No response after {:?}", self.after),
+            TimeoutKind::BodyRead => write!(f, "Body sent nothing for {:?}", self.after),
+            TimeoutKind::Stalled => write!(
+                f,
+                "Body stayed below the minimum throughput for {:?}",
+                self.after
+            ),
+            TimeoutKind::Deadline => write!(f, "Deadline passed after {:?}", self.after),
+        }
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+// What kind of timeout the error is, if it is one, looking through the
+// context SeekableS3Object adds.
+pub fn timeout_kind(error: &std::io::Error) -> Option<TimeoutKind> {
+    let inner = error.get_ref()?;
+    if let Some(context) = inner.downcast_ref::<ObjectErrorContext>() {
+        return timeout_kind(context.error());
+    }
+    inner
+        .downcast_ref::<TimeoutError>()
+        .map(|timeout| timeout.kind)
+}
@@ -0,0 +1,61 @@
+// Spans around the requests we make to S3 and events along the way, only
+// there with the tracing feature. Without it these expand to nothing but the
+// code they wrap.
+
+// Only decompression is left without the s3 feature, which makes no requests.
+#![cfg_attr(not(feature = "s3"), allow(unused_macros))]
+
+// Runs the future within a new span with the given name and fields. The span
+// is created first so that its fields can borrow from whatever the future
+// takes.
+#[cfg(feature = "tracing")]
+macro_rules! traced {
+    ($future:expr, $($span:tt)+) => {{
+        let span = tracing::info_span!($($span)+);
+        tracing::Instrument::instrument($future, span)
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! traced {
+    ($future:expr, $($span:tt)+) => {
+        $future
+    };
+}
+
+// Enters a new span with the given name and fields for the rest of the
+// enclosing block.
+#[cfg(feature = "tracing")]
+macro_rules! enter_span {
+    ($($span:tt)+) => {
+        let _span = tracing::info_span!($($span)+).entered();
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! enter_span {
+    ($($span:tt)+) => {};
+}
+
+// Event at the given level with a target of its own for each part of the
+// lifecycle, so that they can be turned on one by one:
+//
+// zstd_seekable_s3::open: objects opened and seek tables read.
+// zstd_seekable_s3::fetch: requests for object bodies, and bodies dropped by
+// seeks that went too far from them.
+// zstd_seekable_s3::decompress: reads of decompressed data, each going
+// through as many frames as it takes.
+// zstd_seekable_s3::upload: parts uploaded and retried.
+//
+// These are part of the API: don't rename them.
+#[cfg(feature = "tracing")]
+macro_rules! log_event {
+    ($level:ident, $($event:tt)+) => {
+        tracing::$level!($($event)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! log_event {
+    ($level:ident, $($event:tt)+) => {};
+}
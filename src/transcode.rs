@@ -0,0 +1,61 @@
+use async_compression::tokio::bufread::GzipDecoder;
+use rusoto_core::{ByteStream, RusotoError};
+use rusoto_s3::{CompleteMultipartUploadOutput, GetObjectError, GetObjectRequest, S3};
+use std::fmt::Display;
+use tokio::io::BufReader;
+
+use crate::{upload_compressed_reader, UploadError, UploadOptions};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TranscodeError {
+    GetObject(RusotoError<GetObjectError>),
+    // Gzip errors and the source body breaking off come out as
+    // UploadError::Underlying.
+    Upload(UploadError<std::io::Error>),
+}
+
+impl Display for TranscodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranscodeError::GetObject(e) => write!(f, "Failed to get source object: {}", e),
+            TranscodeError::Upload(e) => write!(f, "Failed to upload object: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TranscodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TranscodeError::GetObject(e) => Some(e),
+            TranscodeError::Upload(e) => Some(e),
+        }
+    }
+}
+
+// Turns a gzip object into a seekable one at bucket/key, decompressing the
+// source as it's downloaded and compressing it again as it's uploaded, so
+// neither is ever held whole. Sources made of several gzip members one after
+// the other, as appending to a gzip log file makes, come out as all of the
+// members' data. The source is left as it is.
+pub async fn transcode_gzip_object<C: S3 + Clone + Send + Sync + 'static>(
+    client: &C,
+    source: &GetObjectRequest,
+    bucket: &str,
+    key: &str,
+    options: &UploadOptions,
+) -> Result<CompleteMultipartUploadOutput, TranscodeError> {
+    let get_object = traced!(
+        client.get_object(source.to_owned()),
+        "get_object",
+        bucket = %source.bucket,
+        key = %source.key,
+    );
+    let output = get_object.await.map_err(TranscodeError::GetObject)?;
+    let body = output.body.unwrap_or_else(|| ByteStream::from(Vec::new()));
+    let mut decoder = GzipDecoder::new(BufReader::new(body.into_async_read()));
+    decoder.multiple_members(true);
+    upload_compressed_reader(client, bucket, key, decoder, options)
+        .await
+        .map_err(TranscodeError::Upload)
+}
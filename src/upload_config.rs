@@ -0,0 +1,239 @@
+use rusoto_s3::{CreateMultipartUploadRequest, PutObjectRequest, UploadPartRequest};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "SCREAMING_SNAKE_CASE"))]
+pub enum StorageClass {
+    Standard,
+    ReducedRedundancy,
+    StandardIa,
+    OnezoneIa,
+    IntelligentTiering,
+    Glacier,
+    GlacierIr,
+    DeepArchive,
+    Outposts,
+}
+
+impl StorageClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::ReducedRedundancy => "REDUCED_REDUNDANCY",
+            StorageClass::StandardIa => "STANDARD_IA",
+            StorageClass::OnezoneIa => "ONEZONE_IA",
+            StorageClass::IntelligentTiering => "INTELLIGENT_TIERING",
+            StorageClass::Glacier => "GLACIER",
+            StorageClass::GlacierIr => "GLACIER_IR",
+            StorageClass::DeepArchive => "DEEP_ARCHIVE",
+            StorageClass::Outposts => "OUTPOSTS",
+        }
+    }
+}
+
+// Canned ACLs S3 knows about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum CannedAcl {
+    Private,
+    PublicRead,
+    PublicReadWrite,
+    AuthenticatedRead,
+    AwsExecRead,
+    BucketOwnerRead,
+    BucketOwnerFullControl,
+}
+
+impl CannedAcl {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CannedAcl::Private => "private",
+            CannedAcl::PublicRead => "public-read",
+            CannedAcl::PublicReadWrite => "public-read-write",
+            CannedAcl::AuthenticatedRead => "authenticated-read",
+            CannedAcl::AwsExecRead => "aws-exec-read",
+            CannedAcl::BucketOwnerRead => "bucket-owner-read",
+            CannedAcl::BucketOwnerFullControl => "bucket-owner-full-control",
+        }
+    }
+}
+
+// Object Lock retention modes. Governance mode can be lifted by users with the
+// right permissions, compliance mode can't be lifted by anyone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "SCREAMING_SNAKE_CASE"))]
+pub enum ObjectLockMode {
+    Governance,
+    Compliance,
+}
+
+impl ObjectLockMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjectLockMode::Governance => "GOVERNANCE",
+            ObjectLockMode::Compliance => "COMPLIANCE",
+        }
+    }
+}
+
+// Key to use with SSE-C. The same key has to be given when reading the object
+// back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CustomerKey {
+    pub algorithm: String,
+    // Base64-encoded key and base64-encoded MD5 of the key.
+    pub key: String,
+    pub key_md5: String,
+}
+
+// Settings for the object an upload creates. Most of these only take effect
+// when the upload is created: S3 ignores them on individual parts. Use
+// create_request to make the request for the upload and part_template to make
+// a template to pass to upload_parts that carries everything the parts need
+// to know.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct UploadConfig {
+    pub storage_class: Option<StorageClass>,
+    pub acl: Option<CannedAcl>,
+    // Tags to put on the object, in order.
+    pub tagging: Vec<(String, String)>,
+    pub cache_control: Option<String>,
+    // What S3 sends back in Content-Type and Content-Encoding when the object
+    // is read. Don't set the encoding to anything that makes HTTP clients
+    // decompress the data on the fly, such as "zstd": seeking relies on
+    // getting the compressed bytes as they are.
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    // User metadata, sent as x-amz-meta-* headers. Keys are given without the
+    // prefix.
+    pub metadata: HashMap<String, String>,
+    // Encryption at rest with a key S3 manages ("AES256" or "aws:kms") and for
+    // KMS, which key to use.
+    pub server_side_encryption: Option<String>,
+    pub ssekms_key_id: Option<String>,
+    pub customer_key: Option<CustomerKey>,
+    pub expected_bucket_owner: Option<String>,
+    pub request_payer: Option<String>,
+    // Object Lock settings, which need the bucket to have Object Lock enabled.
+    // The retain until date is an ISO 8601 timestamp such as
+    // "2030-01-01T00:00:00Z". S3 wants parts of locked objects to come with
+    // their Content-MD5, see UploadOptions::content_md5.
+    pub object_lock_mode: Option<ObjectLockMode>,
+    pub object_lock_retain_until_date: Option<String>,
+    pub object_lock_legal_hold: bool,
+}
+
+impl UploadConfig {
+    pub fn create_request(&self, bucket: &str, key: &str) -> CreateMultipartUploadRequest {
+        CreateMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            storage_class: self
+                .storage_class
+                .map(|storage_class| storage_class.as_str().to_owned()),
+            acl: self.acl.map(|acl| acl.as_str().to_owned()),
+            tagging: if self.tagging.is_empty() {
+                None
+            } else {
+                Some(encode_tagging(&self.tagging))
+            },
+            cache_control: self.cache_control.to_owned(),
+            content_type: self.content_type.to_owned(),
+            content_encoding: self.content_encoding.to_owned(),
+            metadata: if self.metadata.is_empty() {
+                None
+            } else {
+                Some(self.metadata.to_owned())
+            },
+            server_side_encryption: self.server_side_encryption.to_owned(),
+            ssekms_key_id: self.ssekms_key_id.to_owned(),
+            sse_customer_algorithm: self.customer_key.as_ref().map(|k| k.algorithm.to_owned()),
+            sse_customer_key: self.customer_key.as_ref().map(|k| k.key.to_owned()),
+            sse_customer_key_md5: self.customer_key.as_ref().map(|k| k.key_md5.to_owned()),
+            expected_bucket_owner: self.expected_bucket_owner.to_owned(),
+            request_payer: self.request_payer.to_owned(),
+            object_lock_mode: self.object_lock_mode.map(|mode| mode.as_str().to_owned()),
+            object_lock_retain_until_date: self.object_lock_retain_until_date.to_owned(),
+            object_lock_legal_hold_status: if self.object_lock_legal_hold {
+                Some("ON".to_owned())
+            } else {
+                None
+            },
+            ..Default::default()
+        }
+    }
+
+    // Request to put a small object in one go with the same settings.
+    pub fn put_request(&self, bucket: &str, key: &str) -> PutObjectRequest {
+        let create = self.create_request(bucket, key);
+        PutObjectRequest {
+            bucket: create.bucket,
+            key: create.key,
+            storage_class: create.storage_class,
+            acl: create.acl,
+            tagging: create.tagging,
+            cache_control: create.cache_control,
+            content_type: create.content_type,
+            content_encoding: create.content_encoding,
+            metadata: create.metadata,
+            server_side_encryption: create.server_side_encryption,
+            ssekms_key_id: create.ssekms_key_id,
+            sse_customer_algorithm: create.sse_customer_algorithm,
+            sse_customer_key: create.sse_customer_key,
+            sse_customer_key_md5: create.sse_customer_key_md5,
+            expected_bucket_owner: create.expected_bucket_owner,
+            request_payer: create.request_payer,
+            object_lock_mode: create.object_lock_mode,
+            object_lock_retain_until_date: create.object_lock_retain_until_date,
+            object_lock_legal_hold_status: create.object_lock_legal_hold_status,
+            ..Default::default()
+        }
+    }
+
+    // Whether the ETag of the object is derived from the MD5 of its data. S3
+    // does something else for objects encrypted with SSE-KMS or SSE-C.
+    pub fn has_predictable_etag(&self) -> bool {
+        self.customer_key.is_none() && self.server_side_encryption.as_deref() != Some("aws:kms")
+    }
+
+    pub fn part_template(&self, bucket: &str, key: &str, upload_id: &str) -> UploadPartRequest {
+        UploadPartRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            sse_customer_algorithm: self.customer_key.as_ref().map(|k| k.algorithm.to_owned()),
+            sse_customer_key: self.customer_key.as_ref().map(|k| k.key.to_owned()),
+            sse_customer_key_md5: self.customer_key.as_ref().map(|k| k.key_md5.to_owned()),
+            expected_bucket_owner: self.expected_bucket_owner.to_owned(),
+            request_payer: self.request_payer.to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+// Tags go in the x-amz-tagging header as URL query parameters.
+fn encode_tagging(tags: &[(String, String)]) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", percent_encode(key), percent_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
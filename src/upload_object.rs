@@ -0,0 +1,664 @@
+use bytes::{Bytes, BytesMut};
+use futures::{stream::MapErr, Stream, TryStreamExt};
+use rusoto_core::{request::HttpDispatchError, RusotoError};
+use rusoto_s3::{
+    AbortMultipartUploadError, CompleteMultipartUploadError, CompleteMultipartUploadOutput,
+    CompletedPart, CreateMultipartUploadError, PutObjectError, UploadPartError, UploadPartRequest,
+    S3,
+};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::progress::ProgressTracker;
+use crate::{
+    is_retryable, Compress, CompressError, CompressItem, FrameIndex, MultipartCreateError,
+    MultipartUpload, PartData, PreparedPart, ProgressCallback, RetryPolicy, StreamCompress,
+    StreamUploadParts, UploadConfig, UploadParts, ZstdError,
+};
+
+// Smallest part size S3 accepts for all but the last part.
+pub const MINIMUM_PART_SIZE: usize = 5 * 1024 * 1024;
+
+// How much we read at a time from readers we upload.
+const READ_SIZE: usize = 64 * 1024;
+
+// Suffix conventionally used for seek table objects, see
+// UploadOptions::seek_table_suffix.
+pub const SEEK_TABLE_OBJECT_SUFFIX: &str = ".idx";
+
+// Gets called with every part right before it's sent, along with the request
+// that's about to go out. The request can be changed, say to set fields the
+// upload doesn't know about. The hook runs again for every retry of the part,
+// with a fresh request each time.
+#[derive(Clone)]
+pub struct PartHook(Arc<dyn Fn(&PreparedPart, &mut UploadPartRequest) + Send + Sync>);
+
+impl PartHook {
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: Fn(&PreparedPart, &mut UploadPartRequest) + Send + Sync + 'static,
+    {
+        PartHook(Arc::new(hook))
+    }
+}
+
+impl std::fmt::Debug for PartHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PartHook").finish()
+    }
+}
+
+// Hooks are only equal to their clones.
+impl PartialEq for PartHook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.0) as *const u8 == Arc::as_ptr(&other.0) as *const u8
+    }
+}
+
+impl Eq for PartHook {}
+
+// How to compress and chunk the data when uploading a whole object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct UploadOptions {
+    pub compression_level: usize,
+    // Uncompressed size of each frame. Smaller frames make for cheaper seeks
+    // at the cost of compression ratio.
+    pub frame_size: usize,
+    pub minimum_part_size: usize,
+    // Grow the part size as the upload goes on so that we never hit the part
+    // count limit, see UploadParts::with_automatic_part_sizing.
+    pub automatic_part_sizing: bool,
+    // How many parts to have in flight at once. Each of these holds a part
+    // worth of data in memory, unless it was spilled to disk.
+    pub concurrency: usize,
+    // Spill parts to disk once more than this many bytes of them are buffered,
+    // see UploadParts::with_disk_spill. Files go into spill_directory or the
+    // system temporary directory.
+    pub spill_threshold: Option<usize>,
+    pub spill_directory: Option<PathBuf>,
+    // Send the MD5 of each part so S3 can reject corrupted ones.
+    pub content_md5: bool,
+    // How to retry parts that failed to upload.
+    pub retry: RetryPolicy,
+    // Told about the progress of the upload as it happens. Callbacks and hooks
+    // are left out of config files.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub progress: Option<ProgressCallback>,
+    // Sees every part before it's sent.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub part_hook: Option<PartHook>,
+    // Settings for the object we create.
+    pub config: UploadConfig,
+    // Check the ETag S3 gives the completed object against one computed from
+    // the data we sent. Objects encrypted with SSE-KMS or SSE-C don't have
+    // ETags we can predict and are never checked.
+    pub verify_etag: bool,
+    // Also upload the seek table on its own, to the key of the object with
+    // this suffix added (see SEEK_TABLE_OBJECT_SUFFIX). Readers can then get
+    // the whole frame index with a single small request, see
+    // fetch_seek_table_object. It's uploaded once the object is complete.
+    pub seek_table_suffix: Option<String>,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            compression_level: 3,
+            frame_size: 1024 * 1024,
+            minimum_part_size: MINIMUM_PART_SIZE,
+            automatic_part_sizing: true,
+            concurrency: 4,
+            spill_threshold: None,
+            spill_directory: None,
+            content_md5: true,
+            retry: RetryPolicy::default(),
+            progress: None,
+            part_hook: None,
+            config: UploadConfig::default(),
+            verify_etag: true,
+            seek_table_suffix: None,
+        }
+    }
+}
+
+// Anything that can go wrong while uploading a whole object.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum UploadError<E> {
+    Compression(ZstdError),
+    // The input stream failed.
+    Underlying(E),
+    CreateMultipartUpload(RusotoError<CreateMultipartUploadError>),
+    // S3 didn't give us an upload ID to upload the parts with.
+    MissingUploadId,
+    UploadPart(RusotoError<UploadPartError>),
+    CompleteMultipartUpload(RusotoError<CompleteMultipartUploadError>),
+    // Writing a part out to disk failed.
+    Spill(std::io::Error),
+    // The object was created but its ETag isn't the one we expected given the
+    // data we sent: something got corrupted on the way.
+    ETagMismatch {
+        expected: String,
+        actual: Option<String>,
+    },
+    // The object was created but uploading its seek table object failed.
+    SeekTableObject(RusotoError<PutObjectError>),
+    // An UploadSink was created outside of a tokio runtime, so there's
+    // nothing to run the upload on.
+    NoRuntime,
+    // The upload failed and so did our attempt to abort it: the parts that
+    // made it to S3 are left behind and have to be cleaned up some other way.
+    Abort {
+        upload_id: String,
+        error: Box<UploadError<E>>,
+        abort_error: RusotoError<AbortMultipartUploadError>,
+    },
+}
+
+impl<E> From<CompressError<E>> for UploadError<E> {
+    fn from(e: CompressError<E>) -> Self {
+        match e {
+            CompressError::Zstd(e) => UploadError::Compression(e),
+            CompressError::Underlying(e) => UploadError::Underlying(e),
+        }
+    }
+}
+
+impl<E> From<std::io::Error> for UploadError<E> {
+    fn from(e: std::io::Error) -> Self {
+        UploadError::Spill(e)
+    }
+}
+
+impl<E: Display> Display for UploadError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::Compression(e) => write!(f, "Compression error: {}", e),
+            UploadError::Underlying(e) => write!(f, "Underlying error: {}", e),
+            UploadError::CreateMultipartUpload(e) => {
+                write!(f, "Failed to create multipart upload: {}", e)
+            }
+            UploadError::MissingUploadId => write!(f, "No upload ID in multipart upload response."),
+            UploadError::NoRuntime => write!(f, "Upload started outside of a tokio runtime."),
+            UploadError::UploadPart(e) => write!(f, "Failed to upload part: {}", e),
+            UploadError::CompleteMultipartUpload(e) => {
+                write!(f, "Failed to complete multipart upload: {}", e)
+            }
+            UploadError::Spill(e) => write!(f, "Failed to spill part to disk: {}", e),
+            UploadError::ETagMismatch { expected, actual } => write!(
+                f,
+                "Uploaded object has ETag {}, expected {}",
+                actual.as_deref().unwrap_or("<none>"),
+                expected
+            ),
+            UploadError::SeekTableObject(e) => {
+                write!(f, "Failed to upload seek table object: {}", e)
+            }
+            UploadError::Abort {
+                upload_id,
+                error,
+                abort_error,
+            } => write!(
+                f,
+                "{}; additionally failed to abort upload {}: {}",
+                error, upload_id, abort_error
+            ),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for UploadError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UploadError::Underlying(e) => Some(e),
+            UploadError::CreateMultipartUpload(e) => Some(e),
+            UploadError::UploadPart(e) => Some(e),
+            UploadError::CompleteMultipartUpload(e) => Some(e),
+            UploadError::Spill(e) => Some(e),
+            UploadError::SeekTableObject(e) => Some(e),
+            UploadError::Abort { error, .. } => Some(error.as_ref()),
+            UploadError::Compression(e) => Some(e),
+            UploadError::MissingUploadId
+            | UploadError::NoRuntime
+            | UploadError::ETagMismatch { .. } => None,
+        }
+    }
+}
+
+// Compresses the stream and uploads it to the given location as a multipart
+// upload. If anything goes wrong after the upload is created, it's aborted so
+// that no parts are left behind. That includes the future being dropped half
+// way, see MultipartUpload.
+pub async fn upload_compressed_object<C, S, I, E>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    stream: S,
+    options: &UploadOptions,
+) -> Result<CompleteMultipartUploadOutput, UploadError<E>>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+    S: Stream<Item = Result<I, E>>,
+    I: AsRef<[u8]>,
+{
+    let req = options.config.create_request(bucket, key);
+    let upload = MultipartUpload::create(client.clone(), req)
+        .await
+        .map_err(|e| match e {
+            MultipartCreateError::Create(e) => UploadError::CreateMultipartUpload(e),
+            MultipartCreateError::MissingUploadId => UploadError::MissingUploadId,
+        })?;
+
+    let uploaded = upload_all_parts(client, upload.upload_id(), bucket, key, stream, options).await;
+    match uploaded {
+        // Completing consumes the upload, which aborts it if that fails. The
+        // object exists once it's complete so there's nothing to abort if the
+        // ETag turns out to be wrong.
+        Ok((completed_parts, expected_etag, frame_index)) => {
+            let output = upload
+                .complete(completed_parts)
+                .await
+                .map_err(UploadError::CompleteMultipartUpload)?;
+            if let Some(expected_etag) = expected_etag {
+                let actual = output.e_tag.as_deref().map(|e_tag| e_tag.trim_matches('"'));
+                if actual != Some(expected_etag.as_str()) {
+                    return Err(UploadError::ETagMismatch {
+                        expected: expected_etag,
+                        actual: output.e_tag,
+                    });
+                }
+            }
+            if let (Some(suffix), Some(frame_index)) = (&options.seek_table_suffix, frame_index) {
+                upload_seek_table_object(
+                    client,
+                    bucket,
+                    &format!("{}{}", key, suffix),
+                    &frame_index,
+                    &options.config,
+                )
+                .await
+                .map_err(UploadError::SeekTableObject)?;
+            }
+            Ok(output)
+        }
+        Err(error) => {
+            let upload_id = upload.upload_id().to_owned();
+            match upload.abort().await {
+                Ok(_) => Err(error),
+                Err(abort_error) => Err(UploadError::Abort {
+                    upload_id,
+                    error: Box::new(error),
+                    abort_error,
+                }),
+            }
+        }
+    }
+}
+
+// Compresses everything the reader gives us and uploads it, see
+// upload_compressed_object. Handy for backing up files and the like.
+pub async fn upload_compressed_reader<C, R>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    reader: R,
+    options: &UploadOptions,
+) -> Result<CompleteMultipartUploadOutput, UploadError<std::io::Error>>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+    R: AsyncRead + Unpin,
+{
+    let chunks = futures::stream::try_unfold(reader, |mut reader| async move {
+        let mut chunk = BytesMut::with_capacity(READ_SIZE);
+        if reader.read_buf(&mut chunk).await? == 0 {
+            Ok(None)
+        } else {
+            Ok(Some((chunk.freeze(), reader)))
+        }
+    });
+    upload_compressed_object(client, bucket, key, chunks, options).await
+}
+
+// Compresses the file at the given path and uploads it, see
+// upload_compressed_object. Failing to open the file is an Underlying error
+// like failing to read it.
+pub async fn upload_compressed<C>(
+    path: impl AsRef<Path>,
+    client: &C,
+    bucket: &str,
+    key: &str,
+    options: &UploadOptions,
+) -> Result<CompleteMultipartUploadOutput, UploadError<std::io::Error>>
+where
+    C: S3 + Clone + Send + Sync + 'static,
+{
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(UploadError::Underlying)?;
+    upload_compressed_reader(client, bucket, key, file, options).await
+}
+
+// Uploads the frame index as a seek table object of its own.
+pub(crate) async fn upload_seek_table_object<C: S3>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    frame_index: &FrameIndex,
+    config: &UploadConfig,
+) -> Result<(), RusotoError<PutObjectError>> {
+    let seek_table = frame_index
+        .to_seek_table()
+        .map_err(|e| RusotoError::Validation(e.to_string()))?;
+    let mut req = config.put_request(bucket, key);
+    req.content_md5 = Some(base64::encode(md5::compute(&seek_table).0));
+    req.body = Some(seek_table.into());
+    client.put_object(req).await?;
+    Ok(())
+}
+
+// Sets up the parts the way the options ask for.
+pub(crate) fn configure_upload_parts<S, E>(
+    mut parts: UploadParts<S, UploadError<E>>,
+    options: &UploadOptions,
+) -> UploadParts<S, UploadError<E>> {
+    if options.content_md5 {
+        parts = parts.with_content_md5();
+    }
+    if options.automatic_part_sizing {
+        parts = parts.with_automatic_part_sizing();
+    }
+    if let Some(threshold) = options.spill_threshold {
+        parts = parts.with_disk_spill(threshold, options.spill_directory.to_owned());
+    }
+    parts
+}
+
+// Parts of a compressed stream, see StreamCompressUploadParts.
+pub type CompressedUploadParts<S, E> =
+    UploadParts<MapErr<Compress<S, E>, fn(CompressError<E>) -> UploadError<E>>, UploadError<E>>;
+
+// Compresses a stream and chunks it into parts in one go, with both
+// compression and input errors ending up in UploadError. The compression and
+// part settings come from the options; the rest of them are ignored as it's
+// up to the caller to upload the parts.
+pub trait StreamCompressUploadParts {
+    fn compress_upload_parts<I, E>(
+        self,
+        part_template: UploadPartRequest,
+        options: &UploadOptions,
+    ) -> Result<CompressedUploadParts<Self, E>, UploadError<E>>
+    where
+        Self: Stream<Item = Result<I, E>> + Sized,
+        I: AsRef<[u8]>;
+}
+
+impl<S> StreamCompressUploadParts for S {
+    fn compress_upload_parts<I, E>(
+        self,
+        part_template: UploadPartRequest,
+        options: &UploadOptions,
+    ) -> Result<CompressedUploadParts<Self, E>, UploadError<E>>
+    where
+        Self: Stream<Item = Result<I, E>> + Sized,
+        I: AsRef<[u8]>,
+    {
+        let parts = self
+            .compress(options.compression_level, options.frame_size)
+            .map_err(UploadError::Compression)?
+            .map_err(UploadError::from as fn(CompressError<E>) -> UploadError<E>)
+            .upload_parts(part_template, options.minimum_part_size);
+        Ok(configure_upload_parts(parts, options))
+    }
+}
+
+// Uploads the parts of the compressed stream, giving them along with the
+// ETag the object should end up with, if we can tell, and the frame index.
+async fn upload_all_parts<C, S, I, E>(
+    client: &C,
+    upload_id: &str,
+    bucket: &str,
+    key: &str,
+    stream: S,
+    options: &UploadOptions,
+) -> Result<(Vec<CompletedPart>, Option<String>, Option<FrameIndex>), UploadError<E>>
+where
+    C: S3,
+    S: Stream<Item = Result<I, E>>,
+    I: AsRef<[u8]>,
+{
+    let part_template = options.config.part_template(bucket, key, upload_id);
+    let verify_etag = options.verify_etag && options.config.has_predictable_etag();
+
+    let progress = ProgressTracker::new(options.progress.to_owned());
+    // Handed to us at the end of the compressed stream.
+    let frame_index = Mutex::new(None);
+    let parts = stream
+        .inspect_ok(|input| progress.uncompressed(input.as_ref().len()))
+        .compress(options.compression_level, options.frame_size)
+        .map_err(UploadError::Compression)?
+        .with_checkpoints()
+        .try_filter_map(|item| {
+            futures::future::ready(Ok(match item {
+                CompressItem::Data(data) => Some(data),
+                CompressItem::FrameIndex(index) => {
+                    *frame_index.lock() = Some(index);
+                    None
+                }
+                CompressItem::Checkpoint(_) => None,
+            }))
+        })
+        .inspect_ok(|compressed| progress.compressed(compressed.len()))
+        .map_err(UploadError::from)
+        .upload_parts(part_template.to_owned(), options.minimum_part_size);
+    let mut parts = configure_upload_parts(parts, options);
+    if verify_etag {
+        parts = parts.with_md5();
+    }
+    let completed_parts = upload_parts_tracked(
+        client,
+        &part_template,
+        parts.prepared_parts(),
+        options.concurrency,
+        &options.retry,
+        &progress,
+        options.part_hook.as_ref(),
+    )
+    .await?;
+    let (completed_parts, part_md5s): (Vec<_>, Vec<_>) = completed_parts.into_iter().unzip();
+    let expected_etag = if verify_etag {
+        part_md5s
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .map(|part_md5s| multipart_etag(&part_md5s))
+    } else {
+        None
+    };
+    Ok((completed_parts, expected_etag, frame_index.into_inner()))
+}
+
+// ETag S3 gives to an object uploaded in parts with the given MD5s, without
+// the quotes. Only holds for objects that aren't encrypted with SSE-KMS or
+// SSE-C.
+pub fn multipart_etag(part_md5s: &[[u8; 16]]) -> String {
+    let md5s: Vec<u8> = part_md5s.iter().flatten().copied().collect();
+    format!("{:x}-{}", md5::compute(&md5s), part_md5s.len())
+}
+
+// Uploads the parts from the stream, with up to concurrency of them in flight
+// at once. The completed parts are returned in part number order, ready to be
+// passed on to CompleteMultipartUpload.
+pub async fn upload_parts_concurrently<C, S, E>(
+    client: &C,
+    parts: S,
+    concurrency: usize,
+    retry: &RetryPolicy,
+) -> Result<Vec<CompletedPart>, UploadError<E>>
+where
+    C: S3,
+    S: Stream<Item = Result<UploadPartRequest, UploadError<E>>>,
+{
+    let mut completed_parts: Vec<CompletedPart> = parts
+        .map_ok(|part| async move {
+            upload_part_with_retry(client, part, retry)
+                .await
+                .map_err(UploadError::UploadPart)
+        })
+        // With no parts allowed in flight we'd never make any progress.
+        .try_buffer_unordered(std::cmp::max(concurrency, 1))
+        .try_collect()
+        .await?;
+    // Parts finish in whatever order S3 gets through them.
+    completed_parts.sort_by_key(|part| part.part_number);
+    Ok(completed_parts)
+}
+
+// Uploads the prepared parts, reporting progress. Along with each completed
+// part we return the MD5 of its data if it was computed.
+async fn upload_parts_tracked<C, S, E>(
+    client: &C,
+    part_template: &UploadPartRequest,
+    parts: S,
+    concurrency: usize,
+    retry: &RetryPolicy,
+    progress: &ProgressTracker,
+    part_hook: Option<&PartHook>,
+) -> Result<Vec<(CompletedPart, Option<[u8; 16]>)>, UploadError<E>>
+where
+    C: S3,
+    S: Stream<Item = Result<PreparedPart, UploadError<E>>>,
+{
+    let mut completed_parts: Vec<(CompletedPart, Option<[u8; 16]>)> = parts
+        .map_ok(|part| async move {
+            let size = usize::try_from(part.data.len()).unwrap_or(usize::MAX);
+            progress.part_started(size);
+            let completed_part =
+                upload_part_attempts(client, part_template, &part, retry, part_hook)
+                    .await
+                    .map_err(UploadError::UploadPart)?;
+            progress.part_completed(size);
+            Ok((completed_part, part.md5))
+        })
+        // With no parts allowed in flight we'd never make any progress.
+        .try_buffer_unordered(std::cmp::max(concurrency, 1))
+        .try_collect()
+        .await?;
+    // Parts finish in whatever order S3 gets through them.
+    completed_parts.sort_by_key(|(part, _)| part.part_number);
+    Ok(completed_parts)
+}
+
+// Uploads a single part, retrying it according to the policy if it fails with
+// something that may go away on its own.
+pub async fn upload_part_with_retry<C: S3>(
+    client: &C,
+    part: UploadPartRequest,
+    retry: &RetryPolicy,
+) -> Result<CompletedPart, RusotoError<UploadPartError>> {
+    let (part_template, part) = prepare_part_request(part).await?;
+    upload_prepared_part(client, &part_template, &part, retry).await
+}
+
+// Splits the part request into a template and a part that can be sent again.
+// The body is consumed by each upload attempt so we need to hold on to the
+// data to be able to retry.
+pub(crate) async fn prepare_part_request(
+    mut part: UploadPartRequest,
+) -> Result<(UploadPartRequest, PreparedPart), RusotoError<UploadPartError>> {
+    let data = match part.body.take() {
+        None => Bytes::new(),
+        Some(body) => body
+            .try_fold(BytesMut::new(), |mut data, chunk| async move {
+                data.extend_from_slice(&chunk);
+                Ok(data)
+            })
+            .await
+            .map_err(|e| RusotoError::HttpDispatch(HttpDispatchError::new(e.to_string())))?
+            .freeze(),
+    };
+    let prepared = PreparedPart {
+        part_number: part.part_number,
+        data: PartData::Memory(data),
+        content_md5: part.content_md5.to_owned(),
+        md5: None,
+    };
+    Ok((part, prepared))
+}
+
+// Uploads the part, retrying as needed. Spilled parts are read back from disk
+// for every attempt.
+pub async fn upload_prepared_part<C: S3>(
+    client: &C,
+    part_template: &UploadPartRequest,
+    part: &PreparedPart,
+    retry: &RetryPolicy,
+) -> Result<CompletedPart, RusotoError<UploadPartError>> {
+    upload_part_attempts(client, part_template, part, retry, None).await
+}
+
+async fn upload_part_attempts<C: S3>(
+    client: &C,
+    part_template: &UploadPartRequest,
+    part: &PreparedPart,
+    retry: &RetryPolicy,
+    part_hook: Option<&PartHook>,
+) -> Result<CompletedPart, RusotoError<UploadPartError>> {
+    let mut failed_attempts = 0;
+    loop {
+        let mut req = part.request(part_template);
+        if let Some(part_hook) = part_hook {
+            (part_hook.0)(part, &mut req);
+        }
+        // Complete with whatever number the part ended up being sent as.
+        let part_number = req.part_number;
+        let upload_part = traced!(
+            client.upload_part(req),
+            "upload_part",
+            bucket = %part_template.bucket,
+            key = %part_template.key,
+            upload_id = %part_template.upload_id,
+            part_number,
+            attempt = failed_attempts + 1,
+        );
+        match upload_part.await {
+            Ok(out) => {
+                log_event!(
+                    debug,
+                    target: "zstd_seekable_s3::upload",
+                    bucket = %part_template.bucket,
+                    key = %part_template.key,
+                    part_number,
+                    attempts = failed_attempts + 1,
+                    "Uploaded part"
+                );
+                break Ok(CompletedPart {
+                    e_tag: out.e_tag,
+                    part_number: Some(part_number),
+                });
+            }
+            Err(e) => {
+                failed_attempts += 1;
+                if !is_retryable(&e) || !retry.should_retry(failed_attempts) {
+                    break Err(e);
+                }
+                log_event!(
+                    info,
+                    target: "zstd_seekable_s3::upload",
+                    bucket = %part_template.bucket,
+                    key = %part_template.key,
+                    part_number,
+                    failed_attempts,
+                    error = %e,
+                    "Retrying part"
+                );
+                tokio::time::sleep(retry.backoff(failed_attempts)).await;
+            }
+        }
+    }
+}
@@ -1,6 +1,12 @@
-use std::{marker::PhantomData, pin::Pin};
+use std::{
+    io::Write,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::{
     ready,
     stream::{FusedStream, Stream},
@@ -8,6 +14,20 @@ use futures::{
 use pin_project_lite::pin_project;
 use rusoto_core::ByteStream;
 use rusoto_s3::UploadPartRequest;
+use std::convert::TryFrom;
+use tokio::io::AsyncReadExt;
+
+// Limits S3 puts on multipart uploads.
+pub const MAXIMUM_PARTS: i64 = 10_000;
+pub const MAXIMUM_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+// With automatic part sizing, how many parts we make before doubling the part
+// size. Starting at the minimum S3 part size of 5MiB, this gets us to the
+// maximum object size of 5TiB right as we hit the part limit.
+const PARTS_PER_SIZE_TIER: i64 = 1_000;
+
+// How much of a spilled part we read back at a time when sending it.
+const SPILL_READ_SIZE: usize = 64 * 1024;
 
 // Uploads a stream of data.
 
@@ -19,7 +39,7 @@ pub trait StreamUploadParts {
     ) -> UploadParts<Self, E>
     where
         Self: Stream<Item = Result<I, E>> + Sized,
-        I: std::borrow::Borrow<[u8]>;
+        I: AsRef<[u8]>;
 }
 
 impl<S> StreamUploadParts for S {
@@ -30,13 +50,155 @@ impl<S> StreamUploadParts for S {
     ) -> UploadParts<Self, E>
     where
         S: Stream<Item = Result<I, E>>,
-        I: std::borrow::Borrow<[u8]>,
+        I: AsRef<[u8]>,
     {
         UploadParts::new(self, part_template, minimum_part_size)
     }
 }
 
+// Data of a part, either held in memory or spilled to a temporary file.
+#[derive(Debug, Clone)]
+pub enum PartData {
+    Memory(Bytes),
+    // The file is removed once the last copy of this is dropped.
+    File(Arc<SpilledPart>),
+}
+
+impl PartData {
+    pub fn len(&self) -> u64 {
+        match self {
+            PartData::Memory(data) => data.len() as u64,
+            PartData::File(spilled) => spilled.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Body to send the data with. Every call gives a fresh body that reads the
+    // data from the start, so this can be used to retry failed uploads. Files
+    // are only opened once the body is first read: failing to read them shows
+    // up as an error from the body.
+    pub fn byte_stream(&self) -> ByteStream {
+        let size_hint = usize::try_from(self.len()).unwrap_or(usize::MAX);
+        match self {
+            PartData::Memory(data) => {
+                let data = data.clone();
+                ByteStream::new_with_size(
+                    futures::stream::once(futures::future::ready(Ok(data))),
+                    size_hint,
+                )
+            }
+            PartData::File(spilled) => {
+                let spilled = Arc::clone(spilled);
+                let chunks = futures::stream::try_unfold(None, move |file| {
+                    let spilled = Arc::clone(&spilled);
+                    async move {
+                        let mut file = match file {
+                            Some(file) => file,
+                            None => tokio::fs::File::open(spilled.path()).await?,
+                        };
+                        let mut chunk = BytesMut::with_capacity(SPILL_READ_SIZE);
+                        if file.read_buf(&mut chunk).await? == 0 {
+                            Ok(None)
+                        } else {
+                            Ok(Some((chunk.freeze(), Some(file))))
+                        }
+                    }
+                });
+                ByteStream::new_with_size(chunks, size_hint)
+            }
+        }
+    }
+}
+
+// Part data that was written out to a temporary file.
+#[derive(Debug)]
+pub struct SpilledPart {
+    file: tempfile::NamedTempFile,
+    len: u64,
+}
+
+impl SpilledPart {
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+// A part ready to be uploaded. Unlike UploadPartRequest, this can be turned
+// into a request as many times as needed.
+#[derive(Debug, Clone)]
+pub struct PreparedPart {
+    pub part_number: i64,
+    pub data: PartData,
+    // Value of the Content-MD5 header to send with the part, if any.
+    pub content_md5: Option<String>,
+    // MD5 of the data, if it was computed while the part was put together.
+    pub md5: Option<[u8; 16]>,
+}
+
+impl PreparedPart {
+    // Request to upload the part with. Everything but the part number, body
+    // and Content-MD5 comes from the template.
+    pub fn request(&self, part_template: &UploadPartRequest) -> UploadPartRequest {
+        UploadPartRequest {
+            body: Some(self.data.byte_stream()),
+            bucket: part_template.bucket.to_owned(),
+            // The body carries a size hint and rusoto fills in content_length
+            // by itself.
+            content_length: None,
+            content_md5: self.content_md5.to_owned(),
+            expected_bucket_owner: part_template.expected_bucket_owner.to_owned(),
+            key: part_template.key.to_owned(),
+            part_number: self.part_number,
+            request_payer: part_template.request_payer.to_owned(),
+            sse_customer_algorithm: part_template.sse_customer_algorithm.to_owned(),
+            sse_customer_key: part_template.sse_customer_key.to_owned(),
+            sse_customer_key_md5: part_template.sse_customer_key_md5.to_owned(),
+            upload_id: part_template.upload_id.to_owned(),
+        }
+    }
+}
+
+// Where and when to spill the part being put together to disk.
+struct Spill<E> {
+    // Spill once more than this many bytes are buffered in memory.
+    threshold: usize,
+    // Directory to put the files in. The system temporary directory if not
+    // set.
+    directory: Option<PathBuf>,
+    // Turns errors writing to the file into the stream error type.
+    io_error: fn(std::io::Error) -> E,
+    // File the current part is being written to, if it got that big.
+    file: Option<std::io::BufWriter<tempfile::NamedTempFile>>,
+    file_len: u64,
+}
+
+impl<E> Spill<E> {
+    fn create_file(&self) -> std::io::Result<tempfile::NamedTempFile> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("zstd-seekable-s3-part-");
+        match &self.directory {
+            Some(directory) => builder.tempfile_in(directory),
+            None => builder.tempfile(),
+        }
+    }
+}
+
 // Chunk into parts for upload.
+//
+// Parts are only ever cut between items of the input stream, never in the
+// middle of one. This means that if each item ends on a frame boundary (see
+// StreamCompress::compress_frame_aligned), no frame straddles two parts.
 pin_project! {
     pub struct UploadParts<S, E> {
         #[pin]
@@ -46,6 +208,16 @@ pin_project! {
         finished: bool,
         part_template: UploadPartRequest,
         minimum_part_size: usize,
+        // Whether to send the MD5 of each part along with it so that S3 can
+        // reject parts that got corrupted on the way.
+        content_md5: bool,
+        // Whether to compute the MD5 of each part, whether it's sent or not.
+        md5: bool,
+        // MD5 of the part so far.
+        md5_context: Option<md5::Context>,
+        // Whether to grow the part size as we make more parts.
+        automatic_part_sizing: bool,
+        spill: Option<Spill<E>>,
         error_type: PhantomData<E>,
     }
 }
@@ -62,10 +234,108 @@ impl<S, E> UploadParts<S, E> {
             finished: false,
             part_template,
             minimum_part_size,
+            content_md5: false,
+            md5: false,
+            md5_context: None,
+            automatic_part_sizing: false,
+            spill: None,
             error_type: PhantomData,
         }
     }
 
+    // Computes the Content-MD5 of each part and sets it on the request. S3
+    // verifies the parts against these and refuses to store corrupted data.
+    //
+    // Newer checksum algorithms (CRC32C, SHA256) can't be set as the request
+    // types we use don't know about them.
+    pub fn with_content_md5(mut self) -> Self {
+        self.content_md5 = true;
+        self.md5 = true;
+        self
+    }
+
+    // Computes the MD5 of each part (see PreparedPart::md5) without sending it
+    // to S3.
+    pub fn with_md5(mut self) -> Self {
+        self.md5 = true;
+        self
+    }
+
+    // Doubles the minimum part size every thousand parts. When we don't know
+    // how much data we're going to upload, this lets us keep parts small for
+    // small uploads while never running into the 10,000 part limit for huge
+    // ones.
+    pub fn with_automatic_part_sizing(mut self) -> Self {
+        self.automatic_part_sizing = true;
+        self
+    }
+
+    // Writes the part being put together out to a temporary file once more
+    // than threshold bytes of it are buffered, rather than keeping all of it
+    // in memory. Useful with big part sizes, which automatic part sizing gets
+    // to for big uploads. The files are removed once the parts made from them
+    // are dropped.
+    //
+    // Writing to the file happens on whatever thread polls the stream.
+    pub fn with_disk_spill(mut self, threshold: usize, directory: Option<PathBuf>) -> Self
+    where
+        E: From<std::io::Error>,
+    {
+        self.spill = Some(Spill {
+            threshold,
+            directory,
+            io_error: E::from,
+            file: None,
+            file_len: 0,
+        });
+        self
+    }
+
+    pub fn part_template(&self) -> &UploadPartRequest {
+        &self.part_template
+    }
+
+    // How many parts we've made so far.
+    pub fn parts_emitted(&self) -> u64 {
+        (self.next_part_number - 1) as u64
+    }
+
+    // Number the next part we make gets.
+    pub fn next_part_number(&self) -> i64 {
+        self.next_part_number
+    }
+
+    // How big the part being put together has to get before we cut it.
+    pub fn current_part_size(&self) -> usize {
+        self.current_minimum_part_size()
+    }
+
+    // The parts themselves rather than requests for them.
+    pub fn prepared_parts(self) -> PreparedParts<S, E> {
+        PreparedParts { inner: self }
+    }
+
+    // How big the next part has to be before we cut it.
+    fn current_minimum_part_size(&self) -> usize {
+        if !self.automatic_part_sizing {
+            return self.minimum_part_size;
+        }
+        let tier = ((self.next_part_number - 1) / PARTS_PER_SIZE_TIER).clamp(0, 63) as u32;
+        let part_size = (self.minimum_part_size as u64)
+            .checked_mul(1 << tier)
+            .unwrap_or(MAXIMUM_PART_SIZE)
+            .min(MAXIMUM_PART_SIZE)
+            // Never go below what we were asked for.
+            .max(self.minimum_part_size as u64);
+        usize::try_from(part_size).unwrap_or(usize::MAX)
+    }
+
+    // How much data we have for the current part, whether it's in memory or
+    // was spilled to disk.
+    pub fn buffered_bytes(&self) -> u64 {
+        self.input.len() as u64 + self.spill.as_ref().map_or(0, |spill| spill.file_len)
+    }
+
     fn next_input(
         self: &mut Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -76,94 +346,131 @@ impl<S, E> UploadParts<S, E> {
         self.as_mut().project().stream.poll_next(cx)
     }
 
-    // Makes a part from internal buffer. Doesn't do any checks about whether we
-    // have any data.
-    //
-    // Kind of inefficient if we read big chunks as we go through an extra buffer. Fix later.
-    fn part_from_buffer(self: &mut Pin<&mut Self>) -> UploadPartRequest {
+    // Makes a part from whatever we have buffered. Doesn't do any checks about
+    // whether we have any data.
+    fn take_part(self: &mut Pin<&mut Self>) -> Result<PreparedPart, E> {
         let this = self.as_mut().project();
-        let buffer: &mut BytesMut = this.input;
-        let part_template: &UploadPartRequest = this.part_template;
-        let req = UploadPartRequest {
-            body: Some(ByteStream::from(Vec::from(&buffer[..]))),
-            bucket: part_template.bucket.to_owned(),
-            // As we're going through Vec in body, the size hint is set and
-            // rusoto fills in content_length by itself.
-            content_length: None,
-            content_md5: part_template.content_md5.to_owned(),
-            expected_bucket_owner: part_template.expected_bucket_owner.to_owned(),
-            key: part_template.key.to_owned(),
+        let spilled = match this.spill {
+            Some(spill) => match spill.file.take() {
+                Some(file) => Some(SpilledPart {
+                    file: file
+                        .into_inner()
+                        .map_err(|e| (spill.io_error)(e.into_error()))?,
+                    len: std::mem::take(&mut spill.file_len),
+                }),
+                None => None,
+            },
+            None => None,
+        };
+        let data = match spilled {
+            Some(spilled) => PartData::File(Arc::new(spilled)),
+            // Future inputs fill the buffer from the start.
+            None => PartData::Memory(this.input.split().freeze()),
+        };
+        let md5 = this.md5_context.take().map(|context| context.compute().0);
+        let content_md5 = if *this.content_md5 {
+            md5.map(base64::encode)
+        } else {
+            this.part_template.content_md5.to_owned()
+        };
+        let part = PreparedPart {
             part_number: *this.next_part_number,
-            request_payer: part_template.request_payer.to_owned(),
-            sse_customer_algorithm: part_template.sse_customer_algorithm.to_owned(),
-            sse_customer_key: part_template.sse_customer_key.to_owned(),
-            sse_customer_key_md5: part_template.sse_customer_key_md5.to_owned(),
-            upload_id: part_template.upload_id.to_owned(),
+            data,
+            content_md5,
+            md5,
         };
         // Next part we make should have new number.
         *this.next_part_number += 1;
-        // Clear the buffer, we copied the data we wanted now and future inputs
-        // should fill it from the start.
-        buffer.clear();
-        req
+        Ok(part)
     }
 
-    fn accept_input(self: &mut Pin<&mut Self>, input: &[u8]) -> Option<UploadPartRequest> {
+    // Adds the input to the part being put together, spilling it to disk if
+    // it's getting too big.
+    fn buffer_input(self: &mut Pin<&mut Self>, input: &[u8]) -> Result<(), E> {
         let this = self.as_mut().project();
+        if *this.md5 {
+            this.md5_context
+                .get_or_insert_with(md5::Context::new)
+                .consume(input);
+        }
         let buffer: &mut BytesMut = this.input;
-        buffer.put(input);
+        let spill = match this.spill {
+            None => {
+                buffer.put(input);
+                return Ok(());
+            }
+            Some(spill) => spill,
+        };
+        let io_error = spill.io_error;
+        if spill.file.is_none() {
+            if buffer.len() + input.len() <= spill.threshold {
+                buffer.put(input);
+                return Ok(());
+            }
+            // Move what we buffered so far over to the file.
+            let mut file = std::io::BufWriter::new(spill.create_file().map_err(io_error)?);
+            file.write_all(&buffer[..]).map_err(io_error)?;
+            spill.file_len = buffer.len() as u64;
+            buffer.clear();
+            spill.file = Some(file);
+        }
+        if let Some(file) = &mut spill.file {
+            file.write_all(input).map_err(io_error)?;
+            spill.file_len += input.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn accept_input(self: &mut Pin<&mut Self>, input: &[u8]) -> Result<Option<PreparedPart>, E> {
+        let minimum_part_size = self.current_minimum_part_size();
+        self.buffer_input(input)?;
         // After combining whatever we had with new input, if we have enough for a part, yield one.
-        if buffer.len() >= *this.minimum_part_size {
-            Some(self.part_from_buffer())
+        if self.buffered_bytes() >= minimum_part_size as u64 {
+            self.take_part().map(Some)
         } else {
-            None
+            Ok(None)
         }
     }
 
     // Create last chunk if we have any data buffered and send completion
     // message.
-    fn end_stream(self: &mut Pin<&mut Self>) -> Option<UploadPartRequest> {
-        let this = self.as_mut().project();
-        *this.finished = true;
+    fn end_stream(self: &mut Pin<&mut Self>) -> Result<Option<PreparedPart>, E> {
+        *self.as_mut().project().finished = true;
 
-        let buffer: &BytesMut = this.input;
         // Last chunk has no size restrictions.
-        if !buffer.is_empty() {
-            Some(self.part_from_buffer())
+        if self.buffered_bytes() > 0 {
+            self.take_part().map(Some)
         } else {
-            None
+            Ok(None)
         }
     }
 
     fn finished(self: &mut Pin<&mut Self>) -> bool {
         *self.as_mut().project().finished
     }
-}
 
-impl<S, I, E> Stream for UploadParts<S, E>
-where
-    S: Stream<Item = Result<I, E>>,
-    I: std::borrow::Borrow<[u8]>,
-{
-    type Item = Result<UploadPartRequest, E>;
-
-    fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
+    fn poll_part<I>(
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Self::Item>> {
+    ) -> std::task::Poll<Option<Result<PreparedPart, E>>>
+    where
+        S: Stream<Item = Result<I, E>>,
+        I: AsRef<[u8]>,
+    {
         if self.finished() {
             return std::task::Poll::Ready(None);
         }
 
         std::task::Poll::Ready(loop {
             match ready!(self.next_input(cx)) {
-                None => break self.end_stream().map(Ok),
+                None => break self.end_stream().transpose(),
                 Some(Err(e)) => break Some(Err(e)),
                 Some(Ok(bytes)) => {
                     // If we had enough input for a part, yield it. Otherwise we
                     // loop to accept more input.
-                    if let Some(part) = self.accept_input(bytes.borrow()) {
-                        break Some(Ok(part));
+                    match self.accept_input(bytes.as_ref()) {
+                        Ok(None) => {}
+                        result => break result.transpose(),
                     }
                 }
             }
@@ -171,12 +478,71 @@ where
     }
 }
 
+impl<S, I, E> Stream for UploadParts<S, E>
+where
+    S: Stream<Item = Result<I, E>>,
+    I: AsRef<[u8]>,
+{
+    type Item = Result<UploadPartRequest, E>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let part = ready!(self.as_mut().poll_part(cx));
+        std::task::Poll::Ready(part.map(|part| part.map(|part| part.request(&self.part_template))))
+    }
+}
+
 impl<S, I, E> FusedStream for UploadParts<S, E>
 where
     S: Stream<Item = Result<I, E>>,
-    I: std::borrow::Borrow<[u8]>,
+    I: AsRef<[u8]>,
 {
     fn is_terminated(&self) -> bool {
         self.finished
     }
 }
+
+pin_project! {
+    // Stream of the parts UploadParts makes, see UploadParts::prepared_parts.
+    pub struct PreparedParts<S, E> {
+        #[pin]
+        inner: UploadParts<S, E>,
+    }
+}
+
+impl<S, E> PreparedParts<S, E> {
+    pub fn part_template(&self) -> &UploadPartRequest {
+        &self.inner.part_template
+    }
+
+    pub fn get_ref(&self) -> &UploadParts<S, E> {
+        &self.inner
+    }
+}
+
+impl<S, I, E> Stream for PreparedParts<S, E>
+where
+    S: Stream<Item = Result<I, E>>,
+    I: AsRef<[u8]>,
+{
+    type Item = Result<PreparedPart, E>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.project().inner.poll_part(cx)
+    }
+}
+
+impl<S, I, E> FusedStream for PreparedParts<S, E>
+where
+    S: Stream<Item = Result<I, E>>,
+    I: AsRef<[u8]>,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.finished
+    }
+}
@@ -0,0 +1,165 @@
+use bytes::Bytes;
+use futures::{channel::mpsc, ready, Future, Sink};
+use rusoto_s3::{CompleteMultipartUploadOutput, S3};
+use std::{
+    io::{Error, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{upload_compressed_object, UploadError, UploadOptions};
+
+type UploadResult = Result<CompleteMultipartUploadOutput, UploadError<Error>>;
+
+// Sink that compresses everything sent into it and uploads it to S3. This is
+// the push-based counterpart of upload_compressed_object: the upload itself
+// runs as a tokio task, the sink just feeds it data. Closing the sink finishes
+// the upload and waits for it to complete. Dropping it without closing it, or
+// calling abort, aborts the upload instead: only closing ends the data.
+//
+// The sink has to be created from within a tokio runtime, creating it
+// anywhere else fails with UploadError::NoRuntime.
+#[derive(Debug)]
+pub struct UploadSink {
+    // Gone once the sink is closed or the upload stopped accepting data.
+    sender: Option<mpsc::Sender<Result<Bytes, Error>>>,
+    // Gone once we've seen the upload finish.
+    upload: Option<tokio::task::JoinHandle<UploadResult>>,
+    output: Option<CompleteMultipartUploadOutput>,
+}
+
+impl UploadSink {
+    pub fn new<C>(
+        client: C,
+        bucket: String,
+        key: String,
+        options: UploadOptions,
+    ) -> Result<Self, UploadError<Error>>
+    where
+        C: S3 + Clone + Send + Sync + 'static,
+    {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_e| UploadError::NoRuntime)?;
+        // A bit of buffering lets the producer get ahead of the compression
+        // a little.
+        let (sender, receiver) = mpsc::channel(16);
+        let upload = runtime.spawn(async move {
+            upload_compressed_object(&client, &bucket, &key, receiver, &options).await
+        });
+        Ok(UploadSink {
+            sender: Some(sender),
+            upload: Some(upload),
+            output: None,
+        })
+    }
+
+    // What S3 told us when the upload completed. Only available once the sink
+    // was closed successfully.
+    pub fn output(&self) -> Option<&CompleteMultipartUploadOutput> {
+        self.output.as_ref()
+    }
+
+    // Gives up on the upload, aborting it rather than completing it with what
+    // was sent so far. The abort itself happens in the background.
+    pub fn abort(self) {
+        drop(self)
+    }
+
+    // Waits for the upload task to finish, remembering its output.
+    fn poll_upload(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), UploadError<Error>>> {
+        let upload = match &mut self.upload {
+            None => return Poll::Ready(Ok(())),
+            Some(upload) => upload,
+        };
+        let result = ready!(Pin::new(upload).poll(cx));
+        self.upload = None;
+        Poll::Ready(match result {
+            Ok(Ok(output)) => {
+                self.output = Some(output);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e),
+            // The upload task panicked or was cancelled.
+            Err(join_error) => Err(UploadError::Underlying(Error::new(
+                ErrorKind::Other,
+                join_error,
+            ))),
+        })
+    }
+
+    // The upload is no longer taking data: it must have failed. Find out how.
+    fn poll_upload_failure(&mut self, cx: &mut Context<'_>) -> Poll<UploadError<Error>> {
+        self.sender = None;
+        Poll::Ready(match ready!(self.poll_upload(cx)) {
+            Err(e) => e,
+            Ok(()) => closed_error(),
+        })
+    }
+}
+
+fn closed_error() -> UploadError<Error> {
+    UploadError::Underlying(Error::new(
+        ErrorKind::BrokenPipe,
+        "upload sink is already closed",
+    ))
+}
+
+impl Sink<Bytes> for UploadSink {
+    type Error = UploadError<Error>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let sender = match &mut self.sender {
+            None => return Poll::Ready(Err(closed_error())),
+            Some(sender) => sender,
+        };
+        match ready!(sender.poll_ready(cx)) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(_) => self.poll_upload_failure(cx).map(Err),
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        let sender = match &mut self.sender {
+            None => return Err(closed_error()),
+            Some(sender) => sender,
+        };
+        // If the upload went away in the meantime, we find out why on the next
+        // poll: the sender stays so that it tells us the upload is gone.
+        let _ = sender.start_send(Ok(item));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Flushing only means handing the data to the upload task: we can't
+        // have anything reach S3 before we have enough data for a part.
+        match &mut self.sender {
+            Some(sender) => match ready!(Pin::new(sender).poll_flush(cx)) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(_) => self.poll_upload_failure(cx).map(Err),
+            },
+            None => match ready!(self.poll_upload(cx)) {
+                // Upload already finished successfully, nothing to flush.
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(e) => Poll::Ready(Err(e)),
+            },
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Dropping the sender ends the input stream which finishes the upload.
+        self.sender = None;
+        self.poll_upload(cx)
+    }
+}
+
+impl Drop for UploadSink {
+    // The sender going away would end the data as if we'd closed the sink, and
+    // the upload would complete with whatever it got. Cancelling the task
+    // first drops its MultipartUpload, which aborts the upload.
+    fn drop(&mut self) {
+        if self.sender.is_some() {
+            if let Some(upload) = &self.upload {
+                upload.abort();
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use parking_lot::Mutex;
+use rusoto_s3::{GetObjectRequest, S3};
+use std::fmt::Display;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::mpsc;
+use std::sync::Arc;
+use zstd_seekable::DStream;
+
+use crate::index_frame::EMPTY_CHECKSUM;
+use crate::repair::{frame_decompressed_size, parse_frame, Parsed};
+use crate::{fetch_object_index, fetch_ranges, read_frame_index};
+use crate::{FetchRangesOptions, FrameEntry, ReadObjectError};
+
+// How much compressed data verify_object holds in memory at once.
+const VERIFY_BATCH_SIZE: u64 = 64 * 1024 * 1024;
+
+// What's wrong with a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameProblem {
+    // The data ends before the frame does.
+    Truncated,
+    // Neither a zstd frame nor a skippable one.
+    BadMagic,
+    // The frame ends somewhere other than where the seek table says, or
+    // doesn't end at all if there's no actual size.
+    CompressedSizeMismatch { expected: u64, actual: Option<u64> },
+    DecompressedSizeMismatch { expected: u64, actual: u64 },
+    // The checksum stored in the frame isn't the one in the seek table.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    // The frame doesn't decompress, which includes its data not matching its
+    // own checksum.
+    Corrupt,
+}
+
+impl Display for FrameProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameProblem::Truncated => write!(f, "Frame is truncated."),
+            FrameProblem::BadMagic => write!(f, "Frame magic number mismatch."),
+            FrameProblem::CompressedSizeMismatch {
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "Expected a frame of {} bytes, found {} bytes",
+                expected, actual
+            ),
+            FrameProblem::CompressedSizeMismatch {
+                expected,
+                actual: None,
+            } => write!(f, "Frame runs past the {} bytes it should be", expected),
+            FrameProblem::DecompressedSizeMismatch { expected, actual } => write!(
+                f,
+                "Expected frame to decompress to {} bytes, got {} bytes",
+                expected, actual
+            ),
+            FrameProblem::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Expected checksum {:08x}, frame has {:08x}",
+                expected, actual
+            ),
+            FrameProblem::Corrupt => write!(f, "Frame doesn't decompress."),
+        }
+    }
+}
+
+// How a single frame fared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameVerification {
+    // Number of the frame in the seek table.
+    pub frame: usize,
+    pub problem: Option<FrameProblem>,
+    // Whether the decompressed data was checked against a checksum, which
+    // zstd only does for frames that carry their own.
+    pub checksum_verified: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    // One for every frame in the seek table, in order.
+    pub frames: Vec<FrameVerification>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.frames.iter().all(|frame| frame.problem.is_none())
+    }
+
+    pub fn problems(&self) -> impl Iterator<Item = &FrameVerification> {
+        self.frames.iter().filter(|frame| frame.problem.is_some())
+    }
+}
+
+// Checks the compressed data of a frame against its entry in the seek table,
+// decompressing it and throwing the data away.
+pub fn verify_frame(frame: usize, entry: &FrameEntry, data: &[u8]) -> FrameVerification {
+    let verification = |problem, checksum_verified| FrameVerification {
+        frame,
+        problem,
+        checksum_verified,
+    };
+    if (data.len() as u64) < entry.compressed_size {
+        return verification(Some(FrameProblem::Truncated), false);
+    }
+    let size_mismatch = |actual| {
+        verification(
+            Some(FrameProblem::CompressedSizeMismatch {
+                expected: entry.compressed_size,
+                actual,
+            }),
+            false,
+        )
+    };
+    match parse_frame(data) {
+        Parsed::Corrupt => verification(Some(FrameProblem::BadMagic), false),
+        Parsed::Incomplete => size_mismatch(None),
+        Parsed::Skippable { size, .. } => {
+            if size != data.len() as u64 {
+                return size_mismatch(Some(size));
+            }
+            if entry.decompressed_size != 0 {
+                let problem = FrameProblem::DecompressedSizeMismatch {
+                    expected: entry.decompressed_size,
+                    actual: 0,
+                };
+                return verification(Some(problem), false);
+            }
+            match entry.checksum {
+                Some(expected) if expected != EMPTY_CHECKSUM => {
+                    let problem = FrameProblem::ChecksumMismatch {
+                        expected,
+                        actual: EMPTY_CHECKSUM,
+                    };
+                    verification(Some(problem), false)
+                }
+                _ => verification(None, false),
+            }
+        }
+        Parsed::Frame { size, checksum, .. } => {
+            if size as u64 != data.len() as u64 {
+                return size_mismatch(Some(size as u64));
+            }
+            if let (Some(expected), Some(actual)) = (entry.checksum, checksum) {
+                if expected != actual {
+                    let problem = FrameProblem::ChecksumMismatch { expected, actual };
+                    return verification(Some(problem), false);
+                }
+            }
+            let decompressed_size = DStream::new().ok().and_then(|mut dstream| {
+                frame_decompressed_size(&mut dstream, &mut Vec::new(), data)
+            });
+            match decompressed_size {
+                None => verification(Some(FrameProblem::Corrupt), false),
+                Some(actual) if actual != entry.decompressed_size => {
+                    let problem = FrameProblem::DecompressedSizeMismatch {
+                        expected: entry.decompressed_size,
+                        actual,
+                    };
+                    verification(Some(problem), checksum.is_some())
+                }
+                Some(_) => verification(None, checksum.is_some()),
+            }
+        }
+    }
+}
+
+// Checks every frame of the compressed data against the seek table, see
+// verify_frame. Frames are read one after the other and decompressed on
+// a number of threads at once.
+pub fn verify<R: Read + Seek>(
+    mut compressed: R,
+    concurrency: usize,
+) -> std::io::Result<VerifyReport> {
+    let index = read_frame_index(&mut compressed)?;
+    let concurrency = std::cmp::max(concurrency, 1);
+    let (job_sender, jobs) = mpsc::sync_channel::<(usize, FrameEntry, Vec<u8>)>(concurrency);
+    let jobs = Arc::new(Mutex::new(jobs));
+    let (result_sender, results) = mpsc::channel();
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let jobs = jobs.clone();
+            let results = result_sender.clone();
+            std::thread::spawn(move || loop {
+                let job = jobs.lock().recv();
+                match job {
+                    Ok((frame, entry, data)) => {
+                        let _ = results.send(verify_frame(frame, &entry, &data));
+                    }
+                    Err(_) => break,
+                }
+            })
+        })
+        .collect();
+    drop(result_sender);
+
+    let mut read_frames = || -> std::io::Result<()> {
+        for (frame, entry) in index.frames().iter().enumerate() {
+            compressed.seek(SeekFrom::Start(entry.compressed_offset))?;
+            let mut data = Vec::with_capacity(entry.compressed_size as usize);
+            (&mut compressed)
+                .take(entry.compressed_size)
+                .read_to_end(&mut data)?;
+            // The workers only go away if one of them panicked.
+            if job_sender.send((frame, *entry, data)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    };
+    let read = read_frames();
+    drop(job_sender);
+    let mut frames: Vec<FrameVerification> = results.iter().collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    read?;
+    frames.sort_by_key(|frame| frame.frame);
+    Ok(VerifyReport { frames })
+}
+
+// Checks every frame of an object against its seek table, see verify_frame,
+// without keeping any of the decompressed data. Frames are fetched in batches,
+// with as many requests in flight as the options allow, and decompressed on
+// tokio's blocking threads.
+pub async fn verify_object<C: S3>(
+    client: &C,
+    req: &GetObjectRequest,
+    options: &FetchRangesOptions,
+) -> Result<VerifyReport, ReadObjectError> {
+    let object_index = fetch_object_index(client, req).await?;
+    let frames = object_index.index.frames();
+    let mut report = VerifyReport::default();
+    let mut start = 0;
+    while start < frames.len() {
+        let mut end = start;
+        let mut batch_size = 0;
+        while end < frames.len() && (end == start || batch_size < VERIFY_BATCH_SIZE) {
+            batch_size += frames[end].compressed_size;
+            end += 1;
+        }
+        let ranges = (start..end)
+            .map(|frame| {
+                let entry = &frames[frame];
+                let range =
+                    entry.compressed_offset..entry.compressed_offset + entry.compressed_size;
+                (frame, range)
+            })
+            .collect();
+        let fetched = fetch_ranges(client, req, ranges, options).await?;
+        let checks = fetched.into_iter().map(|fetched| {
+            let entry = frames[fetched.label];
+            tokio::task::spawn_blocking(move || verify_frame(fetched.label, &entry, &fetched.data))
+        });
+        for check in futures::future::join_all(checks).await {
+            let check = check.map_err(|e| {
+                ReadObjectError::ReadBody(std::io::Error::new(std::io::ErrorKind::Other, e))
+            })?;
+            report.frames.push(check);
+        }
+        start = end;
+    }
+    Ok(report)
+}
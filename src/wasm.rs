@@ -0,0 +1,242 @@
+use js_sys::{Promise, Uint8Array};
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use web_sys::{Request, RequestInit, Response, WorkerGlobalScope};
+use zstd_seekable::DStream;
+
+use crate::content_range::total_size;
+use crate::decompress::decompress_frame;
+use crate::{seek_table_size, FrameIndex, SEEK_TABLE_FOOTER_SIZE};
+
+// Reading seekable objects from the browser, built with the wasm feature for
+// wasm32 targets. Data is fetched with range requests against a URL, usually
+// one presigned for GetObject, so the page never holds AWS credentials. The
+// bucket's CORS configuration has to allow the Range header and expose
+// Content-Range.
+
+fn js_error(e: JsValue) -> Error {
+    let message = e
+        .dyn_ref::<js_sys::Error>()
+        .map(|e| String::from(e.message()))
+        .or_else(|| e.as_string())
+        .unwrap_or_else(|| format!("{:?}", e));
+    Error::new(ErrorKind::Other, message)
+}
+
+fn to_js_error(e: Error) -> JsValue {
+    js_sys::Error::new(&e.to_string()).into()
+}
+
+// fetch of the page or of the worker we're running in.
+fn fetch(request: &Request) -> std::io::Result<Promise> {
+    if let Some(window) = web_sys::window() {
+        return Ok(window.fetch_with_request(request));
+    }
+    match js_sys::global().dyn_into::<WorkerGlobalScope>() {
+        Ok(worker) => Ok(worker.fetch_with_request(request)),
+        Err(_) => Err(Error::new(ErrorKind::Other, "No fetch in this context")),
+    }
+}
+
+// Object read with fetch range requests. The URL can be swapped for a fresh
+// one when a presigned URL is about to expire; the object behind it has to
+// stay the same.
+#[derive(Debug, Clone)]
+pub struct FetchObject {
+    url: String,
+    length: u64,
+    // Shared between the clones reads work on.
+    index: Rc<FrameIndex>,
+}
+
+impl FetchObject {
+    // Fetches the seek table of the object at the URL: the footer first to
+    // find out how big the table is, then the rest of it.
+    pub async fn open(url: String) -> std::io::Result<Self> {
+        let (footer, content_range) =
+            fetch_range(&url, &format!("bytes=-{}", SEEK_TABLE_FOOTER_SIZE)).await?;
+        let length = content_range
+            .as_deref()
+            .and_then(total_size)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No Content-Range in response"))?;
+        let table_size = seek_table_size(&footer)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        if table_size > length {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Seek table is truncated.",
+            ));
+        }
+        let (table, _) = fetch_range(&url, &format!("bytes=-{}", table_size)).await?;
+        let index = FrameIndex::from_seek_table(&table)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        if index.compressed_size().checked_add(table_size) != Some(length) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Frames and seek table don't add up to the object size",
+            ));
+        }
+        Ok(FetchObject {
+            url,
+            length,
+            index: Rc::new(index),
+        })
+    }
+
+    pub fn set_url(&mut self, url: String) {
+        self.url = url;
+    }
+
+    pub fn index(&self) -> &FrameIndex {
+        &self.index
+    }
+
+    // Size of the compressed object.
+    pub fn compressed_len(&self) -> u64 {
+        self.length
+    }
+
+    // Size of the decompressed data.
+    pub fn len(&self) -> u64 {
+        self.index.decompressed_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Reads the given range of the decompressed data, cut short at the end of
+    // it, fetching just the frames the range touches in a single request.
+    pub async fn read_range(&self, range: Range<u64>) -> std::io::Result<Vec<u8>> {
+        let range = range.start..std::cmp::min(range.end, self.len());
+        let compressed_range = match self.index.compressed_range(range.clone()) {
+            Some(compressed_range) => compressed_range,
+            None => return Ok(Vec::new()),
+        };
+        let (compressed, _) = fetch_range(
+            &self.url,
+            &format!(
+                "bytes={}-{}",
+                compressed_range.start,
+                compressed_range.end - 1
+            ),
+        )
+        .await?;
+        if (compressed.len() as u64) < compressed_range.end - compressed_range.start {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Short range response"));
+        }
+        let mut dstream =
+            DStream::new().map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        let mut out = Vec::with_capacity((range.end - range.start) as usize);
+        for entry in self.index.frames() {
+            let frame_end = entry.decompressed_offset + entry.decompressed_size;
+            if entry.decompressed_size == 0
+                || frame_end <= range.start
+                || entry.decompressed_offset >= range.end
+            {
+                continue;
+            }
+            let start = (entry.compressed_offset - compressed_range.start) as usize;
+            let data = &compressed[start..start + entry.compressed_size as usize];
+            let frame = decompress_frame(&mut dstream, data)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Frame doesn't decompress."))?;
+            let from = range.start.saturating_sub(entry.decompressed_offset) as usize;
+            let to = (std::cmp::min(range.end, frame_end) - entry.decompressed_offset) as usize;
+            let frame = frame.get(from..to).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "Frame is shorter than its seek table entry",
+                )
+            })?;
+            out.extend_from_slice(frame);
+        }
+        Ok(out)
+    }
+}
+
+// Gets the given range of the URL, returning the data along with the
+// Content-Range of the response.
+async fn fetch_range(url: &str, range: &str) -> std::io::Result<(Vec<u8>, Option<String>)> {
+    let mut init = RequestInit::new();
+    init.method("GET");
+    let request = Request::new_with_str_and_init(url, &init).map_err(js_error)?;
+    request.headers().set("Range", range).map_err(js_error)?;
+    let response: Response = JsFuture::from(fetch(&request)?)
+        .await
+        .map_err(js_error)?
+        .dyn_into()
+        .map_err(js_error)?;
+    if !response.ok() {
+        let kind = match response.status() {
+            403 => ErrorKind::PermissionDenied,
+            404 => ErrorKind::NotFound,
+            _ => ErrorKind::Other,
+        };
+        return Err(Error::new(
+            kind,
+            format!("Fetch responded with {}", response.status()),
+        ));
+    }
+    let content_range = response.headers().get("content-range").map_err(js_error)?;
+    let body = JsFuture::from(response.array_buffer().map_err(js_error)?)
+        .await
+        .map_err(js_error)?;
+    Ok((Uint8Array::new(&body).to_vec(), content_range))
+}
+
+// JavaScript face of FetchObject:
+//
+//     const reader = await SeekableReader.open(presignedUrl);
+//     const bytes = await reader.read(offset, length);
+#[wasm_bindgen(js_name = SeekableReader)]
+pub struct JsSeekableReader {
+    // Shared with the promises of reads still going on.
+    inner: Rc<RefCell<FetchObject>>,
+}
+
+#[wasm_bindgen(js_class = SeekableReader)]
+impl JsSeekableReader {
+    // Resolves to a reader once the seek table is fetched.
+    pub fn open(url: String) -> Promise {
+        future_to_promise(async move {
+            let object = FetchObject::open(url).await.map_err(to_js_error)?;
+            Ok(JsSeekableReader {
+                inner: Rc::new(RefCell::new(object)),
+            }
+            .into())
+        })
+    }
+
+    // Size of the decompressed data, as a double since that's what
+    // JavaScript numbers are.
+    pub fn length(&self) -> f64 {
+        self.inner.borrow().len() as f64
+    }
+
+    #[wasm_bindgen(js_name = setUrl)]
+    pub fn set_url(&self, url: String) {
+        self.inner.borrow_mut().set_url(url);
+    }
+
+    // Resolves to a Uint8Array with up to length bytes from the offset.
+    pub fn read(&self, offset: f64, length: f64) -> Promise {
+        // Reads see the URL as it was when they started.
+        let object = self.inner.borrow().clone();
+        future_to_promise(async move {
+            if !(offset >= 0.0 && length >= 0.0) {
+                return Err(js_sys::Error::new("Negative offset or length.").into());
+            }
+            let start = offset as u64;
+            let data = object
+                .read_range(start..start.saturating_add(length as u64))
+                .await
+                .map_err(to_js_error)?;
+            Ok(Uint8Array::from(&data[..]).into())
+        })
+    }
+}